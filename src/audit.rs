@@ -0,0 +1,55 @@
+//! Proofs that a peer still holds the data that it was given.
+//!
+//! An auditor challenges a holder to hash a range of the data along with a nonce of the auditor's choosing. Nonces are
+//! never reused, so a holder can't answer from hashes it worked out before throwing the data away. The auditor checks
+//! the answer against its own, which it works out ahead of time while it still has the data to hand, so that it
+//! needn't keep a copy of everything it audits.
+//!
+//! The answer is the digest, made with the algorithm of the data's tag, of the bytes in the range followed by the
+//! nonce as 8 big-endian bytes.
+
+use crate::{HashAlgorithm, Tag};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// The most bytes that a challenge asks the holder to hash (64 KiB), which keeps audits cheap to answer.
+pub const MAX_RANGE: u64 = 64 * 1024;
+
+/// A demand to prove that a range of some data is held.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Challenge {
+    pub offset: u64,
+    pub len: u64,
+    pub nonce: u64,
+}
+
+impl Challenge {
+    /// A challenge over a random range of data `data_len` bytes long, no longer than [`MAX_RANGE`].
+    pub fn random<R: Rng + ?Sized>(rng: &mut R, data_len: usize) -> Self {
+        let data_len = data_len as u64;
+        // An empty range could be answered without the data, so ranges are only empty when the data is
+        let (offset, len) = if data_len == 0 {
+            (0, 0)
+        } else {
+            let offset = rng.gen_range(0..data_len);
+            (
+                offset,
+                rng.gen_range(1..=(data_len - offset).min(MAX_RANGE)),
+            )
+        };
+        Self {
+            offset,
+            len,
+            nonce: rng.gen(),
+        }
+    }
+
+    /// The answer to the challenge for `data`, tagged with `algorithm`, or `None` if the range lies outside of it.
+    pub fn answer(&self, algorithm: HashAlgorithm, data: &[u8]) -> Option<Tag> {
+        let start = usize::try_from(self.offset).ok()?;
+        let end = start.checked_add(usize::try_from(self.len).ok()?)?;
+        let mut bytes = data.get(start..end)?.to_vec();
+        bytes.extend_from_slice(&self.nonce.to_be_bytes());
+        Some(Tag::digest_with(algorithm, &bytes))
+    }
+}
@@ -1,45 +1,129 @@
+#[cfg(any(feature = "http", feature = "wasm-client"))]
+pub mod client;
+#[cfg(feature = "http")]
 pub mod http;
 pub mod mem;
 
-use crate::{Node, PublicId, Tag};
+use crate::{
+    audit::Challenge,
+    msg::{Greet, Pong, SyncReply},
+    sync::{Region, Summary},
+    GreetRefusal, GreetReply, LocateReply, Node, NodeStats, ProtocolError, PublicId, Signature,
+    Signed, SignedAddr, Tag,
+};
 
-use std::{error, fmt, hash::Hash, sync::Arc, time::Duration};
+use bytes::Bytes;
+use serde::Serialize;
+
+use std::{error, fmt, hash::Hash, net::IpAddr, sync::Arc, time::Duration};
 
 #[async_trait::async_trait]
-pub trait Backend: Sized + Sync + 'static {
-    type Addr: Clone + Hash + Eq + fmt::Debug;
+pub trait Backend: Sized + Send + Sync + 'static {
+    type Addr: Clone + Hash + Eq + fmt::Debug + Serialize + Send + Sync;
     type Config;
     type Error: error::Error + Send + Sync;
 
+    /// Whether other nodes can reach us. Data is never placed with a node that can't be reached, so such a node looks
+    /// for data with others even if it's closer to it than they are.
+    const REACHABLE: bool = true;
+
     async fn create(config: Self::Config) -> Result<Self, Self::Error>;
+    /// The canonical form of an address, so that addresses that reach the same node compare equal. Every address is
+    /// put in this form as it enters a node. By default, addresses are already canonical.
+    fn canonicalise(addr: Self::Addr) -> Self::Addr {
+        addr
+    }
+    /// The address that `addr` becomes if our public IP address changes to `ip`, or `None` if it doesn't depend on our
+    /// IP address, or the backend has no such concept.
+    fn with_ip(_addr: &Self::Addr, _ip: IpAddr) -> Option<Self::Addr> {
+        None
+    }
     async fn init(&self, _node: &Arc<Node<Self>>) {}
+    /// Start answering at `addr`, which we're now known by in place of our previous address.
+    fn rebind(&self, _addr: &Self::Addr) {}
     async fn host(node: Arc<Node<Self>>) -> Result<(), Self::Error>;
 
     async fn send_greet(
         &self,
         addr: &Self::Addr,
-        sender: (PublicId, Self::Addr),
-    ) -> Result<Result<PublicId, Option<Self::Addr>>, Self::Error>;
-    async fn send_ping(&self, addr: &Self::Addr) -> Result<Duration, Self::Error>;
+        greet: Greet<Self::Addr>,
+    ) -> Result<Result<GreetReply, GreetRefusal<Self::Addr>>, Self::Error>;
+    async fn send_prove(
+        &self,
+        addr: &Self::Addr,
+        id: PublicId,
+        proof: Signature,
+    ) -> Result<bool, Self::Error>;
+    /// Tell a peer that we've replaced the identity that `endorsement` was signed by.
+    async fn send_rotate(
+        &self,
+        addr: &Self::Addr,
+        endorsement: Signed<PublicId>,
+    ) -> Result<bool, Self::Error>;
+    /// Tell a peer that we've moved to the address in `record`, returning whether it took our new address.
+    async fn send_readdress(
+        &self,
+        addr: &Self::Addr,
+        record: SignedAddr<Self::Addr>,
+    ) -> Result<bool, Self::Error>;
+    /// Ping a peer, returning the round trip time and its answer: its address record, and perhaps one of its peers.
+    async fn send_ping(
+        &self,
+        addr: &Self::Addr,
+    ) -> Result<(Duration, Pong<Self::Addr>), Self::Error>;
+    /// Ask a peer for a summary of its state, which it may decline to give.
+    async fn send_info(&self, addr: &Self::Addr) -> Result<Option<NodeStats>, Self::Error>;
+    /// Ask a peer which IP address our requests appear to come from, if the backend has such a concept.
+    async fn send_observe(&self, _addr: &Self::Addr) -> Result<Option<IpAddr>, Self::Error> {
+        Ok(None)
+    }
     async fn send_discover(
         &self,
         addr: &Self::Addr,
         target: Tag,
         max_level: u16,
-    ) -> Result<Option<(PublicId, Self::Addr)>, Self::Error>;
+    ) -> Result<Option<SignedAddr<Self::Addr>>, Self::Error>;
+    /// Ask a peer for up to `k` of the nodes it knows closest to `target`, closest first.
+    async fn send_find_node(
+        &self,
+        addr: &Self::Addr,
+        target: Tag,
+        k: usize,
+    ) -> Result<Vec<(PublicId, Self::Addr)>, Self::Error>;
     async fn send_locate(
         &self,
         addr: &Self::Addr,
         tag: Tag,
-    ) -> Result<Result<bool, (PublicId, Self::Addr)>, Self::Error>;
+    ) -> Result<LocateReply<Self::Addr>, Self::Error>;
+    // The receiver checks that the data is stored under `tag`, and may not bother if it already has it. If `chunk`, it
+    // keeps the data only for as long as its lease is renewed, or something it holds refers to it.
     async fn send_upload(
         &self,
         addr: &Self::Addr,
-        data: Box<[u8]>,
-    ) -> Result<Result<(), ()>, Self::Error>;
+        tag: Tag,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Result<Tag, ProtocolError>, Self::Error>;
     async fn send_download(
         &self,
         addr: &Self::Addr,
         tag: Tag,
-    ) -> Result<Option<Box<[u8]>>, Self::Error>;
+    ) -> Result<Result<Option<Bytes>, ProtocolError>, Self::Error>;
+    /// Challenge a peer to prove that it holds the data stored under `tag`, returning its answer if it has one.
+    async fn send_audit(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+        challenge: Challenge,
+    ) -> Result<Result<Option<Tag>, ProtocolError>, Self::Error>;
+    /// Renew the leases of the chunks stored under `tags` with a peer, returning how many of them it holds.
+    async fn send_renew(&self, addr: &Self::Addr, tags: Vec<Tag>) -> Result<usize, Self::Error>;
+    /// Send a peer a summary of the tags that we hold in `region`, returning the tags it holds there that we seem to
+    /// be missing, and its own summary.
+    async fn send_sync(
+        &self,
+        addr: &Self::Addr,
+        region: Region,
+        summary: Summary,
+    ) -> Result<Result<SyncReply, ProtocolError>, Self::Error>;
 }
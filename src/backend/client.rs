@@ -0,0 +1,416 @@
+//! A backend for nodes that only ever make requests, such as those running in a browser, which nothing can reach.
+//!
+//! Requests speak the same protocol as the HTTP backend's, and go through `fetch` when built for wasm32. A client
+//! never greets anybody, so nobody peers with it: it reaches the network through the HTTP nodes that it's told to use
+//! as gateways with [`add_gateway`]. Since it has nothing to serve, hosting ends at once, and a client is used without
+//! [`Node::run`].
+
+use crate::{
+    audit::Challenge,
+    msg::{
+        Audit, Discover, Download, FindNode, Greet, Info, Locate, Msg, Ping, Pong, Prove,
+        Readdress, Renew, Rotate, SyncReply, SyncTags, Upload, MAX_MESSAGE_SIZE,
+    },
+    signed::now_millis,
+    sync::{Region, Summary},
+    Backend, GreetRefusal, GreetReply, LocateReply, Node, NodeStats, ProtocolError, PublicId,
+    Signature, SignatureError, Signed, SignedAddr, Tag,
+};
+
+use bytes::Bytes;
+use reqwest::{header::CONTENT_TYPE, StatusCode, Url};
+use serde::Serialize;
+use std::{
+    sync::{Arc, OnceLock, Weak},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("reqwest: {0}")]
+    Reqwest(reqwest::Error),
+    #[error("invalid peer address: {0}")]
+    InvalidAddr(String),
+    #[error("peer responded with {status}: {reason}")]
+    Status { status: StatusCode, reason: String },
+    #[error("invalid signature: {0}")]
+    Signature(SignatureError),
+    #[error("peer sent a message larger than {limit} bytes")]
+    TooLarge { limit: usize },
+    #[error("malformed response: {0}")]
+    Decode(serde_json::Error),
+    #[error("gateway {0} could not be taken on as a peer")]
+    Refused(Url),
+}
+
+/// The default maximum size of a response that carries no data (64 KiB).
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Configuration for the client backend.
+pub struct Config {
+    /// Responses larger than this many bytes are rejected unless they carry data, which may be as large as any peer
+    /// message.
+    pub max_message_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+pub struct Client {
+    config: Config,
+    client: reqwest::Client,
+    // The node we belong to, which signs our messages
+    node: OnceLock<Weak<Node<Client>>>,
+}
+
+#[async_trait::async_trait]
+impl Backend for Client {
+    type Addr = Url;
+    type Config = Config;
+    type Error = Error;
+
+    const REACHABLE: bool = false;
+
+    fn canonicalise(addr: Self::Addr) -> Self::Addr {
+        canonical_addr(addr)
+    }
+
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        Ok(Self {
+            config,
+            client: reqwest::Client::new(),
+            node: OnceLock::new(),
+        })
+    }
+
+    async fn init(&self, node: &Arc<Node<Self>>) {
+        self.node.set(Arc::downgrade(node)).ok().unwrap();
+    }
+
+    async fn host(_node: Arc<Node<Self>>) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    async fn send_greet(
+        &self,
+        addr: &Self::Addr,
+        greet: Greet<Self::Addr>,
+    ) -> Result<Result<GreetReply, GreetRefusal<Self::Addr>>, Self::Error> {
+        let (signer, resp) = self.send("peer/greet", addr, greet).await?;
+        match resp.result {
+            // A peer can only accept us under its own identity
+            Ok(reply) if reply.id != signer => Err(Error::Signature(SignatureError::Invalid)),
+            result => Ok(result),
+        }
+    }
+
+    async fn send_prove(
+        &self,
+        addr: &Self::Addr,
+        id: PublicId,
+        proof: Signature,
+    ) -> Result<bool, Self::Error> {
+        Ok(self
+            .send("peer/prove", addr, Prove { id, proof })
+            .await?
+            .1
+            .accepted)
+    }
+
+    async fn send_rotate(
+        &self,
+        addr: &Self::Addr,
+        endorsement: Signed<PublicId>,
+    ) -> Result<bool, Self::Error> {
+        Ok(self
+            .send("peer/rotate", addr, Rotate { endorsement })
+            .await?
+            .1
+            .forgotten)
+    }
+
+    async fn send_readdress(
+        &self,
+        addr: &Self::Addr,
+        record: SignedAddr<Self::Addr>,
+    ) -> Result<bool, Self::Error> {
+        Ok(self
+            .send("peer/readdress", addr, Readdress { record })
+            .await?
+            .1
+            .updated)
+    }
+
+    async fn send_ping(
+        &self,
+        addr: &Self::Addr,
+    ) -> Result<(Duration, Pong<Self::Addr>), Self::Error> {
+        let now = Instant::now();
+        let ping = Ping { sent: now_millis() };
+        let (_, pong) = self.send("peer/ping", addr, ping).await?;
+        Ok((now.elapsed(), pong))
+    }
+
+    async fn send_info(&self, addr: &Self::Addr) -> Result<Option<NodeStats>, Self::Error> {
+        Ok(self.send("peer/info", addr, Info).await?.1.stats)
+    }
+
+    async fn send_discover(
+        &self,
+        addr: &Self::Addr,
+        target: Tag,
+        max_level: u16,
+    ) -> Result<Option<SignedAddr<Self::Addr>>, Self::Error> {
+        Ok(self
+            .send("peer/discover", addr, Discover { target, max_level })
+            .await?
+            .1
+            .peer)
+    }
+
+    async fn send_find_node(
+        &self,
+        addr: &Self::Addr,
+        target: Tag,
+        k: usize,
+    ) -> Result<Vec<(PublicId, Self::Addr)>, Self::Error> {
+        Ok(self
+            .send("peer/find_node", addr, FindNode { target, k })
+            .await?
+            .1
+            .peers)
+    }
+
+    async fn send_locate(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+    ) -> Result<LocateReply<Self::Addr>, Self::Error> {
+        Ok(self
+            .send("peer/locate", addr, Locate { tag })
+            .await?
+            .1
+            .into())
+    }
+
+    async fn send_upload(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Result<Tag, ProtocolError>, Self::Error> {
+        let upload = Upload {
+            data,
+            algorithm: tag.algorithm(),
+            tag: Some(tag),
+            chunk,
+        };
+        Ok(self.send("peer/upload", addr, upload).await?.1.result)
+    }
+
+    async fn send_download(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+    ) -> Result<Result<Option<Bytes>, ProtocolError>, Self::Error> {
+        Ok(self
+            .send("peer/download", addr, Download { tag })
+            .await?
+            .1
+            .result)
+    }
+
+    async fn send_audit(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+        challenge: Challenge,
+    ) -> Result<Result<Option<Tag>, ProtocolError>, Self::Error> {
+        Ok(self
+            .send("peer/audit", addr, Audit { tag, challenge })
+            .await?
+            .1
+            .result)
+    }
+
+    async fn send_renew(&self, addr: &Self::Addr, tags: Vec<Tag>) -> Result<usize, Self::Error> {
+        Ok(self
+            .send("peer/renew", addr, Renew { tags })
+            .await?
+            .1
+            .renewed)
+    }
+
+    async fn send_sync(
+        &self,
+        addr: &Self::Addr,
+        region: Region,
+        summary: Summary,
+    ) -> Result<Result<SyncReply, ProtocolError>, Self::Error> {
+        Ok(self
+            .send("peer/sync", addr, SyncTags { region, summary })
+            .await?
+            .1
+            .result)
+    }
+}
+
+/// Reach the network through the HTTP node at `gateway`, returning its identity.
+///
+/// The gateway is taken on as a peer by way of its address record, without greeting it, so that it doesn't try to
+/// reach us in turn.
+pub async fn add_gateway(node: &Node<Client>, gateway: Url) -> Result<PublicId, Error> {
+    let gateway = canonical_addr(gateway);
+    let (_, pong) = node.backend.send_ping(&gateway).await?;
+    let id = pong.record.sender;
+    if node.accept_peer(id.clone(), gateway.clone()).await
+        || node.with_routing(|routing| routing.contains(&id))
+    {
+        Ok(id)
+    } else {
+        Err(Error::Refused(gateway))
+    }
+}
+
+/// The canonical form of a peer address.
+///
+/// Parsing a URL already lowercases its scheme and host and drops the scheme's default port. On top of that, the path
+/// ends in exactly one slash, percent-encoded characters that needn't be are decoded and the rest are written in upper
+/// case, and the query and fragment, which play no part in the requests made to a peer, are dropped.
+pub fn canonical_addr(mut url: Url) -> Url {
+    fn hex(byte: u8) -> Option<u8> {
+        char::from(byte).to_digit(16).map(|digit| digit as u8)
+    }
+    let bytes = url.path().as_bytes();
+    let mut path = String::with_capacity(bytes.len() + 1);
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|digits| Some(hex(digits[0])? << 4 | hex(digits[1])?));
+        match escaped {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                path.push(char::from(byte));
+                i += 3;
+            }
+            Some(byte) => {
+                path.push_str(&format!("%{:02X}", byte));
+                i += 3;
+            }
+            None => {
+                path.push(char::from(bytes[i]));
+                i += 1;
+            }
+        }
+    }
+    path.truncate(path.trim_end_matches('/').len());
+    path.push('/');
+    url.set_path(&path);
+    url.set_query(None);
+    url.set_fragment(None);
+    url
+}
+
+impl Client {
+    // Send a signed message, verifying that the response was signed by the peer. Browsers refuse to send a body with
+    // a `GET`, so unlike the HTTP backend, we `POST`.
+    async fn send<M: Msg<Url> + Serialize>(
+        &self,
+        path: &str,
+        addr: &Url,
+        msg: M,
+    ) -> Result<(PublicId, M::Resp), Error>
+    where
+        M::Resp: Serialize,
+    {
+        let node = self
+            .node
+            .get()
+            .and_then(Weak::upgrade)
+            .expect("messages can only be sent by a node");
+        let url = canonical_addr(addr.clone())
+            .join(path)
+            .map_err(|_| Error::InvalidAddr(addr.to_string()))?;
+        let sealed =
+            serde_json::to_vec(&node.seal(&msg).await).expect("message could not be encoded");
+        let limit = if M::DATA {
+            MAX_MESSAGE_SIZE
+        } else {
+            self.config.max_message_size
+        };
+        let body = fetch(
+            self.client.clone(),
+            url,
+            sealed,
+            limit,
+            self.config.max_message_size,
+        )
+        .await?;
+        let resp = serde_json::from_slice::<Signed<M::Resp>>(&body).map_err(Error::Decode)?;
+        node.open(resp).map_err(Error::Signature)
+    }
+}
+
+// Post `body` to `url`, returning the response body if it's a success no larger than `limit`. Failures are described
+// in at most `max_reason` bytes.
+async fn post(
+    client: reqwest::Client,
+    url: Url,
+    body: Vec<u8>,
+    limit: usize,
+    max_reason: usize,
+) -> Result<Bytes, Error> {
+    let resp = client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .map_err(Error::Reqwest)?;
+    let status = resp.status();
+    let limit = if status.is_success() {
+        limit
+    } else {
+        max_reason
+    };
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(Error::TooLarge { limit });
+    }
+    let body = resp.bytes().await.map_err(Error::Reqwest)?;
+    if body.len() > limit {
+        return Err(Error::TooLarge { limit });
+    }
+    if !status.is_success() {
+        let reason = String::from_utf8_lossy(&body).into_owned();
+        return Err(Error::Status { status, reason });
+    }
+    Ok(body)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+use post as fetch;
+
+// In a browser, requests are futures that belong to the page's one thread, so they're run there while we wait on
+// their outcome, which can be awaited from anywhere
+#[cfg(target_arch = "wasm32")]
+async fn fetch(
+    client: reqwest::Client,
+    url: Url,
+    body: Vec<u8>,
+    limit: usize,
+    max_reason: usize,
+) -> Result<Bytes, Error> {
+    let (tx, rx) = futures::channel::oneshot::channel();
+    wasm_bindgen_futures::spawn_local(async move {
+        tx.send(post(client, url, body, limit, max_reason).await)
+            .ok();
+    });
+    rx.await.expect("requests run to completion")
+}
@@ -1,19 +1,46 @@
-use crate::{Backend, Node, PublicId, Tag};
+pub use super::client::canonical_addr;
+
+use crate::{
+    audit::Challenge,
+    msg::{
+        Audit, AuditResp, Discover, DiscoverResp, Download, DownloadResp, FindNode, FindNodeResp,
+        Greet, GreetResp, Info, InfoResp, Locate, LocateResp, Msg, Observe, ObserveResp, Ping,
+        Pong, Prove, ProveResp, Readdress, ReaddressResp, Renew, RenewResp, Rotate, RotateResp,
+        SyncReply, SyncTags, SyncTagsResp, Upload, UploadResp, MAX_MESSAGE_SIZE,
+    },
+    signed::now_millis,
+    store::Publisher,
+    sync::{Region, Summary},
+    Backend, GreetRefusal, GreetReply, LocateReply, Node, NodeStats, ProtocolError, PublicId,
+    PublicIdRef, Request, Signature, SignatureError, Signed, SignedAddr, Tag,
+};
 
 use axum::{
-    body::Bytes,
-    extract::{Path, State},
-    routing::{get, Router},
+    body::{Bytes, HttpBody},
+    extract::{
+        BodyStream, ConnectInfo, DefaultBodyLimit, FromRequest, Multipart, Path, Query, State,
+    },
+    handler::Handler,
+    middleware::{self, Next},
+    response::{Html, IntoResponse, Response},
+    routing::{delete, get, on, post, put, MethodFilter, MethodRouter, Router},
     Json, Server,
 };
-use hyper::StatusCode;
+use futures::{Stream, StreamExt};
+use hyper::{header, header::HeaderValue, HeaderMap, StatusCode};
+use rand::prelude::*;
 use reqwest::Url;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    net::SocketAddr,
-    sync::Arc,
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write as _},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    ops::Range,
+    sync::{Arc, Mutex, OnceLock, Weak},
     time::{Duration, Instant},
 };
+use tokio::select;
+use tracing::{error, info, warn};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -21,149 +48,862 @@ pub enum Error {
     Hyper(hyper::Error),
     #[error("reqwest: {0}")]
     Reqwest(reqwest::Error),
+    #[error("invalid peer address: {0}")]
+    InvalidAddr(String),
+    #[error("reqwest (gave up after {attempts} attempts): {err}")]
+    Retried { attempts: u32, err: reqwest::Error },
+    #[error("peer responded with {status}: {reason}")]
+    Status { status: StatusCode, reason: String },
+    #[error("invalid signature: {0}")]
+    Signature(SignatureError),
+    #[error("peer sent a message larger than {limit} bytes")]
+    TooLarge { limit: usize },
+    #[error("malformed response: {0}")]
+    Decode(serde_json::Error),
 }
 
+/// The default maximum size of a single upload to the data router (16 MiB).
+pub const DEFAULT_MAX_UPLOAD_SIZE: usize = 16 * 1024 * 1024;
+/// The default maximum size of a peer message that carries no data (64 KiB).
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// The number of publishers listed by `GET /publishers`
+const TOP_PUBLISHERS: usize = 20;
+
+/// Configuration for the HTTP backend.
+///
+/// A configuration suited to low-latency links between long-lived peers might keep connections warm and skip the
+/// HTTP/1.1 upgrade dance entirely:
+///
+/// ```
+/// # use nettle::http::Config;
+/// # use std::time::Duration;
+/// let config = Config {
+///     pool_max_idle_per_host: 4,
+///     pool_idle_timeout: Some(Duration::from_secs(300)),
+///     tcp_keepalive: Some(Duration::from_secs(15)),
+///     http2_prior_knowledge: true,
+///     ..Default::default()
+/// };
+/// ```
 pub struct Config {
-    pub bind_addr: SocketAddr,
+    /// The addresses to listen on. These are independent of the address the node advertises to its peers.
+    pub bind_addrs: Vec<SocketAddr>,
+    /// Uploads larger than this many bytes are rejected with `413 Payload Too Large`, and those from peers with
+    /// [`ProtocolError::TooLarge`].
+    pub max_upload_size: usize,
+    /// Peer messages, and responses to our own, larger than this many bytes are rejected unless they carry data. Those
+    /// that do may be large enough to hold an upload of `max_upload_size`.
+    pub max_message_size: usize,
+    /// The number of times a message is retried after a transient failure.
+    pub retries: u32,
+    /// The delay before the first retry, doubling with each subsequent attempt.
+    pub retry_backoff: Duration,
+    /// The maximum number of idle connections kept open to each peer.
+    pub pool_max_idle_per_host: usize,
+    /// How long an idle connection is kept open before being closed, or `None` to keep it open indefinitely.
+    pub pool_idle_timeout: Option<Duration>,
+    /// The interval at which TCP keepalive probes are sent, or `None` to disable them.
+    pub tcp_keepalive: Option<Duration>,
+    /// Talk to peers using HTTP/2 without first negotiating it. All peers must support HTTP/2.
+    pub http2_prior_knowledge: bool,
+    /// How long in-flight requests are given to complete when the node shuts down.
+    pub shutdown_grace: Duration,
+    /// Serve a small web interface for uploading and downloading data at `/`.
+    pub web_ui: bool,
+    /// Serve everything under this path (such as `/nettle`), for when the node sits behind a reverse proxy that
+    /// doesn't strip the prefix itself.
+    pub path_prefix: Option<String>,
+    /// Limit the rate at which each client may make requests to the peer protocol. Disabled by default.
+    pub rate_limit: Option<RateLimit>,
+    /// If set, admin endpoints (such as `/peers`) require an `Authorization: Bearer <token>` header.
+    pub admin_token: Option<String>,
+    /// Tell peers that ask about the node's state, such as its peer count and uptime.
+    pub share_info: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_addrs: vec![(Ipv6Addr::LOCALHOST, 34093).into()],
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            retries: 2,
+            retry_backoff: Duration::from_millis(100),
+            pool_max_idle_per_host: usize::MAX,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            tcp_keepalive: None,
+            http2_prior_knowledge: false,
+            shutdown_grace: Duration::from_secs(10),
+            web_ui: false,
+            path_prefix: None,
+            rate_limit: None,
+            admin_token: None,
+            share_info: true,
+        }
+    }
+}
+
+impl Config {
+    // The largest message of kind `M`, or response to one, that we accept
+    fn message_limit<M: Msg<Url>>(&self) -> usize {
+        let limit = if M::DATA {
+            // Bytes are encoded as JSON arrays, taking up to four characters each
+            self.max_upload_size
+                .saturating_mul(4)
+                .saturating_add(self.max_message_size)
+        } else {
+            self.max_message_size
+        };
+        limit.min(MAX_MESSAGE_SIZE)
+    }
+}
+
+/// Per-client rate limits for the peer protocol.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// The rate for cheap messages: greet, ping and discover.
+    pub cheap: Rate,
+    /// The rate for expensive messages: locate, upload, download, audit, renew and sync.
+    pub expensive: Rate,
+    /// A header set by a trusted reverse proxy (such as `X-Forwarded-For`) from which to take the client's IP. The
+    /// last address in the header is used.
+    pub trusted_proxy_header: Option<String>,
+}
+
+/// A token bucket rate.
+#[derive(Copy, Clone, Debug)]
+pub struct Rate {
+    pub per_second: f64,
+    /// The number of requests that may be made in quick succession before the limit applies.
+    pub burst: u32,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
 }
 
 pub struct Http {
     config: Config,
     client: reqwest::Client,
+    buckets: Mutex<HashMap<(IpAddr, bool), Bucket>>,
+    // Content types provided by clients that uploaded data through this node
+    content_types: Mutex<HashMap<Tag, HeaderValue>>,
+    // The node we belong to, which signs our messages. Unset for backends used without a node.
+    node: OnceLock<Weak<Node<Http>>>,
 }
 
 #[async_trait::async_trait]
 impl Backend for Http {
-    type Addr = String;
+    type Addr = Url;
     type Config = Config;
     type Error = Error;
 
+    fn canonicalise(addr: Self::Addr) -> Self::Addr {
+        canonical_addr(addr)
+    }
+
+    fn with_ip(addr: &Self::Addr, ip: IpAddr) -> Option<Self::Addr> {
+        addr_with_ip(addr, ip)
+    }
+
     async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        let mut client = reqwest::Client::builder()
+            .pool_max_idle_per_host(config.pool_max_idle_per_host)
+            .pool_idle_timeout(config.pool_idle_timeout)
+            .tcp_keepalive(config.tcp_keepalive);
+        if config.http2_prior_knowledge {
+            client = client.http2_prior_knowledge();
+        }
         Ok(Self {
+            client: client.build().map_err(Error::Reqwest)?,
             config,
-            client: reqwest::Client::new(),
+            content_types: Mutex::default(),
+            buckets: Mutex::default(),
+            node: OnceLock::new(),
         })
     }
 
+    async fn init(&self, node: &Arc<Node<Self>>) {
+        self.node.set(Arc::downgrade(node)).ok().unwrap();
+    }
+
     async fn host(node: Arc<Node<Self>>) -> Result<(), Self::Error> {
         let peer_router = Router::new()
             .route(
                 "/greet",
-                get(|node: State<Arc<Node<_>>>, msg: Json<Greet>| async move {
-                    Json(GreetResp {
-                        result: node.recv_greet(msg.0.sender).await,
-                    })
-                }),
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(signer, msg): Verified<Greet<Url>>| async move {
+                        // Only the owner of an identity may introduce it
+                        if msg.sender.0 != signer {
+                            return Err((StatusCode::UNAUTHORIZED, "sender does not match signer"));
+                        }
+                        Ok(Json(node.seal(GreetResp {
+                            result: node.recv_greet(msg).await,
+                        }).await))
+                    },
+                ),
+            )
+            .route(
+                "/prove",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(signer, msg): Verified<Prove>| async move {
+                        if msg.id != signer {
+                            return Err((StatusCode::UNAUTHORIZED, "sender does not match signer"));
+                        }
+                        Ok(Json(node.seal(ProveResp {
+                            accepted: node.recv_prove(msg.id, msg.proof).await,
+                        }).await))
+                    },
+                ),
+            )
+            .route(
+                "/rotate",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(signer, msg): Verified<Rotate>| async move {
+                        // Only the new identity may announce that it replaces the old one
+                        if msg.endorsement.body != signer {
+                            return Err((StatusCode::UNAUTHORIZED, "sender does not match signer"));
+                        }
+                        Ok(Json(node.seal(RotateResp {
+                            forgotten: node.recv_rotate(msg.endorsement).await,
+                        }).await))
+                    },
+                ),
+            )
+            .route(
+                "/readdress",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(signer, msg): Verified<Readdress<Url>>| async move {
+                        Json(node.seal(ReaddressResp {
+                            updated: node.recv_readdress(Some(&signer), msg.record).await,
+                        }).await)
+                    },
+                ),
             )
             .route(
                 "/ping",
-                get(|node: State<Arc<Node<_>>>, _: Json<Ping>| async move {
-                    node.recv_ping().await;
-                    Json(Pong)
+                get_or_post(|node: State<Arc<Node<_>>>, Verified(sender, ping): Verified<Ping>| async move {
+                    let pong = node.recv_ping_from(Some(&sender), ping).await;
+                    Json(node.seal(pong).await)
+                }),
+            )
+            .route(
+                "/info",
+                get_or_post(|node: State<Arc<Node<Http>>>, _: Verified<Info>| async move {
+                    let stats = node.recv_info().await;
+                    Json(node.seal(InfoResp {
+                        stats: node.backend.config.share_info.then_some(stats),
+                    }).await)
                 }),
             )
+            .route(
+                "/observe",
+                get_or_post(
+                    |ConnectInfo(addr): ConnectInfo<SocketAddr>, _: Json<Observe>| async move {
+                        Json(ObserveResp { addr })
+                    },
+                ),
+            )
             .route(
                 "/discover",
-                get(
-                    |node: State<Arc<Node<_>>>, msg: Json<Discover>| async move {
-                        Json(DiscoverResp {
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<Discover>| async move {
+                        Json(node.seal(DiscoverResp {
                             peer: node.recv_discover(msg.target, msg.max_level).await,
-                        })
+                        }).await)
+                    },
+                ),
+            )
+            .route(
+                "/find_node",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<FindNode>| async move {
+                        Json(node.seal(FindNodeResp {
+                            peers: node.recv_find_node(msg.target, msg.k).await,
+                        }).await)
                     },
                 ),
             )
             .route(
                 "/locate",
-                get(|node: State<Arc<Node<_>>>, msg: Json<Locate>| async move {
-                    Json(LocateResp {
-                        result: node.recv_locate(msg.tag).await,
-                    })
-                }),
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<Locate>| async move {
+                        Json(node.seal(LocateResp::from(node.recv_locate(msg.tag).await)).await)
+                    },
+                ),
             )
             .route(
                 "/upload",
-                get(|node: State<Arc<Node<_>>>, msg: Json<Upload>| async move {
-                    Json(UploadResp {
-                        result: node.recv_upload(msg.0.data).await,
-                    })
-                }),
+                get_or_post(
+                    |node: State<Arc<Node<Http>>>, Verified(sender, msg): Verified<Upload>| async move {
+                        // Peers may not store anything larger than clients can upload directly
+                        let result = if msg.data.len() > node.backend.config.max_upload_size {
+                            Err(ProtocolError::TooLarge)
+                        } else {
+                            match msg.tag {
+                                Some(tag) => node.recv_tagged_upload_from(Some(&sender), tag, msg.data, msg.chunk).await,
+                                None => node.recv_upload_from(Some(&sender), msg.algorithm, msg.data).await,
+                            }
+                        };
+                        Json(node.seal(UploadResp { result }).await)
+                    },
+                )
+                .layer(DefaultBodyLimit::max(
+                    node.backend.config.message_limit::<Upload>(),
+                )),
             )
             .route(
                 "/download",
-                get(
-                    |node: State<Arc<Node<_>>>, msg: Json<Download>| async move {
-                        Json(DownloadResp {
-                            data: node.recv_download(msg.tag).await,
-                        })
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<Download>| async move {
+                        Json(node.seal(DownloadResp {
+                            result: node.recv_download(msg.tag).await,
+                        }).await)
                     },
                 ),
-            );
+            )
+            .route(
+                "/audit",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<Audit>| async move {
+                        Json(node.seal(AuditResp {
+                            result: node.recv_audit(msg.tag, msg.challenge).await,
+                        }).await)
+                    },
+                ),
+            )
+            .route(
+                "/renew",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<Renew>| async move {
+                        Json(node.seal(RenewResp {
+                            renewed: node.recv_renew(msg.tags).await,
+                        }).await)
+                    },
+                ),
+            )
+            .route(
+                "/sync",
+                get_or_post(
+                    |node: State<Arc<Node<_>>>, Verified(_, msg): Verified<SyncTags>| async move {
+                        Json(node.seal(SyncTagsResp {
+                            result: node.recv_sync(msg.region, msg.summary).await,
+                        }).await)
+                    },
+                ),
+            )
+            .route_layer(middleware::from_fn_with_state(node.clone(), rate_limit))
+            // Messages that carry no data are small, so anything larger is refused before it's read in full
+            .layer(DefaultBodyLimit::max(
+                node.backend.config.message_limit::<Locate>(),
+            ))
+            .layer(middleware::from_fn(cors));
 
         let data_router = Router::new()
             .route(
                 "/:hash",
-                get(|node: State<Arc<Node<_>>>, Path(id)| async move {
-                    match Tag::try_from_hex::<String>(id) {
-                        Ok(tag) => match node.do_download(tag).await {
-                            Ok(Some(data)) => (StatusCode::OK, Ok(Bytes::from(data))),
-                            Ok(None) => (StatusCode::NOT_FOUND, Err("data does not exist")),
-                            Err(err) => (StatusCode::BAD_GATEWAY, Err(err)),
-                        },
-                        Err(err) => (StatusCode::BAD_REQUEST, Err(err)),
-                    }
-                }),
+                get(
+                    |node: State<Arc<Node<Http>>>, Path(id), headers: HeaderMap| async move {
+                        let mut resp = match Tag::try_from_hex::<String>(id) {
+                            // Data is content-addressed, so a matching tag means the client's copy is valid
+                            Ok(tag) if etag_matches(&headers, tag) => {
+                                with_cache_headers(StatusCode::NOT_MODIFIED.into_response(), tag)
+                            }
+                            Ok(tag) => match node.do_download(tag).await {
+                                Ok(Some(data)) => {
+                                    let content_type = node
+                                        .backend
+                                        .content_types
+                                        .lock()
+                                        .unwrap()
+                                        .get(&tag)
+                                        .cloned()
+                                        .unwrap_or_else(|| sniff_content_type(&data));
+                                    let mut resp = serve_data(&headers, data);
+                                    resp.headers_mut().insert(header::CONTENT_TYPE, content_type);
+                                    with_cache_headers(resp, tag)
+                                }
+                                Ok(None) => {
+                                    (StatusCode::NOT_FOUND, "data does not exist").into_response()
+                                }
+                                Err(err) => {
+                                    (StatusCode::BAD_GATEWAY, err.to_string()).into_response()
+                                }
+                            },
+                            Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+                        };
+                        resp.headers_mut().insert(
+                            header::X_CONTENT_TYPE_OPTIONS,
+                            HeaderValue::from_static("nosniff"),
+                        );
+                        resp
+                    },
+                )
+                .put(
+                    |node: State<Arc<Node<Http>>>,
+                     Path(id),
+                     headers: HeaderMap,
+                     body: BodyStream| async move {
+                        let tag = match Tag::try_from_hex::<String>(id) {
+                            Ok(tag) => tag,
+                            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+                        };
+                        // The client told us the tag up front, so there's no need to read data that we already have
+                        if node.has_data(tag).await {
+                            return (StatusCode::OK, tag.to_string()).into_response();
+                        }
+                        let Ok(_permit) = node.upload_permit().await else {
+                            return busy();
+                        };
+                        let limit = node.backend.config.max_upload_size;
+                        let data = match read_body(&headers, body, limit).await {
+                            Ok(data) => data,
+                            Err(err) => return err.into_response(),
+                        };
+                        if !tag.is_digest_of(&*data) {
+                            let actual = Tag::digest_with(tag.algorithm(), &*data);
+                            return (
+                                StatusCode::UNPROCESSABLE_ENTITY,
+                                format!("data hashes to {}, not {}", actual, tag),
+                            )
+                                .into_response();
+                        }
+                        match node.do_upload_with(tag.algorithm(), data).await {
+                            Ok(tag) => {
+                                if let Some(content_type) = headers.get(header::CONTENT_TYPE) {
+                                    node.backend
+                                        .content_types
+                                        .lock()
+                                        .unwrap()
+                                        .insert(tag, content_type.clone());
+                                }
+                                (StatusCode::CREATED, tag.to_string()).into_response()
+                            }
+                            Err(err) => (StatusCode::BAD_GATEWAY, err.to_string()).into_response(),
+                        }
+                    },
+                ),
             )
             .route(
-                "/upload",
-                get(|node: State<Arc<Node<_>>>, bytes: Bytes| async move {
-                    match node.do_upload(bytes.to_vec().into_boxed_slice()).await {
-                        Ok(tag) => (StatusCode::CREATED, tag.to_string()),
-                        Err(err) => (StatusCode::BAD_GATEWAY, err.into()),
-                    }
-                }),
-            );
-
-        let router = Router::new()
-            .nest("/peer", peer_router)
-            .nest("/data", data_router)
-            .route(
-                "/list_peers",
-                get(|node: State<Arc<Node<_>>>| async move {
-                    let peers = node.with_state(|state| {
-                        state
-                            .peers
-                            .values()
-                            .map(|p| (format!("{:?}", p.id), format!("{}", p.addr)))
-                            .collect::<Vec<_>>()
-                    });
-                    (StatusCode::OK, Json(peers))
-                }),
+                "/",
+                post(
+                    |node: State<Arc<Node<Http>>>, headers: HeaderMap, body: BodyStream| async move {
+                        // Wait for our turn before reading the body, so that a burst of uploads isn't all buffered
+                        let Ok(_permit) = node.upload_permit().await else {
+                            return busy();
+                        };
+                        let limit = node.backend.config.max_upload_size;
+                        let data = match read_body(&headers, body, limit).await {
+                            Ok(data) => data,
+                            Err(err) => return err.into_response(),
+                        };
+                        match node.do_upload(data).await {
+                            Ok(tag) => {
+                                // Remember the type the uploader gave us so it can be served back to clients later
+                                if let Some(content_type) = headers.get(header::CONTENT_TYPE) {
+                                    node.backend
+                                        .content_types
+                                        .lock()
+                                        .unwrap()
+                                        .insert(tag, content_type.clone());
+                                }
+                                (StatusCode::CREATED, tag.to_string()).into_response()
+                            }
+                            Err(err) => (StatusCode::BAD_GATEWAY, err.to_string()).into_response(),
+                        }
+                    },
+                ),
             )
-            .with_state(node.clone());
+            .route(
+                "/form",
+                post(
+                    |node: State<Arc<Node<Http>>>, mut form: Multipart| async move {
+                        let Ok(_permit) = node.upload_permit().await else {
+                            return busy();
+                        };
+                        let limit = node.backend.config.max_upload_size;
+                        let mut uploads = Vec::new();
+                        loop {
+                            let field = match form.next_field().await {
+                                Ok(Some(field)) => field,
+                                Ok(None) => break,
+                                Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+                            };
+                            // Only file parts are uploaded, other form fields are ignored
+                            let Some(filename) = field.file_name().map(str::to_string) else {
+                                continue;
+                            };
+                            let content_type = field.content_type().and_then(|ty| ty.parse().ok());
+                            let data = match read_body(&HeaderMap::new(), field, limit).await {
+                                Ok(data) => data,
+                                Err(err) => return err.into_response(),
+                            };
+                            let bytes = data.len();
+                            let tag = match node.do_upload(data).await {
+                                Ok(tag) => tag,
+                                Err(err) => return (StatusCode::BAD_GATEWAY, err.to_string()).into_response(),
+                            };
+                            if let Some(content_type) = content_type {
+                                node.backend
+                                    .content_types
+                                    .lock()
+                                    .unwrap()
+                                    .insert(tag, content_type);
+                            }
+                            uploads.push(FormUpload {
+                                tag,
+                                filename,
+                                bytes,
+                            });
+                        }
+                        if uploads.is_empty() {
+                            (StatusCode::BAD_REQUEST, "no file parts in form").into_response()
+                        } else {
+                            (StatusCode::CREATED, Json(uploads)).into_response()
+                        }
+                    },
+                )
+                // Leave some room for the multipart framing
+                .layer(DefaultBodyLimit::max(
+                    node.backend.config.max_upload_size.saturating_add(64 * 1024),
+                )),
+            );
 
-        eprintln!("Starting HTTP server on {}", node.backend.config.bind_addr);
+        let mut router =
+            Router::new()
+                .nest("/peer", peer_router)
+                .nest("/data", data_router)
+                .route(
+                    "/list_peers",
+                    get(
+                        |node: State<Arc<Node<Http>>>, headers: HeaderMap| async move {
+                            // The same topology as `/peers` gives, so it's guarded the same way
+                            node.backend.check_admin(&headers)?;
+                            let peers = node.with_routing(|routing| {
+                                routing
+                                    .iter()
+                                    .map(|p| (format!("{:?}", p.id), format!("{}", p.addr)))
+                                    .collect::<Vec<_>>()
+                            });
+                            Ok::<_, StatusCode>(Json(peers))
+                        },
+                    ),
+                )
+                .route(
+                    "/status",
+                    get(
+                        |node: State<Arc<Node<Http>>>, query: Query<StatusQuery>| async move {
+                            let stats = node.stats();
+                            let status = Status {
+                                version: stats.version,
+                                tag: node.id().tag,
+                                name: node.id().human_readable_name(2),
+                                addr: node.addr(),
+                                peers: stats.peers,
+                                levels: stats.levels.unwrap_or_default(),
+                                entries: stats.entries,
+                                uptime_secs: stats.uptime_secs,
+                                observed_ips: node.observed_ips(),
+                            };
+                            if query.ready && status.peers == 0 {
+                                (StatusCode::SERVICE_UNAVAILABLE, Json(status))
+                            } else {
+                                (StatusCode::OK, Json(status))
+                            }
+                        },
+                    ),
+                )
+                .route(
+                    "/peers",
+                    get(
+                        |node: State<Arc<Node<Http>>>, headers: HeaderMap| async move {
+                            node.backend.check_admin(&headers)?;
+                            let peers = node
+                                .peer_info()
+                                .into_iter()
+                                .map(|p| PeerEntry {
+                                    tag: p.id.tag,
+                                    name: p.id.human_readable_name(2),
+                                    addr: p.addr,
+                                    ping_ms: p.ping.as_secs_f64() * 1000.0,
+                                    level: p.level,
+                                    clock_offset_ms: p.clock_offset,
+                                })
+                                .collect::<Vec<_>>();
+                            Ok::<_, StatusCode>(Json(peers))
+                        },
+                    ),
+                )
+                .route(
+                    "/peers/:id",
+                    delete(
+                        |node: State<Arc<Node<Http>>>,
+                         Path(id): Path<String>,
+                         headers: HeaderMap| async move {
+                            node.backend
+                                .check_admin(&headers)
+                                .map_err(IntoResponse::into_response)?;
+                            let id = id.parse::<PublicIdRef>().map_err(|err| {
+                                (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+                            })?;
+                            if node.disconnect(&id).await {
+                                Ok(StatusCode::NO_CONTENT)
+                            } else {
+                                Err((StatusCode::NOT_FOUND, "not peered with that node")
+                                    .into_response())
+                            }
+                        },
+                    ),
+                )
+                .route(
+                    "/locate/:tag",
+                    get(
+                        |node: State<Arc<Node<Http>>>,
+                         Path(tag): Path<String>,
+                         headers: HeaderMap| async move {
+                            node.backend
+                                .check_admin(&headers)
+                                .map_err(IntoResponse::into_response)?;
+                            let tag = Tag::try_from_hex(&tag).map_err(|err| {
+                                (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+                            })?;
+                            let located = node.locate(tag).await.map_err(|err| {
+                                (StatusCode::BAD_GATEWAY, err.to_string()).into_response()
+                            })?;
+                            let summary = |id: &PublicId, addr: Url| LocatedNode {
+                                tag: id.tag,
+                                name: id.human_readable_name(2),
+                                addr,
+                            };
+                            Ok::<_, Response>(Json(LocateReport {
+                                tag,
+                                found: located.found,
+                                node: summary(&located.owner.0, located.owner.1),
+                                path: located
+                                    .path
+                                    .iter()
+                                    .map(|id| PathEntry {
+                                        tag: id.tag,
+                                        name: id.human_readable_name(2),
+                                    })
+                                    .collect(),
+                            }))
+                        },
+                    ),
+                )
+                .route(
+                    "/entries",
+                    get(
+                        |node: State<Arc<Node<Http>>>, headers: HeaderMap| async move {
+                            node.backend.check_admin(&headers)?;
+                            Ok::<_, StatusCode>(Json(node.list_data()))
+                        },
+                    ),
+                )
+                .route(
+                    "/publishers",
+                    get(
+                        |node: State<Arc<Node<Http>>>, headers: HeaderMap| async move {
+                            node.backend.check_admin(&headers)?;
+                            let publishers = node
+                                .top_publishers(TOP_PUBLISHERS)
+                                .into_iter()
+                                .map(|(publisher, bytes)| PublisherEntry { publisher, bytes })
+                                .collect::<Vec<_>>();
+                            Ok::<_, StatusCode>(Json(publishers))
+                        },
+                    ),
+                )
+                .route(
+                    "/pins/:tag",
+                    put(
+                        |node: State<Arc<Node<Http>>>,
+                         Path(tag): Path<String>,
+                         headers: HeaderMap| async move {
+                            node.backend
+                                .check_admin(&headers)
+                                .map_err(IntoResponse::into_response)?;
+                            let tag = Tag::try_from_hex(&tag).map_err(|err| {
+                                (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+                            })?;
+                            match node.pin_and_fetch(tag).await {
+                                Ok(true) => Ok(StatusCode::NO_CONTENT),
+                                Ok(false) => {
+                                    Err((StatusCode::NOT_FOUND, "no such data").into_response())
+                                }
+                                Err(err) => {
+                                    Err((StatusCode::BAD_GATEWAY, err.to_string()).into_response())
+                                }
+                            }
+                        },
+                    )
+                    .delete(
+                        |node: State<Arc<Node<Http>>>,
+                         Path(tag): Path<String>,
+                         headers: HeaderMap| async move {
+                            node.backend
+                                .check_admin(&headers)
+                                .map_err(IntoResponse::into_response)?;
+                            let tag = Tag::try_from_hex(&tag).map_err(|err| {
+                                (StatusCode::BAD_REQUEST, err.to_string()).into_response()
+                            })?;
+                            if node.unpin(tag) {
+                                Ok(StatusCode::NO_CONTENT)
+                            } else {
+                                Err((StatusCode::NOT_FOUND, "no such pin").into_response())
+                            }
+                        },
+                    ),
+                )
+                .route(
+                    "/debug/graph",
+                    get(
+                        |node: State<Arc<Node<Http>>>, headers: HeaderMap| async move {
+                            node.backend.check_admin(&headers)?;
+                            Ok::<_, StatusCode>((
+                                [(header::CONTENT_TYPE, "text/vnd.graphviz")],
+                                render_graph(&node),
+                            ))
+                        },
+                    ),
+                )
+                .route(
+                    "/metrics",
+                    get(|node: State<Arc<Node<Http>>>| async move {
+                        (
+                            [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                            render_metrics(&node),
+                        )
+                    }),
+                );
+        if node.backend.config.web_ui {
+            router = router.route("/", get(web_ui));
+        }
+        let router = match node
+            .backend
+            .config
+            .path_prefix
+            .as_deref()
+            .map(|p| p.trim_matches('/'))
+        {
+            Some(prefix) if !prefix.is_empty() => {
+                let prefix = format!("/{}", prefix);
+                let mut outer = Router::new();
+                // The page uses relative links, so it must also be reachable with a trailing slash
+                if node.backend.config.web_ui {
+                    outer = outer.route(&format!("{}/", prefix), get(web_ui));
+                }
+                outer.nest(&prefix, router)
+            }
+            _ => router,
+        }
+        .with_state(node.clone());
 
-        Server::bind(&node.backend.config.bind_addr)
-            .serve(router.into_make_service())
-            .await
-            .map_err(Error::Hyper)
+        // Bind every address up front so that a failure to bind any of them prevents the others from serving
+        let servers = node
+            .backend
+            .config
+            .bind_addrs
+            .iter()
+            .map(|addr| {
+                Ok(Server::try_bind(addr)
+                    .map_err(Error::Hyper)?
+                    .serve(
+                        router
+                            .clone()
+                            .into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .with_graceful_shutdown(node.shutdown_requested()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        info!(
+            "Started HTTP server on {:?}",
+            node.backend.config.bind_addrs
+        );
+        let server = futures::future::try_join_all(servers);
+        // Give in-flight requests a bounded amount of time to finish once shutdown is requested
+        let grace = async {
+            node.shutdown_requested().await;
+            tokio::time::sleep(node.backend.config.shutdown_grace).await;
+        };
+        select! {
+            res = server => res.map(|_| ()).map_err(Error::Hyper),
+            () = grace => Ok(()),
+        }
     }
 
     async fn send_greet(
         &self,
         addr: &Self::Addr,
-        sender: (PublicId, Self::Addr),
-    ) -> Result<Result<PublicId, Option<Self::Addr>>, Self::Error> {
+        greet: Greet<Self::Addr>,
+    ) -> Result<Result<GreetReply, GreetRefusal<Self::Addr>>, Self::Error> {
+        let (signer, resp) = self.send_signed("peer/greet", addr, greet).await?;
+        match resp.result {
+            // A peer can only accept us under its own identity
+            Ok(reply) if reply.id != signer => Err(Error::Signature(SignatureError::Invalid)),
+            result => Ok(result),
+        }
+    }
+
+    async fn send_prove(
+        &self,
+        addr: &Self::Addr,
+        id: PublicId,
+        proof: Signature,
+    ) -> Result<bool, Self::Error> {
         Ok(self
-            .send_inner("/peer/greet", addr, Greet { sender })
+            .send_signed("peer/prove", addr, Prove { id, proof })
             .await?
-            .result)
+            .1
+            .accepted)
     }
 
-    async fn send_ping(&self, addr: &Self::Addr) -> Result<Duration, Self::Error> {
+    async fn send_rotate(
+        &self,
+        addr: &Self::Addr,
+        endorsement: Signed<PublicId>,
+    ) -> Result<bool, Self::Error> {
+        Ok(self
+            .send_signed("peer/rotate", addr, Rotate { endorsement })
+            .await?
+            .1
+            .forgotten)
+    }
+
+    async fn send_readdress(
+        &self,
+        addr: &Self::Addr,
+        record: SignedAddr<Self::Addr>,
+    ) -> Result<bool, Self::Error> {
+        Ok(self
+            .send_signed("peer/readdress", addr, Readdress { record })
+            .await?
+            .1
+            .updated)
+    }
+
+    async fn send_ping(
+        &self,
+        addr: &Self::Addr,
+    ) -> Result<(Duration, Pong<Self::Addr>), Self::Error> {
         let now = Instant::now();
-        self.send_inner("/peer/ping", addr, Ping).await?;
-        Ok(now.elapsed())
+        let ping = Ping { sent: now_millis() };
+        let (_, pong) = self.send_signed("peer/ping", addr, ping).await?;
+        Ok((now.elapsed(), pong))
+    }
+
+    async fn send_info(&self, addr: &Self::Addr) -> Result<Option<NodeStats>, Self::Error> {
+        Ok(self.send_signed("peer/info", addr, Info).await?.1.stats)
+    }
+
+    async fn send_observe(&self, addr: &Self::Addr) -> Result<Option<IpAddr>, Self::Error> {
+        Ok(Some(
+            self.send_inner::<_, ObserveResp>("peer/observe", addr, Observe)
+                .await?
+                .addr
+                .ip(),
+        ))
     }
 
     async fn send_discover(
@@ -171,32 +911,56 @@ impl Backend for Http {
         addr: &Self::Addr,
         target: Tag,
         max_level: u16,
-    ) -> Result<Option<(PublicId, Self::Addr)>, Self::Error> {
+    ) -> Result<Option<SignedAddr<Self::Addr>>, Self::Error> {
         Ok(self
-            .send_inner("/peer/discover", addr, Discover { target, max_level })
+            .send_signed("peer/discover", addr, Discover { target, max_level })
             .await?
+            .1
             .peer)
     }
 
+    async fn send_find_node(
+        &self,
+        addr: &Self::Addr,
+        target: Tag,
+        k: usize,
+    ) -> Result<Vec<(PublicId, Self::Addr)>, Self::Error> {
+        Ok(self
+            .send_signed("peer/find_node", addr, FindNode { target, k })
+            .await?
+            .1
+            .peers)
+    }
+
     async fn send_locate(
         &self,
         addr: &Self::Addr,
         tag: Tag,
-    ) -> Result<Result<bool, (PublicId, Self::Addr)>, Self::Error> {
+    ) -> Result<LocateReply<Self::Addr>, Self::Error> {
         Ok(self
-            .send_inner("/peer/locate", addr, Locate { tag })
+            .send_signed("peer/locate", addr, Locate { tag })
             .await?
-            .result)
+            .1
+            .into())
     }
 
     async fn send_upload(
         &self,
         addr: &Self::Addr,
-        data: Box<[u8]>,
-    ) -> Result<Result<(), ()>, Self::Error> {
+        tag: Tag,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Result<Tag, ProtocolError>, Self::Error> {
+        let upload = Upload {
+            data,
+            algorithm: tag.algorithm(),
+            tag: Some(tag),
+            chunk,
+        };
         Ok(self
-            .send_inner("/peer/upload", addr, Upload { data })
+            .send_signed("peer/upload", addr, upload)
             .await?
+            .1
             .result)
     }
 
@@ -204,125 +968,755 @@ impl Backend for Http {
         &self,
         addr: &Self::Addr,
         tag: Tag,
-    ) -> Result<Option<Box<[u8]>>, Self::Error> {
+    ) -> Result<Result<Option<Bytes>, ProtocolError>, Self::Error> {
+        Ok(self
+            .send_signed("peer/download", addr, Download { tag })
+            .await?
+            .1
+            .result)
+    }
+
+    async fn send_audit(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+        challenge: Challenge,
+    ) -> Result<Result<Option<Tag>, ProtocolError>, Self::Error> {
+        Ok(self
+            .send_signed("peer/audit", addr, Audit { tag, challenge })
+            .await?
+            .1
+            .result)
+    }
+
+    async fn send_renew(&self, addr: &Self::Addr, tags: Vec<Tag>) -> Result<usize, Self::Error> {
+        Ok(self
+            .send_signed("peer/renew", addr, Renew { tags })
+            .await?
+            .1
+            .renewed)
+    }
+
+    async fn send_sync(
+        &self,
+        addr: &Self::Addr,
+        region: Region,
+        summary: Summary,
+    ) -> Result<Result<SyncReply, ProtocolError>, Self::Error> {
         Ok(self
-            .send_inner("/peer/download", addr, Download { tag })
+            .send_signed("peer/sync", addr, SyncTags { region, summary })
             .await?
-            .data)
+            .1
+            .result)
+    }
+}
+
+/// Parse a peer address, normalising it with [`canonical_addr`] so that equivalent addresses compare equal.
+pub fn parse_addr(addr: &str) -> Result<Url, Error> {
+    match addr.parse::<Url>() {
+        Ok(url) if !url.cannot_be_a_base() => Ok(canonical_addr(url)),
+        _ => Err(Error::InvalidAddr(addr.to_string())),
+    }
+}
+
+/// The address that `addr` becomes if our public IP address changes to `ip`, or `None` if it's given by name, which
+/// may well follow us to our new IP address by itself.
+pub fn addr_with_ip(addr: &Url, ip: IpAddr) -> Option<Url> {
+    if let url::Host::Domain(_) = addr.host()? {
+        return None;
+    }
+    let mut addr = addr.clone();
+    addr.set_ip_host(ip).ok()?;
+    Some(addr)
+}
+
+/// Ask the given peers which IP address our requests appear to come from, returning the most commonly observed one.
+///
+/// This is useful for determining the address to advertise before a node has been created.
+pub async fn observe_public_ip(peers: &[Url]) -> Option<IpAddr> {
+    let http = Http::create(Config::default()).await.ok()?;
+    let mut observed = BTreeMap::<IpAddr, usize>::new();
+    for peer in peers {
+        match http.send_observe(peer).await {
+            Ok(Some(ip)) => *observed.entry(ip).or_default() += 1,
+            Ok(None) => {}
+            Err(err) => error!("Failed to ask {} for our address: {}", peer, err),
+        }
+    }
+    if observed.len() > 1 {
+        warn!(
+            "Peers disagree about our address ({:?}), we may be behind a symmetric NAT.",
+            observed
+        );
     }
+    observed
+        .into_iter()
+        .max_by_key(|(_, n)| *n)
+        .map(|(ip, _)| ip)
 }
 
 impl Http {
-    async fn send_inner<M: Msg + Serialize>(
+    fn check_admin(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        match &self.config.admin_token {
+            Some(token) => {
+                let provided = headers
+                    .get(header::AUTHORIZATION)
+                    .and_then(|auth| auth.to_str().ok()?.strip_prefix("Bearer "));
+                if provided == Some(token.as_str()) {
+                    Ok(())
+                } else {
+                    Err(StatusCode::UNAUTHORIZED)
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Send a signed message, verifying that the response was signed by the peer
+    async fn send_signed<M: Msg<Url> + Serialize>(
         &self,
         path: &str,
-        addr: &str,
+        addr: &Url,
         msg: M,
-    ) -> Result<M::Resp, Error> {
-        let url = addr.parse::<Url>().unwrap().join(path).unwrap();
-        self.client
-            .get(url)
-            .json(&msg)
-            .send()
-            .await
-            .map_err(Error::Reqwest)?
-            .json()
-            .await
-            .map_err(Error::Reqwest)
+    ) -> Result<(PublicId, M::Resp), Error>
+    where
+        M::Resp: Serialize,
+    {
+        let node = self
+            .node
+            .get()
+            .and_then(Weak::upgrade)
+            .expect("signed messages can only be sent by a node");
+        let resp = self
+            .send_inner::<_, Signed<M::Resp>>(path, addr, msg)
+            .await?;
+        node.open(resp).map_err(Error::Signature)
+    }
+
+    async fn send_inner<M: Msg<Url> + Serialize, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        addr: &Url,
+        msg: M,
+    ) -> Result<R, Error> {
+        // Paths are joined relative to the peer's address, so that peers hosted under a path prefix work
+        let url = canonical_addr(addr.clone())
+            .join(path)
+            .map_err(|_| Error::InvalidAddr(addr.to_string()))?;
+        let node = self
+            .node
+            .get()
+            .and_then(Weak::upgrade)
+            .filter(|_| M::SIGNED);
+        let mut sealed = None;
+        let mut backoff = self.config.retry_backoff;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            // Idempotent messages are signed afresh for each attempt, since the peer may have received (and so will
+            // reject) an earlier one. Others keep their nonce, so that the peer acts on at most one attempt.
+            if let Some(node) = &node {
+                if sealed.is_none() || M::IDEMPOTENT {
+                    sealed = Some(node.seal(&msg).await);
+                }
+            }
+            let req = match &sealed {
+                Some(sealed) => self.client.get(url.clone()).json(sealed),
+                None => self.client.get(url.clone()).json(&msg),
+            };
+            match req.send().await {
+                Ok(resp) if !resp.status().is_success() => {
+                    let status = resp.status();
+                    let reason = read_limited(resp, self.config.max_message_size)
+                        .await
+                        .map(|body| String::from_utf8_lossy(&body).into_owned())
+                        .unwrap_or_default();
+                    break Err(Error::Status { status, reason });
+                }
+                Ok(resp) => {
+                    let limit = self.config.message_limit::<M>();
+                    break match read_limited(resp, limit).await {
+                        Ok(body) => serde_json::from_slice(&body).map_err(Error::Decode),
+                        Err(err) => {
+                            if let (Error::TooLarge { .. }, Some(node)) =
+                                (&err, self.node.get().and_then(Weak::upgrade))
+                            {
+                                node.metrics().oversized();
+                            }
+                            Err(err)
+                        }
+                    };
+                }
+                // Only retry failures that suggest the connection, rather than the peer, was at fault
+                Err(err)
+                    if attempts <= self.config.retries
+                        && (err.is_connect() || err.is_timeout() || err.is_request()) =>
+                {
+                    // Jitter the delay to avoid retrying in lockstep with other nodes
+                    let delay = backoff.mul_f64(thread_rng().gen_range(0.5..1.5));
+                    tokio::time::sleep(delay).await;
+                    backoff *= 2;
+                }
+                Err(err) if attempts > 1 => break Err(Error::Retried { attempts, err }),
+                Err(err) => break Err(Error::Reqwest(err)),
+            }
+        }
     }
 }
 
-pub trait Msg {
-    type Resp: DeserializeOwned;
+// Read a response body, giving up as soon as it's known to be larger than `limit`
+async fn read_limited(mut resp: reqwest::Response, limit: usize) -> Result<Vec<u8>, Error> {
+    if resp.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(Error::TooLarge { limit });
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await.map_err(Error::Reqwest)? {
+        if body.len() + chunk.len() > limit {
+            return Err(Error::TooLarge { limit });
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
 }
 
-#[derive(Serialize, Deserialize)]
-struct Greet {
-    sender: (PublicId, String),
+/// A summary of a node's identity and health, as returned by `GET /status`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    /// The version of the peer protocol that the node speaks.
+    pub version: u16,
+    pub tag: Tag,
+    pub name: String,
+    /// The address the node advertises to its peers.
+    pub addr: Url,
+    pub peers: usize,
+    /// The number of peers in each non-empty level.
+    pub levels: BTreeMap<u16, usize>,
+    /// The number of data entries stored locally.
+    pub entries: usize,
+    pub uptime_secs: u64,
+    /// The IP addresses our peers have observed us at, and how many peers observed each. More than one suggests that
+    /// we're behind a symmetric NAT.
+    pub observed_ips: BTreeMap<IpAddr, usize>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct GreetResp {
-    result: Result<PublicId, Option<String>>,
+/// A file uploaded through the multipart form endpoint, `POST /data/form`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FormUpload {
+    pub tag: Tag,
+    pub filename: String,
+    pub bytes: usize,
 }
 
-impl Msg for Greet {
-    type Resp = GreetResp;
+/// An entry in a node's routing table, as returned by `GET /peers`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PeerEntry {
+    pub tag: Tag,
+    pub name: String,
+    pub addr: Url,
+    pub ping_ms: f64,
+    pub level: u16,
+    /// How far ahead of the node's clock the peer's is, in milliseconds, if that has been estimated.
+    #[serde(default)]
+    pub clock_offset_ms: Option<i64>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Ping;
+/// A publisher with data stored on a node, as returned by `GET /publishers`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublisherEntry {
+    pub publisher: Publisher,
+    /// The number of bytes stored, before any compression.
+    pub bytes: usize,
+}
 
-#[derive(Serialize, Deserialize)]
-struct Pong;
+/// Where a tag resolves to, as returned by `GET /locate/:tag`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocateReport {
+    pub tag: Tag,
+    /// Whether `node` holds the data.
+    pub found: bool,
+    /// The node holding the data or, if nobody does, the closest node to the tag that could be reached.
+    pub node: LocatedNode,
+    /// The nodes asked along the way, in order. Its length is the number of hops the lookup took.
+    pub path: Vec<PathEntry>,
+}
 
-impl Msg for Ping {
-    type Resp = Pong;
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LocatedNode {
+    pub tag: Tag,
+    pub name: String,
+    pub addr: Url,
 }
 
-/// Attempt to discover a new peer by asking existing peers.
-///
-/// `addr` specifies the original requesting peer.
-/// `max_level` specifies the maximum distance (log2) that the returned peer should be from the given address
-#[derive(Serialize, Deserialize)]
-struct Discover {
-    target: Tag,
-    max_level: u16,
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathEntry {
+    pub tag: Tag,
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    // When set, respond with `503 Service Unavailable` if the node has no peers (for use as a readiness probe)
+    #[serde(default)]
+    ready: bool,
 }
 
-#[derive(Serialize, Deserialize)]
-struct DiscoverResp {
-    peer: Option<(PublicId, String)>,
+// Render this node's view of the network as a DOT graph. Node IDs are hex tags, so the output of several nodes can
+// be combined to reconstruct the wider network.
+fn render_graph(node: &Node<Http>) -> String {
+    let peers = node.peer_info();
+    let mut out = "graph network {\n".to_string();
+    for id in std::iter::once(&node.id()).chain(peers.iter().map(|p| &p.id)) {
+        let name = id.human_readable_name(2);
+        writeln!(out, "    \"{}\" [label=\"{}\"];", id.tag, name).unwrap();
+    }
+    for peer in &peers {
+        let (a, b) = (node.id().tag, peer.id.tag);
+        writeln!(
+            out,
+            "    \"{}\" -- \"{}\" [label=\"{}\"];",
+            a, b, peer.level
+        )
+        .unwrap();
+    }
+    out += "}\n";
+    out
 }
 
-impl Msg for Discover {
-    type Resp = DiscoverResp;
+// Render a node's metrics in the Prometheus text exposition format
+fn render_metrics(node: &Node<Http>) -> String {
+    let (peers, levels) = node.with_routing(|routing| {
+        let levels = (0..256)
+            .map(|level| (level, routing.bucket_len(level)))
+            .filter(|(_, peers)| *peers > 0)
+            .collect::<Vec<_>>();
+        (routing.len(), levels)
+    });
+    let stats = node.stats();
+    let metrics = node.metrics();
+    let (lookups, hops) = metrics.lookup_hops();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, u64)]| {
+        writeln!(out, "# HELP nettle_{} {}", name, help).unwrap();
+        writeln!(out, "# TYPE nettle_{} {}", name, kind).unwrap();
+        for (labels, value) in values {
+            writeln!(out, "nettle_{}{} {}", name, labels, value).unwrap();
+        }
+    };
+    metric(
+        "peers",
+        "gauge",
+        "Number of peers in the routing table.",
+        &[(String::new(), peers as u64)],
+    );
+    metric(
+        "level_peers",
+        "gauge",
+        "Number of peers in each non-empty level.",
+        &levels
+            .into_iter()
+            .map(|(level, n)| (format!("{{level=\"{}\"}}", level), n as u64))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "stored_entries",
+        "gauge",
+        "Number of data entries stored locally.",
+        &[(String::new(), stats.entries as u64)],
+    );
+    metric(
+        "stored_bytes",
+        "gauge",
+        "Total size of data stored locally, after any compression.",
+        &[(String::new(), stats.stored_bytes)],
+    );
+    metric(
+        "logical_bytes",
+        "gauge",
+        "Total size of data stored locally, before any compression.",
+        &[(String::new(), stats.logical_bytes)],
+    );
+    metric(
+        "pinned_bytes",
+        "gauge",
+        "Total size of pinned data stored locally, after any compression.",
+        &[(String::new(), stats.pinned_bytes)],
+    );
+    metric(
+        "requests_total",
+        "counter",
+        "Requests received from peers, by message type.",
+        &Request::ALL
+            .into_iter()
+            .map(|req| {
+                (
+                    format!("{{type=\"{}\"}}", req.name()),
+                    metrics.requests(req),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "request_failures_total",
+        "counter",
+        "Requests sent to peers that failed.",
+        &[(String::new(), metrics.failures())],
+    );
+    metric(
+        "oversized_messages_total",
+        "counter",
+        "Messages from peers that were rejected for being too large.",
+        &[(String::new(), metrics.oversized_messages())],
+    );
+    metric(
+        "lookups_total",
+        "counter",
+        "Lookups performed.",
+        &[(String::new(), lookups)],
+    );
+    metric(
+        "lookup_hops_total",
+        "counter",
+        "Total hops taken by lookups.",
+        &[(String::new(), hops)],
+    );
+    metric(
+        "upload_bytes_total",
+        "counter",
+        "Bytes received in uploads.",
+        &[(String::new(), metrics.upload_bytes())],
+    );
+    metric(
+        "download_bytes_total",
+        "counter",
+        "Bytes served in downloads.",
+        &[(String::new(), metrics.download_bytes())],
+    );
+    metric(
+        "busy_rejections_total",
+        "counter",
+        "Uploads turned away because too many others were being handled.",
+        &[(String::new(), metrics.busy_rejections())],
+    );
+    out
 }
 
-/// Attempt to discover a tag in the network.
-#[derive(Serialize, Deserialize)]
-struct Locate {
-    tag: Tag,
+// Peers send their messages with `GET`, but browsers refuse to send a body with one, so clients `POST` them instead
+fn get_or_post<H, T, S, B>(handler: H) -> MethodRouter<S, B>
+where
+    H: Handler<T, S, B>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+    B: HttpBody + Send + 'static,
+{
+    on(MethodFilter::GET | MethodFilter::POST, handler)
 }
 
-#[derive(Serialize, Deserialize)]
-struct LocateResp {
-    // Ok(true) => I own the resource
-    // Ok(false) => I do not own the resource and do not know anybody closer to the resource (404!)
-    // Err(_) => I do not own the resource but this other node is closer to it
-    pub result: Result<bool, (PublicId, String)>,
+// Let pages from any origin use the peer protocol. Its messages are signed, so a page can't act with anybody's
+// authority but its own.
+async fn cors<B>(req: hyper::Request<B>, next: Next<B>) -> Response {
+    let mut resp = if req.method() == hyper::Method::OPTIONS {
+        StatusCode::NO_CONTENT.into_response()
+    } else {
+        next.run(req).await
+    };
+    let headers = resp.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("content-type"),
+    );
+    resp
 }
 
-impl Msg for Locate {
-    type Resp = LocateResp;
+// Reject peer requests from clients that have exceeded their rate limit before they reach the node
+async fn rate_limit<B>(
+    node: State<Arc<Node<Http>>>,
+    req: hyper::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(limits) = &node.backend.config.rate_limit else {
+        return next.run(req).await;
+    };
+    let proxied_ip = limits.trusted_proxy_header.as_ref().and_then(|header| {
+        req.headers()
+            .get(header.as_str())?
+            .to_str()
+            .ok()?
+            .rsplit(',')
+            .next()?
+            .trim()
+            .parse::<IpAddr>()
+            .ok()
+    });
+    let Some(ip) = proxied_ip.or_else(|| {
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|info| info.0.ip())
+    }) else {
+        return next.run(req).await;
+    };
+    let expensive = [
+        "/locate",
+        "/upload",
+        "/download",
+        "/audit",
+        "/renew",
+        "/sync",
+    ]
+    .iter()
+    .any(|path| req.uri().path().ends_with(path));
+    let rate = if expensive {
+        limits.expensive
+    } else {
+        limits.cheap
+    };
+
+    let retry_after = {
+        let mut buckets = node.backend.buckets.lock().unwrap();
+        let now = Instant::now();
+        // Forget about clients that have been quiet long enough to have refilled their bucket
+        if buckets.len() > 4096 {
+            buckets.retain(|(_, expensive), bucket| {
+                let rate = if *expensive {
+                    limits.expensive
+                } else {
+                    limits.cheap
+                };
+                bucket.tokens + (now - bucket.last).as_secs_f64() * rate.per_second
+                    < rate.burst as f64
+            });
+        }
+        let bucket = buckets.entry((ip, expensive)).or_insert(Bucket {
+            tokens: rate.burst as f64,
+            last: now,
+        });
+        bucket.tokens = (bucket.tokens + (now - bucket.last).as_secs_f64() * rate.per_second)
+            .min(rate.burst as f64);
+        bucket.last = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(((1.0 - bucket.tokens) / rate.per_second).ceil() as u64)
+        }
+    };
+    match retry_after {
+        None => next.run(req).await,
+        Some(secs) => (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, secs.to_string())],
+        )
+            .into_response(),
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct Upload {
-    data: Box<[u8]>,
+async fn web_ui() -> Html<&'static str> {
+    Html(include_str!("../../data/index.html"))
 }
 
-#[derive(Serialize, Deserialize)]
-struct UploadResp {
-    result: Result<(), ()>,
+// Turn away a client's upload when the node is handling as many as it can
+fn busy() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "1")],
+        "too many uploads in progress",
+    )
+        .into_response()
 }
 
-impl Msg for Upload {
-    type Resp = UploadResp;
+// Guess the content type of some data from its leading bytes
+fn sniff_content_type(data: &[u8]) -> HeaderValue {
+    const MAGIC: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"\0asm", "application/wasm"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+    let content_type =
+        if let Some((_, ty)) = MAGIC.iter().find(|(magic, _)| data.starts_with(magic)) {
+            ty
+        } else if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP") {
+            "image/webp"
+        } else if data.get(4..8) == Some(b"ftyp") {
+            "video/mp4"
+        } else if std::str::from_utf8(data).is_ok() {
+            "text/plain; charset=utf-8"
+        } else {
+            "application/octet-stream"
+        };
+    HeaderValue::from_static(content_type)
 }
 
-#[derive(Serialize, Deserialize)]
-struct Download {
-    pub tag: Tag,
+// Whether the request's `If-None-Match` header matches the given tag
+fn etag_matches(headers: &HeaderMap, tag: Tag) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|etags| etags.to_str().ok())
+        .flat_map(|etags| etags.split(','))
+        .map(|etag| etag.trim())
+        .any(|etag| {
+            etag == "*"
+                || etag
+                    .trim_start_matches("W/")
+                    .trim_matches('"')
+                    .eq_ignore_ascii_case(&tag.to_string())
+        })
+}
+
+// Mark a successful response for some data as cacheable forever
+fn with_cache_headers(mut resp: Response, tag: Tag) -> Response {
+    if resp.status().is_success() || resp.status() == StatusCode::NOT_MODIFIED {
+        let headers = resp.headers_mut();
+        headers.insert(header::ETAG, format!("\"{}\"", tag).parse().unwrap());
+        headers.insert(
+            header::CACHE_CONTROL,
+            "public, max-age=31536000, immutable".parse().unwrap(),
+        );
+    }
+    resp
+}
+
+// Serve a blob, honouring any `Range` header in the request
+fn serve_data(headers: &HeaderMap, data: Bytes) -> Response {
+    let len = data.len();
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .map_or(Ok(None), |range| parse_range(range, len));
+    match range {
+        Ok(None) => ([(header::ACCEPT_RANGES, "bytes")], data).into_response(),
+        Ok(Some(range)) => (
+            StatusCode::PARTIAL_CONTENT,
+            [
+                (header::ACCEPT_RANGES, "bytes".to_string()),
+                (
+                    header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+                ),
+            ],
+            data.slice(range),
+        )
+            .into_response(),
+        Err(()) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+        )
+            .into_response(),
+    }
 }
 
-#[derive(Serialize, Deserialize)]
-struct DownloadResp {
-    // Some(_) => I own the resource and here it is
-    // None => I do not own the resource
-    pub data: Option<Box<[u8]>>,
+// Parse a `Range` header against a blob of the given length.
+// Ok(None) => the header is malformed or asks for multiple ranges, so should be ignored
+// Err(()) => the range can't be satisfied
+fn parse_range(range: &str, len: usize) -> Result<Option<Range<usize>>, ()> {
+    let Some((start, end)) = range
+        .trim()
+        .strip_prefix("bytes=")
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'))
+    else {
+        return Ok(None);
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix range, the last `n` bytes
+        ("", n) => match n.parse::<usize>() {
+            Ok(0) => return Err(()),
+            Ok(n) => (len.saturating_sub(n), len),
+            Err(_) => return Ok(None),
+        },
+        (start, "") => match start.parse::<usize>() {
+            Ok(start) => (start, len),
+            Err(_) => return Ok(None),
+        },
+        (start, end) => match (start.parse::<usize>(), end.parse::<usize>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.saturating_add(1).min(len)),
+            _ => return Ok(None),
+        },
+    };
+    if start < len {
+        Ok(Some(start..end))
+    } else {
+        Err(())
+    }
 }
 
-impl Msg for Download {
-    type Resp = DownloadResp;
+// Buffer a request body, rejecting it as soon as it grows beyond `limit` bytes
+async fn read_body<E: fmt::Display>(
+    headers: &HeaderMap,
+    body: impl Stream<Item = Result<Bytes, E>>,
+    limit: usize,
+) -> Result<Bytes, (StatusCode, String)> {
+    let mut body = std::pin::pin!(body);
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("upload exceeds the maximum size of {} bytes", limit),
+        )
+    };
+    // Trust the advertised length only as a hint, the stream is checked regardless
+    let len_hint = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+    if len_hint.is_some_and(|len| len > limit) {
+        return Err(too_large());
+    }
+
+    let mut data = Vec::with_capacity(len_hint.unwrap_or(0));
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        if data.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data.into())
+}
+
+/// Extracts a signed message, rejecting it with `401 Unauthorized` if its signature is invalid, it has expired, or it
+/// has been seen before.
+struct Verified<M>(PublicId, M);
+
+#[async_trait::async_trait]
+impl<M, B> FromRequest<Arc<Node<Http>>, B> for Verified<M>
+where
+    M: Msg<Url> + Serialize + DeserializeOwned,
+    Json<Signed<M>>: FromRequest<Arc<Node<Http>>, B>,
+    B: Send + 'static,
+{
+    type Rejection = Response;
+
+    async fn from_request(
+        req: hyper::Request<B>,
+        node: &Arc<Node<Http>>,
+    ) -> Result<Self, Self::Rejection> {
+        let Json(msg) = Json::<Signed<M>>::from_request(req, node)
+            .await
+            .map_err(|rejection| {
+                let resp = rejection.into_response();
+                if resp.status() == StatusCode::PAYLOAD_TOO_LARGE {
+                    node.metrics().oversized();
+                }
+                resp
+            })?;
+        node.open(msg)
+            .map(|(signer, msg)| Verified(signer, msg))
+            .map_err(|err| (StatusCode::UNAUTHORIZED, err.to_string()).into_response())
+    }
 }
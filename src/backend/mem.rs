@@ -1,5 +1,26 @@
-use crate::{Backend, Node, PublicId, Tag};
-use std::{cmp, fmt, hash, sync::Arc, sync::OnceLock, time::Duration};
+use crate::{
+    audit::Challenge,
+    msg::{Greet, Ping, Pong, SyncReply},
+    signed::now_millis,
+    sync::{Region, Summary},
+    Backend, GreetRefusal, GreetReply, LocateReply, Node, NodeStats, ProtocolError, PublicId,
+    Request, Signature, SignatureError, Signed, SignedAddr, Tag,
+};
+use bytes::Bytes;
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+use std::{
+    cmp,
+    collections::{HashMap, HashSet},
+    fmt, fs, hash,
+    io::{self, Write},
+    path::Path,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, warn};
 
 #[derive(Clone, Default)]
 pub struct Addr(pub Arc<OnceLock<Arc<Node<Mem>>>>);
@@ -16,46 +37,475 @@ impl cmp::PartialEq for Addr {
 }
 impl cmp::Eq for Addr {}
 
+// Addresses never leave the process, so only their identity needs to be covered by signatures
+impl Serialize for Addr {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (Arc::as_ptr(&self.0) as usize).serialize(serializer)
+    }
+}
+
 impl fmt::Debug for Addr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "<addr>")
+        // Safe to do even while the node is busy: `Node::id` only briefly takes the read lock on the node's identity, and
+        // rotating it never formats an address while holding the write lock
+        match self.0.get() {
+            Some(node) => write!(f, "{:?}", node.id()),
+            // Until the node is created, fall back to a short (but stable) identifier for the address itself
+            None => write!(
+                f,
+                "<addr {:04x}>",
+                (Arc::as_ptr(&self.0) as usize >> 4) & 0xFFFF
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("message was dropped by the network")]
+    Dropped,
+    #[error("destination is in a different partition")]
+    Partitioned,
+    #[error("destination is offline")]
+    Offline,
+    #[error("message was rejected: {0}")]
+    Signature(#[from] SignatureError),
+}
+
+/// The conditions that messages between nodes are subject to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkConfig {
+    /// Round-trip latency is sampled uniformly between these bounds.
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// The probability, between `0.0` and `1.0`, that a message is lost.
+    pub drop_chance: f64,
+    /// The probability, between `0.0` and `1.0`, that the data carried by an upload or download arrives corrupted.
+    pub corrupt_chance: f64,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            min_latency: Duration::ZERO,
+            max_latency: Duration::ZERO,
+            drop_chance: 0.0,
+            corrupt_chance: 0.0,
+        }
+    }
+}
+
+/// A message sent between nodes, as captured by [`Network::record`].
+#[derive(Clone, Debug)]
+pub struct Message {
+    /// The time since recording began at which the message was sent.
+    pub time: Duration,
+    pub from: Addr,
+    pub to: Addr,
+    pub kind: Request,
+    /// A compact description of the message's contents.
+    pub summary: String,
+    /// Whether the message made it to its destination.
+    pub delivered: bool,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>10.3}s {:?} -> {:?} {}",
+            self.time.as_secs_f64(),
+            self.from,
+            self.to,
+            self.kind.name(),
+        )?;
+        if !self.summary.is_empty() {
+            write!(f, " {}", self.summary)?;
+        }
+        if !self.delivered {
+            write!(f, " (lost)")?;
+        }
+        Ok(())
+    }
+}
+
+struct Recording {
+    started: Instant,
+    messages: Vec<Message>,
+}
+
+struct NetworkState {
+    default: NetworkConfig,
+    links: HashMap<(Addr, Addr), NetworkConfig>,
+    partitions: HashMap<Addr, usize>,
+    offline: HashSet<Addr>,
+    recording: Option<Recording>,
+    rng: ChaCha8Rng,
+}
+
+impl NetworkState {
+    fn capture(
+        &mut self,
+        from: &Addr,
+        to: &Addr,
+        kind: Request,
+        summary: impl FnOnce() -> String,
+        delivered: bool,
+    ) {
+        if let Some(recording) = &mut self.recording {
+            recording.messages.push(Message {
+                time: recording.started.elapsed(),
+                from: from.clone(),
+                to: to.clone(),
+                kind,
+                summary: summary(),
+                delivered,
+            });
+        }
+    }
+}
+
+/// A simulated network shared between in-memory nodes.
+///
+/// By default, messages are delivered instantly and are never lost.
+#[derive(Clone)]
+pub struct Network(Arc<Mutex<NetworkState>>);
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::new(NetworkConfig::default(), 0)
+    }
+}
+
+impl Network {
+    /// Create a network applying `config` to every link, with a seed used to make runs reproducible.
+    pub fn new(config: NetworkConfig, seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(NetworkState {
+            default: config,
+            links: HashMap::new(),
+            partitions: HashMap::new(),
+            offline: HashSet::new(),
+            recording: None,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        })))
+    }
+
+    /// Change the conditions of every link that has not been configured individually.
+    pub fn set_default(&self, config: NetworkConfig) {
+        self.0.lock().unwrap().default = config;
+    }
+
+    /// Change the conditions of the link between two nodes, in both directions.
+    pub fn set_link(&self, a: &Addr, b: &Addr, config: NetworkConfig) {
+        let mut state = self.0.lock().unwrap();
+        state.links.insert((a.clone(), b.clone()), config.clone());
+        state.links.insert((b.clone(), a.clone()), config);
+    }
+
+    /// Split the network into the given groups of nodes, such that messages can only be sent within a group.
+    ///
+    /// Nodes not in any group can only talk to each other.
+    pub fn partition<'a, G: IntoIterator<Item = &'a Addr>>(
+        &self,
+        groups: impl IntoIterator<Item = G>,
+    ) {
+        let mut state = self.0.lock().unwrap();
+        state.partitions.clear();
+        for (id, group) in groups.into_iter().enumerate() {
+            for addr in group {
+                state.partitions.insert(addr.clone(), id);
+            }
+        }
+    }
+
+    /// Undo any partitioning, allowing every node to talk to every other node again.
+    pub fn heal(&self) {
+        self.0.lock().unwrap().partitions.clear();
+    }
+
+    /// Take a node off the network, such that any message sent to or from it fails.
+    pub fn disconnect(&self, addr: &Addr) {
+        self.0.lock().unwrap().offline.insert(addr.clone());
+    }
+
+    /// Put a node taken off the network with [`Network::disconnect`] back on it.
+    pub fn reconnect(&self, addr: &Addr) {
+        self.0.lock().unwrap().offline.remove(addr);
+    }
+
+    /// Start capturing every message sent across the network, discarding any previously captured.
+    pub fn record(&self) {
+        self.0.lock().unwrap().recording = Some(Recording {
+            started: Instant::now(),
+            messages: Vec::new(),
+        });
+    }
+
+    /// Stop capturing messages, returning those captured so far.
+    pub fn stop_recording(&self) -> Vec<Message> {
+        self.0
+            .lock()
+            .unwrap()
+            .recording
+            .take()
+            .map_or_else(Vec::new, |r| r.messages)
+    }
+
+    /// The messages captured so far, in the order they were sent.
+    pub fn messages(&self) -> Vec<Message> {
+        self.0
+            .lock()
+            .unwrap()
+            .recording
+            .as_ref()
+            .map_or_else(Vec::new, |r| r.messages.clone())
+    }
+
+    /// Write the messages captured so far to a file, one per line.
+    pub fn dump(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for msg in self.messages() {
+            writeln!(file, "{}", msg)?;
+        }
+        file.flush()
+    }
+
+    // Maybe flip a bit of data sent from one node to another, according to the link's corruption chance
+    fn tamper(&self, from: &Addr, to: &Addr, data: &mut [u8]) {
+        let mut state = self.0.lock().unwrap();
+        let corrupt_chance = state
+            .links
+            .get(&(from.clone(), to.clone()))
+            .unwrap_or(&state.default)
+            .corrupt_chance;
+        if !data.is_empty() && state.rng.gen_bool(corrupt_chance) {
+            let idx = state.rng.gen_range(0..data.len());
+            data[idx] ^= 1 << state.rng.gen_range(0..8);
+            debug!("Corrupted a message from {:?} to {:?}", from, to);
+        }
+    }
+
+    async fn transit(
+        &self,
+        from: &Addr,
+        to: &Addr,
+        kind: Request,
+        summary: impl FnOnce() -> String,
+    ) -> Result<(), Error> {
+        let (latency, dropped) = {
+            let mut state = self.0.lock().unwrap();
+            let failure = if state.offline.contains(from) || state.offline.contains(to) {
+                Some(Error::Offline)
+            } else if state.partitions.get(from) != state.partitions.get(to) {
+                Some(Error::Partitioned)
+            } else {
+                None
+            };
+            if let Some(err) = failure {
+                state.capture(from, to, kind, summary, false);
+                return Err(err);
+            }
+            let config = state
+                .links
+                .get(&(from.clone(), to.clone()))
+                .unwrap_or(&state.default)
+                .clone();
+            let latency = if config.max_latency > config.min_latency {
+                state.rng.gen_range(config.min_latency..=config.max_latency)
+            } else {
+                config.min_latency
+            };
+            let dropped = state.rng.gen_bool(config.drop_chance);
+            state.capture(from, to, kind, summary, !dropped);
+            (latency, dropped)
+        };
+        tokio::time::sleep(latency).await;
+        if dropped {
+            Err(Error::Dropped)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+pub struct Config {
+    pub addr: Addr,
+    pub network: Network,
+    /// Whether to sign messages and verify them on receipt, as a real network would. Large simulations may wish
+    /// to disable this, since nodes sharing a process have no reason to distrust each other.
+    pub sign_messages: bool,
+}
+
+impl From<Addr> for Config {
+    fn from(addr: Addr) -> Self {
+        Self {
+            addr,
+            network: Network::default(),
+            sign_messages: true,
+        }
     }
 }
 
 pub struct Mem {
-    addr: Addr,
+    // Only replaced by `rebind`
+    addr: Mutex<Addr>,
+    network: Network,
+    sign_messages: bool,
+}
+
+impl Mem {
+    fn addr(&self) -> Addr {
+        self.addr.lock().unwrap().clone()
+    }
+
+    // Carry a request body to another node, returning the node along with the body as it arrived
+    async fn deliver<'a, T: Serialize + Send>(
+        &self,
+        addr: &'a Addr,
+        kind: Request,
+        body: T,
+        summary: impl FnOnce(&T) -> String,
+    ) -> Result<(&'a Arc<Node<Mem>>, T), Error> {
+        self.deliver_from(addr, kind, body, summary)
+            .await
+            .map(|(node, _, body)| (node, body))
+    }
+
+    // Like `deliver`, also returning the verified sender of the body if messages are signed
+    async fn deliver_from<'a, T: Serialize + Send>(
+        &self,
+        addr: &'a Addr,
+        kind: Request,
+        body: T,
+        summary: impl FnOnce(&T) -> String,
+    ) -> Result<(&'a Arc<Node<Mem>>, Option<PublicId>, T), Error> {
+        let node = addr.0.get().unwrap();
+        let from = self.addr();
+        if self.sign_messages {
+            let sealed = from.0.get().unwrap().seal(body).await;
+            self.network
+                .transit(&from, addr, kind, || summary(&sealed.body))
+                .await?;
+            let (sender, body) = node.open(sealed).inspect_err(|err| {
+                warn!(
+                    "Message from {:?} to {:?} failed verification: {}",
+                    from, addr, err
+                )
+            })?;
+            Ok((node, Some(sender), body))
+        } else {
+            self.network
+                .transit(&from, addr, kind, || summary(&body))
+                .await?;
+            Ok((node, None, body))
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl Backend for Mem {
     type Addr = Addr;
-    type Config = Addr;
-    type Error = std::convert::Infallible;
+    type Config = Config;
+    type Error = Error;
 
-    async fn create(addr: Self::Config) -> Result<Self, Self::Error> {
-        Ok(Self { addr })
+    async fn create(config: Self::Config) -> Result<Self, Self::Error> {
+        Ok(Self {
+            addr: Mutex::new(config.addr),
+            network: config.network,
+            sign_messages: config.sign_messages,
+        })
     }
 
     async fn init(&self, node: &Arc<Node<Self>>) {
-        node.backend.addr.0.set(node.clone()).ok().unwrap();
+        self.addr().0.set(node.clone()).ok().unwrap();
     }
 
-    async fn host(_: Arc<Node<Self>>) -> Result<(), Self::Error> {
-        let () = futures::future::pending().await;
+    fn rebind(&self, addr: &Self::Addr) {
+        let mut current = self.addr.lock().unwrap();
+        let node = current.0.get().unwrap().clone();
+        // Like a fresh address in `init`, the new one mustn't already belong to another node
+        if addr.0.set(node.clone()).is_err() {
+            assert!(Arc::ptr_eq(addr.0.get().unwrap(), &node));
+        }
+        *current = addr.clone();
+    }
+
+    async fn host(node: Arc<Node<Self>>) -> Result<(), Self::Error> {
+        node.shutdown_requested().await;
         Ok(())
     }
 
     async fn send_greet(
         &self,
         addr: &Self::Addr,
-        sender: (PublicId, Self::Addr),
-    ) -> Result<Result<PublicId, Option<Self::Addr>>, Self::Error> {
-        Ok(addr.0.get().unwrap().recv_greet(sender).await)
+        greet: Greet<Self::Addr>,
+    ) -> Result<Result<GreetReply, GreetRefusal<Self::Addr>>, Self::Error> {
+        let (node, greet) = self
+            .deliver(addr, Request::Greet, greet, |greet| {
+                format!("{:?}", greet.sender.0)
+            })
+            .await?;
+        Ok(node.recv_greet(greet).await)
     }
 
-    async fn send_ping(&self, addr: &Self::Addr) -> Result<Duration, Self::Error> {
-        addr.0.get().unwrap().recv_ping().await;
-        Ok(Duration::ZERO)
+    async fn send_prove(
+        &self,
+        addr: &Self::Addr,
+        id: PublicId,
+        proof: Signature,
+    ) -> Result<bool, Self::Error> {
+        let (node, (id, proof)) = self
+            .deliver(addr, Request::Prove, (id, proof), |(id, _)| {
+                format!("{:?}", id)
+            })
+            .await?;
+        Ok(node.recv_prove(id, proof).await)
+    }
+
+    async fn send_rotate(
+        &self,
+        addr: &Self::Addr,
+        endorsement: Signed<PublicId>,
+    ) -> Result<bool, Self::Error> {
+        let (node, endorsement) = self
+            .deliver(addr, Request::Rotate, endorsement, |endorsement| {
+                format!("{:?} -> {:?}", endorsement.sender, endorsement.body)
+            })
+            .await?;
+        Ok(node.recv_rotate(endorsement).await)
+    }
+
+    async fn send_readdress(
+        &self,
+        addr: &Self::Addr,
+        record: SignedAddr<Self::Addr>,
+    ) -> Result<bool, Self::Error> {
+        let (node, sender, record) = self
+            .deliver_from(addr, Request::Readdress, record, |record| {
+                format!("{:?}", record.sender)
+            })
+            .await?;
+        Ok(node.recv_readdress(sender.as_ref(), record).await)
+    }
+
+    async fn send_ping(
+        &self,
+        addr: &Self::Addr,
+    ) -> Result<(Duration, Pong<Self::Addr>), Self::Error> {
+        let start = tokio::time::Instant::now();
+        let ping = Ping { sent: now_millis() };
+        let (node, sender, ping) = self
+            .deliver_from(addr, Request::Ping, ping, |ping| ping.sent.to_string())
+            .await?;
+        let pong = node.recv_ping_from(sender.as_ref(), ping).await;
+        Ok((start.elapsed(), pong))
+    }
+
+    async fn send_info(&self, addr: &Self::Addr) -> Result<Option<NodeStats>, Self::Error> {
+        let (node, ()) = self
+            .deliver(addr, Request::Info, (), |_| String::new())
+            .await?;
+        Ok(Some(node.recv_info().await))
     }
 
     async fn send_discover(
@@ -63,31 +513,115 @@ impl Backend for Mem {
         addr: &Self::Addr,
         target: Tag,
         max_level: u16,
-    ) -> Result<Option<(PublicId, Self::Addr)>, Self::Error> {
-        Ok(addr.0.get().unwrap().recv_discover(target, max_level).await)
+    ) -> Result<Option<SignedAddr<Self::Addr>>, Self::Error> {
+        let (node, (target, max_level)) = self
+            .deliver(
+                addr,
+                Request::Discover,
+                (target, max_level),
+                |(target, max_level)| format!("{} level {}", target, max_level),
+            )
+            .await?;
+        Ok(node.recv_discover(target, max_level).await)
+    }
+
+    async fn send_find_node(
+        &self,
+        addr: &Self::Addr,
+        target: Tag,
+        k: usize,
+    ) -> Result<Vec<(PublicId, Self::Addr)>, Self::Error> {
+        let (node, (target, k)) = self
+            .deliver(addr, Request::FindNode, (target, k), |(target, k)| {
+                format!("{} k {}", target, k)
+            })
+            .await?;
+        Ok(node.recv_find_node(target, k).await)
     }
 
     async fn send_locate(
         &self,
         addr: &Self::Addr,
         tag: Tag,
-    ) -> Result<Result<bool, (PublicId, Self::Addr)>, Self::Error> {
-        Ok(addr.0.get().unwrap().recv_locate(tag).await)
+    ) -> Result<LocateReply<Self::Addr>, Self::Error> {
+        let (node, tag) = self
+            .deliver(addr, Request::Locate, tag, Tag::to_string)
+            .await?;
+        Ok(node.recv_locate(tag).await)
     }
 
     async fn send_upload(
         &self,
         addr: &Self::Addr,
-        data: Box<[u8]>,
-    ) -> Result<Result<(), ()>, Self::Error> {
-        Ok(addr.0.get().unwrap().recv_upload(data).await)
+        tag: Tag,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Result<Tag, ProtocolError>, Self::Error> {
+        let (node, sender, (tag, data)) = self
+            .deliver_from(addr, Request::Upload, (tag, data), |(tag, data)| {
+                format!("{} bytes as {}", data.len(), tag)
+            })
+            .await?;
+        // The receiver gets its own copy, as it would over a real network
+        let mut data = data.to_vec();
+        self.network.tamper(&self.addr(), addr, &mut data);
+        Ok(node
+            .recv_tagged_upload_from(sender.as_ref(), tag, data.into(), chunk)
+            .await)
     }
 
     async fn send_download(
         &self,
         addr: &Self::Addr,
         tag: Tag,
-    ) -> Result<Option<Box<[u8]>>, Self::Error> {
-        Ok(addr.0.get().unwrap().recv_download(tag).await)
+    ) -> Result<Result<Option<Bytes>, ProtocolError>, Self::Error> {
+        let (node, tag) = self
+            .deliver(addr, Request::Download, tag, Tag::to_string)
+            .await?;
+        let mut data = node.recv_download(tag).await;
+        if let Ok(Some(data)) = &mut data {
+            // Like a real network, the downloader gets its own copy, which may be corrupted in transit
+            let mut copy = data.to_vec();
+            self.network.tamper(addr, &self.addr(), &mut copy);
+            *data = copy.into();
+        }
+        Ok(data)
+    }
+
+    async fn send_audit(
+        &self,
+        addr: &Self::Addr,
+        tag: Tag,
+        challenge: Challenge,
+    ) -> Result<Result<Option<Tag>, ProtocolError>, Self::Error> {
+        let (node, (tag, challenge)) = self
+            .deliver(addr, Request::Audit, (tag, challenge), |(tag, _)| {
+                tag.to_string()
+            })
+            .await?;
+        Ok(node.recv_audit(tag, challenge).await)
+    }
+
+    async fn send_renew(&self, addr: &Self::Addr, tags: Vec<Tag>) -> Result<usize, Self::Error> {
+        let (node, tags) = self
+            .deliver(addr, Request::Renew, tags, |tags| {
+                format!("{} chunks", tags.len())
+            })
+            .await?;
+        Ok(node.recv_renew(tags).await)
+    }
+
+    async fn send_sync(
+        &self,
+        addr: &Self::Addr,
+        region: Region,
+        summary: Summary,
+    ) -> Result<Result<SyncReply, ProtocolError>, Self::Error> {
+        let (node, (region, summary)) = self
+            .deliver(addr, Request::Sync, (region, summary), |(region, _)| {
+                format!("{}/{}", region.prefix, region.len)
+            })
+            .await?;
+        Ok(node.recv_sync(region, summary).await)
     }
 }
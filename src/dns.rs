@@ -0,0 +1,93 @@
+//! Finding bootstrap peers that are published in DNS.
+//!
+//! A domain lists peers in TXT records of the form `nettle-peer=<url>`. It may also list them as SRV records under
+//! `_nettle._tcp.<domain>`, which are reached over plain HTTP.
+
+use crate::http;
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::{ResolveError, ResolveErrorKind},
+    TokioAsyncResolver,
+};
+use thiserror::Error;
+use url::Url;
+
+/// The prefix of the TXT records that list peers.
+pub const TXT_PREFIX: &str = "nettle-peer=";
+/// The service and protocol labels of the SRV records that list peers.
+pub const SRV_SERVICE: &str = "_nettle._tcp";
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    #[error("resolve: {0}")]
+    Resolve(#[from] ResolveError),
+    #[error("{domain} has no usable `nettle-peer=<url>` TXT records or _nettle._tcp SRV records ({malformed} malformed)")]
+    NoUsableRecords { domain: String, malformed: usize },
+}
+
+/// Parse the character strings of a TXT record, returning `None` if it doesn't list a peer.
+///
+/// Records longer than 255 bytes are split into several strings, which are joined back together first.
+pub fn parse_txt<S: AsRef<[u8]>>(strings: &[S]) -> Option<Result<Url, http::Error>> {
+    let text = strings
+        .iter()
+        .map(|s| String::from_utf8_lossy(s.as_ref()))
+        .collect::<String>();
+    let addr = text.trim().strip_prefix(TXT_PREFIX)?;
+    Some(http::parse_addr(addr.trim()))
+}
+
+/// The address of a peer listed in an SRV record.
+pub fn parse_srv(target: &str, port: u16) -> Result<Url, http::Error> {
+    // Names in records are fully qualified, but the trailing dot would be kept in the URL's host
+    http::parse_addr(&format!("http://{}:{}", target.trim_end_matches('.'), port))
+}
+
+// Whether a lookup failed only because there was nothing to find
+fn is_empty(err: &ResolveError) -> bool {
+    matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. })
+}
+
+/// Look up the peers listed under `domain`, in the order they were found.
+pub async fn resolve_peers(domain: &str) -> Result<Vec<Url>, DnsError> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap_or_else(|_| {
+        TokioAsyncResolver::tokio(ResolverConfig::default(), ResolverOpts::default())
+    });
+    let mut found = Vec::new();
+    let mut failure = None;
+
+    match resolver.txt_lookup(domain).await {
+        Ok(lookup) => found.extend(lookup.iter().filter_map(|txt| parse_txt(txt.txt_data()))),
+        Err(err) if is_empty(&err) => {}
+        Err(err) => failure = Some(err),
+    }
+    match resolver
+        .srv_lookup(format!("{}.{}", SRV_SERVICE, domain))
+        .await
+    {
+        Ok(lookup) => found.extend(
+            lookup
+                .iter()
+                .map(|srv| parse_srv(&srv.target().to_utf8(), srv.port())),
+        ),
+        Err(err) if is_empty(&err) => {}
+        Err(err) => failure = failure.or(Some(err)),
+    }
+
+    let malformed = found.iter().filter(|peer| peer.is_err()).count();
+    let mut peers = Vec::new();
+    for peer in found.into_iter().flatten() {
+        if !peers.contains(&peer) {
+            peers.push(peer);
+        }
+    }
+    match failure {
+        // Only give up on a failed lookup if the other one found nothing either
+        Some(err) if peers.is_empty() => Err(err.into()),
+        _ if peers.is_empty() => Err(DnsError::NoUsableRecords {
+            domain: domain.to_string(),
+            malformed,
+        }),
+        _ => Ok(peers),
+    }
+}
@@ -0,0 +1,80 @@
+//! Data encrypted for a single recipient, such that the nodes storing it only ever see ciphertext.
+//!
+//! An envelope is laid out as follows:
+//!
+//! | Bytes     | Contents                                                       |
+//! |-----------|----------------------------------------------------------------|
+//! | 1         | The format version, currently `1`                              |
+//! | 2         | The length of the wrapped key, big-endian                      |
+//! | *n*       | The symmetric key, encrypted to the recipient's RSA key (OAEP) |
+//! | 24        | The XChaCha20-Poly1305 nonce                                   |
+//! | Remainder | The payload, encrypted and authenticated with the key          |
+//!
+//! Everything preceding the payload is authenticated along with it.
+
+use crate::{PrivateId, PublicId};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use rand::prelude::*;
+
+const VERSION: u8 = 1;
+const NONCE_LEN: usize = 24;
+
+/// Encrypt `data` such that only `recipient` can read it.
+pub fn seal(recipient: &PublicId, data: &[u8]) -> Box<[u8]> {
+    let key = XChaCha20Poly1305::generate_key(&mut thread_rng());
+    let mut nonce = XNonce::default();
+    thread_rng().fill_bytes(&mut nonce);
+    let wrapped_key = recipient.encrypt(&key);
+
+    let mut envelope = vec![VERSION];
+    envelope.extend_from_slice(&(wrapped_key.len() as u16).to_be_bytes());
+    envelope.extend_from_slice(&wrapped_key);
+    envelope.extend_from_slice(&nonce);
+    let ciphertext = XChaCha20Poly1305::new(&key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: data,
+                aad: &envelope,
+            },
+        )
+        .expect("payload too large to encrypt");
+    envelope.extend_from_slice(&ciphertext);
+    envelope.into_boxed_slice()
+}
+
+/// Decrypt an envelope produced by [`seal`], checking that it hasn't been tampered with.
+pub fn open(id: &PrivateId, envelope: &[u8]) -> Result<Box<[u8]>, &'static str> {
+    match envelope.first() {
+        Some(&VERSION) => {}
+        Some(_) => return Err("unsupported envelope version"),
+        None => return Err("envelope is empty"),
+    }
+    let key_len = envelope
+        .get(1..3)
+        .map(|len| u16::from_be_bytes([len[0], len[1]]) as usize)
+        .ok_or("envelope is truncated")?;
+    let header_len = 3 + key_len + NONCE_LEN;
+    if envelope.len() < header_len {
+        return Err("envelope is truncated");
+    }
+    let (header, ciphertext) = envelope.split_at(header_len);
+    let key = id
+        .decrypt(&header[3..3 + key_len])
+        .filter(|key| key.len() == 32)
+        .ok_or("envelope is not addressed to us")?;
+    let nonce = XNonce::from_slice(&header[3 + key_len..]);
+    XChaCha20Poly1305::new(key.as_slice().into())
+        .decrypt(
+            nonce,
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map(Vec::into_boxed_slice)
+        .map_err(|_| "envelope has been tampered with")
+}
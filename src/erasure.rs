@@ -0,0 +1,229 @@
+//! Reed–Solomon erasure coding, for storing content at a lower cost than keeping whole copies of it.
+//!
+//! Content is split into `k` data shards, from which `m` parity shards are computed. Each shard is stored under its own
+//! digest, and any `k` of them are enough to reconstruct the content. A manifest describing the scheme is stored under
+//! the tag that names the content as a whole.
+//!
+//! A manifest is laid out as follows, with integers big-endian:
+//!
+//! | Bytes          | Contents                                                                         |
+//! |----------------|----------------------------------------------------------------------------------|
+//! | 4              | The magic bytes `NTEC`                                                           |
+//! | 1              | The format version, currently `1`                                                |
+//! | 1              | The number of data shards, *k*, which is at least `1`                            |
+//! | 1              | The number of parity shards, *m*, which is at least `1`                          |
+//! | 8              | The length of the content                                                        |
+//! | 33             | The content's tag: its [algorithm code](HashAlgorithm::code), then its digest    |
+//! | 33 × (*k + m*) | Each shard's tag in the same form, data shards first                             |
+//!
+//! Nothing follows the last shard's tag. Every shard is `len / k` bytes long, rounded up, but at least one byte. The
+//! content is the data shards one after the other, with the padding at the end of the last one dropped. *k + m* may
+//! be no more than 256.
+
+use crate::{HashAlgorithm, Tag};
+use bytes::Bytes;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::cmp::Ordering;
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"NTEC";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 15;
+const TAG_LEN: usize = 33;
+const MAX_SHARDS: usize = 256;
+
+/// How content is split into shards by [`encode`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Scheme {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+    /// The algorithm that the content and its shards are tagged with.
+    pub algorithm: HashAlgorithm,
+}
+
+impl Default for Scheme {
+    fn default() -> Self {
+        Self {
+            data_shards: 4,
+            parity_shards: 2,
+            algorithm: HashAlgorithm::default(),
+        }
+    }
+}
+
+impl Scheme {
+    fn is_valid(&self) -> bool {
+        self.data_shards >= 1
+            && self.parity_shards >= 1
+            && self.data_shards + self.parity_shards <= MAX_SHARDS
+    }
+
+    fn codec(&self) -> ReedSolomon {
+        ReedSolomon::new(self.data_shards, self.parity_shards).expect("scheme was checked")
+    }
+}
+
+/// Why erasure-coded content could not be read.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum ErasureError {
+    #[error("not an erasure-coded manifest")]
+    NotAManifest,
+    #[error("unsupported manifest version {0}")]
+    UnsupportedVersion(u8),
+    #[error("manifest is truncated")]
+    Truncated,
+    #[error("manifest has trailing bytes")]
+    Trailing,
+    #[error("manifest has unknown hash algorithm code {0:02x}")]
+    UnknownAlgorithm(u8),
+    #[error("manifest has {data} data and {parity} parity shards")]
+    InvalidScheme { data: usize, parity: usize },
+    #[error("only {found} of the {needed} shards needed could be fetched")]
+    TooFewShards { found: usize, needed: usize },
+    /// The shards were reconstructed into something other than the content that the manifest names.
+    #[error("reconstructed content does not match its tag")]
+    Mismatch,
+}
+
+/// A description of erasure-coded content, as stored under the content's root tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub scheme: Scheme,
+    /// The length of the content.
+    pub len: u64,
+    /// The tag of the content itself.
+    pub content: Tag,
+    /// The tags of the data shards, then the tags of the parity shards.
+    pub shards: Vec<Tag>,
+}
+
+impl Manifest {
+    /// The length of each shard.
+    pub fn shard_len(&self) -> usize {
+        (self.len as usize).div_ceil(self.scheme.data_shards).max(1)
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + TAG_LEN * (1 + self.shards.len()));
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.scheme.data_shards as u8);
+        bytes.push(self.scheme.parity_shards as u8);
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        for tag in std::iter::once(&self.content).chain(&self.shards) {
+            bytes.push(tag.algorithm().code());
+            bytes.extend_from_slice(&**tag);
+        }
+        bytes.into()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ErasureError> {
+        if bytes.get(..4) != Some(MAGIC.as_slice()) {
+            return Err(ErasureError::NotAManifest);
+        }
+        let header = bytes.get(..HEADER_LEN).ok_or(ErasureError::Truncated)?;
+        if header[4] != VERSION {
+            return Err(ErasureError::UnsupportedVersion(header[4]));
+        }
+        let (data, parity) = (header[5] as usize, header[6] as usize);
+        let len = u64::from_be_bytes(header[7..15].try_into().unwrap());
+        let body = &bytes[HEADER_LEN..];
+        match body.len().cmp(&(TAG_LEN * (1 + data + parity))) {
+            Ordering::Less => return Err(ErasureError::Truncated),
+            Ordering::Greater => return Err(ErasureError::Trailing),
+            Ordering::Equal => {}
+        }
+        let mut tags = body.chunks(TAG_LEN).map(|tag| {
+            let algorithm =
+                HashAlgorithm::from_code(tag[0]).ok_or(ErasureError::UnknownAlgorithm(tag[0]))?;
+            Ok(Tag::from_digest(algorithm, tag[1..].try_into().unwrap()))
+        });
+        let content = tags.next().expect("checked to be present")?;
+        let scheme = Scheme {
+            data_shards: data,
+            parity_shards: parity,
+            algorithm: content.algorithm(),
+        };
+        if !scheme.is_valid() {
+            return Err(ErasureError::InvalidScheme { data, parity });
+        }
+        Ok(Self {
+            scheme,
+            len,
+            content,
+            shards: tags.collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Split `data` into shards according to `scheme`, returning the manifest describing them along with the shards, in
+/// the same order as their tags in the manifest.
+///
+/// Panics if the scheme has no data or no parity shards, or more than 256 shards in total.
+pub fn encode(data: &[u8], scheme: Scheme) -> (Manifest, Vec<Bytes>) {
+    assert!(scheme.is_valid(), "invalid erasure coding scheme");
+    let mut manifest = Manifest {
+        scheme,
+        len: data.len() as u64,
+        content: Tag::digest_with(scheme.algorithm, data),
+        shards: Vec::new(),
+    };
+    let shard_len = manifest.shard_len();
+    let mut shards = (0..scheme.data_shards + scheme.parity_shards)
+        .map(|i| {
+            let mut shard = vec![0; shard_len];
+            if let Some(part) = data.get(i * shard_len..) {
+                let part = &part[..part.len().min(shard_len)];
+                shard[..part.len()].copy_from_slice(part);
+            }
+            shard
+        })
+        .collect::<Vec<_>>();
+    scheme
+        .codec()
+        .encode(&mut shards)
+        .expect("shards are all the same length");
+    manifest.shards = shards
+        .iter()
+        .map(|shard| Tag::digest_with(scheme.algorithm, shard))
+        .collect();
+    (manifest, shards.into_iter().map(Bytes::from).collect())
+}
+
+/// Reconstruct the content that `manifest` describes from whichever of its shards are available, given in the same
+/// order as their tags in the manifest.
+///
+/// Shards that don't match their tags are ignored, and the result is checked against the content's tag.
+pub fn reconstruct(manifest: &Manifest, shards: Vec<Option<Bytes>>) -> Result<Bytes, ErasureError> {
+    let needed = manifest.scheme.data_shards;
+    let mut shards = shards
+        .into_iter()
+        .zip(&manifest.shards)
+        .map(|(shard, tag)| {
+            shard
+                .filter(|shard| shard.len() == manifest.shard_len() && tag.is_digest_of(shard))
+                .map(|shard| shard.to_vec())
+        })
+        .collect::<Vec<_>>();
+    shards.resize(manifest.shards.len(), None);
+    let found = shards.iter().flatten().count();
+    if found < needed {
+        return Err(ErasureError::TooFewShards { found, needed });
+    }
+    manifest
+        .scheme
+        .codec()
+        .reconstruct_data(&mut shards)
+        .map_err(|_| ErasureError::Mismatch)?;
+    let mut data = shards
+        .into_iter()
+        .take(needed)
+        .flat_map(|shard| shard.expect("data shards were reconstructed"))
+        .collect::<Vec<_>>();
+    data.truncate(manifest.len as usize);
+    if manifest.content.is_digest_of(&data) {
+        Ok(data.into())
+    } else {
+        Err(ErasureError::Mismatch)
+    }
+}
@@ -1,10 +1,79 @@
-use crate::Tag;
+use crate::{NameFormat, Signer, Tag};
 
 use rand::prelude::*;
 use rand_chacha::ChaCha20Rng;
-use rsa::{RsaPrivateKey, RsaPublicKey};
+use rsa::{
+    pkcs8::{self, DecodePrivateKey, EncodePrivateKey, LineEnding},
+    traits::PublicKeyParts,
+    Oaep, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey,
+};
 use serde::{Deserialize, Serialize};
-use std::fmt::{self, Write as _};
+use sha3::Sha3_256;
+use std::{fmt, fs, io, path::Path, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("failed to access key file: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed key: {0}")]
+    Malformed(#[from] pkcs8::Error),
+    #[error("unsupported key size of {0} bits, expected a multiple of 8 between {MIN_KEY_BITS} and {MAX_KEY_BITS}")]
+    InvalidSize(usize),
+    #[error("failed to generate key: {0}")]
+    Generate(#[from] rsa::Error),
+    #[error("the private key is held by an external signer")]
+    External,
+}
+
+/// The size of newly generated keys, unless otherwise specified.
+pub const DEFAULT_KEY_BITS: usize = 2048;
+/// Keys smaller than 2048 bits are insecure, but are quick to generate, which is handy for tests and simulations.
+pub const MIN_KEY_BITS: usize = 1024;
+pub const MAX_KEY_BITS: usize = 8192;
+
+/// An RSA signature over the SHA3-256 digest of a message.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String")]
+#[serde(into = "String")]
+pub struct Signature(Box<[u8]>);
+
+impl TryFrom<String> for Signature {
+    type Error = hex::FromHexError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Ok(Self(hex::decode(s)?.into_boxed_slice()))
+    }
+}
+
+impl From<Signature> for String {
+    fn from(sig: Signature) -> Self {
+        hex::encode(sig.0)
+    }
+}
+
+impl From<Vec<u8>> for Signature {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes.into_boxed_slice())
+    }
+}
+
+impl AsRef<[u8]> for Signature {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", hex::encode(&self.0))
+    }
+}
+
+// The bytes signed to answer a greeting challenge. The challenger's tag is included so that an answer can't be relayed
+// to a different node, and the prefix keeps answers from being mistaken for any other kind of signature.
+fn challenge_bytes(challenge: Tag, challenger: &PublicId) -> Vec<u8> {
+    [b"nettle greeting".as_slice(), &*challenge, &*challenger.tag].concat()
+}
 
 #[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(from = "RsaPublicKey")]
@@ -15,22 +84,43 @@ pub struct PublicId {
 }
 
 impl PublicId {
+    /// Check that `sig` was produced by the private key belonging to this identity over `bytes`.
+    pub fn verify(&self, bytes: &[u8], sig: &Signature) -> bool {
+        self.key
+            .verify(Pkcs1v15Sign::new_unprefixed(), &*Tag::digest(bytes), &sig.0)
+            .is_ok()
+    }
+
+    /// Encrypt a short message (such as a symmetric key) such that only the owner of this identity can read it.
+    pub fn encrypt(&self, bytes: &[u8]) -> Vec<u8> {
+        self.key
+            .encrypt(&mut thread_rng(), Oaep::new::<Sha3_256>(), bytes)
+            .expect("message too long to encrypt")
+    }
+
+    /// Check that `proof` answers a greeting `challenge` issued by `challenger`, as produced by
+    /// [`PrivateId::answer_challenge`].
+    pub fn verify_challenge(
+        &self,
+        challenge: Tag,
+        challenger: &PublicId,
+        proof: &Signature,
+    ) -> bool {
+        self.verify(&challenge_bytes(challenge, challenger), proof)
+    }
+
+    /// The first 8 hex digits of the tag, for display where space is limited.
+    pub fn short(&self) -> String {
+        hex::encode(&self.tag[..4])
+    }
+
+    /// A memorable name for this identity, made of `entropy` words and a checksum.
     pub fn human_readable_name(&self, entropy: usize) -> String {
-        let mut name = String::new();
-        for b in self.tag.into_iter().take(entropy) {
-            write!(
-                name,
-                "{}{}",
-                if name.is_empty() { "" } else { "_" },
-                include_str!("../data/words.txt")
-                    .lines()
-                    .nth(b as usize)
-                    .unwrap()
-                    .trim(),
-            )
-            .unwrap();
-        }
-        name
+        self.name(&NameFormat::default().with_word_count(entropy))
+    }
+
+    pub fn name(&self, format: &NameFormat) -> String {
+        format.name(self.tag)
     }
 }
 
@@ -49,6 +139,16 @@ impl Into<RsaPublicKey> for PublicId {
     }
 }
 
+// Distinguishes textual identities from other hex strings, such as data tags
+const ID_PREFIX: &str = "nettle:";
+
+/// The canonical textual form of an identity: `nettle:` followed by the full hex tag.
+impl fmt::Display for PublicId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        PublicIdRef::from(self).fmt(f)
+    }
+}
+
 impl fmt::Debug for PublicId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.human_readable_name(2))
@@ -56,30 +156,190 @@ impl fmt::Debug for PublicId {
     }
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum IdParseError {
+    #[error("expected 64 or 66 hex digits, found {0} hex digits")]
+    WrongLength(usize),
+    #[error("identity contains characters that are not hex digits")]
+    NotHex,
+}
+
+/// A reference to an identity by its tag alone, as written by [`PublicId`]'s `Display` impl.
+///
+/// The key can't be recovered from the tag, so this is only useful for picking out identities we already know, such
+/// as peers in admin commands.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PublicIdRef(pub Tag);
+
+impl PublicIdRef {
+    pub fn matches(&self, id: &PublicId) -> bool {
+        self.0 == id.tag
+    }
+}
+
+impl From<&PublicId> for PublicIdRef {
+    fn from(id: &PublicId) -> Self {
+        Self(id.tag)
+    }
+}
+
+impl fmt::Display for PublicIdRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", ID_PREFIX, self.0)
+    }
+}
+
+/// Parses the full hex tag, with or without the `nettle:` prefix.
+impl FromStr for PublicIdRef {
+    type Err = IdParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix(ID_PREFIX).unwrap_or(s);
+        if hex.len() != 64 && hex.len() != 66 {
+            Err(IdParseError::WrongLength(hex.len()))
+        } else {
+            Tag::try_from_hex(hex)
+                .map(Self)
+                .map_err(|_| IdParseError::NotHex)
+        }
+    }
+}
+
 pub struct PrivateId {
     pub pub_id: PublicId,
+    // For identities derived from a seed, the digest of that seed. Otherwise, the digest of the private key. Either way,
+    // it must never be revealed. Identities with an external signer have no secret of their own to derive it from.
     #[allow(dead_code)]
-    priv_tag: Tag,
-    #[allow(dead_code)]
-    priv_key: RsaPrivateKey,
+    priv_tag: Option<Tag>,
+    signer: Box<dyn Signer>,
 }
 
 impl PrivateId {
+    /// An identity whose signatures are made by `signer`, which need not hold the key in memory.
+    pub fn from_signer(signer: impl Signer + 'static) -> Self {
+        Self {
+            pub_id: PublicId::from(signer.public_key().clone()),
+            priv_tag: None,
+            signer: Box::new(signer),
+        }
+    }
+
+    fn from_key(priv_key: RsaPrivateKey, priv_tag: Tag) -> Self {
+        Self {
+            pub_id: PublicId::from(priv_key.to_public_key()),
+            priv_tag: Some(priv_tag),
+            signer: Box::new(priv_key),
+        }
+    }
+
+    fn priv_key(&self) -> Result<&RsaPrivateKey, KeyError> {
+        self.signer.private_key().ok_or(KeyError::External)
+    }
+
+    pub async fn sign(&self, bytes: &[u8]) -> Signature {
+        self.signer.sign(bytes).await
+    }
+
+    /// Decrypt a message produced by [`PublicId::encrypt`], or return `None` if it wasn't encrypted for us or the
+    /// key is held by an external signer.
+    pub fn decrypt(&self, bytes: &[u8]) -> Option<Vec<u8>> {
+        self.priv_key()
+            .ok()?
+            .decrypt(Oaep::new::<Sha3_256>(), bytes)
+            .ok()
+    }
+
+    /// Prove ownership of this identity's key to `challenger`, which sent us `challenge` when we greeted one another.
+    pub async fn answer_challenge(&self, challenge: Tag, challenger: &PublicId) -> Signature {
+        self.sign(&challenge_bytes(challenge, challenger)).await
+    }
+
     pub fn from_seed<B: AsRef<[u8]>>(bytes: B) -> Self {
+        Self::from_seed_with_bits(bytes, DEFAULT_KEY_BITS).expect("default key size is valid")
+    }
+
+    /// Like [`PrivateId::from_seed`], but with a key of the given size. Different sizes give unrelated identities.
+    pub fn from_seed_with_bits<B: AsRef<[u8]>>(bytes: B, bits: usize) -> Result<Self, KeyError> {
+        if !(MIN_KEY_BITS..=MAX_KEY_BITS).contains(&bits) || !bits.is_multiple_of(8) {
+            return Err(KeyError::InvalidSize(bits));
+        }
         // The private tag should not be revealed, since it acts as the seed for deriving the key pair
         let priv_tag = Tag::digest(bytes);
         // Generate the key pair from the private tag in a deterministic manner
-        let priv_key = RsaPrivateKey::new(&mut ChaCha20Rng::from_seed(*priv_tag), 2048).unwrap();
+        let priv_key = RsaPrivateKey::new(&mut ChaCha20Rng::from_seed(*priv_tag), bits)?;
 
-        Self {
-            pub_id: PublicId::from(priv_key.to_public_key()),
-            priv_tag,
-            priv_key,
+        Ok(Self::from_key(priv_key, priv_tag))
+    }
+
+    /// The size of the key, in bits.
+    pub fn key_bits(&self) -> usize {
+        self.pub_id.key.size() * 8
+    }
+
+    /// Encode the private key as a PKCS#8 PEM document.
+    ///
+    /// Only the key is encoded, so an identity derived from a seed loses its connection to that seed once loaded, but
+    /// is otherwise identical.
+    pub fn to_pem(&self) -> Result<String, KeyError> {
+        Ok(self.priv_key()?.to_pkcs8_pem(LineEnding::LF)?.to_string())
+    }
+
+    pub fn from_pem(pem: &str) -> Result<Self, KeyError> {
+        let priv_key = RsaPrivateKey::from_pkcs8_pem(pem)?;
+        let priv_tag = Tag::digest(priv_key.to_pkcs8_der()?.as_bytes());
+        Ok(Self::from_key(priv_key, priv_tag))
+    }
+
+    /// Write the private key to a file that, on unix, only the current user can read.
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), KeyError> {
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // The mode only applies to newly created files, so tighten the permissions of any existing file first
+            if let Ok(meta) = fs::metadata(&path) {
+                let mut perms = meta.permissions();
+                perms.set_mode(0o600);
+                fs::set_permissions(&path, perms)?;
+            }
         }
+        io::Write::write_all(&mut options.open(path)?, self.to_pem()?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, KeyError> {
+        Self::from_pem(&fs::read_to_string(path)?)
     }
 
     pub fn generate() -> Self {
-        Self::from_seed(&thread_rng().gen::<[u8; 32]>())
+        Self::generate_with(&mut thread_rng())
+    }
+
+    /// Generate an identity using the given source of randomness, such as a seeded RNG in tests.
+    pub fn generate_with<R: Rng + ?Sized>(rng: &mut R) -> Self {
+        Self::from_seed(rng.gen::<[u8; 32]>())
+    }
+
+    /// Like [`PrivateId::from_seed`], but without blocking the async runtime while the key pair is generated.
+    pub async fn from_seed_async<B: AsRef<[u8]> + Send + 'static>(bytes: B) -> Self {
+        tokio::task::spawn_blocking(move || Self::from_seed(bytes))
+            .await
+            .unwrap()
+    }
+
+    /// Like [`PrivateId::generate`], but without blocking the async runtime while the key pair is generated.
+    pub async fn generate_async() -> Self {
+        let seed = thread_rng().gen::<[u8; 32]>();
+        Self::from_seed_async(seed).await
+    }
+
+    /// Like [`PrivateId::generate_async`], but with a key of the given size.
+    pub async fn generate_async_with_bits(bits: usize) -> Result<Self, KeyError> {
+        let seed = thread_rng().gen::<[u8; 32]>();
+        tokio::task::spawn_blocking(move || Self::from_seed_with_bits(seed, bits))
+            .await
+            .unwrap()
     }
 }
 
@@ -1,54 +1,540 @@
 #![deny(warnings)]
 
+pub mod audit;
 mod backend;
+#[cfg(feature = "http")]
+pub mod dns;
+pub mod envelope;
+pub mod erasure;
 mod identity;
+mod metrics;
+pub mod msg;
+mod names;
+pub mod output;
+pub mod routing;
+mod signed;
+mod signer;
+pub mod sim;
+pub mod store;
+// Browsers don't let pages send UDP
+#[cfg(not(target_arch = "wasm32"))]
+pub mod stun;
+pub mod sync;
 mod tag;
+pub mod tree;
 
+#[cfg(any(feature = "http", feature = "wasm-client"))]
+pub use crate::backend::client;
+#[cfg(feature = "http")]
+pub use crate::backend::http;
 pub use crate::{
-    backend::{http, mem},
-    identity::{PrivateId, PublicId},
-    tag::Tag,
+    backend::mem,
+    identity::{
+        IdParseError, KeyError, PrivateId, PublicId, PublicIdRef, Signature, DEFAULT_KEY_BITS,
+        MAX_KEY_BITS, MIN_KEY_BITS,
+    },
+    metrics::{Metrics, Request},
+    names::NameFormat,
+    signed::{AddrRecord, SignatureError, Signed, SignedAddr, MAX_CLOCK_SKEW},
+    signer::{CallbackSigner, Signer},
+    tag::{HashAlgorithm, Tag, TagHasher, TagParseError},
 };
 
-use crate::backend::Backend;
+use crate::{
+    backend::Backend,
+    msg::Greet,
+    routing::{InsertOutcome, Peer, RoutingTable},
+    signed::now_millis,
+    store::Store,
+};
 
+use bytes::Bytes;
+use futures::{future::FusedFuture, FutureExt, StreamExt};
 use rand::prelude::*;
-use slotmap::SlotMap;
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
-    time::Duration,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock},
+    time::{Duration, Instant},
 };
-use tokio::select;
+use tokio::{select, sync::watch, time::error::Elapsed};
+use tracing::{debug, debug_span, error, info, warn, Instrument};
 
-const MAX_LEVEL_PEERS: usize = 2;
+// The number of unresponsive peers we remember so that we can try to reconnect to them later
+const MAX_LOST_PEERS: usize = 16;
+// How long a greeter has to answer our challenge, and how many may be outstanding at once
+const GREET_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PENDING_GREETS: usize = 64;
+/// How long the address records that a node issues remain valid. Nodes issue a fresh record once half of this has
+/// passed.
+pub const ADDR_RECORD_TTL: Duration = Duration::from_secs(60 * 60);
+/// How long a node keeps vouching for its previous identity after [`Node::rotate_identity`].
+pub const ROTATION_GRACE: Duration = Duration::from_secs(5 * 60);
+/// The version of the peer protocol that this node speaks. Nodes only peer with others that speak the same version.
+pub const PROTOCOL_VERSION: u16 = 9;
+// The number of addresses we remember as speaking another protocol version, so that we don't keep greeting them
+const MAX_INCOMPATIBLE_ADDRS: usize = 64;
+// The number of peers closer to a tag that a node suggests when asked to locate data it doesn't have
+const MAX_LOCATE_PEERS: usize = 8;
+// The number of peers that a node suggests in their place if none of its peers are closer, and the most nodes that a
+// lookup asks before giving up
+const MAX_SIDEWAYS_PEERS: usize = 2;
+const MAX_LOCATE_HOPS: usize = 32;
+/// How long a lookup that came up empty is remembered by default, answering repeated downloads of the same tag without
+/// asking the network again.
+pub const DEFAULT_MISS_TTL: Duration = Duration::from_secs(5);
+// The most empty lookups we remember at once
+const MAX_CACHED_MISSES: usize = 1024;
+/// The default number of nodes that [`Node::do_download`] fetches data from at once.
+pub const DEFAULT_DOWNLOAD_FANOUT: usize = 2;
+// How long a download waits for an answer before asking another node too
+const DOWNLOAD_HEDGE_DELAY: Duration = Duration::from_millis(200);
+/// The default number of uploads that a node handles at once.
+pub const DEFAULT_UPLOAD_PERMITS: usize = 16;
+/// How long an upload waits for its turn by default before it's turned away as [`ProtocolError::Busy`].
+pub const DEFAULT_UPLOAD_WAIT: Duration = Duration::from_secs(5);
+// The most peers that a node returns when asked to find the nodes closest to a tag
+const MAX_FIND_NODE_PEERS: usize = 20;
+// The number of initial peers we try to reach at once
+const BOOTSTRAP_CONCURRENCY: usize = 8;
+/// The default number of suggestions of other peers to try that [`Node::discover_from`] follows before giving up.
+pub const DEFAULT_MAX_REDIRECTS: usize = 8;
+// The number of discover requests in a row that may fail before we give up on a walk
+const MAX_DISCOVER_FAILURES: usize = 3;
+// How often we hand data on to peers that are closer to it than we are, and how many blobs we hand on each time
+const HANDOFF_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_HANDOFFS_PER_TICK: usize = 4;
+// How often to drop data that has outlived its time to live
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+// How often we collect chunks whose lease has run out, and how many we look at each time
+const GC_INTERVAL: Duration = Duration::from_secs(10);
+const GC_BATCH: usize = 256;
+/// How long a chunk whose lease has run out must also have gone unused before it's collected, giving downloads under
+/// way time to fetch it.
+pub const GC_GRACE: Duration = Duration::from_secs(60);
+/// How long a chunk is kept after its lease was last renewed by a node holding a tree node or manifest that refers to
+/// it. See [`store`] for more.
+pub const CHUNK_LEASE: Duration = Duration::from_secs(60 * 60);
+// How often we renew the leases of the chunks that what we hold refers to, which leaves room for a few to go astray
+const RENEW_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// The most chunks whose leases a node renews for a peer in one go.
+pub const MAX_RENEW_TAGS: usize = 256;
+/// How long a node that is shutting down spends passing its data on to its peers before abandoning what's left.
+pub const REHOME_BUDGET: Duration = Duration::from_secs(10);
+// The number of peers closest to a blob that we try to pass it on to when shutting down, closest first
+const REHOME_CANDIDATES: usize = 3;
+// The number of nodes closest to a blob of a tree that we try to fetch it from once its owner gave us a bad copy
+const MAX_TREE_REPLICAS: usize = 4;
+// The number of nodes closest to a shard that we look for it on, and how many shards we fetch at once
+const MAX_SHARD_HOLDERS: usize = 8;
+const SHARD_CONCURRENCY: usize = 8;
+// How often we audit one of the peers that we've placed data with, and how many audits we prepare for each copy placed
+const AUDIT_INTERVAL: Duration = Duration::from_secs(60);
+const AUDIT_CHALLENGES: usize = 8;
+// The most copies that we prepare audits for at once
+const MAX_AUDITED: usize = 4096;
+/// The number of audits that a peer may fail, or corrupt copies of data it may send us, before we stop trusting it,
+/// dropping it and refusing to peer with it again.
+pub const MAX_AUDIT_FAILURES: usize = 3;
+// How long it takes the count of requests for data we don't hold to decay by half, and the most tags we count at once
+const POPULARITY_HALF_LIFE: Duration = Duration::from_secs(60);
+const MAX_POPULARITY_TRACKED: usize = 1024;
+/// The decayed number of requests for data that we don't hold beyond which we fetch and cache a copy of it.
+pub const POPULARITY_THRESHOLD: f64 = 8.0;
+// How often we cache the data that has become popular, and how much of it we fetch each time
+const CACHE_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_CACHED_PER_TICK: usize = 4;
+// How often we compare the data we hold with a neighbour's, how many of our closest peers we pick that neighbour
+// from, and the most tags that pass either way each time
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+const SYNC_CANDIDATES: usize = 3;
+const MAX_SYNC_TAGS: usize = 128;
+/// The default chance that an anti-entropy summary claims to hold a tag that it doesn't, hiding it until next time.
+pub const DEFAULT_SYNC_FALSE_POSITIVE_RATE: f64 = 0.01;
+/// The default number of the nodes closest to some data that [`Node::do_upload`] places a copy with.
+pub const DEFAULT_REPLICAS: usize = 3;
+/// The default number of those nodes that must take a copy for an upload to succeed.
+pub const DEFAULT_UPLOAD_QUORUM: usize = 2;
+// How often we ask some of our peers which IP address we appear at, if we follow changes to it, how many we ask, and
+// how many of them must agree on a new one
+const ADDR_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const ADDR_CHECK_PEERS: usize = 5;
+const MIN_ADDR_CHECK_AGREEMENT: usize = 2;
+// The number of peers we tell about our new address at once
+const READDRESS_CONCURRENCY: usize = 8;
+// The most peers passed on to us in pongs that we try peering with in each interval
+const HINT_INTERVAL: Duration = Duration::from_secs(10);
+const MAX_HINTS_PER_INTERVAL: usize = 2;
 
-#[derive(Debug)]
+/// Why a [`Node`] couldn't be created, or stopped running.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error<B> {
-    Backend(B),
+    #[error("backend error: {0}")]
+    Backend(#[source] B),
+    /// The backend panicked while hosting the node.
+    #[error("host panicked")]
+    HostPanic,
+    #[error("timed out")]
+    Timeout,
+    /// The node was asked to shut down before it could finish.
+    #[error("node shut down")]
+    Shutdown,
+}
+
+/// How a peer fared when audited by [`Node::audit`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AuditOutcome {
+    /// The peer proved that it still holds the data.
+    Passed,
+    /// The peer no longer has the data, or gave the wrong answer.
+    Failed,
+    /// The peer couldn't be reached or wouldn't answer, so proved nothing either way.
+    Inconclusive,
+}
+
+/// Information about a peer in a node's routing table.
+#[derive(Clone, Debug)]
+pub struct PeerInfo<A> {
+    pub id: PublicId,
+    pub addr: A,
+    /// The most recently measured round trip time.
+    pub ping: Duration,
+    pub level: u16,
+    pub capabilities: Capabilities,
+    /// How far ahead of our clock the peer's is, in milliseconds, as most recently estimated, if it has been.
+    pub clock_offset: Option<i64>,
+}
+
+/// Where a tag resolves to, as found by [`Node::locate`].
+#[derive(Clone, Debug)]
+pub struct Located<A> {
+    /// Whether `owner` holds the data.
+    pub found: bool,
+    /// The node holding the data or, if nobody does, the closest node to the tag that could be reached.
+    pub owner: (PublicId, A),
+    /// The nodes asked along the way, in the order they were asked. Its length is the number of hops taken.
+    pub path: Vec<PublicId>,
+}
+
+/// Where some data was uploaded to, as reported by [`Node::place`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Placement {
+    pub tag: Tag,
+    /// The number of nodes that took a copy of the data, or `1` if it had already been uploaded.
+    pub copies: usize,
+}
+
+/// What became of a node's data when it passed it on to its peers, as reported by [`Node::rehome_data`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rehomed {
+    /// The number of entries that a peer now holds.
+    pub rehomed: usize,
+    /// The number of entries that no peer could be found to take, or that there wasn't time for.
+    pub abandoned: usize,
+}
+
+/// What passed between two nodes as they compared the data they hold, as reported by [`Node::sync_with`].
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Synced {
+    /// The number of entries that we were missing and fetched from the peer.
+    pub fetched: usize,
+    /// The number of entries that the peer was missing and we sent to it.
+    pub sent: usize,
+}
+
+/// A summary of a node's state, as returned by [`Node::stats`] and shared with peers that ask for it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeStats {
+    /// The version of the peer protocol that the node speaks.
+    pub version: u16,
+    pub peers: usize,
+    /// The number of data entries stored locally.
+    pub entries: usize,
+    /// The number of bytes that the entries take up as stored, after any compression.
+    #[serde(default)]
+    pub stored_bytes: u64,
+    /// The number of bytes that the entries hold, before any compression.
+    #[serde(default)]
+    pub logical_bytes: u64,
+    /// The number of bytes that pinned entries take up as stored.
+    #[serde(default)]
+    pub pinned_bytes: u64,
+    pub uptime_secs: u64,
+    /// The number of peers in each non-empty level, if the node is willing to share it.
+    #[serde(default)]
+    pub levels: Option<BTreeMap<u16, usize>>,
+}
+
+/// A set of optional protocol features, such as compression, that a node supports. Peers agree to use those that
+/// both of them support when they greet each other.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(pub u64);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// The features that this version of nettle supports.
+    pub const SUPPORTED: Self = Self::NONE;
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// A node's answer to a greeting it's willing to accept, proving its own identity and challenging the greeter to
+/// prove theirs in turn.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GreetReply {
+    pub id: PublicId,
+    /// The greeter's challenge, answered by `id`.
+    pub proof: Signature,
+    pub challenge: Tag,
+    /// If the node recently rotated its identity, its previous identity's endorsement of `id`.
+    #[serde(default)]
+    pub endorsement: Option<Signed<PublicId>>,
+    /// The optional features that the node supports.
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+/// A node's reason for turning down a greeting.
+#[derive(Clone, Debug, Serialize, Deserialize, thiserror::Error)]
+pub enum GreetRefusal<A> {
+    /// The node has no room for the greeter, but may suggest another peer for it to try instead.
+    #[error("greeting declined")]
+    Redirect(Option<SignedAddr<A>>),
+    /// The node speaks version `ours` of the protocol, and the greeter version `theirs`.
+    #[error("version mismatch {ours} vs {theirs}")]
+    VersionMismatch { ours: u16, theirs: u16 },
+}
+
+/// A node's answer when asked where some data is, as given by [`Node::recv_locate`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LocateReply<A> {
+    /// The node holds the data.
+    Found,
+    /// The node doesn't hold the data, but these peers of it are closer to it, closest first.
+    Closer(Vec<(PublicId, A)>),
+    /// The node doesn't hold the data and knows nobody closer to it, but these are the closest peers that it does
+    /// know, closest first. They may be no closer than the node itself, and if there are none, the lookup ends here.
+    NotCloser(Vec<(PublicId, A)>),
+}
+
+/// Why a node refused to serve a request, sent back to the requester in place of a response.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("request too large")]
+    TooLarge,
+    /// The request concerns data that the node isn't responsible for.
+    #[error("not responsible")]
+    NotResponsible,
+    #[error("storage full")]
+    StorageFull,
+    #[error("too many requests")]
+    Throttled,
+    /// The node was handling as many uploads as it could, and didn't get to this one in time.
+    #[error("busy")]
+    Busy,
+    /// The uploaded data didn't hash to the tag that the uploader said it would.
+    #[error("tag mismatch")]
+    Mismatch,
+    /// The uploader has as much data stored with the node as it's allowed.
+    #[error("quota exceeded")]
+    QuotaExceeded,
+    /// The node doesn't speak the protocol version that the request assumes.
+    #[error("version mismatch")]
+    VersionMismatch,
+    #[error("internal error")]
+    Internal,
+}
+
+/// Why [`Node::do_upload`] failed to place some data.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum UploadError {
+    /// The closest node to the data could not be found.
+    #[error("{0}")]
+    Locate(&'static str),
+    #[error("peer did not respond")]
+    Unreachable,
+    #[error("{peer:?} refused the upload: {reason}")]
+    Rejected {
+        peer: PublicId,
+        reason: ProtocolError,
+    },
+    /// The node that received the data stored it under another tag, so it didn't receive the data that we sent.
+    #[error("peer stored the data as {stored} rather than {expected}")]
+    Integrity { expected: Tag, stored: Tag },
+    /// Some nodes took a copy of the data, but fewer than the quorum.
+    #[error("placed {copies} copies of the {quorum} needed")]
+    Quorum { copies: usize, quorum: usize },
 }
 
-slotmap::new_key_type! { struct PeerIdx; }
+/// Why [`Node::discover_from`] failed to peer with any of the nodes it was pointed to.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DiscoverError<A: fmt::Debug> {
+    /// The last node we were pointed to couldn't be peered with, and didn't suggest another.
+    #[error("could not peer with {0:?}")]
+    Failed(A),
+    /// We were pointed back to a node that we had already tried, or to ourselves.
+    #[error("redirected back to {0:?}, which was already tried")]
+    Loop(A),
+    #[error("gave up after {0} redirects")]
+    TooManyRedirects(usize),
+}
+
+/// Why [`Node::do_download`] failed to fetch some data.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum DownloadError {
+    /// The node holding the data could not be found.
+    #[error("{0}")]
+    Locate(&'static str),
+    #[error("peer did not respond")]
+    Unreachable,
+    #[error("{peer:?} refused the download: {reason}")]
+    Rejected {
+        peer: PublicId,
+        reason: ProtocolError,
+    },
+    #[error("peer reported data but did not provide any")]
+    Missing,
+    #[error("integrity check failed")]
+    Integrity,
+    /// The data could not be decrypted by [`Node::download_encrypted`].
+    #[error("{0}")]
+    Decrypt(&'static str),
+    /// The data fetched by [`Node::download_tree`] was not a well-formed tree.
+    #[error("{0}")]
+    Tree(tree::TreeError),
+    /// The data fetched by [`Node::download_erasure`] could not be reconstructed.
+    #[error("{0}")]
+    Erasure(erasure::ErasureError),
+}
+
+// An identity we've rotated away from, which we keep answering for until `expires`
+struct Retired {
+    id: Arc<PrivateId>,
+    endorsement: Signed<PublicId>,
+    expires: tokio::time::Instant,
+}
+
+// Audits of a peer that we placed some data with, prepared while we had the data to hand, with their answers
+struct Audits<A> {
+    addr: A,
+    challenges: Vec<(audit::Challenge, Tag)>,
+}
 
-struct Peer<B: Backend> {
-    id: PublicId,
-    addr: B::Addr,
-    ping: Duration, // Total round trip
+// A greeting we're willing to accept once the greeter proves that they hold their key
+struct PendingGreet<A> {
+    addr: A,
+    challenge: Tag,
+    issued: tokio::time::Instant,
+    capabilities: Capabilities,
 }
 
 struct State<B: Backend> {
-    peers: SlotMap<PeerIdx, Peer<B>>,
-    peers_by_id: HashMap<PublicId, PeerIdx>,
-    peers_by_level: [Vec<PeerIdx>; 256],
-    data: HashMap<Tag, Arc<[u8]>>,
+    data: Store,
+    // Whether we've successfully peered with any of our initial peers
+    bootstrapped: bool,
+    // Peers we dropped because they stopped responding, oldest first
+    lost_peers: VecDeque<(PublicId, B::Addr)>,
+    // Greeters we've challenged but who have yet to answer
+    pending_greets: HashMap<PublicId, PendingGreet<B::Addr>>,
+    // The nonces of recently received messages, to detect replays, along with the order in which to forget them
+    seen_nonces: HashSet<(Tag, u128)>,
+    nonce_expiry: VecDeque<(u64, Tag, u128)>,
+    // The IP address each peer has most recently observed our requests coming from
+    observed_ips: HashMap<PublicId, IpAddr>,
+    // Our previous identity, if we rotated away from it recently
+    retired: Option<Retired>,
+    // The most recent record we issued of our own address
+    addr_record: Option<SignedAddr<B::Addr>>,
+    // Addresses of nodes that speak another protocol version
+    incompatible: HashSet<B::Addr>,
+    // Data to hand on to peers that joined closer to it than we are, oldest first
+    handoffs: VecDeque<(Tag, (PublicId, B::Addr))>,
+    // Whether we keep a copy of data we've handed on, to serve as a cache
+    keep_handoffs: bool,
+    // How many copies of data we upload to place, and how many must be placed
+    replicas: usize,
+    upload_quorum: usize,
+    // Audits of the peers that we've placed data with, by the data's tag and the peer holding it
+    audits: HashMap<(Tag, PublicId), Audits<B::Addr>>,
+    // The number of audits that each peer has failed or corrupt downloads it has served, and the peers with too many
+    audit_failures: HashMap<PublicId, usize>,
+    distrusted: HashSet<PublicId>,
+    // The decaying number of requests for each tag we don't hold, as of when it was last counted
+    popularity: HashMap<Tag, (f64, tokio::time::Instant)>,
+    // Tags that have become popular enough to cache, oldest first
+    popular: VecDeque<Tag>,
+    sync_false_positive_rate: f64,
+    // Whether we try peering with the peers passed on to us in pongs, and how many we've tried this interval
+    peer_hints: bool,
+    hints: (tokio::time::Instant, usize),
+    max_redirects: usize,
+    track_public_ip: bool,
+    // Tags that lookups recently came up empty for, with when to forget them, and how long to remember them for
+    misses: HashMap<Tag, tokio::time::Instant>,
+    miss_ttl: Option<Duration>,
+    download_fanout: usize,
+    // The turns to handle uploads, how many there are, and how long an upload waits for one
+    upload_permits: (Arc<tokio::sync::Semaphore>, usize),
+    upload_wait: Duration,
+}
+
+// Who to charge for data uploaded by `sender`
+fn publisher_of(sender: Option<&PublicId>) -> store::Publisher {
+    match sender {
+        Some(sender) => store::Publisher::Node(sender.tag),
+        None => store::Publisher::Anonymous,
+    }
+}
+
+// Whether `endorsement` shows that the identity `old` has been replaced by `new`
+fn endorses(endorsement: Option<&Signed<PublicId>>, old: &PublicId, new: &PublicId) -> bool {
+    endorsement.is_some_and(|endorsement| {
+        &endorsement.sender == old && &endorsement.body == new && endorsement.verify().is_ok()
+    })
+}
+
+// Lock a mutex, even if a panic elsewhere poisoned it, returning whether it was poisoned. A node should keep serving
+// its peers after a bug takes down one request, rather than every request that follows.
+fn lock_or_recover<'a, T>(mutex: &'a Mutex<T>, name: &str) -> (MutexGuard<'a, T>, bool) {
+    match mutex.lock() {
+        Ok(guard) => (guard, false),
+        Err(poisoned) => {
+            error!("A panic poisoned the node's {} lock, recovering", name);
+            mutex.clear_poison();
+            (poisoned.into_inner(), true)
+        }
+    }
 }
 
 pub struct Node<B: Backend> {
-    self_id: PrivateId,
-    self_addr: B::Addr,
+    // Only replaced by `rotate_identity`
+    self_id: RwLock<Arc<PrivateId>>,
+    // Only replaced by `set_addr`
+    self_addr: RwLock<B::Addr>,
     initial_peers: Vec<B::Addr>,
     backend: B,
+    routing: Mutex<RoutingTable<B::Addr>>,
     state: Mutex<State<B>>,
+    started: Instant,
+    metrics: Metrics,
+    shutdown: watch::Sender<bool>,
+    // Held while bootstrapping, so that only one bootstrap runs at a time
+    bootstrapping: tokio::sync::Mutex<()>,
+    // The number of peers in our routing table, updated whenever it changes for those waiting on it
+    peers: watch::Sender<usize>,
+    // All of the node's random choices are made with this, so that simulations can be made reproducible
+    rng: Mutex<ChaCha20Rng>,
 }
 
 impl<B: Backend> Node<B> {
@@ -58,92 +544,625 @@ impl<B: Backend> Node<B> {
         initial_peers: Vec<B::Addr>,
         config: B::Config,
     ) -> Result<Arc<Self>, Error<B::Error>> {
-        let this = Self {
+        Self::new_inner(
+            self_id,
+            self_addr,
+            initial_peers,
+            config,
+            ChaCha20Rng::from_entropy(),
+        )
+        .await
+    }
+
+    /// Like [`Node::new`], but with every random choice the node makes derived from `seed`.
+    ///
+    /// Together with a deterministic backend, this makes the behaviour of a network reproducible.
+    pub async fn with_seed(
+        self_id: PrivateId,
+        self_addr: B::Addr,
+        initial_peers: Vec<B::Addr>,
+        config: B::Config,
+        seed: u64,
+    ) -> Result<Arc<Self>, Error<B::Error>> {
+        Self::new_inner(
             self_id,
             self_addr,
             initial_peers,
+            config,
+            ChaCha20Rng::seed_from_u64(seed),
+        )
+        .await
+    }
+
+    async fn new_inner(
+        self_id: PrivateId,
+        self_addr: B::Addr,
+        initial_peers: Vec<B::Addr>,
+        config: B::Config,
+        rng: ChaCha20Rng,
+    ) -> Result<Arc<Self>, Error<B::Error>> {
+        // The same initial peer may have been given more than once in different forms
+        let mut initial_peers = initial_peers
+            .into_iter()
+            .map(B::canonicalise)
+            .collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        initial_peers.retain(|addr| seen.insert(addr.clone()));
+        let this = Self {
+            routing: Mutex::new(RoutingTable::new(self_id.pub_id.tag)),
+            self_id: RwLock::new(Arc::new(self_id)),
+            self_addr: RwLock::new(B::canonicalise(self_addr)),
+            initial_peers,
             backend: B::create(config).await.map_err(Error::Backend)?,
             state: Mutex::new(State {
-                peers: SlotMap::default(),
-                peers_by_id: HashMap::default(),
-                peers_by_level: {
-                    const EMPTY: Vec<PeerIdx> = Vec::new();
-                    [EMPTY; 256]
-                },
-                data: HashMap::default(),
+                data: Store::default(),
+                bootstrapped: false,
+                lost_peers: VecDeque::default(),
+                pending_greets: HashMap::default(),
+                seen_nonces: HashSet::default(),
+                nonce_expiry: VecDeque::default(),
+                observed_ips: HashMap::default(),
+                retired: None,
+                addr_record: None,
+                incompatible: HashSet::new(),
+                handoffs: VecDeque::default(),
+                keep_handoffs: false,
+                replicas: DEFAULT_REPLICAS,
+                upload_quorum: DEFAULT_UPLOAD_QUORUM,
+                audits: HashMap::default(),
+                audit_failures: HashMap::default(),
+                distrusted: HashSet::default(),
+                popularity: HashMap::default(),
+                popular: VecDeque::default(),
+                sync_false_positive_rate: DEFAULT_SYNC_FALSE_POSITIVE_RATE,
+                peer_hints: true,
+                hints: (tokio::time::Instant::now(), 0),
+                max_redirects: DEFAULT_MAX_REDIRECTS,
+                track_public_ip: false,
+                misses: HashMap::default(),
+                miss_ttl: Some(DEFAULT_MISS_TTL),
+                download_fanout: DEFAULT_DOWNLOAD_FANOUT,
+                upload_permits: (
+                    Arc::new(tokio::sync::Semaphore::new(DEFAULT_UPLOAD_PERMITS)),
+                    DEFAULT_UPLOAD_PERMITS,
+                ),
+                upload_wait: DEFAULT_UPLOAD_WAIT,
             }),
+            started: Instant::now(),
+            metrics: Metrics::default(),
+            shutdown: watch::channel(false).0,
+            bootstrapping: tokio::sync::Mutex::new(()),
+            peers: watch::channel(0).0,
+            rng: Mutex::new(rng),
         };
         let this = Arc::new(this);
         this.backend.init(&this).await;
         Ok(this)
     }
 
-    pub fn id(&self) -> &PublicId {
-        &self.self_id.pub_id
+    pub fn id(&self) -> PublicId {
+        self.identity().pub_id.clone()
     }
 
-    pub fn addr(&self) -> &B::Addr {
-        &self.self_addr
+    fn identity(&self) -> Arc<PrivateId> {
+        self.self_id
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
     }
 
-    pub fn get_peers(&self) -> Vec<PublicId> {
-        self.with_state(|state| state.peers.values().map(|p| p.id.clone()).collect())
+    pub fn addr(&self) -> B::Addr {
+        self.self_addr
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
     }
 
-    fn with_state<F: FnOnce(&mut State<B>) -> R, R>(&self, f: F) -> R {
-        f(&mut self.state.lock().unwrap())
+    /// A signed record of our address, issued afresh if the last one is more than half way to expiring.
+    pub async fn addr_record(&self) -> SignedAddr<B::Addr> {
+        let self_id = self.identity();
+        let now = now_millis();
+        let ttl = ADDR_RECORD_TTL.as_millis() as u64;
+        let addr = self.addr();
+        let current = self.with_state(|state| {
+            state.addr_record.clone().filter(|record| {
+                record.sender == self_id.pub_id
+                    && record.body.addr == addr
+                    && record.body.expires > now + ttl / 2
+            })
+        });
+        match current {
+            Some(record) => record,
+            None => {
+                let record = Signed::new(
+                    &self_id,
+                    now,
+                    rand::random(),
+                    AddrRecord {
+                        addr,
+                        expires: now + ttl,
+                    },
+                )
+                .await;
+                self.with_state(|state| state.addr_record = Some(record.clone()));
+                record
+            }
+        }
     }
 
-    pub async fn accept_peer(&self, id: PublicId, addr: B::Addr) -> bool {
-        if id != self.self_id.pub_id
-            && !self.with_state(|state| state.peers_by_id.contains_key(&id))
-        {
-            if let Ok(ping) = self.backend.send_ping(&addr).await {
-                let level = self.self_id.pub_id.tag.dist_to(id.tag).level();
-                self.with_state(|state| {
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Ask the node to shut down.
+    ///
+    /// The backend stops accepting new requests and finishes serving those in flight, after which [`Node::run`]
+    /// returns.
+    pub fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    /// Resolves once [`Node::shutdown`] has been called.
+    pub async fn shutdown_requested(&self) {
+        let _ = self
+            .shutdown
+            .subscribe()
+            .wait_for(|shutdown| *shutdown)
+            .await;
+    }
+
+    /// The IP addresses our peers have observed our requests coming from, with the number of peers that observed each.
+    ///
+    /// More than one address suggests that we're behind a symmetric NAT, or that our address has recently changed.
+    pub fn observed_ips(&self) -> BTreeMap<IpAddr, usize> {
+        self.with_state(|state| {
+            let mut ips = BTreeMap::new();
+            for ip in state.observed_ips.values() {
+                *ips.entry(*ip).or_default() += 1;
+            }
+            ips
+        })
+    }
+
+    async fn observe_self(&self, peer: &(PublicId, B::Addr)) -> Option<IpAddr> {
+        match self.backend.send_observe(&peer.1).await {
+            Ok(Some(ip)) => {
+                let others = self.with_state(|state| {
+                    state.observed_ips.insert(peer.0.clone(), ip);
                     state
-                        .peers_by_id
-                        .entry(id.clone())
-                        .and_modify(|idx| state.peers[*idx].ping = ping)
-                        .or_insert_with(|| {
-                            let idx = state.peers.insert(Peer { id, addr, ping });
-                            state.peers_by_level[level as usize].push(idx);
-                            idx
-                        });
+                        .observed_ips
+                        .values()
+                        .filter(|other| **other != ip)
+                        .count()
                 });
-                true
-            } else {
-                eprintln!(
-                    "Tried to accept peer {:?} but they did not respond to a ping",
-                    id
+                if others > 0 {
+                    warn!(
+                        "{:?} observed us at {}, but {} other peer(s) disagree. We may be behind a symmetric NAT.",
+                        peer.0, ip, others
+                    );
+                }
+                Some(ip)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                self.metrics.failure();
+                error!("Failed to ask {:?} for our address: {:?}", peer.0, err);
+                None
+            }
+        }
+    }
+
+    /// Set whether we move to a new address when the peers we ask see our requests coming from a new IP address, as a
+    /// residential connection's may. This is off by default, and only has an effect with backends whose addresses can
+    /// contain an IP address.
+    pub fn set_track_public_ip(&self, enabled: bool) {
+        self.with_state(|state| state.track_public_ip = enabled);
+    }
+
+    // Ask a few of our peers which IP address we appear at, moving to it if most of those that answer agree on a new one
+    async fn check_public_ip(&self) {
+        let peers = self.with_routing(|routing| {
+            let mut rng = self.rng();
+            routing
+                .iter()
+                .map(|peer| (peer.id.clone(), peer.addr.clone()))
+                .choose_multiple(&mut *rng, ADDR_CHECK_PEERS)
+        });
+        let mut seen = BTreeMap::<IpAddr, usize>::new();
+        for peer in &peers {
+            if let Some(ip) = self.observe_self(peer).await {
+                *seen.entry(ip).or_default() += 1;
+            }
+        }
+        let answers = seen.values().sum::<usize>();
+        let Some((ip, agreeing)) = seen.into_iter().max_by_key(|(_, agreeing)| *agreeing) else {
+            return;
+        };
+        if agreeing < MIN_ADDR_CHECK_AGREEMENT || agreeing * 2 <= answers {
+            return;
+        }
+        match B::with_ip(&self.addr(), ip) {
+            Some(addr) if addr != self.addr() => {
+                info!(
+                    "{} of our peers see us at {}, so we're moving there",
+                    agreeing, ip
                 );
-                false
+                self.set_addr(addr).await;
             }
-        } else {
-            false
+            _ => {}
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Summarise the node's current state.
+    pub fn stats(&self) -> NodeStats {
+        let (peers, levels) = self.with_routing(|routing| {
+            let levels = (0..256)
+                .map(|level| (level as u16, routing.bucket_len(level)))
+                .filter(|(_, peers)| *peers > 0)
+                .collect();
+            (routing.len(), levels)
+        });
+        let (entries, stored_bytes, logical_bytes, pinned_bytes) = self.with_state(|state| {
+            (
+                state.data.len(),
+                state.data.stored_bytes(),
+                state.data.logical_bytes(),
+                state.data.pinned_bytes(),
+            )
+        });
+        NodeStats {
+            version: PROTOCOL_VERSION,
+            peers,
+            entries,
+            stored_bytes: stored_bytes as u64,
+            logical_bytes: logical_bytes as u64,
+            pinned_bytes: pinned_bytes as u64,
+            uptime_secs: self.uptime().as_secs(),
+            levels: Some(levels),
+        }
+    }
+
+    /// Ask one of our peers about itself, returning `None` if it isn't willing to say.
+    pub async fn query_peer_info(
+        &self,
+        peer: &PublicId,
+    ) -> Result<Option<NodeStats>, &'static str> {
+        let addr = self
+            .with_routing(|routing| routing.get(peer).map(|peer| peer.addr.clone()))
+            .ok_or("not peered with that node")?;
+        self.backend.send_info(&addr).await.map_err(|_err| {
+            self.metrics.failure();
+            "peer did not respond"
+        })
+    }
+
+    /// The number of nodes that we're peered with.
+    pub fn peer_count(&self) -> usize {
+        self.with_routing(|routing| routing.len())
+    }
+
+    /// Wait until we're peered with at least `n` nodes, or fail once `timeout` has passed.
+    pub async fn wait_for_peers(&self, n: usize, timeout: Duration) -> Result<(), Elapsed> {
+        let mut peers = self.peers.subscribe();
+        tokio::time::timeout(timeout, peers.wait_for(|peers| *peers >= n))
+            .await
+            .map(|_| ())
+    }
+
+    // Let anybody waiting on our number of peers know that it may have changed
+    fn peers_changed(&self) {
+        self.peers.send_replace(self.peer_count());
+    }
+
+    pub fn get_peers(&self) -> Vec<PublicId> {
+        self.with_routing(|routing| routing.iter().map(|p| p.id.clone()).collect())
+    }
+
+    pub fn peer_info(&self) -> Vec<PeerInfo<B::Addr>> {
+        self.with_routing(|routing| {
+            routing
+                .iter()
+                .map(|p| PeerInfo {
+                    id: p.id.clone(),
+                    addr: p.addr.clone(),
+                    ping: p.ping,
+                    level: routing.level_of(&p.id).expect("peers never share our tag") as u16,
+                    capabilities: p.capabilities,
+                    clock_offset: p.clock_offset,
+                })
+                .collect()
+        })
+    }
+
+    /// The fraction of levels spanned by our peers at which we have at least one peer, between `0.0` and `1.0`.
+    ///
+    /// Our closest peer gives an idea of how dense the network is around us, and so of the levels at which we should
+    /// expect to know somebody: a well-converged routing table has a peer at every level between the closest and
+    /// furthest of our peers.
+    pub fn routing_completeness(&self) -> f64 {
+        let levels = self
+            .peer_info()
+            .into_iter()
+            .map(|peer| peer.level)
+            .collect::<BTreeSet<_>>();
+        match (levels.first(), levels.last()) {
+            (Some(min), Some(max)) => levels.len() as f64 / (max - min + 1) as f64,
+            _ => 0.0,
         }
     }
 
-    async fn remove_peer(&self, peer_idx: PeerIdx) -> bool {
+    /// Sign a message on behalf of this node.
+    pub async fn seal<T: Serialize + Send>(&self, body: T) -> Signed<T> {
+        // Nonces don't come from the node's own RNG, so that seeded nodes make the same choices however many messages
+        // they send
+        let nonce = rand::random();
+        Signed::new(&self.identity(), now_millis(), nonce, body).await
+    }
+
+    /// Verify a message signed by another node, checking that it is recent and that we haven't seen it before.
+    pub fn open<T: Serialize>(&self, msg: Signed<T>) -> Result<(PublicId, T), SignatureError> {
+        msg.verify()?;
+        let now = now_millis();
+        let max_skew = MAX_CLOCK_SKEW.as_millis() as u64;
+        if msg.timestamp.abs_diff(now) > max_skew {
+            return Err(SignatureError::Expired);
+        }
         self.with_state(|state| {
-            if let Some(peer) = state.peers.remove(peer_idx) {
-                let level = self.self_id.pub_id.tag.dist_to(peer.id.tag).level();
-                state.peers_by_id.remove(&peer.id);
-                state.peers_by_level[level as usize].retain(|idx| idx != &peer_idx);
-                true
+            // Messages older than this would be rejected as expired anyway, so there's no need to remember them
+            while let Some((_, tag, nonce)) = state
+                .nonce_expiry
+                .front()
+                .filter(|(timestamp, ..)| timestamp + max_skew < now)
+                .copied()
+            {
+                state.nonce_expiry.pop_front();
+                state.seen_nonces.remove(&(tag, nonce));
+            }
+            if state.seen_nonces.insert((msg.sender.tag, msg.nonce)) {
+                state
+                    .nonce_expiry
+                    .push_back((msg.timestamp, msg.sender.tag, msg.nonce));
+                Ok(())
             } else {
+                Err(SignatureError::Replayed)
+            }
+        })?;
+        Ok((msg.sender, msg.body))
+    }
+
+    fn rng(&self) -> MutexGuard<'_, ChaCha20Rng> {
+        lock_or_recover(&self.rng, "RNG").0
+    }
+
+    fn with_state<F: FnOnce(&mut State<B>) -> R, R>(&self, f: F) -> R {
+        f(&mut lock_or_recover(&self.state, "state").0)
+    }
+
+    fn with_routing<F: FnOnce(&mut RoutingTable<B::Addr>) -> R, R>(&self, f: F) -> R {
+        let (mut routing, recovered) = lock_or_recover(&self.routing, "routing table");
+        if recovered {
+            // The panic may have struck halfway through an update, so rebuild the indices from the peers themselves
+            let self_tag = routing.self_tag();
+            for peer in routing.rekey(self_tag) {
+                warn!(
+                    "Dropped peer {:?} while repairing the routing table",
+                    peer.id
+                );
+            }
+            self.peers.send_replace(routing.len());
+        }
+        f(&mut routing)
+    }
+
+    /// Change how data is compressed as we store it, or stop compressing it with `None`. Data already stored is left
+    /// as it is. By default, data is stored uncompressed.
+    pub fn set_compression(&self, compression: Option<store::Compression>) {
+        self.with_state(|state| state.data.set_compression(compression));
+    }
+
+    /// Limit the number of bytes that our stored data may take up, or lift the limit with `None`. Once over budget, we
+    /// evict the least recently used data that isn't pinned. By default, there is no limit.
+    pub fn set_storage_budget(&self, budget: Option<usize>) {
+        self.with_state(|state| state.data.set_budget(budget));
+    }
+
+    /// Drop data that isn't pinned once we've held it for `ttl`, or keep it indefinitely with `None`, as by default.
+    pub fn set_data_ttl(&self, ttl: Option<Duration>) {
+        self.with_state(|state| state.data.set_ttl(ttl));
+    }
+
+    /// Remember lookups that came up empty for `ttl`, answering downloads of the same tag in the meantime without asking
+    /// the network again, or not at all with `None`. By default, they're remembered for [`DEFAULT_MISS_TTL`].
+    pub fn set_miss_ttl(&self, ttl: Option<Duration>) {
+        self.with_state(|state| {
+            state.miss_ttl = ttl;
+            if ttl.is_none() {
+                state.misses.clear();
+            }
+        });
+    }
+
+    // Whether a lookup for `tag` came up empty recently enough that it's still remembered
+    fn cached_miss(&self, tag: Tag) -> bool {
+        self.with_state(|state| match state.misses.get(&tag) {
+            Some(expires) if *expires > tokio::time::Instant::now() => true,
+            Some(_) => {
+                state.misses.remove(&tag);
                 false
             }
+            None => false,
         })
     }
 
+    // Remember that a lookup for `tag` came up empty, if there's room to
+    fn cache_miss(&self, tag: Tag) {
+        self.with_state(|state| {
+            let Some(ttl) = state.miss_ttl else {
+                return;
+            };
+            let now = tokio::time::Instant::now();
+            if state.misses.len() >= MAX_CACHED_MISSES {
+                state.misses.retain(|_, expires| *expires > now);
+            }
+            if state.misses.len() < MAX_CACHED_MISSES {
+                state.misses.insert(tag, now + ttl);
+            }
+        });
+    }
+
+    // Forget that a lookup for `tag` came up empty, now that the data is around
+    fn forget_miss(&self, tag: Tag) {
+        self.with_state(|state| state.misses.remove(&tag));
+    }
+
+    /// Limit how much data each node may upload to us, and how much may be uploaded anonymously. By default, there
+    /// are no limits.
+    pub fn set_quotas(&self, quotas: store::Quotas) {
+        self.with_state(|state| state.data.set_quotas(quotas));
+    }
+
+    /// Up to `n` of the publishers with the most data stored with us, and how many bytes each has, most first.
+    pub fn top_publishers(&self, n: usize) -> Vec<(store::Publisher, usize)> {
+        self.with_state(|state| state.data.top_publishers(n))
+    }
+
+    /// Whether to keep our copy of data once we've handed it on to a peer that joined closer to it than we are. By
+    /// default, the copy is dropped.
+    pub fn keep_handoffs(&self, keep: bool) {
+        self.with_state(|state| state.keep_handoffs = keep);
+    }
+
+    /// Limit the number of peers that we keep in total, beyond the limit of [`MAX_LEVEL_PEERS`] at each level, or lift
+    /// the limit with `None`, as by default. Each peer is pinged regularly, so this bounds our background traffic. Once
+    /// at the limit, a newcomer takes the place of the slowest peer at the fullest, farthest level, if that level would
+    /// be fuller or farther than the newcomer's; peers beyond a lowered limit are dropped in the same order.
+    ///
+    /// [`MAX_LEVEL_PEERS`]: routing::MAX_LEVEL_PEERS
+    pub fn set_max_peers(&self, max_peers: Option<usize>) {
+        for peer in self.with_routing(|routing| routing.set_max_peers(max_peers)) {
+            info!(
+                "Dropped peer {:?} to keep within our limit on peers",
+                peer.id
+            );
+            self.with_state(|state| state.observed_ips.remove(&peer.id));
+        }
+    }
+
+    /// Look at our routing table.
+    pub fn inspect_routing<F: FnOnce(&RoutingTable<B::Addr>) -> R, R>(&self, f: F) -> R {
+        self.with_routing(|routing| f(routing))
+    }
+
+    pub async fn accept_peer(&self, id: PublicId, addr: B::Addr) -> bool {
+        self.accept_peer_with(id, addr, Capabilities::NONE).await
+    }
+
+    // Like `accept_peer`, for a peer we've agreed a set of capabilities with
+    async fn accept_peer_with(
+        &self,
+        id: PublicId,
+        addr: B::Addr,
+        capabilities: Capabilities,
+    ) -> bool {
+        let addr = B::canonicalise(addr);
+        if id.tag == self.id().tag || self.with_routing(|routing| routing.contains(&id)) {
+            return false;
+        }
+        if self.with_state(|state| state.distrusted.contains(&id)) {
+            debug!(
+                "Refused to peer with {:?}, which failed too many audits",
+                id
+            );
+            return false;
+        }
+        let Ok((ping, pong)) = self.backend.send_ping(&addr).await else {
+            debug!(
+                "Tried to accept peer {:?} but they did not respond to a ping",
+                id
+            );
+            return false;
+        };
+        let clock_offset = self.clock_offset(&id, &pong);
+        let record = pong.record;
+        if record.sender != id || record.verify_record().is_err() {
+            warn!(
+                "Tried to accept peer {:?} but it did not provide a valid address record",
+                id
+            );
+            return false;
+        }
+        let peer = Peer {
+            id: id.clone(),
+            addr: addr.clone(),
+            ping,
+            record,
+            capabilities,
+            clock_offset,
+        };
+        match self.with_routing(|routing| routing.insert(peer)) {
+            InsertOutcome::Inserted { level } => {
+                info!("Added peer {:?} at level {}", id, level);
+                self.peers_changed();
+                self.queue_handoffs(&id, &addr);
+                true
+            }
+            InsertOutcome::Replaced { level, evicted } => {
+                info!(
+                    "Added peer {:?} at level {} in place of {:?}",
+                    id, level, evicted
+                );
+                self.with_state(|state| state.observed_ips.remove(&evicted));
+                self.queue_handoffs(&id, &addr);
+                true
+            }
+            InsertOutcome::Updated => true,
+            // Somebody else took the last place at the peer's level while we waited for it
+            InsertOutcome::Full { level } => {
+                debug!("No room left for peer {:?} at level {}", id, level);
+                false
+            }
+            InsertOutcome::AtLimit { level } => {
+                debug!(
+                    "No room left for peer {:?} at level {} within our limit on peers",
+                    id, level
+                );
+                false
+            }
+            // Our identity was rotated to share the peer's tag while we waited
+            InsertOutcome::Ours => false,
+        }
+    }
+
+    /// Drop a peer from our routing table, returning whether we were peered with it.
+    pub async fn disconnect(&self, peer: &PublicIdRef) -> bool {
+        let id = self.with_routing(|routing| {
+            routing
+                .iter()
+                .find(|p| peer.matches(&p.id))
+                .map(|p| p.id.clone())
+        });
+        match id {
+            Some(id) => self.remove_peer(&id).await,
+            None => false,
+        }
+    }
+
+    async fn remove_peer(&self, id: &PublicId) -> bool {
+        if self.with_routing(|routing| routing.remove(id)).is_none() {
+            return false;
+        }
+        info!("Removed peer {:?}", id);
+        self.peers_changed();
+        self.with_state(|state| state.observed_ips.remove(id));
+        true
+    }
+
     pub fn can_accept_peer(&self, id: &PublicId) -> bool {
-        id != &self.self_id.pub_id
-            && self.with_state(|state| {
-                let level = self.self_id.pub_id.tag.dist_to(id.tag).level();
-                state.peers_by_level[level as usize].len() < MAX_LEVEL_PEERS
-                    && !state.peers_by_id.contains_key(id)
-            })
+        self.with_routing(|routing| routing.has_room_for(id))
     }
 
     // Ok(()) => discovery was successful and we're now peered with the node
@@ -154,270 +1173,2176 @@ impl<B: Backend> Node<B> {
         supposed_id: Option<&PublicId>,
         addr: B::Addr,
     ) -> Result<(), Option<B::Addr>> {
-        if supposed_id.map_or(true, |sid| self.can_accept_peer(&sid)) {
-            match self
-                .backend
-                .send_greet(&addr, (self.id().clone(), self.addr().clone()))
-                .await
-            {
-                Ok(Ok(id)) if supposed_id.map_or(true, |sid| sid == &id) => {
-                    eprintln!("{:?} discovered accepting peer {:?}!", self.self_id, id);
-                    self.accept_peer(id, addr).await;
-                    Ok(())
+        let addr = B::canonicalise(addr);
+        if self.with_state(|state| state.incompatible.contains(&addr)) {
+            Err(None)
+        } else if supposed_id.map_or(true, |sid| self.can_accept_peer(&sid)) {
+            let self_id = self.identity();
+            let challenge = Tag::generate();
+            let greet = Greet::new((self_id.pub_id.clone(), self.addr()), challenge);
+            match self.backend.send_greet(&addr, greet).await {
+                Ok(Ok(reply))
+                    if supposed_id.map_or(true, |sid| {
+                        sid == &reply.id || endorses(reply.endorsement.as_ref(), sid, &reply.id)
+                    }) =>
+                {
+                    if !reply
+                        .id
+                        .verify_challenge(challenge, &self_id.pub_id, &reply.proof)
+                    {
+                        warn!(
+                            "{:?} peer could not prove that it owns {:?}!",
+                            self_id, reply.id
+                        );
+                        return Err(None);
+                    }
+                    // Now that they've proven who they are, prove who we are
+                    let proof = self_id.answer_challenge(reply.challenge, &reply.id).await;
+                    match self
+                        .backend
+                        .send_prove(&addr, self_id.pub_id.clone(), proof)
+                        .await
+                    {
+                        Ok(true) => {
+                            debug!("{:?} discovered accepting peer {:?}", self_id, reply.id);
+                            let capabilities =
+                                Capabilities::SUPPORTED.intersection(reply.capabilities);
+                            self.accept_peer_with(reply.id, addr, capabilities).await;
+                            Ok(())
+                        }
+                        Ok(false) => Err(None),
+                        Err(err) => {
+                            self.metrics.failure();
+                            error!("Failed to prove our identity to peer: {}", err);
+                            Err(None)
+                        }
+                    }
                 }
-                Ok(Ok(id)) => {
-                    eprintln!(
+                Ok(Ok(reply)) => {
+                    warn!(
                         "{:?} peer got a different ID ({:?}) to the ID it was reported ({:?})!",
-                        self.self_id, id, supposed_id
+                        self_id, reply.id, supposed_id
+                    );
+                    Err(None)
+                }
+                // Only follow suggestions that the suggested node vouches for
+                Ok(Err(GreetRefusal::Redirect(alt))) => Err(alt
+                    .filter(|alt| alt.verify_record().is_ok())
+                    .map(|alt| B::canonicalise(alt.body.addr))),
+                // There's no point trying again, or following suggestions from a node we can't talk to
+                Ok(Err(GreetRefusal::VersionMismatch { ours, theirs })) => {
+                    warn!(
+                        "{:?} speaks protocol version {} but we speak version {}, so will not greet it again",
+                        addr, ours, theirs
                     );
+                    self.with_state(|state| {
+                        if state.incompatible.len() < MAX_INCOMPATIBLE_ADDRS {
+                            state.incompatible.insert(addr);
+                        }
+                    });
                     Err(None)
                 }
-                Ok(Err(alt)) => Err(alt),
                 Err(err) => {
-                    eprintln!("Failed to sent greeting to initial peer: {}", err);
+                    self.metrics.failure();
+                    error!("Failed to send greeting to peer: {}", err);
                     Err(None)
                 }
             }
         } else {
-            eprintln!("Can't accept peer {:?}", supposed_id);
+            debug!("Can't accept peer {:?}", supposed_id);
             Err(None)
         }
     }
 
+    /// Peer with the node at `addr`, or failing that with whichever node it suggests instead, and so on.
+    ///
+    /// The suggestions are followed until one is repeated, since the nodes making them would only go on suggesting
+    /// each other, or until [`DEFAULT_MAX_REDIRECTS`] of them have been followed, unless changed with
+    /// [`Node::set_max_redirects`].
+    pub async fn discover_from(&self, addr: B::Addr) -> Result<(), DiscoverError<B::Addr>> {
+        let max_redirects = self.with_state(|state| state.max_redirects);
+        let mut addr = B::canonicalise(addr);
+        let mut visited = HashSet::from([self.addr()]);
+        let mut redirects = 0;
+        loop {
+            if !visited.insert(addr.clone()) {
+                return Err(DiscoverError::Loop(addr));
+            }
+            match self.discover_peer(None, addr.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(None) => return Err(DiscoverError::Failed(addr)),
+                Err(Some(_)) if redirects == max_redirects => {
+                    return Err(DiscoverError::TooManyRedirects(redirects))
+                }
+                Err(Some(alt)) => {
+                    debug!("{:?} suggested that we try {:?} instead", addr, alt);
+                    addr = alt;
+                    redirects += 1;
+                }
+            }
+        }
+    }
+
+    /// Set how many suggestions of other peers to try [`Node::discover_from`] follows before giving up. This is
+    /// [`DEFAULT_MAX_REDIRECTS`] by default.
+    pub fn set_max_redirects(&self, max_redirects: usize) {
+        self.with_state(|state| state.max_redirects = max_redirects);
+    }
+
     pub async fn recv_greet(
         &self,
-        sender: (PublicId, B::Addr),
-    ) -> Result<PublicId, Option<B::Addr>> {
-        // If we're willing to
-        if self.can_accept_peer(&sender.0) && self.accept_peer(sender.0.clone(), sender.1).await {
-            eprintln!("{:?} accepted peer {:?}!", self.self_id, sender.0);
-            Ok(self.id().clone())
+        greet: Greet<B::Addr>,
+    ) -> Result<GreetReply, GreetRefusal<B::Addr>> {
+        self.metrics.request(Request::Greet);
+        let Greet {
+            sender,
+            challenge,
+            version,
+            capabilities,
+        } = greet;
+        if version != PROTOCOL_VERSION {
+            info!(
+                "Rejected greeting from {:?}, which speaks protocol version {}",
+                sender.0, version
+            );
+            return Err(GreetRefusal::VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: version,
+            });
+        }
+        // If we're willing to, challenge the greeter to prove that they own the identity they claim
+        let our_challenge = Tag::generate();
+        let challenged = self.can_accept_peer(&sender.0)
+            && self.with_state(|state| {
+                let now = tokio::time::Instant::now();
+                state
+                    .pending_greets
+                    .retain(|_, greet| now.duration_since(greet.issued) < GREET_TIMEOUT);
+                if state.pending_greets.len() < MAX_PENDING_GREETS {
+                    state.pending_greets.insert(
+                        sender.0.clone(),
+                        PendingGreet {
+                            addr: sender.1.clone(),
+                            challenge: our_challenge,
+                            issued: now,
+                            capabilities: Capabilities::SUPPORTED.intersection(capabilities),
+                        },
+                    );
+                    true
+                } else {
+                    false
+                }
+            });
+        if challenged {
+            let self_id = self.identity();
+            Ok(GreetReply {
+                id: self_id.pub_id.clone(),
+                proof: self_id.answer_challenge(challenge, &sender.0).await,
+                challenge: our_challenge,
+                // Greeters who heard of us under our previous identity can see that this one replaces it
+                endorsement: self.with_state(|state| {
+                    state
+                        .retired
+                        .as_ref()
+                        .filter(|retired| retired.expires > tokio::time::Instant::now())
+                        .map(|retired| retired.endorsement.clone())
+                }),
+                capabilities: Capabilities::SUPPORTED,
+            })
         } else {
             // Choose one of our existing peers to have the greeter talk to instead
             // ("I don't want to be friends with you, go ask that other person")
-            let alt = self.with_state(|state| {
-                state
-                    .peers
-                    .values()
-                    .choose(&mut thread_rng())
-                    .map(|peer| peer.addr.clone())
+            let now = now_millis();
+            let alt = self.with_routing(|routing| {
+                routing
+                    .iter()
+                    .filter(|peer| peer.record.body.expires > now)
+                    .choose(&mut *self.rng())
+                    .map(|peer| peer.record.clone())
             });
-            eprintln!(
+            debug!(
                 "Rejected greeting from {:?}, returned alternative peer {:?}",
                 sender.0, alt
             );
-            Err(alt)
+            Err(GreetRefusal::Redirect(alt))
         }
     }
 
-    pub async fn recv_ping(&self) {}
-
-    pub async fn recv_discover(&self, target: Tag, max_level: u16) -> Option<(PublicId, B::Addr)> {
-        // Determine whether we have a peer within at given distance
-        self.with_state(|state| {
-            state
-                .peers
-                .values()
+    /// Handle a greeter's answer to our challenge, accepting them as a peer if it's valid.
+    pub async fn recv_prove(&self, id: PublicId, proof: Signature) -> bool {
+        self.metrics.request(Request::Prove);
+        let Some(greet) = self.with_state(|state| state.pending_greets.remove(&id)) else {
+            return false;
+        };
+        // A greeting that arrived before we rotated our identity will have been answered for our previous one
+        if greet.issued.elapsed() >= GREET_TIMEOUT
+            || !std::iter::once(self.id())
+                .chain(
+                    self.retired_identity()
+                        .map(|retired| retired.pub_id.clone()),
+                )
+                .any(|self_id| id.verify_challenge(greet.challenge, &self_id, &proof))
+        {
+            warn!("{:?} could not prove that it owns its identity!", id);
+            false
+        } else if self.can_accept_peer(&id)
+            && self
+                .accept_peer_with(id.clone(), greet.addr, greet.capabilities)
+                .await
+        {
+            debug!("{:?} accepted greeting from {:?}", self.identity(), id);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Answer a ping with our address record, so that our peers always have a current one to pass on.
+    pub async fn recv_ping(&self) -> SignedAddr<B::Addr> {
+        let ping = msg::Ping { sent: now_millis() };
+        self.recv_ping_from(None, ping).await.record
+    }
+
+    /// Like [`Node::recv_ping`], also passing on the record of one of our peers, picked at random, other than `pinger`.
+    /// This spreads word of the network's topology without costing any extra requests.
+    ///
+    /// The pong is stamped with when the ping was sent and when we received and answered it, so that the pinger can
+    /// work out how far apart our clocks are.
+    pub async fn recv_ping_from(
+        &self,
+        pinger: Option<&PublicId>,
+        ping: msg::Ping,
+    ) -> msg::Pong<B::Addr> {
+        let now = now_millis();
+        self.metrics.request(Request::Ping);
+        let peer = self.with_routing(|routing| {
+            routing
+                .iter()
+                .filter(|peer| peer.record.body.expires > now)
+                .filter(|peer| Some(&peer.id) != pinger)
+                .choose(&mut *self.rng())
+                .map(|peer| peer.record.clone())
+        });
+        let record = self.addr_record().await;
+        msg::Pong {
+            record,
+            peer,
+            ping_sent: ping.sent,
+            received: now,
+            sent: now_millis(),
+        }
+    }
+
+    // How far ahead of our clock a peer's is by its pong, which we've only just received, warning if it's far enough
+    // out that the peer's messages and records may be taken for expired
+    fn clock_offset(&self, id: &PublicId, pong: &msg::Pong<B::Addr>) -> Option<i64> {
+        let offset = pong.clock_offset(now_millis())?;
+        if offset.unsigned_abs() > MAX_CLOCK_SKEW.as_millis() as u64 / 2 {
+            warn!(
+                "{:?}'s clock is {}ms out from ours, which is close to the {}s of skew that we tolerate",
+                id,
+                offset,
+                MAX_CLOCK_SKEW.as_secs()
+            );
+        }
+        Some(offset)
+    }
+
+    /// Set whether we try peering with the peers that our peers pass on to us when they answer our pings. This is on
+    /// by default.
+    pub fn set_peer_hints(&self, enabled: bool) {
+        self.with_state(|state| state.peer_hints = enabled);
+    }
+
+    // Try peering with a node that a peer passed on to us, if we have room for it and haven't tried too many lately
+    async fn follow_hint(&self, record: SignedAddr<B::Addr>) {
+        let id = record.sender.clone();
+        if id.tag == self.id().tag
+            || self.with_routing(|routing| routing.contains(&id))
+            || !self.can_accept_peer(&id)
+            || record.verify_record().is_err()
+        {
+            return;
+        }
+        let now = tokio::time::Instant::now();
+        let follow = self.with_state(|state| {
+            if !state.peer_hints
+                || state.distrusted.contains(&id)
+                || state.incompatible.contains(&record.body.addr)
+            {
+                return false;
+            }
+            if now.duration_since(state.hints.0) >= HINT_INTERVAL {
+                state.hints = (now, 0);
+            }
+            state.hints.1 += 1;
+            state.hints.1 <= MAX_HINTS_PER_INTERVAL
+        });
+        if follow {
+            debug!("Trying {:?}, which a peer passed on to us", id);
+            let _ = self.discover_peer(Some(&id), record.body.addr).await;
+        }
+    }
+
+    pub async fn recv_discover(&self, target: Tag, max_level: u16) -> Option<SignedAddr<B::Addr>> {
+        self.metrics.request(Request::Discover);
+        let now = now_millis();
+        // Determine whether we have a peer within at given distance
+        self.with_routing(|routing| {
+            routing
+                .iter()
+                // Don't pass on records that the receiver would reject
+                .filter(|peer| peer.record.body.expires > now)
                 // Don't tell the peer about itself
                 .filter(|peer| peer.id.tag != target)
                 // Only consider peers that are closer than the target
-                .filter(|peer| peer.id.tag.dist_to(target).level() <= max_level)
-                .choose(&mut thread_rng())
+                .filter(|peer| {
+                    peer.id
+                        .tag
+                        .bucket_index(target)
+                        .is_some_and(|level| level <= max_level as usize)
+                })
+                .choose(&mut *self.rng())
                 // // Try to find that which has the greatest distance within the maximum distance
                 // .min_by_key(|peer| peer.id.tag.dist_to(discover.target.tag))
                 // // Only pass that peer on about a third of the time
-                // .filter(|_| thread_rng().gen_bool(0.3))
-                .map(|peer| (peer.id.clone(), peer.addr.clone()))
+                // .filter(|_| self.rng().gen_bool(0.3))
+                .map(|peer| peer.record.clone())
         })
     }
 
-    pub async fn load_data(&self, tag: Tag) -> Option<Box<[u8]>> {
-        Some(
-            self.with_state(|state| state.data.get(&tag).cloned())?
-                .to_vec()
-                .into_boxed_slice(),
-        )
+    pub async fn recv_info(&self) -> NodeStats {
+        self.metrics.request(Request::Info);
+        self.stats()
+    }
+
+    /// Answer a request for up to `k` of our peers closest to `target`, closest first.
+    ///
+    /// Unlike [`Node::recv_discover`], this is purely informational: nobody is greeted as a result.
+    pub async fn recv_find_node(&self, target: Tag, k: usize) -> Vec<(PublicId, B::Addr)> {
+        self.metrics.request(Request::FindNode);
+        self.closest_peers(target, None, k.min(MAX_FIND_NODE_PEERS))
+    }
+
+    /// Find up to `k` of the reachable nodes closest to `target`, closest first, by repeatedly asking the closest
+    /// nodes found so far who they know near it.
+    ///
+    /// Nobody is greeted along the way, and our own peers are left as they are.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub async fn find_node(&self, target: Tag, k: usize) -> Vec<(PublicId, B::Addr)> {
+        let self_id = self.id();
+        let mut candidates = self
+            .closest_peers(target, None, k)
+            .into_iter()
+            .map(|peer| (peer.0.tag.dist_to(target), peer))
+            .collect::<BTreeMap<_, _>>();
+        let mut queried = HashSet::new();
+        let mut hops = 0;
+        // Stop once the closest `k` candidates have all been asked
+        while let Some((dist, peer)) = candidates
+            .iter()
+            .take(k)
+            .find(|(dist, _)| !queried.contains(*dist))
+            .map(|(dist, peer)| (*dist, peer.clone()))
+        {
+            queried.insert(dist);
+            hops += 1;
+            match self.backend.send_find_node(&peer.1, target, k).await {
+                Ok(found) => {
+                    for found in found.into_iter().take(k) {
+                        let found_dist = found.0.tag.dist_to(target);
+                        // Anybody we've already asked is either a candidate already or didn't respond
+                        if found.0 != self_id && !queried.contains(&found_dist) {
+                            candidates.entry(found_dist).or_insert(found);
+                        }
+                    }
+                }
+                Err(_err) => {
+                    self.metrics.failure();
+                    // Make way for the next closest candidate
+                    candidates.remove(&dist);
+                }
+            }
+        }
+        debug!("Lookup took {} hop(s)", hops);
+        self.metrics.lookup(hops);
+        candidates.into_values().take(k).collect()
+    }
+
+    /// The data stored under `tag`, if we have it. Unless it was compressed, this is the stored copy itself, so is cheap
+    /// to clone.
+    pub async fn load_data(&self, tag: Tag) -> Option<Bytes> {
+        self.with_state(|state| state.data.get(tag))
     }
 
     pub async fn has_data(&self, tag: Tag) -> bool {
-        self.with_state(|state| state.data.contains_key(&tag))
+        self.with_state(|state| state.data.contains(tag))
+    }
+
+    /// Store `data` under `tag`, returning whether it's new to us rather than a copy of data we already had.
+    pub async fn save_data(&self, tag: Tag, data: Bytes) -> bool {
+        self.with_state(|state| state.data.insert(tag, data))
+    }
+
+    /// Drop our copy of the data stored under `tag`, even if it's pinned, returning whether we had one.
+    ///
+    /// If it's a tree node or an erasure-coded manifest, the chunks that it refers to are left for
+    /// [`Node::collect_garbage`].
+    pub async fn remove_data(&self, tag: Tag) -> bool {
+        self.with_state(|state| state.data.remove(tag))
+    }
+
+    /// Drop a batch of the chunks that no tree node or manifest we hold refers to, whose lease hasn't been renewed for
+    /// [`CHUNK_LEASE`] and that have gone unused for [`GC_GRACE`], returning how many were dropped. This is done
+    /// periodically by [`Node::run`].
+    pub fn collect_garbage(&self) -> usize {
+        self.with_state(|state| state.data.collect_garbage(GC_BATCH, GC_GRACE))
+    }
+
+    /// Renew the leases of the chunks that the tree nodes and manifests we hold refer to, with each of the nodes closest
+    /// to them, returning how many copies were renewed. This is done periodically by [`Node::run`], so that chunks
+    /// outlive their lease for as long as something refers to them, wherever that is.
+    pub async fn renew_chunks(&self) -> usize {
+        let (chunks, replicas) =
+            self.with_state(|state| (state.data.referenced().collect::<Vec<_>>(), state.replicas));
+        // Chunks are placed with the nodes closest to them, which are asked to renew all of theirs at once
+        let mut holders = HashMap::<B::Addr, Vec<Tag>>::new();
+        for chunk in chunks {
+            for (_, addr) in self.find_node(chunk, replicas).await {
+                holders.entry(addr).or_default().push(chunk);
+            }
+        }
+        let mut renewed = 0;
+        for (addr, tags) in holders {
+            for tags in tags.chunks(MAX_RENEW_TAGS) {
+                match self.backend.send_renew(&addr, tags.to_vec()).await {
+                    Ok(count) => renewed += count,
+                    Err(err) => {
+                        self.metrics.failure();
+                        debug!("Failed to renew chunks with {:?}: {}", addr, err);
+                    }
+                }
+            }
+        }
+        renewed
+    }
+
+    /// Renew the leases of those of the first [`MAX_RENEW_TAGS`] of `tags` that we hold, returning how many we hold.
+    pub async fn recv_renew(&self, tags: Vec<Tag>) -> usize {
+        self.metrics.request(Request::Renew);
+        self.with_state(|state| {
+            tags.into_iter()
+                .take(MAX_RENEW_TAGS)
+                .filter(|tag| state.data.renew(*tag))
+                .count()
+        })
+    }
+
+    /// Keep our copy of the data stored under `tag` until it's unpinned, exempting it from eviction and expiry, and
+    /// from being dropped once handed on to a closer peer. Returns whether we have a copy to pin.
+    pub fn pin(&self, tag: Tag) -> bool {
+        self.with_state(|state| state.data.pin(tag))
+    }
+
+    /// Undo [`Node::pin`], returning whether the data was pinned.
+    pub fn unpin(&self, tag: Tag) -> bool {
+        self.with_state(|state| state.data.unpin(tag))
+    }
+
+    /// Like [`Node::pin`], but first downloading the data if we don't have a copy. Returns `Ok(false)` if nobody
+    /// does, or if it won't fit within our storage budget.
+    pub async fn pin_and_fetch(&self, tag: Tag) -> Result<bool, DownloadError> {
+        if self.pin(tag) {
+            return Ok(true);
+        }
+        let Some(data) = self.do_download(tag).await? else {
+            return Ok(false);
+        };
+        // Stored and pinned at once, so that storing it can't evict it before it's pinned
+        Ok(self.with_state(|state| {
+            state.data.insert(tag, data);
+            state.data.pin(tag)
+        }))
+    }
+
+    /// A summary of each entry that we store, including whether it's pinned.
+    pub fn list_data(&self) -> Vec<store::EntryInfo> {
+        self.with_state(|state| state.data.entries().collect())
+    }
+
+    // Ok(None) => we don't have the data
+    pub async fn recv_download(&self, tag: Tag) -> Result<Option<Bytes>, ProtocolError> {
+        self.metrics.request(Request::Download);
+        let data = self.load_data(tag).await;
+        match &data {
+            Some(data) => self.metrics.downloaded(data.len()),
+            None => self.count_request(tag),
+        }
+        Ok(data)
+    }
+
+    // Count a request for data that we don't hold, queueing it to be cached once it's popular enough
+    fn count_request(&self, tag: Tag) {
+        let now = tokio::time::Instant::now();
+        self.with_state(|state| {
+            if state.popular.contains(&tag) {
+                return;
+            }
+            let decayed = |(count, at): (f64, tokio::time::Instant)| {
+                count
+                    * 0.5f64.powf(
+                        now.duration_since(at).as_secs_f64() / POPULARITY_HALF_LIFE.as_secs_f64(),
+                    )
+            };
+            if !state.popularity.contains_key(&tag)
+                && state.popularity.len() >= MAX_POPULARITY_TRACKED
+            {
+                // Make room by forgetting whichever tag is least popular now
+                let least = state
+                    .popularity
+                    .iter()
+                    .min_by(|(_, a), (_, b)| decayed(**a).total_cmp(&decayed(**b)))
+                    .map(|(tag, _)| *tag);
+                if let Some(least) = least {
+                    state.popularity.remove(&least);
+                }
+            }
+            let count = state.popularity.get(&tag).copied().map_or(0.0, decayed) + 1.0;
+            if count >= POPULARITY_THRESHOLD {
+                debug!("{} has become popular, so we'll cache a copy", tag);
+                state.popularity.remove(&tag);
+                state.popular.push_back(tag);
+            } else {
+                state.popularity.insert(tag, (count, now));
+            }
+        });
+    }
+
+    /// Fetch and cache a copy of a few of the tags that we don't hold but have received more than
+    /// [`POPULARITY_THRESHOLD`] recent requests for, returning how many were cached. This is done periodically by
+    /// [`Node::run`].
+    ///
+    /// Cached copies let us answer requests for the data ourselves, taking load off its owner. They're evicted before
+    /// any other data and are never passed on to other nodes as if they were ours.
+    pub async fn cache_popular(&self) -> usize {
+        let mut cached = 0;
+        for _ in 0..MAX_CACHED_PER_TICK {
+            let Some(tag) = self.with_state(|state| state.popular.pop_front()) else {
+                break;
+            };
+            if self.has_data(tag).await {
+                continue;
+            }
+            match self.do_download(tag).await {
+                Ok(Some(data)) => {
+                    if self.with_state(|state| state.data.insert_cached(tag, data)) {
+                        debug!("Cached a copy of {}", tag);
+                        cached += 1;
+                    }
+                }
+                Ok(None) => debug!("Could not find popular data {} to cache", tag),
+                Err(err) => debug!("Failed to fetch popular data {} to cache: {:?}", tag, err),
+            }
+        }
+        cached
+    }
+
+    // Ok(None) => we don't have the data, or the challenge doesn't fit it
+    pub async fn recv_audit(
+        &self,
+        tag: Tag,
+        challenge: audit::Challenge,
+    ) -> Result<Option<Tag>, ProtocolError> {
+        self.metrics.request(Request::Audit);
+        Ok(self
+            .load_data(tag)
+            .await
+            .and_then(|data| challenge.answer(tag.algorithm(), &data)))
+    }
+
+    // Prepare to audit a peer that we've just placed `data` with, replacing any audits of its copy that were left
+    fn prepare_audits(&self, tag: Tag, data: &[u8], holder: (PublicId, B::Addr)) {
+        let challenges = {
+            let mut rng = self.rng();
+            (0..AUDIT_CHALLENGES)
+                .map(|_| audit::Challenge::random(&mut *rng, data.len()))
+                .collect::<Vec<_>>()
+        };
+        let challenges = challenges
+            .into_iter()
+            .map(|challenge| {
+                let answer = challenge.answer(tag.algorithm(), data);
+                (challenge, answer.expect("challenge fits the data"))
+            })
+            .collect();
+        let (id, addr) = holder;
+        self.with_state(|state| {
+            let key = (tag, id);
+            if state.audits.len() < MAX_AUDITED || state.audits.contains_key(&key) {
+                state.audits.insert(key, Audits { addr, challenges });
+            }
+        });
+    }
+
+    /// Challenge `holder`, one of the peers that we placed the data stored under `tag` with, to prove that it still
+    /// holds it, returning `None` if we have no audits of its copy left to make. Audits are prepared for each copy
+    /// that [`Node::do_upload`] places with a peer, and made periodically by [`Node::run`].
+    ///
+    /// A peer that fails [`MAX_AUDIT_FAILURES`] audits is dropped, and we refuse to peer with it again. If we have our
+    /// own copy of data that a peer failed an audit of, we place it with the closest of our peers again.
+    pub async fn audit(&self, tag: Tag, holder: &PublicId) -> Option<AuditOutcome> {
+        let (addr, (challenge, expected)) = self.with_state(|state| {
+            let key = (tag, holder.clone());
+            let audits = state.audits.get_mut(&key)?;
+            let challenge = audits.challenges.pop()?;
+            let addr = audits.addr.clone();
+            if audits.challenges.is_empty() {
+                state.audits.remove(&key);
+            }
+            Some((addr, challenge))
+        })?;
+        let outcome = match self.backend.send_audit(&addr, tag, challenge).await {
+            Ok(Ok(Some(answer))) if answer == expected => AuditOutcome::Passed,
+            Ok(Ok(_)) => AuditOutcome::Failed,
+            Ok(Err(reason)) => {
+                debug!("{:?} refused an audit of {}: {}", holder, tag, reason);
+                AuditOutcome::Inconclusive
+            }
+            Err(err) => {
+                self.metrics.failure();
+                debug!("Failed to audit {:?}: {}", holder, err);
+                AuditOutcome::Inconclusive
+            }
+        };
+        if outcome == AuditOutcome::Failed {
+            self.audit_failed(tag, holder).await;
+        }
+        Some(outcome)
+    }
+
+    // Count a failure to keep data intact against a peer, dropping it if it has failed too often, and returning how many
+    // times it has
+    async fn penalise(&self, peer: &PublicId) -> usize {
+        let failures = self.with_state(|state| {
+            let failures = state.audit_failures.entry(peer.clone()).or_default();
+            *failures += 1;
+            if *failures >= MAX_AUDIT_FAILURES {
+                state.distrusted.insert(peer.clone());
+            }
+            *failures
+        });
+        if failures >= MAX_AUDIT_FAILURES {
+            warn!("No longer trusting {:?}", peer);
+            self.remove_peer(peer).await;
+        }
+        failures
+    }
+
+    // Hold a failed audit against the peer, and place the data again if we can
+    async fn audit_failed(&self, tag: Tag, holder: &PublicId) {
+        // Any audits left of the holder are of a copy that's gone
+        self.with_state(|state| state.audits.remove(&(tag, holder.clone())));
+        let failures = self.penalise(holder).await;
+        warn!(
+            "{:?} failed an audit of {} ({} of {} allowed)",
+            holder, tag, failures, MAX_AUDIT_FAILURES
+        );
+        let Some(data) = self.load_data(tag).await else {
+            warn!(
+                "{} may have been lost, and we have no copy to place again",
+                tag
+            );
+            return;
+        };
+        // Those that we still audit have a copy already
+        let candidates = self.closest_peers(tag, None, REHOME_CANDIDATES + 1);
+        let candidates = self.with_state(|state| {
+            candidates
+                .into_iter()
+                .filter(|(id, _)| !state.audits.contains_key(&(tag, id.clone())))
+                .take(REHOME_CANDIDATES)
+                .collect::<Vec<_>>()
+        });
+        for (id, addr) in candidates {
+            match self
+                .backend
+                .send_upload(&addr, tag, data.clone(), self.held_as_chunk(tag))
+                .await
+            {
+                Ok(Ok(stored)) if stored == tag => {
+                    info!("Placed {} with {:?} again", tag, id);
+                    self.prepare_audits(tag, &data, (id, addr));
+                    return;
+                }
+                Ok(res) => warn!("{:?} would not take {}: {:?}", id, tag, res),
+                Err(err) => {
+                    self.metrics.failure();
+                    debug!("Failed to place {} with {:?}: {}", tag, id, err);
+                }
+            }
+        }
+    }
+
+    /// Change how likely the summaries that we compare with our neighbours are to claim tags we don't hold. Lower
+    /// rates make for larger summaries, up to [`sync::MAX_SUMMARY_BYTES`].
+    pub fn set_sync_false_positive_rate(&self, rate: f64) {
+        self.with_state(|state| state.sync_false_positive_rate = rate);
     }
 
-    pub async fn save_data(&self, tag: Tag, data: Box<[u8]>) {
-        let data = data.to_vec().into();
+    // The tags of the data that we hold in `region`, and a summary of them
+    fn summarise(&self, region: sync::Region) -> (Vec<Tag>, sync::Summary) {
+        let seed = self.rng().gen();
         self.with_state(|state| {
-            state.data.entry(tag).or_insert(data);
+            let tags = state
+                .data
+                .tags()
+                .filter(|tag| region.contains(*tag))
+                .collect::<Vec<_>>();
+            let summary = sync::Summary::new(&tags, state.sync_false_positive_rate, seed);
+            (tags, summary)
         })
     }
 
-    pub async fn recv_download(&self, tag: Tag) -> Option<Box<[u8]>> {
-        self.load_data(tag).await
+    // Reply with the tags we hold in the region that the peer's summary lacks, and a summary of our own
+    pub async fn recv_sync(
+        &self,
+        region: sync::Region,
+        summary: sync::Summary,
+    ) -> Result<msg::SyncReply, ProtocolError> {
+        self.metrics.request(Request::Sync);
+        if !region.contains(self.id().tag) {
+            return Err(ProtocolError::NotResponsible);
+        }
+        let (tags, ours) = self.summarise(region);
+        Ok(msg::SyncReply {
+            missing: tags
+                .into_iter()
+                .filter(|tag| !summary.contains(*tag))
+                .take(MAX_SYNC_TAGS)
+                .collect(),
+            summary: ours,
+        })
     }
 
-    pub async fn recv_upload(&self, data: Box<[u8]>) -> Result<(), ()> {
-        let tag = Tag::digest(&*data);
-        self.save_data(tag, data).await;
-        Ok(())
+    /// Compare the data that we hold with that held by `peer`, in the region of the keyspace that we're both
+    /// responsible for, and fill in the gaps on either side. Returns `None` if the peer couldn't be reached or refused.
+    ///
+    /// No more than a bounded number of entries pass either way, so stores that have drifted far apart take a few
+    /// rounds to converge.
+    pub async fn sync_with(&self, peer: &(PublicId, B::Addr)) -> Option<Synced> {
+        let region = sync::Region::shared(self.id().tag, peer.0.tag);
+        let (tags, summary) = self.summarise(region);
+        let reply = match self.backend.send_sync(&peer.1, region, summary).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(reason)) => {
+                debug!("{:?} would not compare notes with us: {}", peer.0, reason);
+                return None;
+            }
+            Err(err) => {
+                self.metrics.failure();
+                debug!("Failed to compare notes with {:?}: {}", peer.0, err);
+                return None;
+            }
+        };
+        let mut synced = Synced::default();
+        for tag in reply.missing.into_iter().take(MAX_SYNC_TAGS) {
+            if !region.contains(tag) || self.has_data(tag).await {
+                continue;
+            }
+            match self.backend.send_download(&peer.1, tag).await {
+                Ok(Ok(Some(data))) if tag.is_digest_of(&data) => {
+                    self.save_data(tag, data).await;
+                    synced.fetched += 1;
+                }
+                Ok(Ok(Some(_))) => {
+                    self.metrics.failure();
+                    warn!("{:?} sent us a bad copy of {}", peer.0, tag);
+                }
+                res => debug!("Could not fetch {} from {:?}: {:?}", tag, peer.0, res.ok()),
+            }
+        }
+        let missing = tags
+            .into_iter()
+            .filter(|tag| !reply.summary.contains(*tag))
+            .take(MAX_SYNC_TAGS);
+        for tag in missing {
+            let Some(data) = self.load_data(tag).await else {
+                continue;
+            };
+            match self
+                .backend
+                .send_upload(&peer.1, tag, data, self.held_as_chunk(tag))
+                .await
+            {
+                Ok(Ok(stored)) if stored == tag => synced.sent += 1,
+                Ok(res) => debug!("{:?} would not take {}: {:?}", peer.0, tag, res),
+                Err(err) => {
+                    self.metrics.failure();
+                    debug!("Failed to send {} to {:?}: {}", tag, peer.0, err);
+                }
+            }
+        }
+        if synced != Synced::default() {
+            info!(
+                "Fetched {} entries from {:?} and sent it {}",
+                synced.fetched, peer.0, synced.sent
+            );
+        }
+        Some(synced)
+    }
+
+    /// [Sync](Node::sync_with) with one of our closest peers, picked at random, returning `None` if we have no peers
+    /// or the exchange failed. This is done periodically by [`Node::run`].
+    pub async fn anti_entropy(&self) -> Option<Synced> {
+        let peers = self.closest_peers(self.id().tag, None, SYNC_CANDIDATES);
+        let peer = peers.choose(&mut *self.rng())?.clone();
+        self.sync_with(&peer).await
+    }
+
+    // Store uploaded data, returning the tag that we stored it under so that the uploader can check that we
+    // received what they sent
+    pub async fn recv_upload(
+        &self,
+        algorithm: HashAlgorithm,
+        data: Bytes,
+    ) -> Result<Tag, ProtocolError> {
+        self.recv_upload_from(None, algorithm, data).await
+    }
+
+    /// Like [`Node::recv_upload`], for an upload signed by `sender`. The data is charged to the sender if new to us,
+    /// or to anonymous uploads if the upload wasn't signed, and refused if that would take them over their quota.
+    pub async fn recv_upload_from(
+        &self,
+        sender: Option<&PublicId>,
+        algorithm: HashAlgorithm,
+        data: Bytes,
+    ) -> Result<Tag, ProtocolError> {
+        self.metrics.request(Request::Upload);
+        let _permit = self.upload_permit().await?;
+        self.metrics.uploaded(data.len());
+        let tag = Tag::digest_with(algorithm, &*data);
+        self.store_upload(sender, tag, data, false)
+    }
+
+    /// Like [`Node::recv_upload_from`], for an upload that the uploader says is stored under `tag`.
+    ///
+    /// If we already store data under that tag, the upload is accepted without looking at the data, since we have it
+    /// already. Otherwise, including when all we hold is a cached copy, data that doesn't hash to `tag` is refused with
+    /// [`ProtocolError::Mismatch`].
+    ///
+    /// If `chunk`, the data is kept only as a chunk of a tree or an erasure-coded upload, to be dropped by
+    /// [`Node::collect_garbage`] once nothing renews its lease. See [`store`] for more.
+    pub async fn recv_tagged_upload_from(
+        &self,
+        sender: Option<&PublicId>,
+        tag: Tag,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Tag, ProtocolError> {
+        self.metrics.request(Request::Upload);
+        // The data that was sent is never stored unchecked, so that nothing else can end up under `tag` if our copy is
+        // dropped in the meantime
+        let publisher = publisher_of(sender);
+        if self.with_state(|state| {
+            !state.data.is_cached(tag) && state.data.reinsert_from(tag, Some(publisher), chunk)
+        }) {
+            debug!(
+                "Skipped checking an upload of {}, which we already have",
+                tag
+            );
+            self.forget_miss(tag);
+            return Ok(tag);
+        }
+        let _permit = self.upload_permit().await?;
+        self.metrics.uploaded(data.len());
+        if !tag.is_digest_of(&*data) {
+            warn!("An upload claiming to be {} was something else", tag);
+            self.metrics.failure();
+            return Err(ProtocolError::Mismatch);
+        }
+        self.store_upload(sender, tag, data, chunk)
+    }
+
+    // Store data that has been checked to be stored under `tag`, charging it to whoever uploaded it
+    fn store_upload(
+        &self,
+        sender: Option<&PublicId>,
+        tag: Tag,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Tag, ProtocolError> {
+        let len = data.len();
+        let publisher = publisher_of(sender);
+        let saved = self.with_state(|state| {
+            // Copies of data we already have cost us nothing, unless all we had was a cached copy
+            let held = state.data.contains(tag) && !state.data.is_cached(tag);
+            if !held && !state.data.has_quota_for(publisher, len) {
+                return Err(ProtocolError::QuotaExceeded);
+            }
+            Ok(if chunk {
+                state.data.insert_chunk(tag, data, Some(publisher))
+            } else {
+                state.data.insert_from(tag, data, Some(publisher))
+            })
+        });
+        if saved.inspect_err(|_| info!("{:?} is over quota", publisher))? {
+            info!("Storing {} bytes uploaded as {}", len, tag);
+        } else {
+            debug!("Already had the {} bytes uploaded as {}", len, tag);
+        }
+        self.forget_miss(tag);
+        Ok(tag)
+    }
+
+    // Whether we hold the data stored under `tag` only as a chunk, so that whoever we pass it on to should do the same
+    fn held_as_chunk(&self, tag: Tag) -> bool {
+        self.with_state(|state| state.data.contains(tag) && !state.data.is_independent(tag))
+    }
+
+    /// Whether the data stored under `tag` can be found, with us or elsewhere. Like [`Node::do_download_with`], this is
+    /// answered from a recent lookup that came up empty unless `bypass_cache`.
+    pub async fn exists(&self, tag: Tag, bypass_cache: bool) -> Result<bool, &'static str> {
+        if !bypass_cache && self.cached_miss(tag) {
+            return Ok(false);
+        }
+        let (found, _) = self.locate_data(tag).await?;
+        if !found {
+            self.cache_miss(tag);
+        }
+        Ok(found)
+    }
+
+    /// Wait for a turn to handle an upload, which lasts until the permit is dropped, as [`Node::recv_upload`] does.
+    /// Backends that read uploads from clients themselves should do the same before they start.
+    ///
+    /// Fails with [`ProtocolError::Busy`] if no turn comes up in time. See [`Node::set_upload_limit`].
+    pub async fn upload_permit(&self) -> Result<tokio::sync::OwnedSemaphorePermit, ProtocolError> {
+        let ((permits, total), wait) =
+            self.with_state(|state| (state.upload_permits.clone(), state.upload_wait));
+        match tokio::time::timeout(wait, permits.clone().acquire_owned()).await {
+            Ok(Ok(permit)) => {
+                self.metrics
+                    .upload_began(total - permits.available_permits());
+                Ok(permit)
+            }
+            // The semaphore is never closed
+            Ok(Err(_)) => Err(ProtocolError::Internal),
+            Err(_) => {
+                info!("Turned away an upload after waiting {:?}", wait);
+                self.metrics.busy();
+                Err(ProtocolError::Busy)
+            }
+        }
+    }
+
+    /// Handle at most `permits` uploads at once, which is at least one, turning away those that wait longer than `wait`
+    /// for their turn. By default, these are [`DEFAULT_UPLOAD_PERMITS`] and [`DEFAULT_UPLOAD_WAIT`].
+    ///
+    /// Uploads already being handled carry on, but don't count towards the new limit.
+    pub fn set_upload_limit(&self, permits: usize, wait: Duration) {
+        let permits = permits.max(1);
+        self.with_state(|state| {
+            state.upload_permits = (Arc::new(tokio::sync::Semaphore::new(permits)), permits);
+            state.upload_wait = wait;
+        });
     }
 
     pub async fn locate_data(&self, tag: Tag) -> Result<(bool, (PublicId, B::Addr)), &'static str> {
+        self.locate(tag)
+            .await
+            .map(|located| (located.found, located.owner))
+    }
+
+    /// Like [`Node::locate_data`], but also reporting the route that the lookup took.
+    pub async fn locate(&self, tag: Tag) -> Result<Located<B::Addr>, &'static str> {
         if self.has_data(tag).await {
-            Ok((true, (self.id().clone(), self.self_addr.clone())))
-        } else if let Some(mut closest) = self.with_state(|state| {
-            let self_dist = self.id().tag.dist_to(tag);
-            // Find the peer with the closest tag
-            state
-                .peers
-                .values()
-                .filter(|peer| peer.id.tag.dist_to(tag) < self_dist)
-                .min_by_key(|peer| peer.id.tag.dist_to(tag))
-                .map(|peer| (peer.id.clone(), peer.addr.clone()))
-        }) {
-            loop {
-                match self.backend.send_locate(&closest.1, tag).await {
-                    Ok(Ok(has_data)) => break Ok((has_data, closest)),
-                    Ok(Err(next_closest)) => {
-                        if next_closest.0.tag.dist_to(tag) < closest.0.tag.dist_to(tag) {
-                            // Not found yet, but we have another link to follow
-                            closest = next_closest;
-                        } else {
-                            // We found a liar! Peer returned a node that was further. Assume this means that it can't
-                            // locate it.
-                            eprintln!("{:?} lied to {:?} and returned a node that was *further* from the target!", closest.0, self.id());
-                            break Ok((false, (self.id().clone(), self.self_addr.clone())));
+            Ok(Located {
+                found: true,
+                owner: (self.id(), self.addr()),
+                path: Vec::new(),
+            })
+        } else {
+            self.locate_remote(tag).await
+        }
+    }
+
+    // Like `locate`, but without considering any copy that we hold ourselves
+    #[tracing::instrument(level = "debug", skip(self))]
+    async fn locate_remote(&self, tag: Tag) -> Result<Located<B::Addr>, &'static str> {
+        let self_id = self.id();
+        // Everybody we know of that is closer to the tag than we are, by their distance to it. Nobody expects a node
+        // that can't be reached to hold anything, so it asks everybody.
+        let max_dist = B::REACHABLE.then(|| self_id.tag.dist_to(tag));
+        let mut candidates = self
+            .closest_peers(tag, max_dist, MAX_LOCATE_PEERS)
+            .into_iter()
+            .map(|peer| (peer.0.tag.dist_to(tag), peer))
+            .collect::<BTreeMap<_, _>>();
+        if candidates.is_empty() {
+            return Ok(Located {
+                found: false,
+                owner: (self_id, self.addr()),
+                path: Vec::new(),
+            });
+        }
+        let mut queried = HashSet::new();
+        // Those that a dead end pointed us to instead, by their distance to the tag
+        let mut sideways = HashSet::new();
+        // The closest peer that answered us, and whether any failed to
+        let mut responded = None;
+        let mut failed = false;
+        let mut path = Vec::new();
+        // Ask the closest candidate that we haven't yet asked, falling back to the next closest if it doesn't respond
+        let res = loop {
+            let Some((dist, closest)) = candidates
+                .iter()
+                .find(|(dist, _)| !queried.contains(*dist))
+                .map(|(dist, peer)| (*dist, peer.clone()))
+                .filter(|_| path.len() < MAX_LOCATE_HOPS)
+            else {
+                // Everybody closer was unreachable, or their suggestions were all bogus
+                break match responded {
+                    Some((_, closest)) if !failed => Ok((false, closest)),
+                    _ => Err("peer did not respond"),
+                };
+            };
+            queried.insert(dist);
+            path.push(closest.0.clone());
+            // The peers that were suggested to us, and whether they were suggested as being closer
+            let reply = match self.backend.send_locate(&closest.1, tag).await {
+                Ok(LocateReply::Found) => break Ok((true, closest)),
+                Ok(LocateReply::Closer(peers)) => Ok((peers, true)),
+                // Dead ends that we were pointed to sideways are where the lookup ends
+                Ok(LocateReply::NotCloser(_)) if sideways.contains(&dist) => {
+                    Ok((Vec::new(), false))
+                }
+                Ok(LocateReply::NotCloser(peers)) => {
+                    // A dead end is as far as the lookup gets, but for the peers it suggests in its place
+                    candidates.retain(|dist, _| queried.contains(dist));
+                    failed = false;
+                    Ok((peers, false))
+                }
+                Err(err) => Err(err),
+            };
+            match reply {
+                Ok((suggested, closer)) => {
+                    if responded.as_ref().is_none_or(|(best, _)| dist < *best) {
+                        responded = Some((dist, closest.clone()));
+                    }
+                    for peer in suggested.into_iter().take(MAX_LOCATE_PEERS) {
+                        let peer_dist = peer.0.tag.dist_to(tag);
+                        if closer && peer_dist >= dist {
+                            // We found a liar! Peer returned a node that was further. Ignore the suggestion.
+                            warn!("{:?} lied to {:?} and returned a node that was *further* from the target!", closest.0, self_id);
+                        } else if peer.0 != self_id && !candidates.contains_key(&peer_dist) {
+                            if !closer {
+                                sideways.insert(peer_dist);
+                            }
+                            candidates.insert(peer_dist, peer);
                         }
                     }
-                    Err(_err) => break Err("peer did not respond"),
+                }
+                // Suggestions from a dead end are only a chance at finding the data, so those that fail don't count
+                Err(_err) if sideways.contains(&dist) => self.metrics.failure(),
+                Err(_err) => {
+                    self.metrics.failure();
+                    failed = true;
                 }
             }
-        } else {
-            Ok((false, (self.id().clone(), self.self_addr.clone())))
-        }
+        };
+        debug!("Lookup took {} hop(s)", path.len());
+        self.metrics.lookup(path.len() as u64);
+        res.map(|(found, owner)| Located { found, owner, path })
     }
 
-    pub async fn recv_locate(&self, tag: Tag) -> Result<bool, (PublicId, B::Addr)> {
+    // Up to `count` of our peers that are closer than `max_dist` (if given) to `tag`, closest first
+    fn closest_peers(
+        &self,
+        tag: Tag,
+        max_dist: Option<Tag>,
+        count: usize,
+    ) -> Vec<(PublicId, B::Addr)> {
+        self.with_routing(|routing| {
+            routing
+                .closest(tag, count)
+                .into_iter()
+                // Peers come closest first, so any within `max_dist` come before all of those that aren't
+                .take_while(|peer| {
+                    max_dist.is_none_or(|max_dist| peer.id.tag.dist_to(tag) < max_dist)
+                })
+                .map(|peer| (peer.id.clone(), peer.addr.clone()))
+                .collect()
+        })
+    }
+
+    /// Answer a request for where the data stored under `tag` is: with us, or with one of the peers we suggest.
+    pub async fn recv_locate(&self, tag: Tag) -> LocateReply<B::Addr> {
+        self.metrics.request(Request::Locate);
         if self.has_data(tag).await {
             // If we have the data, return it
-            Ok(true)
+            LocateReply::Found
         } else {
             // If we don't have the data, attempt to find someone closer to it
-            let self_dist = self.id().tag.dist_to(tag);
-            self.with_state(|state| {
-                state
-                    .peers
-                    .values()
-                    .filter(|peer| peer.id.tag.dist_to(tag) < self_dist)
-                    .min_by_key(|peer| peer.id.tag.dist_to(tag))
-                    .map(|peer| (peer.id.clone(), peer.addr.clone()))
+            let closer =
+                self.closest_peers(tag, Some(self.id().tag.dist_to(tag)), MAX_LOCATE_PEERS);
+            if closer.is_empty() {
+                // Failing that, one of the nodes about as close as us might have it
+                LocateReply::NotCloser(self.closest_peers(tag, None, MAX_SIDEWAYS_PEERS))
+            } else {
+                self.count_request(tag);
+                LocateReply::Closer(closer)
+            }
+        }
+    }
+
+    /// Upload data to the nodes closest to it, returning the tag it's stored under. See [`Node::place`] for how many
+    /// copies are placed.
+    pub async fn do_upload(&self, data: Bytes) -> Result<Tag, UploadError> {
+        self.do_upload_with(HashAlgorithm::default(), data).await
+    }
+
+    /// Like [`Node::do_upload`], but storing the data under a tag made with `algorithm`.
+    pub async fn do_upload_with(
+        &self,
+        algorithm: HashAlgorithm,
+        data: Bytes,
+    ) -> Result<Tag, UploadError> {
+        self.place(algorithm, data)
+            .await
+            .map(|placement| placement.tag)
+    }
+
+    /// Like [`Node::do_upload_with`], also reporting how many copies of the data were placed.
+    ///
+    /// A copy is sent to each of the [`DEFAULT_REPLICAS`] nodes closest to the data at once, or as many as were set by
+    /// [`Node::set_replication`], and the upload succeeds if enough of them take it. We keep a copy ourselves if we're
+    /// one of those nodes. If there aren't that many nodes to be found, the quorum shrinks to match.
+    pub async fn place(
+        &self,
+        algorithm: HashAlgorithm,
+        data: Bytes,
+    ) -> Result<Placement, UploadError> {
+        self.place_as(algorithm, data, false).await
+    }
+
+    // Like `place`, with the data kept only as a chunk of a tree or an erasure-coded upload if `chunk`
+    async fn place_as(
+        &self,
+        algorithm: HashAlgorithm,
+        data: Bytes,
+        chunk: bool,
+    ) -> Result<Placement, UploadError> {
+        let tag = Tag::digest_with(algorithm, &*data);
+        self.metrics.uploaded(data.len());
+        let closest = match self.locate_data(tag).await {
+            // Already uploaded
+            Ok((true, _)) => {
+                self.forget_miss(tag);
+                return Ok(Placement { tag, copies: 1 });
+            }
+            Ok((false, closest)) => closest,
+            Err(err) => return Err(UploadError::Locate(err)),
+        };
+        let (replicas, quorum) = self.with_state(|state| (state.replicas, state.upload_quorum));
+        let self_id = self.id();
+        let mut targets = self.find_node(tag, replicas).await;
+        if B::REACHABLE {
+            targets.push((self_id.clone(), self.addr()));
+        }
+        targets.retain(|target| target.0 != closest.0);
+        targets.sort_by_key(|target| target.0.tag.dist_to(tag));
+        // Lookups for the data lead to the node that ours did, so it gets a copy even if the search found closer ones
+        targets.insert(0, closest);
+        targets.truncate(replicas);
+        let quorum = quorum.min(targets.len());
+
+        // Those that fail don't hold up the others
+        let placed = futures::future::join_all(targets.iter().map(|target| async {
+            if target.0 == self_id {
+                let saved = self.with_state(|state| {
+                    if chunk {
+                        state.data.insert_chunk(tag, data.clone(), None)
+                    } else {
+                        state.data.insert(tag, data.clone())
+                    }
+                });
+                if !saved {
+                    debug!("Already had {}", tag);
+                }
+                Ok(())
+            } else {
+                self.place_copy(tag, data.clone(), target, chunk).await
+            }
+        }))
+        .await;
+        for (target, _) in targets
+            .iter()
+            .zip(&placed)
+            .filter(|(target, placed)| target.0 != self_id && placed.is_ok())
+        {
+            self.prepare_audits(tag, &data, target.clone());
+        }
+        let copies = placed.iter().filter(|placed| placed.is_ok()).count();
+        if copies > 0 {
+            self.forget_miss(tag);
+        }
+        if copies >= quorum {
+            Ok(Placement { tag, copies })
+        } else if copies > 0 {
+            Err(UploadError::Quorum { copies, quorum })
+        } else {
+            // Nobody took a copy, so the closest node's reason stands for the rest
+            Err(placed
+                .into_iter()
+                .find_map(Result::err)
+                .unwrap_or(UploadError::Unreachable))
+        }
+    }
+
+    // Send a copy of the data stored under `tag` to a node, checking that it stored it under the same tag
+    async fn place_copy(
+        &self,
+        tag: Tag,
+        data: Bytes,
+        target: &(PublicId, B::Addr),
+        chunk: bool,
+    ) -> Result<(), UploadError> {
+        match self.backend.send_upload(&target.1, tag, data, chunk).await {
+            Ok(Ok(stored)) if stored == tag => {
+                info!("Uploaded {} to {:?}", tag, target.0);
+                Ok(())
+            }
+            Ok(Ok(stored)) => {
+                warn!(
+                    "{:?} stored an upload as {} rather than {}",
+                    target.0, stored, tag
+                );
+                self.metrics.failure();
+                Err(UploadError::Integrity {
+                    expected: tag,
+                    stored,
+                })
+            }
+            Ok(Err(reason)) => {
+                info!("{:?} refused an upload: {}", target.0, reason);
+                Err(UploadError::Rejected {
+                    peer: target.0.clone(),
+                    reason,
+                })
+            }
+            Err(_err) => {
+                self.metrics.failure();
+                Err(UploadError::Unreachable)
+            }
+        }
+    }
+
+    /// Set how many of the nodes closest to some data [`Node::do_upload`] places a copy with, and how many of them
+    /// must take it, which is at least one and at most `replicas`. By default, these are [`DEFAULT_REPLICAS`] and
+    /// [`DEFAULT_UPLOAD_QUORUM`].
+    pub fn set_replication(&self, replicas: usize, quorum: usize) {
+        let replicas = replicas.max(1);
+        self.with_state(|state| {
+            state.replicas = replicas;
+            state.upload_quorum = quorum.clamp(1, replicas);
+        });
+    }
+
+    /// Download the data stored under `tag`, or `None` if nobody has it. A lookup that comes up empty is remembered for
+    /// a while (see [`Node::set_miss_ttl`]), so that asking again straight away doesn't ask the network again.
+    pub async fn do_download(&self, tag: Tag) -> Result<Option<Bytes>, DownloadError> {
+        self.do_download_with(tag, false).await
+    }
+
+    /// Like [`Node::do_download`], but if `bypass_cache`, looking the data up even if a recent lookup came up empty.
+    pub async fn do_download_with(
+        &self,
+        tag: Tag,
+        bypass_cache: bool,
+    ) -> Result<Option<Bytes>, DownloadError> {
+        if !bypass_cache && self.cached_miss(tag) {
+            debug!("Nobody had {} when we last looked", tag);
+            return Ok(None);
+        }
+        let located = self.locate_data(tag).await.map_err(DownloadError::Locate)?;
+        let data = match located {
+            (true, closest) if closest.0 == self.id() => Ok(self.load_data(tag).await),
+            (true, closest) => self.fetch_from_holders(tag, closest).await,
+            (false, _) => {
+                self.cache_miss(tag);
+                Ok(None)
+            }
+        }?;
+        if let Some(data) = &data {
+            self.metrics.downloaded(data.len());
+        }
+        Ok(data)
+    }
+
+    /// Set how many nodes [`Node::do_download`] fetches data from at once, which is at least one. By default, this is
+    /// [`DEFAULT_DOWNLOAD_FANOUT`].
+    pub fn set_download_fanout(&self, fanout: usize) {
+        self.with_state(|state| state.download_fanout = fanout.max(1));
+    }
+
+    // Fetch data from the node that locating it led to, or whichever of the other nodes it was placed with answers
+    // first with a copy that matches its tag. Rather than ask several at once straight away, we give each a moment to
+    // answer before asking the next, so that data from a responsive node is only fetched once.
+    async fn fetch_from_holders(
+        &self,
+        tag: Tag,
+        owner: (PublicId, B::Addr),
+    ) -> Result<Option<Bytes>, DownloadError> {
+        let self_id = self.id();
+        let (fanout, replicas) = self.with_state(|state| (state.download_fanout, state.replicas));
+        let mut candidates = self
+            .closest_peers(tag, None, replicas)
+            .into_iter()
+            .filter(|peer| peer.0 != owner.0 && peer.0 != self_id)
+            .collect::<VecDeque<_>>();
+        candidates.push_front(owner.clone());
+
+        let fetch = |(id, addr): (PublicId, B::Addr)| async move {
+            let res = self.backend.send_download(&addr, tag).await;
+            (id, res)
+        };
+        let mut fetches = futures::stream::FuturesUnordered::new();
+        fetches.extend(candidates.pop_front().map(fetch));
+        let mut hedge = Box::pin(tokio::time::sleep(DOWNLOAD_HEDGE_DELAY));
+        // The owner's reason for failing stands above the others', since it's the one that claimed to have the data
+        let (mut owner_err, mut err) = (None, None);
+        while !fetches.is_empty() {
+            let (id, res) = select! {
+                Some(fetched) = fetches.next() => fetched,
+                _ = &mut hedge, if fetches.len() < fanout && !candidates.is_empty() => {
+                    fetches.extend(candidates.pop_front().map(fetch));
+                    hedge = Box::pin(tokio::time::sleep(DOWNLOAD_HEDGE_DELAY));
+                    continue;
+                }
+            };
+            let failed = match res {
+                // Dropping the other fetches cancels them
+                Ok(Ok(Some(data))) if tag.is_digest_of(&*data) => return Ok(Some(data)),
+                Ok(Ok(Some(_))) => {
+                    warn!("Data integrity check from {:?} failed", id);
+                    self.penalise(&id).await;
+                    Some(DownloadError::Integrity)
+                }
+                Ok(Ok(None)) => (id == owner.0).then_some(DownloadError::Missing),
+                Ok(Err(reason)) => {
+                    info!("{:?} refused a download: {}", id, reason);
+                    Some(DownloadError::Rejected {
+                        peer: id.clone(),
+                        reason,
+                    })
+                }
+                Err(_err) => {
+                    self.metrics.failure();
+                    Some(DownloadError::Unreachable)
+                }
+            };
+            if id == owner.0 {
+                owner_err = failed;
+            } else if let Some(failed) = failed {
+                err.get_or_insert(failed);
+            }
+            // Whoever failed makes way for the next candidate straight away
+            fetches.extend(candidates.pop_front().map(fetch));
+        }
+        match owner_err.or(err) {
+            Some(err) => Err(err),
+            None => Ok(None),
+        }
+    }
+
+    /// Upload data that only `recipient` can read, returning the tag under which the encrypted envelope is stored.
+    ///
+    /// See [`envelope`] for the format.
+    pub async fn upload_for(&self, recipient: &PublicId, data: &[u8]) -> Result<Tag, UploadError> {
+        self.do_upload(envelope::seal(recipient, data).into()).await
+    }
+
+    /// Download and decrypt data uploaded for us with [`Node::upload_for`].
+    ///
+    /// For a while after [`Node::rotate_identity`], data uploaded for our previous identity can be decrypted too.
+    pub async fn download_encrypted(&self, tag: Tag) -> Result<Option<Box<[u8]>>, DownloadError> {
+        let Some(data) = self.do_download(tag).await? else {
+            return Ok(None);
+        };
+        envelope::open(&self.identity(), &data)
+            .or_else(|err| match self.retired_identity() {
+                Some(retired) => envelope::open(&retired, &data),
+                None => Err(err),
             })
-            .map(Err)
-            .unwrap_or(Ok(false))
+            .map(Some)
+            .map_err(DownloadError::Decrypt)
+    }
+
+    /// Upload data as a [tree](tree) of chunks, returning the tag of its root.
+    pub async fn upload_tree(&self, data: Bytes) -> Result<Tag, UploadError> {
+        self.upload_tree_with(data, tree::Layout::default()).await
+    }
+
+    /// Like [`Node::upload_tree`], but splitting the data up according to `layout`.
+    pub async fn upload_tree_with(
+        &self,
+        data: Bytes,
+        layout: tree::Layout,
+    ) -> Result<Tag, UploadError> {
+        let (root, blobs) = tree::build(&data, layout);
+        debug!("Uploading {} as a tree of {} blobs", root, blobs.len());
+        for (tag, blob) in blobs {
+            // Everything but the root is only kept for as long as something refers to it
+            let stored = self
+                .place_as(layout.algorithm, blob, tag != root)
+                .await?
+                .tag;
+            debug_assert_eq!(stored, tag);
         }
+        Ok(root)
     }
 
-    pub async fn do_upload(&self, data: Box<[u8]>) -> Result<Tag, &'static str> {
-        let tag = Tag::digest(&*data);
-        match self.locate_data(tag).await {
-            Ok((true, _)) => Ok(tag), // Already uploaded
-            // We're the closest node
-            Ok((false, closest)) if closest.0.tag == self.id().tag => {
-                self.save_data(tag, data).await;
-                Ok(tag)
+    /// Download data uploaded with [`Node::upload_tree`].
+    ///
+    /// Each blob is checked against the tag that its parent gives for it as soon as it arrives, so a bad copy is
+    /// rejected before anything beneath it is fetched, and is fetched again from another of the closest nodes to it.
+    pub async fn download_tree(&self, root: Tag) -> Result<Option<Bytes>, DownloadError> {
+        let Some(blob) = self.fetch_verified(root).await? else {
+            return Ok(None);
+        };
+        let root = tree::TreeNode::decode(&blob).map_err(DownloadError::Tree)?;
+        let mut data = Vec::new();
+        // Blobs still to fetch along with their expected depth, the next one last
+        let mut pending = root
+            .children
+            .iter()
+            .rev()
+            .map(|child| (*child, root.depth - 1))
+            .collect::<Vec<_>>();
+        while let Some((tag, depth)) = pending.pop() {
+            let blob = self
+                .fetch_verified(tag)
+                .await?
+                .ok_or(DownloadError::Missing)?;
+            if depth == 0 {
+                data.extend_from_slice(&blob);
+                // Don't let a lying root make us fetch without end
+                if data.len() as u64 > root.len {
+                    return Err(DownloadError::Tree(tree::TreeError::WrongLength));
+                }
+                continue;
+            }
+            let node = tree::TreeNode::decode(&blob).map_err(DownloadError::Tree)?;
+            if node.depth != depth {
+                return Err(DownloadError::Tree(tree::TreeError::WrongDepth {
+                    expected: depth,
+                    found: node.depth,
+                }));
             }
-            // The closest node is another node
-            Ok((false, closest)) => match self.backend.send_upload(&closest.1, data).await {
-                Ok(resp) => resp.map(|()| tag).map_err(|()| "peer did not respond"),
-                Err(_err) => Err("peer did not respond"),
-            },
-            Err(err) => Err(err),
+            pending.extend(node.children.iter().rev().map(|child| (*child, depth - 1)));
         }
+        if data.len() as u64 != root.len {
+            return Err(DownloadError::Tree(tree::TreeError::WrongLength));
+        }
+        Ok(Some(data.into()))
     }
 
-    pub async fn do_download(&self, tag: Tag) -> Result<Option<Box<[u8]>>, &'static str> {
-        match self.locate_data(tag).await? {
-            (true, closest) if closest.0 == *self.id() => Ok(self.load_data(tag).await),
-            (true, closest) => match self.backend.send_download(&closest.1, tag).await {
-                Ok(Some(data)) if Tag::digest(&*data) == tag => Ok(Some(data)),
-                Ok(Some(_)) => {
-                    eprintln!("data integrity check from {:?} failed", closest.0);
-                    Err("integrity check failed")
+    // Fetch a blob of a tree, falling back to the other nodes closest to it if the one that locating it leads to
+    // doesn't give us a copy that matches its tag
+    async fn fetch_verified(&self, tag: Tag) -> Result<Option<Bytes>, DownloadError> {
+        let mut err = match self.do_download(tag).await {
+            Ok(Some(data)) if tag.is_digest_of(&*data) => return Ok(Some(data)),
+            Ok(_) => None,
+            Err(err) => Some(err),
+        };
+        for (id, addr) in self.find_node(tag, MAX_TREE_REPLICAS).await {
+            match self.backend.send_download(&addr, tag).await {
+                Ok(Ok(Some(data))) if tag.is_digest_of(&*data) => {
+                    debug!("Fetched {} from {:?} instead", tag, id);
+                    self.metrics.downloaded(data.len());
+                    return Ok(Some(data));
+                }
+                Ok(Ok(Some(_))) => {
+                    warn!("Data integrity check from {:?} failed", id);
+                    err = Some(DownloadError::Integrity);
+                }
+                Ok(Ok(None)) => {}
+                Ok(Err(reason)) => {
+                    err.get_or_insert(DownloadError::Rejected { peer: id, reason });
+                }
+                Err(_err) => {
+                    self.metrics.failure();
+                    err.get_or_insert(DownloadError::Unreachable);
                 }
-                Ok(None) => Err("peer reported data but did not provide any"),
-                Err(_err) => Err("peer did not respond"),
-            },
-            (false, _) => Ok(None),
+            }
         }
+        err.map_or(Ok(None), Err)
     }
 
-    pub async fn run(self: Arc<Self>) -> Result<(), Error<B::Error>> {
-        let mut host = tokio::task::spawn(B::host(self.clone()));
-
-        eprintln!("Starting node `{:?}`", self.self_id);
-
-        // Automatically discover all initial peers
-        for mut peer_addr in self.initial_peers.iter().cloned() {
-            loop {
-                match self.discover_peer(None, peer_addr.clone()).await {
-                    Ok(()) => break,
-                    Err(None) => {
-                        eprintln!(
-                            "{:?} failed to peer with initial peer {:?}!",
-                            self.id(),
-                            peer_addr
-                        );
-                        break;
+    /// Upload data split into [erasure-coded](erasure) shards, returning the tag of the manifest describing them.
+    ///
+    /// Each shard is placed on a different node where possible: the closest to it that doesn't already hold another.
+    pub async fn upload_erasure(
+        &self,
+        data: Bytes,
+        scheme: erasure::Scheme,
+    ) -> Result<Tag, UploadError> {
+        let (manifest, shards) = erasure::encode(&data, scheme);
+        let mut used = HashSet::new();
+        for (tag, shard) in manifest.shards.iter().zip(shards) {
+            let holders = self.find_node(*tag, MAX_SHARD_HOLDERS).await;
+            let Some((id, addr)) = holders
+                .iter()
+                .find(|(id, _)| !used.contains(id))
+                .or_else(|| {
+                    warn!(
+                        "Too few nodes to give each shard of {} its own",
+                        manifest.content
+                    );
+                    holders.first()
+                })
+                .cloned()
+            else {
+                return Err(UploadError::Locate("no nodes to hold shards"));
+            };
+            match self.backend.send_upload(&addr, *tag, shard, true).await {
+                Ok(Ok(stored)) if stored == *tag => debug!("Placed shard {} on {:?}", tag, id),
+                Ok(Ok(stored)) => {
+                    self.metrics.failure();
+                    return Err(UploadError::Integrity {
+                        expected: *tag,
+                        stored,
+                    });
+                }
+                Ok(Err(reason)) => return Err(UploadError::Rejected { peer: id, reason }),
+                Err(_err) => {
+                    self.metrics.failure();
+                    return Err(UploadError::Unreachable);
+                }
+            }
+            used.insert(id);
+        }
+        self.do_upload_with(scheme.algorithm, manifest.encode())
+            .await
+    }
+
+    /// Download data uploaded with [`Node::upload_erasure`], reconstructing it from whichever shards arrive first.
+    pub async fn download_erasure(&self, root: Tag) -> Result<Option<Bytes>, DownloadError> {
+        let Some(manifest) = self.fetch_verified(root).await? else {
+            return Ok(None);
+        };
+        let manifest = erasure::Manifest::decode(&manifest).map_err(DownloadError::Erasure)?;
+        let needed = manifest.scheme.data_shards;
+        let mut fetches = futures::stream::iter(manifest.shards.iter().enumerate())
+            .map(|(i, tag)| async move { (i, self.fetch_shard(*tag).await) })
+            .buffer_unordered(SHARD_CONCURRENCY);
+        let mut shards = vec![None; manifest.shards.len()];
+        let mut found = 0;
+        while let Some((i, shard)) = fetches.next().await {
+            if let Some(shard) = shard {
+                shards[i] = Some(shard);
+                found += 1;
+                if found == needed {
+                    break;
+                }
+            }
+        }
+        drop(fetches);
+        let data = erasure::reconstruct(&manifest, shards).map_err(DownloadError::Erasure)?;
+        self.metrics.downloaded(data.len());
+        Ok(Some(data))
+    }
+
+    // Look for a shard on the nodes closest to it, since it may have been placed on any of them
+    async fn fetch_shard(&self, tag: Tag) -> Option<Bytes> {
+        if let Some(shard) = self.load_data(tag).await {
+            return Some(shard);
+        }
+        for (id, addr) in self.find_node(tag, MAX_SHARD_HOLDERS).await {
+            match self.backend.send_download(&addr, tag).await {
+                Ok(Ok(Some(shard))) if tag.is_digest_of(&*shard) => return Some(shard),
+                Ok(Ok(Some(_))) => warn!("Data integrity check from {:?} failed", id),
+                Ok(_) => {}
+                Err(_err) => self.metrics.failure(),
+            }
+        }
+        None
+    }
+
+    // Our previous identity, if we rotated away from it within the grace period
+    fn retired_identity(&self) -> Option<Arc<PrivateId>> {
+        self.with_state(|state| {
+            state
+                .retired
+                .as_ref()
+                .filter(|retired| retired.expires > tokio::time::Instant::now())
+                .map(|retired| retired.id.clone())
+        })
+    }
+
+    /// Replace this node's identity with `new`, keeping its peers and the data it stores.
+    ///
+    /// Each peer is sent an endorsement of `new` signed by the old identity, and is then greeted again under the new
+    /// one. Data that the new identity is no longer the closest node to is handed on to whichever node now is. For
+    /// [`ROTATION_GRACE`] afterwards, the node keeps answering for its old identity.
+    pub async fn rotate_identity(&self, new: PrivateId) {
+        let new = Arc::new(new);
+        let old = std::mem::replace(
+            &mut *self.self_id.write().unwrap_or_else(PoisonError::into_inner),
+            new.clone(),
+        );
+        info!("{:?} is rotating its identity to {:?}", old, new);
+        let endorsement = Signed::new(&old, now_millis(), rand::random(), new.pub_id.clone()).await;
+
+        self.with_state(|state| {
+            state.retired = Some(Retired {
+                id: old.clone(),
+                endorsement,
+                expires: tokio::time::Instant::now() + ROTATION_GRACE,
+            })
+        });
+        // Our peers are bucketed by their distance from our identity, which has changed. Those that no longer fit are
+        // dropped, but everybody is greeted again below anyway.
+        let peers = self.with_routing(|routing| {
+            let peers = routing
+                .iter()
+                .map(|peer| (peer.id.clone(), peer.addr.clone()))
+                .collect::<Vec<_>>();
+            routing.rekey(new.pub_id.tag);
+            peers
+        });
+        self.peers_changed();
+
+        for (id, addr) in peers {
+            // Each peer gets a fresh endorsement, since greeting those before it may outlast the skew that peers allow for
+            let endorsement =
+                Signed::new(&old, now_millis(), rand::random(), new.pub_id.clone()).await;
+            if let Err(err) = self.backend.send_rotate(&addr, endorsement).await {
+                self.metrics.failure();
+                error!("Failed to tell {:?} about our new identity: {}", id, err);
+            }
+            self.remove_peer(&id).await;
+            if self.discover_peer(Some(&id), addr.clone()).await.is_err() {
+                self.with_state(|state| {
+                    if state.lost_peers.len() >= MAX_LOST_PEERS {
+                        state.lost_peers.pop_front();
                     }
-                    Err(Some(alt_addr)) => {
-                        eprintln!("{:?} attempted to connect to initial peer, but was rejected. Peer suggested {:?} instead.", self.id(), alt_addr);
-                        peer_addr = alt_addr;
+                    state.lost_peers.push_back((id, addr));
+                });
+            }
+        }
+
+        // Hand on the data that somebody else is now closer to
+        let tags = self.with_state(|state| state.data.tags().collect::<Vec<_>>());
+        for tag in tags {
+            let handed_on = match self.locate_remote(tag).await {
+                // Somebody closer already has a copy
+                Ok(Located { found: true, .. }) => true,
+                Ok(Located { owner, .. }) if owner.0 == new.pub_id => false,
+                Ok(Located { owner: closest, .. }) => match self.load_data(tag).await {
+                    Some(data) => {
+                        matches!(
+                            self.backend
+                                .send_upload(&closest.1, tag, data, self.held_as_chunk(tag))
+                                .await,
+                            Ok(Ok(stored)) if stored == tag
+                        )
+                    }
+                    None => false,
+                },
+                Err(_) => false,
+            };
+            if handed_on {
+                self.with_state(|state| {
+                    if !state.data.is_pinned(tag) {
+                        state.data.remove(tag);
                     }
+                });
+            }
+        }
+    }
+
+    // Queue up the data that a newly added peer is closer to than we are, since lookups will now go to it instead
+    fn queue_handoffs(&self, id: &PublicId, addr: &B::Addr) {
+        let our_tag = self.id().tag;
+        self.with_state(|state| {
+            let tags = state
+                .data
+                .tags()
+                .filter(|tag| id.tag.dist_to(*tag) < our_tag.dist_to(*tag))
+                .collect::<Vec<_>>();
+            if !tags.is_empty() {
+                debug!("Queued {} blob(s) to hand on to {:?}", tags.len(), id);
+            }
+            state.handoffs.extend(
+                tags.into_iter()
+                    .map(|tag| (tag, (id.clone(), addr.clone()))),
+            );
+        });
+    }
+
+    // Hand on a few of the queued blobs, dropping our copy of each once its new owner has stored it unless asked to
+    // keep it
+    async fn hand_off(&self) {
+        for _ in 0..MAX_HANDOFFS_PER_TICK {
+            let Some((tag, (id, addr))) = self.with_state(|state| state.handoffs.pop_front())
+            else {
+                break;
+            };
+            // The peer may have gone, or our identity changed, since the blob was queued
+            if !self.with_routing(|routing| routing.contains(&id))
+                || id.tag.dist_to(tag) >= self.id().tag.dist_to(tag)
+            {
+                continue;
+            }
+            let Some(data) = self.load_data(tag).await else {
+                continue;
+            };
+            match self
+                .backend
+                .send_upload(&addr, tag, data, self.held_as_chunk(tag))
+                .await
+            {
+                Ok(Ok(stored)) if stored == tag => {
+                    info!("Handed {} on to {:?}", tag, id);
+                    self.with_state(|state| {
+                        if !state.keep_handoffs && !state.data.is_pinned(tag) {
+                            state.data.remove(tag);
+                        }
+                    });
+                }
+                Ok(res) => warn!("{:?} would not take {} off our hands: {:?}", id, tag, res),
+                Err(err) => {
+                    self.metrics.failure();
+                    error!("Failed to hand {} on to {:?}: {}", tag, id, err);
                 }
             }
         }
+    }
+
+    /// Pass each entry we hold on to the closest peer to it that will take it, unless one of them already has it.
+    ///
+    /// Entries that haven't been passed on once `budget` has passed are abandoned. This is done by [`Node::run`] as
+    /// the node shuts down, so that its data outlives it.
+    pub async fn rehome_data(&self, budget: Duration) -> Rehomed {
+        let tags = self.with_state(|state| state.data.tags().collect::<Vec<_>>());
+        let deadline = tokio::time::Instant::now() + budget;
+        let mut report = Rehomed::default();
+        for (i, tag) in tags.iter().enumerate() {
+            match tokio::time::timeout_at(deadline, self.rehome(*tag)).await {
+                Ok(true) => report.rehomed += 1,
+                Ok(false) => report.abandoned += 1,
+                Err(_) => {
+                    warn!("Ran out of time to pass on our data");
+                    report.abandoned += tags.len() - i;
+                    break;
+                }
+            }
+        }
+        report
+    }
+
+    // Make sure that one of the closest peers to a blob holds it, returning whether one does
+    async fn rehome(&self, tag: Tag) -> bool {
+        let Some(data) = self.load_data(tag).await else {
+            // Handed on in the meantime
+            return true;
+        };
+        for (id, addr) in self.closest_peers(tag, None, REHOME_CANDIDATES) {
+            match self.backend.send_locate(&addr, tag).await {
+                Ok(LocateReply::Found) => {
+                    debug!("{:?} already holds {}", id, tag);
+                    return true;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    self.metrics.failure();
+                    debug!("Failed to ask {:?} about {}: {}", id, tag, err);
+                    continue;
+                }
+            }
+            match self
+                .backend
+                .send_upload(&addr, tag, data.clone(), self.held_as_chunk(tag))
+                .await
+            {
+                Ok(Ok(stored)) if stored == tag => {
+                    debug!("Passed {} on to {:?}", tag, id);
+                    return true;
+                }
+                Ok(res) => warn!("{:?} would not take {}: {:?}", id, tag, res),
+                Err(err) => {
+                    self.metrics.failure();
+                    debug!("Failed to pass {} on to {:?}: {}", tag, id, err);
+                }
+            }
+        }
+        false
+    }
+
+    /// Move to a new address, as after our public IP address changes, telling each of our peers so that they don't go
+    /// on trying the old one. Returns the number of peers that took the new address.
+    pub async fn set_addr(&self, addr: B::Addr) -> usize {
+        let addr = B::canonicalise(addr);
+        let old = std::mem::replace(
+            &mut *self
+                .self_addr
+                .write()
+                .unwrap_or_else(PoisonError::into_inner),
+            addr.clone(),
+        );
+        if old == addr {
+            return 0;
+        }
+        info!("{:?} is moving from {:?} to {:?}", self.id(), old, addr);
+        self.backend.rebind(&addr);
+        // The record of our old address no longer matches, so a fresh one is issued
+        let record = self.addr_record().await;
+        let peers = self.with_routing(|routing| {
+            routing
+                .iter()
+                .map(|peer| (peer.id.clone(), peer.addr.clone()))
+                .collect::<Vec<_>>()
+        });
+        futures::stream::iter(peers)
+            .map(|(id, peer_addr)| {
+                let record = record.clone();
+                async move {
+                    match self.backend.send_readdress(&peer_addr, record).await {
+                        Ok(updated) => updated,
+                        Err(err) => {
+                            self.metrics.failure();
+                            error!("Failed to tell {:?} about our new address: {}", id, err);
+                            false
+                        }
+                    }
+                }
+            })
+            .buffer_unordered(READDRESS_CONCURRENCY)
+            .filter(|updated| std::future::ready(*updated))
+            .count()
+            .await
+    }
+
+    /// Handle a peer's news that it has moved to the address in `record`, which we take once the peer answers there.
+    ///
+    /// `sender` is the identity that the message carrying the record was signed by, if the backend checks. Either way,
+    /// the record must be signed by the peer itself, and be newer than the one we have.
+    pub async fn recv_readdress(
+        &self,
+        sender: Option<&PublicId>,
+        record: SignedAddr<B::Addr>,
+    ) -> bool {
+        self.metrics.request(Request::Readdress);
+        if record.verify_record().is_err() || sender.is_some_and(|sender| *sender != record.sender)
+        {
+            return false;
+        }
+        let id = record.sender.clone();
+        let addr = B::canonicalise(record.body.addr.clone());
+        let newer = self.with_routing(|routing| {
+            routing
+                .get(&id)
+                .is_some_and(|peer| peer.record.timestamp < record.timestamp)
+        });
+        if !newer {
+            return false;
+        }
+        // Check that the peer really is there, lest we lose track of it
+        let Ok((ping, pong)) = self.backend.send_ping(&addr).await else {
+            debug!("{:?} moved to {:?}, but did not answer there", id, addr);
+            return false;
+        };
+        if pong.record.sender != id || pong.record.verify_record().is_err() {
+            warn!(
+                "{:?} moved to {:?}, but somebody else answered there",
+                id, addr
+            );
+            return false;
+        }
+        let clock_offset = self.clock_offset(&id, &pong);
+        let updated = self.with_routing(|routing| match routing.get_mut(&id) {
+            Some(peer) => {
+                peer.addr = addr.clone();
+                peer.record = pong.record;
+                peer.ping = ping;
+                peer.clock_offset = clock_offset;
+                true
+            }
+            None => false,
+        });
+        if updated {
+            info!("Peer {:?} moved to {:?}", id, addr);
+        }
+        updated
+    }
+
+    /// Handle a peer announcing that it has rotated its identity, returning whether we were peered with its old one.
+    ///
+    /// The old identity is forgotten, since the peer will greet us again under its new one. Like other signed
+    /// messages, endorsements that are stale or that we've seen before are refused, so a captured one can't be used to
+    /// make us drop the peer again later.
+    pub async fn recv_rotate(&self, endorsement: Signed<PublicId>) -> bool {
+        self.metrics.request(Request::Rotate);
+        if !self.with_routing(|routing| routing.contains(&endorsement.sender)) {
+            return false;
+        }
+        match self.open(endorsement) {
+            Ok((old, _)) => self.remove_peer(&old).await,
+            Err(_) => false,
+        }
+    }
+
+    /// Peer with each of our initial peers, following any redirections they suggest, then learn what our peers see
+    /// of us.
+    ///
+    /// The initial peers are tried at once, so those that don't answer don't hold up the others. We count as
+    /// bootstrapped as soon as any of them succeeds. Bootstrapping again tries them all again, which [`Node::maintain`]
+    /// does if we never reached any or have since lost every peer. If a bootstrap is already under way, this waits
+    /// for it to finish rather than starting another.
+    pub async fn bootstrap(&self) {
+        let _bootstrapping = match self.bootstrapping.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                let _ = self.bootstrapping.lock().await;
+                return;
+            }
+        };
+        futures::stream::iter(self.initial_peers.iter().cloned())
+            .map(|addr| self.bootstrap_from(addr))
+            .buffer_unordered(BOOTSTRAP_CONCURRENCY)
+            .for_each(|_| async {})
+            .await;
+        for peer in self.with_routing(|routing| {
+            routing
+                .iter()
+                .map(|peer| (peer.id.clone(), peer.addr.clone()))
+                .collect::<Vec<_>>()
+        }) {
+            self.observe_self(&peer).await;
+        }
+    }
+
+    async fn bootstrap_from(&self, peer_addr: B::Addr) {
+        match self.discover_from(peer_addr.clone()).await {
+            Ok(()) => self.with_state(|state| state.bootstrapped = true),
+            Err(err) => warn!(
+                "{:?} failed to peer with initial peer {:?}: {}",
+                self.id(),
+                peer_addr,
+                err
+            ),
+        }
+    }
+
+    /// Run the node: serve requests with [`Node::serve`], [bootstrap](Node::bootstrap), and keep up with
+    /// [maintenance](Node::maintain), until the backend stops hosting.
+    pub async fn run(self: Arc<Self>) -> Result<(), Error<B::Error>> {
+        info!("Starting node `{:?}`", self.identity());
+        let mut serve = std::pin::pin!(self.clone().serve());
+        // Bootstrapping runs alongside maintenance, so that initial peers which never answer don't hold up upkeep
+        let upkeep = futures::future::join(self.bootstrap(), self.clone().maintain());
+        select! {
+            biased;
+            res = &mut serve => res,
+            // Maintenance only ends once we've been asked to shut down, after which the backend soon stops hosting
+            _ = upkeep => serve.await,
+        }
+    }
+
+    /// Serve requests from other nodes through the backend until it stops hosting, usually because
+    /// [`Node::shutdown`] was called, then find a home for the data we hold before returning.
+    ///
+    /// This doesn't peer with anybody or look after our peers. See [`Node::bootstrap`] and [`Node::maintain`].
+    pub async fn serve(self: Arc<Self>) -> Result<(), Error<B::Error>> {
+        let res = tokio::task::spawn(B::host(self.clone())).await;
+        // We no longer accept new data, so find a home for what we have before we go
+        let report = self.rehome_data(REHOME_BUDGET).await;
+        if report != Rehomed::default() {
+            info!(
+                "Passed {} entries on to our peers and abandoned {}",
+                report.rehomed, report.abandoned
+            );
+        }
+        res.map_err(|_| Error::HostPanic)?.map_err(Error::Backend)
+    }
+
+    /// Look after the node until [`Node::shutdown`] is called: ping our peers and drop those that don't answer, look
+    /// for new ones, hand on, audit and sync data, and everything else that keeps the node healthy.
+    pub async fn maintain(self: Arc<Self>) {
+        // Set whenever we need to bootstrap again
+        let mut bootstrap = std::pin::pin!(futures::future::Fuse::terminated());
 
         let mut ping = tokio::time::interval(Duration::from_secs(10));
         let mut discover = tokio::time::interval(Duration::from_secs(5));
+        let mut handoff = tokio::time::interval(HANDOFF_INTERVAL);
+        let mut expiry = tokio::time::interval(EXPIRY_INTERVAL);
+        let mut gc = tokio::time::interval(GC_INTERVAL);
+        let mut renew = tokio::time::interval(RENEW_INTERVAL);
+        let mut audit = tokio::time::interval(AUDIT_INTERVAL);
+        let mut cache = tokio::time::interval(CACHE_INTERVAL);
+        let mut sync = tokio::time::interval(SYNC_INTERVAL);
+        let mut addr_check = tokio::time::interval(ADDR_CHECK_INTERVAL);
 
         loop {
             select! {
-                res = &mut host => break res.unwrap().map_err(Error::Backend),
+                // Ticks that fall due together are handled in the same order every time, so that seeded simulations
+                // behave identically on every run
+                biased;
+                _ = self.shutdown_requested() => break,
+                _ = &mut bootstrap => {},
                 _ = ping.tick() => {
-                    for (peer_idx, peer) in self.with_state(|state| state.peers
+                    for peer in self.with_routing(|routing| routing
                         .iter()
-                        .map(|(idx, peer)| (idx, peer.addr.clone()))
+                        .map(|peer| (peer.id.clone(), peer.addr.clone()))
                         .collect::<Vec<_>>())
                     {
-                        match self.backend.send_ping(&peer).await {
-                            Ok(_) => {},
-                            Err(_) => {
-                                eprintln!("Failed to sent ping to peer, removing from list.");
-                                self.remove_peer(peer_idx).await;
+                        match self.backend.send_ping(&peer.1).await {
+                            Ok((ping, pong)) => {
+                                let clock_offset = self.clock_offset(&peer.0, &pong);
+                                let msg::Pong { record, peer: hint, .. } = pong;
+                                self.with_routing(|routing| {
+                                    if let Some(peer) = routing.get_mut(&peer.0) {
+                                        peer.ping = ping;
+                                        peer.clock_offset = clock_offset;
+                                        // Keep hold of the peer's latest record, since the one we have will expire
+                                        if record.sender == peer.id && record.verify_record().is_ok() {
+                                            peer.record = record;
+                                        }
+                                    }
+                                });
+                                if let Some(hint) = hint {
+                                    self.follow_hint(hint).await;
+                                }
+                            },
+                            Err(err) => {
+                                self.metrics.failure();
+                                error!("Failed to send ping to {:?}, removing it from our peers: {}", peer.0, err);
+                                if self.remove_peer(&peer.0).await {
+                                    self.with_state(|state| {
+                                        if state.lost_peers.len() >= MAX_LOST_PEERS {
+                                            state.lost_peers.pop_front();
+                                        }
+                                        state.lost_peers.push_back(peer);
+                                    });
+                                }
                             },
                         }
                     }
                 },
+                _ = handoff.tick() => self.hand_off().await,
+                _ = expiry.tick() => {
+                    let expired = self.with_state(|state| state.data.expire());
+                    if expired > 0 {
+                        debug!("Dropped {} expired entries", expired);
+                    }
+                },
+                _ = audit.tick() => {
+                    let copies = self.with_state(|state| state.audits.keys().cloned().collect::<Vec<_>>());
+                    let copy = copies.choose(&mut *self.rng()).cloned();
+                    if let Some((tag, holder)) = copy {
+                        self.audit(tag, &holder).await;
+                    }
+                },
+                _ = cache.tick() => {
+                    self.cache_popular().await;
+                },
+                _ = sync.tick() => {
+                    self.anti_entropy().await;
+                },
+                _ = addr_check.tick() => {
+                    if self.with_state(|state| state.track_public_ip) {
+                        self.check_public_ip().await;
+                    }
+                },
+                _ = gc.tick() => {
+                    let collected = self.collect_garbage();
+                    if collected > 0 {
+                        debug!("Collected {} chunks whose lease ran out", collected);
+                    }
+                },
+                _ = renew.tick() => {
+                    let renewed = self.renew_chunks().await;
+                    debug!("Renewed the leases of {} chunks", renewed);
+                },
                 _ = discover.tick() => {
-                    if let Some(mut current_peer) = self.with_state(|state| state.peers
-                        .values()
-                        .choose(&mut thread_rng())
+                    // If we never reached any of our initial peers or have since lost contact with everybody, and aren't
+                    // still trying them, start again from our initial peers. Otherwise, a handful of nodes that only know
+                    // each other can form an island.
+                    if bootstrap.is_terminated()
+                        && (!self.with_state(|state| state.bootstrapped)
+                            || self.with_routing(|routing| routing.is_empty()))
+                    {
+                        bootstrap.set(self.bootstrap().fuse());
+                    }
+                    // Reissue our address record before it expires
+                    self.addr_record().await;
+                    // Peers that stopped responding may have only been temporarily unreachable, so try one of them again
+                    if let Some(lost) = self.with_state(|state| state.lost_peers.pop_front()) {
+                        if self.discover_peer(Some(&lost.0), lost.1.clone()).await.is_err()
+                            && !self.with_routing(|routing| routing.contains(&lost.0))
+                        {
+                            self.with_state(|state| {
+                                if state.lost_peers.len() < MAX_LOST_PEERS
+                                    && !state.incompatible.contains(&lost.1)
+                                {
+                                    state.lost_peers.push_back(lost);
+                                }
+                            });
+                        }
+                    }
+                    if let Some(mut current_peer) = self.with_routing(|routing| routing
+                        .random(&mut *self.rng())
                         .map(|peer| (peer.id.clone(), peer.addr.clone())))
                     {
-                        for current_level in (0..256).rev() {
-                            match self.backend
-                                .send_discover(&current_peer.1, self.id().tag, current_level)
-                                .await
-                            {
-                                Ok(Some(closest)) => if closest.0.tag.dist_to(self.id().tag).level() <= current_level {
-                                    let _ = self.discover_peer(Some(&closest.0), closest.1.clone()).await;
-                                    current_peer = closest;
-                                } else {
-                                    eprintln!("{:?} lied to peer {:?} and returned a node that was *further* from the target!", closest.0, self.id());
-                                    break
-                                },
-                                Ok(None) => break, // Trail has gone cold
-                                Err(err) => eprintln!("Failed to sent discover to peer: {:?}", err),
+                        self.observe_self(&current_peer).await;
+                        let target = self.id().tag;
+                        async {
+                            // Anybody the peer could tell us about at a higher level than its own would be no closer to
+                            // us than it is
+                            let Some(mut current_level) = current_peer.0.tag.bucket_index(target) else {
+                                return;
+                            };
+                            let mut failures = 0;
+                            while failures < MAX_DISCOVER_FAILURES {
+                                match self.backend
+                                    .send_discover(&current_peer.1, target, current_level as u16)
+                                    .await
+                                {
+                                    Ok(Some(record)) if record.verify_record().is_err() => {
+                                        warn!("{:?} passed on a forged or expired address record for {:?}", current_peer.0, record.sender);
+                                        break
+                                    },
+                                    Ok(Some(record)) => match record.sender.tag.bucket_index(target) {
+                                        Some(level) if level <= current_level => {
+                                            let closest = (record.sender, record.body.addr);
+                                            let _ = self.discover_peer(Some(&closest.0), closest.1.clone()).await;
+                                            current_peer = closest;
+                                            failures = 0;
+                                            // Levels between the one we asked about and the one we got may hold others,
+                                            // but the next peer can only point us closer by looking below its own
+                                            match level.checked_sub(1) {
+                                                Some(next) => current_level = next,
+                                                None => break,
+                                            }
+                                        },
+                                        _ => {
+                                            warn!("{:?} lied to peer {:?} and returned a node that was *further* from the target!", record.sender, self.id());
+                                            break
+                                        },
+                                    },
+                                    // The peer knows nobody at this level or below, so the trail has gone cold
+                                    Ok(None) => break,
+                                    Err(err) => {
+                                        self.metrics.failure();
+                                        error!("Failed to send discover to {:?}: {:?}", current_peer.0, err);
+                                        failures += 1;
+                                    },
+                                }
                             }
                         }
+                        .instrument(debug_span!("discover", ?target))
+                        .await;
                     }
                 },
             }
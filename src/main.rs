@@ -1,43 +1,1155 @@
-use clap::Parser;
-use nettle::{http, Error, Node, PrivateId};
+use clap::{Parser, Subcommand};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use nettle::{
+    dns, http,
+    output::{Downloaded, ErrorKind, ErrorReport, Running, Tagged, Uploaded},
+    store, stun, HashAlgorithm, KeyError, Node, PrivateId, Tag, TagParseError, PROTOCOL_VERSION,
+};
+use reqwest::{Body, StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    fs,
+    future::Future,
+    io::{self, IsTerminal},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tracing::{info, warn};
+use tracing_subscriber::EnvFilter;
+
+// How often to look up the peers published under `--bootstrap-domain` while we have no peers
+const DNS_REFRESH: Duration = Duration::from_secs(60);
+
+fn parse_addr(addr: &str) -> Result<Url, http::Error> {
+    http::parse_addr(addr)
+}
+
+// An IP address to listen on, which may be bracketed like the host of a URL
+fn parse_listen_addr(addr: &str) -> Result<IpAddr, String> {
+    addr.strip_prefix('[')
+        .and_then(|addr| addr.strip_suffix(']'))
+        .unwrap_or(addr)
+        .parse()
+        .map_err(|_| format!("`{}` is not an IP address", addr))
+}
+
+fn deserialize_listen_addrs<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<IpAddr>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|addr| parse_listen_addr(addr).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn parse_tag(tag: &str) -> Result<Tag, TagParseError> {
+    Tag::try_from_hex(tag)
+}
+
+fn parse_log_level(level: &str) -> Result<String, String> {
+    EnvFilter::try_new(level)
+        .map(|_| level.to_string())
+        .map_err(|err| err.to_string())
+}
 
 #[derive(Parser)]
 #[command(version, about)]
 struct Args {
+    #[command(subcommand)]
+    command: Command,
+    /// What to log: a level such as `debug`, or directives like `nettle=debug,hyper=warn`. Takes precedence over
+    /// `RUST_LOG` [default: info]
+    #[arg(long, global = true, value_parser = parse_log_level)]
+    log_level: Option<String>,
+    /// Print results as JSON, and failures as JSON on stderr
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a node
+    Run(RunArgs),
+    /// Upload a file through a node, printing the tag it was stored under
+    Upload {
+        /// The file to upload, or `-` to read from stdin
+        file: PathBuf,
+        /// The node to upload through
+        #[arg(short, long, value_parser = parse_addr)]
+        node: Url,
+        /// Don't show progress
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Download data through a node, checking that it matches its tag
+    Download {
+        #[arg(value_parser = parse_tag)]
+        tag: Tag,
+        /// The node to download through
+        #[arg(short, long, value_parser = parse_addr)]
+        node: Url,
+        /// Where to write the data, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Don't show progress
+        #[arg(short, long)]
+        quiet: bool,
+    },
+    /// Print the tags that files would be stored under, without uploading them
+    Tag {
+        /// The files to tag, or `-` to read from stdin
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Check that a file matches a tag
+    Verify {
+        #[arg(value_parser = parse_tag)]
+        tag: Tag,
+        /// The file to check, or `-` to read from stdin
+        file: PathBuf,
+    },
+    /// Find which node holds data, or the closest node to it if nobody does
+    Locate {
+        #[arg(value_parser = parse_tag)]
+        tag: Tag,
+        /// The node to look up the tag from
+        #[arg(short, long, value_parser = parse_addr)]
+        node: Url,
+        /// The node's admin token, if it has one
+        #[arg(long)]
+        admin_token: Option<String>,
+    },
+    /// Show the status of a running node
+    Status {
+        #[arg(short, long, value_parser = parse_addr)]
+        node: Url,
+        /// Keep showing the status, refreshed every this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        watch: Option<u64>,
+    },
+    /// List the peers of a running node
+    Peers {
+        #[arg(short, long, value_parser = parse_addr)]
+        node: Url,
+        /// The node's admin token, if it has one
+        #[arg(long)]
+        admin_token: Option<String>,
+        /// Keep showing the peers, refreshed every this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        watch: Option<u64>,
+    },
+}
+
+// Flags left unset fall back to the config file, and then to the defaults in `Config`
+#[derive(clap::Args)]
+struct RunArgs {
+    /// A TOML file to read settings from. Flags take precedence over it.
     #[arg(short, long)]
-    initial_peers: Vec<String>,
-    #[arg(short, long, default_value = "[::1]")]
-    address: String,
+    config: Option<PathBuf>,
+    /// Print the configuration that would be used, with flags and the config file applied, then exit
+    #[arg(long)]
+    print_config: bool,
+    #[arg(short, long, value_parser = parse_addr)]
+    initial_peers: Vec<Url>,
+    /// An IP address to listen on, may be given multiple times [default: ::1]
+    #[arg(short, long, value_parser = parse_listen_addr)]
+    address: Vec<IpAddr>,
+    #[arg(short, long, value_parser = parse_addr)]
+    url: Option<Url>,
+    /// The port to listen on [default: 34093]
     #[arg(short, long)]
-    url: Option<String>,
-    #[arg(short, long, default_value_t = 34093)]
+    port: Option<u16>,
+    /// The maximum size, in bytes, of a single upload [default: 16 MiB]
+    #[arg(long)]
+    max_upload_size: Option<usize>,
+    /// The maximum size, in bytes, of a peer message that carries no data [default: 64 KiB]
+    #[arg(long)]
+    max_message_size: Option<usize>,
+    /// A token required to access admin endpoints such as `/peers`
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// Serve a web interface for uploading and downloading data
+    #[arg(long)]
+    web_ui: bool,
+    /// Serve everything under this path prefix
+    #[arg(long)]
+    path_prefix: Option<String>,
+    /// The size, in bits, of the RSA key generated for this node's identity [default: 2048]
+    #[arg(long)]
+    key_bits: Option<usize>,
+    /// A file holding this node's private key. If it doesn't exist, a new key is generated and written to it.
+    #[arg(short, long)]
+    key: Option<PathBuf>,
+    /// A STUN server (`host:port`) to ask for our public address, may be given multiple times [default: several
+    /// public servers]
+    #[arg(long)]
+    stun: Vec<String>,
+    /// A domain that lists peers in `nettle-peer=<url>` TXT records or `_nettle._tcp` SRV records, to use as
+    /// initial peers alongside any given directly
+    #[arg(long)]
+    bootstrap_domain: Option<String>,
+}
+
+/// The settings for `nettle run`, as read from a config file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    initial_peers: Vec<Url>,
+    #[serde(deserialize_with = "deserialize_listen_addrs")]
+    address: Vec<IpAddr>,
+    url: Option<Url>,
     port: u16,
+    max_upload_size: usize,
+    max_message_size: usize,
+    admin_token: Option<String>,
+    web_ui: bool,
+    path_prefix: Option<String>,
+    key_bits: usize,
+    key: Option<PathBuf>,
+    /// STUN servers to ask for our public address. Leave empty to not use STUN.
+    stun: Vec<String>,
+    /// A domain that lists initial peers in DNS.
+    bootstrap_domain: Option<String>,
+    /// The number of times a message to a peer is retried after a transient failure.
+    retries: u32,
+    /// Whether to tell peers that ask about this node's state.
+    share_info: bool,
+    /// A codec to compress stored data with.
+    compression: Option<store::Codec>,
+    /// The fraction of an entry's size that compressing it must save for it to be stored compressed.
+    min_compression_gain: f64,
+    /// Whether to move to a new address when peers see this node's requests coming from a new IP address. Only
+    /// addresses given by IP, rather than by name, change.
+    track_public_ip: bool,
+    /// The most peers to keep, beyond the limit at each level.
+    max_peers: Option<usize>,
+    /// The most bytes that stored data may take up, beyond which the least recently used unpinned data is evicted.
+    storage_budget: Option<usize>,
+    /// How many seconds to keep unpinned data for.
+    data_ttl_secs: Option<u64>,
+    /// The most bytes that each node may upload to this one.
+    publisher_quota: Option<usize>,
+    /// The most bytes that may be uploaded to this node without signing the upload, or `0` to refuse such uploads.
+    anonymous_quota: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let http = http::Config::default();
+        Self {
+            initial_peers: Vec::new(),
+            address: vec![Ipv6Addr::LOCALHOST.into()],
+            url: None,
+            port: 34093,
+            max_upload_size: http.max_upload_size,
+            max_message_size: http.max_message_size,
+            admin_token: None,
+            web_ui: false,
+            path_prefix: None,
+            key_bits: nettle::DEFAULT_KEY_BITS,
+            key: None,
+            stun: stun::DEFAULT_SERVERS
+                .iter()
+                .map(|server| server.to_string())
+                .collect(),
+            bootstrap_domain: None,
+            retries: http.retries,
+            share_info: http.share_info,
+            compression: None,
+            min_compression_gain: store::Compression::default().min_gain,
+            track_public_ip: false,
+            max_peers: None,
+            storage_budget: None,
+            data_ttl_secs: None,
+            publisher_quota: None,
+            anonymous_quota: None,
+        }
+    }
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read config file {:?}: {}", path, err))?;
+        let mut config = toml::from_str::<Self>(&text)
+            .map_err(|err| format!("Invalid config file {:?}: {}", path, err))?;
+        // Normalise addresses in the same way as those given as flags
+        let normalise = |url: &Url| {
+            parse_addr(url.as_str())
+                .map_err(|err| format!("Invalid config file {:?}: {}", path, err))
+        };
+        config.initial_peers = config
+            .initial_peers
+            .iter()
+            .map(normalise)
+            .collect::<Result<_, _>>()?;
+        config.url = config.url.as_ref().map(normalise).transpose()?;
+        Ok(config)
+    }
+
+    // Override our settings with any that were given as flags
+    fn apply(&mut self, args: RunArgs) {
+        if !args.initial_peers.is_empty() {
+            self.initial_peers = args.initial_peers;
+        }
+        if !args.address.is_empty() {
+            self.address = args.address;
+        }
+        self.url = args.url.or(self.url.take());
+        self.port = args.port.unwrap_or(self.port);
+        self.max_upload_size = args.max_upload_size.unwrap_or(self.max_upload_size);
+        self.max_message_size = args.max_message_size.unwrap_or(self.max_message_size);
+        self.admin_token = args.admin_token.or(self.admin_token.take());
+        self.web_ui |= args.web_ui;
+        self.path_prefix = args.path_prefix.or(self.path_prefix.take());
+        self.key_bits = args.key_bits.unwrap_or(self.key_bits);
+        self.key = args.key.or(self.key.take());
+        if !args.stun.is_empty() {
+            self.stun = args.stun;
+        }
+        self.bootstrap_domain = args.bootstrap_domain.or(self.bootstrap_domain.take());
+    }
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Error<http::Error>> {
+async fn main() -> ExitCode {
     let args = Args::parse();
+    let filter = match &args.log_level {
+        Some(level) => EnvFilter::new(level),
+        None => EnvFilter::builder()
+            .with_default_directive(tracing::Level::INFO.into())
+            .from_env_lossy(),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .init();
 
-    let host_addr = if let Some(url) = args.url {
+    let json = args.json;
+    let res = match args.command {
+        Command::Run(args) => run(args, json).await,
+        Command::Upload { file, node, quiet } => {
+            upload(&file, &node, progress_bar(quiet), json).await
+        }
+        Command::Download {
+            tag,
+            node,
+            output,
+            quiet,
+        } => download(tag, &node, output.as_deref(), progress_bar(quiet), json).await,
+        Command::Tag { files } => tag_files(&files, json).await,
+        Command::Verify { tag, file } => verify(tag, &file, json).await,
+        Command::Locate {
+            tag,
+            node,
+            admin_token,
+        } => locate(tag, &node, admin_token.as_deref(), json).await,
+        Command::Status { node, watch } => {
+            watching(watch, json, || async {
+                let status = get_status(&node).await?;
+                Ok(if json {
+                    serde_json::to_string_pretty(&status).unwrap() + "\n"
+                } else {
+                    render_status(&status)
+                })
+            })
+            .await
+        }
+        Command::Peers {
+            node,
+            admin_token,
+            watch,
+        } => {
+            watching(watch, json, || async {
+                get_status(&node).await?;
+                let mut peers =
+                    get_json::<Vec<http::PeerEntry>>(&node, "peers", admin_token.as_deref())
+                        .await?;
+                peers.sort_by_key(|peer| peer.level);
+                Ok(if json {
+                    serde_json::to_string_pretty(&peers).unwrap() + "\n"
+                } else {
+                    render_peers(&peers)
+                })
+            })
+            .await
+        }
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err((kind, msg)) => {
+            print_error(kind, &msg, json);
+            ExitCode::from(kind.exit_code())
+        }
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+// Failures are printed to stderr either way, on a single line when they're JSON
+fn print_error(kind: ErrorKind, msg: &str, json: bool) {
+    if json {
+        eprintln!(
+            "{}",
+            serde_json::to_string(&ErrorReport::new(kind, msg)).unwrap()
+        );
+    } else {
+        eprintln!("{}", msg);
+    }
+}
+
+async fn run(args: RunArgs, json: bool) -> Result<(), (ErrorKind, String)> {
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).map_err(|err| (ErrorKind::Failure, err))?,
+        None => Config::default(),
+    };
+    let print_config = args.print_config;
+    config.apply(args);
+    if print_config {
+        if json {
+            print_json(&config);
+        } else {
+            print!("{}", toml::to_string(&config).unwrap());
+        }
+        return Ok(());
+    }
+    if config.address.is_empty() {
+        return Err((ErrorKind::Failure, "No address to listen on".to_string()));
+    }
+
+    let private_id = load_identity(config.key.as_deref(), config.key_bits)
+        .await
+        .map_err(|err| (ErrorKind::Failure, err))?;
+    if !json {
+        println!(
+            "Running as {} ({})",
+            private_id.pub_id.human_readable_name(2),
+            private_id.pub_id.tag
+        );
+    }
+
+    if let Some(domain) = &config.bootstrap_domain {
+        for peer in dns_peers(domain).await {
+            if !config.initial_peers.contains(&peer) {
+                config.initial_peers.push(peer);
+            }
+        }
+    }
+
+    let stun_ip = stun_ip(&config.stun).await;
+    let host_url = if let Some(url) = config.url {
+        if let Some(stun_ip) = stun_ip {
+            check_url(&url, stun_ip).await;
+        }
         url
+    } else if let Some(public_ip) = stun_ip {
+        parse_addr(&format!(
+            "http://{}",
+            SocketAddr::new(public_ip, config.port)
+        ))
+        .unwrap()
+    } else if let Some(public_ip) = http::observe_public_ip(&config.initial_peers).await {
+        parse_addr(&format!(
+            "http://{}",
+            SocketAddr::new(public_ip, config.port)
+        ))
+        .unwrap()
     } else {
-        let public_ip = public_ip_addr::get_public_ip()
-            .await
-            .expect("failed to get public IP");
-        format!("http://{}:{}", public_ip, args.port)
+        // With nobody to ask, fall back to the address we're listening on
+        warn!("Could not learn our public address from any initial peer");
+        parse_addr(&format!(
+            "http://{}",
+            SocketAddr::new(config.address[0], config.port)
+        ))
+        .unwrap()
     };
-    let host_url = host_addr.parse().unwrap();
-    println!("Using {} as the host URL", host_url);
+    info!("Using {} as the host URL", host_url);
+    if json {
+        print_json(&Running {
+            name: private_id.pub_id.human_readable_name(2),
+            tag: private_id.pub_id.tag,
+            url: host_url.clone(),
+        });
+    }
 
-    Node::<http::Http>::new(
-        PrivateId::generate(),
+    let bootstrap_domain = config.bootstrap_domain.clone();
+    let node = Node::<http::Http>::new(
+        private_id,
         host_url,
-        args.initial_peers,
+        config.initial_peers,
         http::Config {
-            bind_addr: format!("{}:{}", args.address, args.port).parse().unwrap(),
+            bind_addrs: config
+                .address
+                .iter()
+                .map(|addr| SocketAddr::new(*addr, config.port))
+                .collect(),
+            max_upload_size: config.max_upload_size,
+            max_message_size: config.max_message_size,
+            retries: config.retries,
+            admin_token: config.admin_token,
+            web_ui: config.web_ui,
+            path_prefix: config.path_prefix,
+            share_info: config.share_info,
+            ..Default::default()
         },
     )
-    .await?
-    .run()
     .await
+    .map_err(|err| (ErrorKind::Failure, format!("Could not start node: {}", err)))?;
+    node.set_compression(config.compression.map(|codec| store::Compression {
+        codec,
+        min_gain: config.min_compression_gain,
+        ..Default::default()
+    }));
+    node.set_track_public_ip(config.track_public_ip);
+    node.set_max_peers(config.max_peers);
+    node.set_storage_budget(config.storage_budget);
+    node.set_data_ttl(config.data_ttl_secs.map(Duration::from_secs));
+    node.set_quotas(store::Quotas {
+        per_publisher: config.publisher_quota,
+        anonymous: config.anonymous_quota,
+    });
+    // The first signal shuts the node down gracefully, and a second gives up on waiting for it
+    let mut signals = ShutdownSignals::new().map_err(|err| {
+        (
+            ErrorKind::Failure,
+            format!("Could not listen for signals: {}", err),
+        )
+    })?;
+    tokio::spawn({
+        let node = node.clone();
+        async move {
+            signals.recv().await;
+            info!(
+                "Shutting down, leaving {} peer(s). Interrupt again to exit immediately.",
+                node.peer_count()
+            );
+            node.shutdown();
+            signals.recv().await;
+            warn!("Exiting without waiting for the node to shut down");
+            std::process::exit(ErrorKind::Failure.exit_code().into());
+        }
+    });
+    if let Some(domain) = bootstrap_domain {
+        tokio::spawn(rediscover(node.clone(), domain));
+    }
+    node.run()
+        .await
+        .map_err(|err| (ErrorKind::Failure, format!("Node failed: {}", err)))
+}
+
+// Look up the peers published under `domain`, if any
+async fn dns_peers(domain: &str) -> Vec<Url> {
+    match dns::resolve_peers(domain).await {
+        Ok(peers) => {
+            info!("Found {} peer(s) published under {}", peers.len(), domain);
+            peers
+        }
+        Err(err) => {
+            warn!(
+                "Could not find any peers published under {}: {}",
+                domain, err
+            );
+            Vec::new()
+        }
+    }
+}
+
+// The peers published in DNS may have changed since we started, so look them up again whenever we have no peers
+async fn rediscover(node: Arc<Node<http::Http>>, domain: String) {
+    let mut interval = tokio::time::interval(DNS_REFRESH);
+    // The first tick completes immediately, but we've only just looked
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if node.peer_count() > 0 {
+            continue;
+        }
+        for peer in dns_peers(&domain).await {
+            if node.discover_from(peer).await.is_ok() {
+                break;
+            }
+        }
+    }
+}
+
+// Learn our public IP from STUN servers, if any are configured
+async fn stun_ip(servers: &[String]) -> Option<IpAddr> {
+    if servers.is_empty() {
+        return None;
+    }
+    match stun::discover(servers).await {
+        Ok(mapping) => {
+            if mapping.symmetric {
+                warn!(
+                    "STUN servers saw us at different ports, so we're behind a symmetric NAT. Peers will only be able \
+                    to reach us if port {} is forwarded to this machine.",
+                    mapping.addr.port()
+                );
+            }
+            Some(mapping.addr.ip())
+        }
+        Err(err) => {
+            warn!("Could not learn our public address with STUN: {}", err);
+            None
+        }
+    }
+}
+
+// Warn loudly if the URL we were told to advertise doesn't point at the address that STUN servers see us at
+async fn check_url(url: &Url, stun_ip: IpAddr) {
+    let Some(host) = url.host_str() else {
+        return;
+    };
+    let port = url.port_or_known_default().unwrap_or(80);
+    let resolved = match tokio::net::lookup_host((host.trim_matches(['[', ']']), port)).await {
+        Ok(addrs) => addrs.map(|addr| addr.ip()).collect::<Vec<_>>(),
+        Err(_) => Vec::new(),
+    };
+    if !resolved.contains(&stun_ip) {
+        warn!("{}", "*".repeat(80));
+        warn!(
+            "{} resolves to {:?}, but STUN servers see us at {}.",
+            url, resolved, stun_ip
+        );
+        warn!("Peers may be unable to reach this node at the URL it advertises.");
+        warn!("{}", "*".repeat(80));
+    }
+}
+
+// Requests to stop the process: Ctrl-C, or SIGTERM on unix
+struct ShutdownSignals {
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+}
+
+impl ShutdownSignals {
+    // Start listening straight away, so that a signal sent before the first call to `recv` isn't missed
+    fn new() -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Self {
+                interrupt: signal(SignalKind::interrupt())?,
+                terminate: signal(SignalKind::terminate())?,
+            })
+        }
+        #[cfg(not(unix))]
+        Ok(Self {})
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        tokio::select! {
+            _ = self.interrupt.recv() => {}
+            _ = self.terminate.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+// Load the identity kept in `path`, or generate one and keep it there if it doesn't exist yet
+async fn load_identity(path: Option<&Path>, bits: usize) -> Result<PrivateId, String> {
+    let generate = || async {
+        PrivateId::generate_async_with_bits(bits)
+            .await
+            .map_err(|err| format!("Could not generate an identity: {}", err))
+    };
+    let Some(path) = path else {
+        return generate().await;
+    };
+    match PrivateId::load_from_file(path) {
+        Ok(private_id) => Ok(private_id),
+        Err(KeyError::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+            let private_id = generate().await?;
+            private_id
+                .save_to_file(path)
+                .map_err(|err| format!("Could not save identity to {:?}: {}", path, err))?;
+            info!("Saved a new identity to {:?}", path);
+            Ok(private_id)
+        }
+        // Never replace a key that we failed to read, since that would silently give the node a new identity
+        Err(err) => Err(format!("Could not load identity from {:?}: {}", path, err)),
+    }
+}
+
+// Read `reader` in chunks, so that large files needn't be held in memory while they're uploaded, calling `progress`
+// with the size of each chunk as it's read
+fn chunks<R, F>(reader: R, progress: F) -> Body
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    F: FnMut(usize) + Send + 'static,
+{
+    Body::wrap_stream(futures::stream::try_unfold(
+        (reader, progress),
+        |(mut reader, mut progress)| async {
+            let mut buf = vec![0; 64 * 1024];
+            let n = reader.read(&mut buf).await?;
+            buf.truncate(n);
+            progress(n);
+            Ok::<_, std::io::Error>((n > 0).then_some((buf, (reader, progress))))
+        },
+    ))
+}
+
+// A progress indicator for transfers, drawn on stderr so that it doesn't mix with the output. It's hidden when
+// stderr isn't a terminal.
+fn progress_bar(quiet: bool) -> ProgressBar {
+    if quiet {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr())
+    }
+}
+
+// Show the progress of a transfer of `len` bytes, or count bytes with a spinner if we don't know how many to expect
+fn start_progress(bar: &ProgressBar, len: Option<u64>) {
+    let style = match len {
+        Some(len) => {
+            bar.set_length(len);
+            ProgressStyle::with_template(
+                "{wide_bar} {bytes}/{total_bytes} {percent}% ({bytes_per_sec}, {eta})",
+            )
+        }
+        None => ProgressStyle::with_template("{spinner} {bytes} ({bytes_per_sec})"),
+    };
+    bar.set_style(style.unwrap());
+    bar.enable_steady_tick(Duration::from_millis(100));
+}
+
+// Compute the tag of a file, or of stdin for `-`, without reading all of it into memory
+async fn digest_file(algorithm: HashAlgorithm, file: &Path) -> io::Result<Tag> {
+    if file == Path::new("-") {
+        Tag::digest_async_reader_with(algorithm, tokio::io::stdin()).await
+    } else {
+        Tag::digest_async_reader_with(algorithm, tokio::fs::File::open(file).await?).await
+    }
+}
+
+// Print the tag of each file in the style of `sha256sum`, carrying on past any that can't be read
+async fn tag_files(files: &[PathBuf], json: bool) -> Result<(), (ErrorKind, String)> {
+    let mut tagged = Vec::new();
+    let mut failed = 0;
+    for file in files {
+        match digest_file(HashAlgorithm::default(), file).await {
+            Ok(tag) if json => tagged.push(Tagged {
+                tag,
+                file: file.clone(),
+            }),
+            Ok(tag) => println!("{}  {}", tag, file.display()),
+            Err(err) => {
+                let msg = format!("Could not read {:?}: {}", file, err);
+                print_error(ErrorKind::Failure, &msg, json);
+                failed += 1;
+            }
+        }
+    }
+    if json {
+        print_json(&tagged);
+    }
+    if failed == 0 {
+        Ok(())
+    } else {
+        Err((
+            ErrorKind::Failure,
+            format!("Could not read {} of {} file(s)", failed, files.len()),
+        ))
+    }
+}
+
+async fn verify(tag: Tag, file: &Path, json: bool) -> Result<(), (ErrorKind, String)> {
+    // Use whichever algorithm the tag was made with, which needn't be the default
+    let actual = digest_file(tag.algorithm(), file).await.map_err(|err| {
+        (
+            ErrorKind::Failure,
+            format!("Could not read {:?}: {}", file, err),
+        )
+    })?;
+    if actual == tag && json {
+        print_json(&Tagged {
+            tag,
+            file: file.to_path_buf(),
+        });
+        Ok(())
+    } else if actual == tag {
+        println!("{}: OK", file.display());
+        Ok(())
+    } else {
+        Err((
+            ErrorKind::Mismatch,
+            format!(
+                "{} does not match {}, its tag is {}",
+                file.display(),
+                tag,
+                actual
+            ),
+        ))
+    }
+}
+
+async fn upload(
+    file: &Path,
+    node: &Url,
+    progress: ProgressBar,
+    json: bool,
+) -> Result<(), (ErrorKind, String)> {
+    let sent = {
+        let progress = progress.clone();
+        move |n| progress.inc(n as u64)
+    };
+    let body = if file == Path::new("-") {
+        start_progress(&progress, None);
+        chunks(tokio::io::stdin(), sent)
+    } else {
+        let file = tokio::fs::File::open(file).await.map_err(|err| {
+            (
+                ErrorKind::Failure,
+                format!("Could not open {:?}: {}", file, err),
+            )
+        })?;
+        start_progress(&progress, file.metadata().await.ok().map(|meta| meta.len()));
+        chunks(file, sent)
+    };
+    let resp = reqwest::Client::new()
+        .post(node.join("data").unwrap())
+        .body(body)
+        .send()
+        .await;
+    progress.finish_and_clear();
+    let resp =
+        resp.map_err(|err| (ErrorKind::Failure, format!("Could not reach node: {}", err)))?;
+    let status = resp.status();
+    let text = resp.text().await.map_err(|err| {
+        (
+            ErrorKind::Failure,
+            format!("Could not read response: {}", err),
+        )
+    })?;
+    if !status.is_success() {
+        return Err((
+            ErrorKind::Failure,
+            format!("Upload failed with {}: {}", status, text),
+        ));
+    }
+    if json {
+        let tag = Tag::try_from_hex(&text).map_err(|err| {
+            (
+                ErrorKind::Incompatible,
+                format!("The node returned an invalid tag {:?}: {}", text, err),
+            )
+        })?;
+        // The bar counts what was sent even when it's hidden
+        print_json(&Uploaded {
+            tag,
+            bytes: progress.position(),
+        });
+    } else {
+        println!("{}", text);
+    }
+    Ok(())
+}
+
+async fn download(
+    tag: Tag,
+    node: &Url,
+    output: Option<&Path>,
+    progress: ProgressBar,
+    json: bool,
+) -> Result<(), (ErrorKind, String)> {
+    let mut resp = reqwest::get(node.join(&format!("data/{}", tag)).unwrap())
+        .await
+        .map_err(|err| (ErrorKind::Failure, format!("Could not reach node: {}", err)))?;
+    match resp.status() {
+        StatusCode::NOT_FOUND => {
+            return Err((ErrorKind::NotFound, format!("{} does not exist", tag)))
+        }
+        status if !status.is_success() => {
+            let text = resp.text().await.unwrap_or_default();
+            return Err((
+                ErrorKind::Failure,
+                format!("Download failed with {}: {}", status, text),
+            ));
+        }
+        _ => {}
+    }
+    start_progress(&progress, resp.content_length());
+    let mut data = Vec::new();
+    loop {
+        match resp.chunk().await {
+            Ok(Some(chunk)) => {
+                progress.inc(chunk.len() as u64);
+                data.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(err) => {
+                progress.finish_and_clear();
+                return Err((
+                    ErrorKind::Failure,
+                    format!("Could not read response: {}", err),
+                ));
+            }
+        }
+    }
+    progress.finish_and_clear();
+    // Don't trust the node to have checked the data for us
+    if !tag.is_digest_of(&data) {
+        return Err((
+            ErrorKind::Failure,
+            format!("The node returned data that does not match {}", tag),
+        ));
+    }
+    match output {
+        Some(path) => tokio::fs::write(path, &data).await,
+        None => {
+            let mut stdout = tokio::io::stdout();
+            stdout.write_all(&data).await.and(stdout.flush().await)
+        }
+    }
+    .map_err(|err| (ErrorKind::Failure, format!("Could not write data: {}", err)))?;
+    if json {
+        let downloaded = Downloaded {
+            tag,
+            bytes: data.len() as u64,
+            output: output.map(Path::to_path_buf),
+        };
+        // Keep stdout to the data itself when that's where it went
+        match output {
+            Some(_) => print_json(&downloaded),
+            None => eprintln!("{}", serde_json::to_string(&downloaded).unwrap()),
+        }
+    }
+    Ok(())
+}
+
+async fn locate(
+    tag: Tag,
+    node: &Url,
+    admin_token: Option<&str>,
+    json: bool,
+) -> Result<(), (ErrorKind, String)> {
+    let report =
+        get_json::<http::LocateReport>(node, &format!("locate/{}", tag), admin_token).await?;
+    if json {
+        print_json(&report);
+    } else if report.found {
+        println!(
+            "{} is held by {} ({}) at {}, found in {} hop(s)",
+            tag,
+            report.node.name,
+            report.node.tag,
+            report.node.addr,
+            report.path.len()
+        );
+    }
+    if report.found {
+        Ok(())
+    } else {
+        Err((
+            ErrorKind::NotFound,
+            format!(
+                "{} was not found. The closest node reached was {} ({}) at {}, after {} hop(s)",
+                tag,
+                report.node.name,
+                report.node.tag,
+                report.node.addr,
+                report.path.len()
+            ),
+        ))
+    }
+}
+
+// Fetch a JSON document from a node's HTTP API, telling apart the ways in which that can fail
+async fn get_json<T: DeserializeOwned>(
+    node: &Url,
+    path: &str,
+    admin_token: Option<&str>,
+) -> Result<T, (ErrorKind, String)> {
+    let mut req = reqwest::Client::new().get(node.join(path).unwrap());
+    if let Some(token) = admin_token {
+        req = req.bearer_auth(token);
+    }
+    let resp = req.send().await.map_err(|err| {
+        (
+            ErrorKind::Unreachable,
+            format!("Could not reach node at {}: {}", node, err),
+        )
+    })?;
+    match resp.status() {
+        StatusCode::UNAUTHORIZED => {
+            return Err((
+                ErrorKind::Unauthorized,
+                match admin_token {
+                    Some(_) => "The node did not accept the admin token".to_string(),
+                    None => {
+                        "The node requires an admin token, given with --admin-token".to_string()
+                    }
+                },
+            ))
+        }
+        // Every endpoint that we use answers with something other than 404, so the node must be too old to have it
+        StatusCode::NOT_FOUND => {
+            return Err((
+                ErrorKind::Incompatible,
+                format!(
+                "The node does not serve /{}, so is probably running an older version of nettle",
+                path
+            ),
+            ))
+        }
+        status if !status.is_success() => {
+            let text = resp.text().await.unwrap_or_default();
+            return Err((
+                ErrorKind::Failure,
+                format!("Request failed with {}: {}", status, text),
+            ));
+        }
+        _ => {}
+    }
+    let bytes = resp.bytes().await.map_err(|err| {
+        (
+            ErrorKind::Failure,
+            format!("Could not read response: {}", err),
+        )
+    })?;
+    serde_json::from_slice(&bytes).map_err(|err| {
+        (
+            ErrorKind::Incompatible,
+            format!(
+                "Could not understand the node's response, so it may be running a different version of nettle: {}",
+                err
+            ),
+        )
+    })
+}
+
+// Fetch a node's status, checking that it speaks the same protocol that we do
+async fn get_status(node: &Url) -> Result<http::Status, (ErrorKind, String)> {
+    let status = get_json::<http::Status>(node, "status", None).await?;
+    if status.version != PROTOCOL_VERSION {
+        return Err((
+            ErrorKind::Incompatible,
+            format!(
+                "The node speaks protocol version {}, but this version of nettle speaks {}",
+                status.version, PROTOCOL_VERSION
+            ),
+        ));
+    }
+    Ok(status)
+}
+
+// Print the output of `show` once or, with an interval given, redraw it that often until interrupted
+async fn watching<F, Fut>(
+    interval: Option<u64>,
+    json: bool,
+    show: F,
+) -> Result<(), (ErrorKind, String)>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<String, (ErrorKind, String)>>,
+{
+    let Some(secs) = interval else {
+        print!("{}", show().await?);
+        return Ok(());
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(secs.max(1)));
+    loop {
+        interval.tick().await;
+        // Keep going through failures, since the node may only be restarting
+        let out = match show().await {
+            Ok(out) => out,
+            Err((kind, msg)) if json => {
+                print_error(kind, &msg, json);
+                continue;
+            }
+            Err((_, msg)) => msg + "\n",
+        };
+        if json {
+            print!("{}", out);
+        } else {
+            // Clear the terminal and move to its top left before redrawing
+            print!("\x1b[2J\x1b[H{}", out);
+        }
+        io::Write::flush(&mut io::stdout()).ok();
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", mins, secs % 60),
+        (0, _, _) => format!("{}h {}m", hours, mins),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
+
+fn render_status(status: &http::Status) -> String {
+    let mut out = String::new();
+    let mut row = |label: &str, value: String| writeln!(out, "{:<10}{}", label, value).unwrap();
+    row("Name", status.name.clone());
+    row("Tag", status.tag.to_string());
+    row("Address", status.addr.to_string());
+    row("Protocol", status.version.to_string());
+    row("Uptime", format_duration(status.uptime_secs));
+    row("Entries", status.entries.to_string());
+    row("Peers", status.peers.to_string());
+    if !status.levels.is_empty() {
+        let levels = status
+            .levels
+            .iter()
+            .map(|(level, n)| format!("{}: {}", level, n))
+            .collect::<Vec<_>>();
+        row("Levels", levels.join(", "));
+    }
+    for (ip, n) in &status.observed_ips {
+        row("Seen at", format!("{} (by {} peer(s))", ip, n));
+    }
+    out
+}
+
+// Render peers as a table, with columns as wide as their widest entry
+fn render_peers(peers: &[http::PeerEntry]) -> String {
+    let header = ["NAME", "TAG", "ADDRESS", "PING", "LEVEL", "OFFSET"].map(str::to_string);
+    let rows = std::iter::once(header)
+        .chain(peers.iter().map(|peer| {
+            [
+                peer.name.clone(),
+                // Enough of the tag to tell peers apart at a glance, after the two digits of the algorithm code
+                peer.tag.to_string()[..10].to_string(),
+                peer.addr.to_string(),
+                format!("{:.1}ms", peer.ping_ms),
+                peer.level.to_string(),
+                // How far ahead of ours the peer's clock is
+                peer.clock_offset_ms
+                    .map_or("-".to_string(), |offset| format!("{:+}ms", offset)),
+            ]
+        }))
+        .collect::<Vec<_>>();
+    let widths = (0..6)
+        .map(|col| rows.iter().map(|row| row[col].len()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+    let mut out = String::new();
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(out, "{}", line.trim_end()).unwrap();
+    }
+    if peers.is_empty() {
+        out.push_str("(no peers)\n");
+    }
+    out
 }
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The kinds of request a node may receive from its peers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Greet,
+    Prove,
+    Rotate,
+    Ping,
+    Info,
+    Discover,
+    FindNode,
+    Locate,
+    Upload,
+    Download,
+    Audit,
+    Sync,
+    Readdress,
+    Renew,
+}
+
+impl Request {
+    pub const ALL: [Self; 14] = [
+        Self::Greet,
+        Self::Prove,
+        Self::Rotate,
+        Self::Ping,
+        Self::Info,
+        Self::Discover,
+        Self::FindNode,
+        Self::Locate,
+        Self::Upload,
+        Self::Download,
+        Self::Audit,
+        Self::Sync,
+        Self::Readdress,
+        Self::Renew,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Greet => "greet",
+            Self::Prove => "prove",
+            Self::Rotate => "rotate",
+            Self::Ping => "ping",
+            Self::Info => "info",
+            Self::Discover => "discover",
+            Self::FindNode => "find_node",
+            Self::Locate => "locate",
+            Self::Upload => "upload",
+            Self::Download => "download",
+            Self::Audit => "audit",
+            Self::Sync => "sync",
+            Self::Readdress => "readdress",
+            Self::Renew => "renew",
+        }
+    }
+}
+
+/// Counters maintained by a node as it handles traffic.
+///
+/// These are cheap to update and are independent of the backend in use.
+#[derive(Default)]
+pub struct Metrics {
+    requests: [AtomicU64; Request::ALL.len()],
+    failures: AtomicU64,
+    oversized: AtomicU64,
+    lookups: AtomicU64,
+    lookup_hops: AtomicU64,
+    upload_bytes: AtomicU64,
+    download_bytes: AtomicU64,
+    busy: AtomicU64,
+    peak_uploads: AtomicU64,
+}
+
+impl Metrics {
+    pub(crate) fn request(&self, req: Request) {
+        self.requests[req as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Only the HTTP backend has a size limit to enforce
+    #[cfg(feature = "http")]
+    pub(crate) fn oversized(&self) {
+        self.oversized.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn lookup(&self, hops: u64) {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        self.lookup_hops.fetch_add(hops, Ordering::Relaxed);
+    }
+
+    pub(crate) fn uploaded(&self, bytes: usize) {
+        self.upload_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn busy(&self) {
+        self.busy.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn upload_began(&self, concurrent: usize) {
+        self.peak_uploads
+            .fetch_max(concurrent as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn downloaded(&self, bytes: usize) {
+        self.download_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// The number of requests of the given kind received from peers.
+    pub fn requests(&self, req: Request) -> u64 {
+        self.requests[req as usize].load(Ordering::Relaxed)
+    }
+
+    /// The number of requests to peers that failed.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// The number of messages, sent to us or in response to our requests, that were rejected for being too large.
+    pub fn oversized_messages(&self) -> u64 {
+        self.oversized.load(Ordering::Relaxed)
+    }
+
+    /// The number of lookups performed, and the total number of hops they took.
+    pub fn lookup_hops(&self) -> (u64, u64) {
+        (
+            self.lookups.load(Ordering::Relaxed),
+            self.lookup_hops.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn upload_bytes(&self) -> u64 {
+        self.upload_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn download_bytes(&self) -> u64 {
+        self.download_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The number of uploads turned away because too many others were being handled.
+    pub fn busy_rejections(&self) -> u64 {
+        self.busy.load(Ordering::Relaxed)
+    }
+
+    /// The most uploads that have been handled at once.
+    pub fn peak_concurrent_uploads(&self) -> u64 {
+        self.peak_uploads.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,376 @@
+//! The messages that nodes exchange with their peers.
+//!
+//! Backends are free to carry these however they like, but those that serialise them should do so with serde, so
+//! that nodes on different backends agree on their shape.
+
+use crate::{
+    audit::Challenge,
+    sync::{Region, Summary},
+    Capabilities, GreetRefusal, GreetReply, HashAlgorithm, LocateReply, NodeStats, ProtocolError,
+    PublicId, Signature, Signed, SignedAddr, Tag, PROTOCOL_VERSION,
+};
+use bytes::Bytes;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::net::SocketAddr;
+
+/// No message, however it's encoded, may be larger than this many bytes (256 MiB). Backends should refuse anything
+/// bigger before they try to decode it, whatever they have been configured to accept.
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
+
+/// A request, along with the type of the response that it expects.
+///
+/// Responses that mention peers name them by address, so messages are generic over the backend's address type.
+pub trait Msg<A>: Send + Sync {
+    type Resp: DeserializeOwned;
+    /// Whether the message may safely be acted on more than once. Retries of other messages are sent verbatim, so that
+    /// a peer that received an earlier attempt drops them as replays.
+    const IDEMPOTENT: bool = false;
+    /// Whether the message is signed by its sender. Unsigned messages can be sent before a node exists.
+    const SIGNED: bool = true;
+    /// Whether the message or its response carries stored data, and so may be far larger than other messages.
+    const DATA: bool = false;
+}
+
+/// Ask a peer to accept us, challenging it to prove its identity.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Greet<A> {
+    pub sender: (PublicId, A),
+    pub challenge: Tag,
+    // Absent from nodes that predate protocol versions
+    #[serde(default)]
+    pub version: u16,
+    #[serde(default)]
+    pub capabilities: Capabilities,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GreetResp<A> {
+    // Ok(_) => I'm willing to accept you, if you can answer my challenge
+    // Err(_) => I won't accept you, and here's why
+    pub result: Result<GreetReply, GreetRefusal<A>>,
+}
+
+impl<A> Greet<A> {
+    /// A greeting in the protocol version that this node speaks, offering the capabilities that it supports.
+    pub fn new(sender: (PublicId, A), challenge: Tag) -> Self {
+        Self {
+            sender,
+            challenge,
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Greet<A> {
+    type Resp = GreetResp<A>;
+}
+
+/// Answer the challenge of a peer that we greeted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Prove {
+    pub id: PublicId,
+    pub proof: Signature,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProveResp {
+    pub accepted: bool,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Prove {
+    type Resp = ProveResp;
+}
+
+/// Tell a peer that we've replaced our identity with the one endorsed.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Rotate {
+    pub endorsement: Signed<PublicId>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RotateResp {
+    pub forgotten: bool,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Rotate {
+    type Resp = RotateResp;
+}
+
+/// Tell a peer that we've moved to the address in our record.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Readdress<A> {
+    pub record: SignedAddr<A>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReaddressResp {
+    pub updated: bool,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Readdress<A> {
+    type Resp = ReaddressResp;
+    const IDEMPOTENT: bool = true;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ping {
+    /// When the ping was sent, in milliseconds since the unix epoch by the pinger's clock.
+    pub sent: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pong<A> {
+    pub record: SignedAddr<A>,
+    // One of the responder's other peers, if it has any. Absent from peers that predate passing them on.
+    #[serde(default = "Option::default")]
+    pub peer: Option<SignedAddr<A>>,
+    // When the ping was sent, as it said, and when the responder received it and sent this answer, by the responder's
+    // clock. All are in milliseconds since the unix epoch, and all are zero from peers that predate them.
+    #[serde(default)]
+    pub ping_sent: u64,
+    #[serde(default)]
+    pub received: u64,
+    #[serde(default)]
+    pub sent: u64,
+}
+
+impl<A> Pong<A> {
+    /// How far ahead of the pinger's clock the responder's is, in milliseconds, given when the pinger received the
+    /// pong by its own clock. Negative if the responder's clock is behind, and `None` if it gave no timestamps.
+    ///
+    /// This is the estimate that NTP makes, which assumes that the ping and the pong spent as long as each other in
+    /// transit. It's out by no more than half of the round trip time.
+    pub fn clock_offset(&self, received: u64) -> Option<i64> {
+        if self.received == 0 || self.sent == 0 {
+            return None;
+        }
+        let [t0, t1, t2, t3] = [self.ping_sent, self.received, self.sent, received].map(i128::from);
+        i64::try_from(((t1 - t0) + (t2 - t3)) / 2).ok()
+    }
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Ping {
+    type Resp = Pong<A>;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Ask a peer for the address it sees our requests coming from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Observe;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ObserveResp {
+    pub addr: SocketAddr,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Observe {
+    type Resp = ObserveResp;
+    const IDEMPOTENT: bool = true;
+    const SIGNED: bool = false;
+}
+
+/// Ask a peer for a summary of its state.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Info;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InfoResp {
+    // None => I don't share my state
+    pub stats: Option<NodeStats>,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Info {
+    type Resp = InfoResp;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Attempt to discover a new peer by asking existing peers.
+///
+/// `addr` specifies the original requesting peer.
+/// `max_level` specifies the maximum distance (log2) that the returned peer should be from the given address
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Discover {
+    pub target: Tag,
+    pub max_level: u16,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DiscoverResp<A> {
+    pub peer: Option<SignedAddr<A>>,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Discover {
+    type Resp = DiscoverResp<A>;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Ask a peer for up to `k` of the nodes it knows closest to `target`. Unlike [`Discover`], this is purely
+/// informational: neither side greets anybody as a result.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FindNode {
+    pub target: Tag,
+    pub k: usize,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FindNodeResp<A> {
+    // The responder's peers, closest to the target first
+    pub peers: Vec<(PublicId, A)>,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for FindNode {
+    type Resp = FindNodeResp<A>;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Attempt to discover a tag in the network.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Locate {
+    pub tag: Tag,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LocateResp<A> {
+    // Ok(true) => I own the resource
+    // Ok(false) => I do not own the resource and do not know anybody closer to the resource (404!)
+    // Err(_) => I do not own the resource but these other nodes are closer to it, closest first
+    pub result: Result<bool, Vec<(PublicId, A)>>,
+    // Alongside `Ok(false)`, the closest nodes I do know, closest first. Absent from peers that predate suggesting them.
+    #[serde(default = "Vec::new")]
+    pub not_closer: Vec<(PublicId, A)>,
+}
+
+impl<A> From<LocateReply<A>> for LocateResp<A> {
+    fn from(reply: LocateReply<A>) -> Self {
+        let (result, not_closer) = match reply {
+            LocateReply::Found => (Ok(true), Vec::new()),
+            LocateReply::Closer(peers) => (Err(peers), Vec::new()),
+            LocateReply::NotCloser(peers) => (Ok(false), peers),
+        };
+        Self { result, not_closer }
+    }
+}
+
+impl<A> From<LocateResp<A>> for LocateReply<A> {
+    fn from(resp: LocateResp<A>) -> Self {
+        match resp.result {
+            Ok(true) => Self::Found,
+            Ok(false) => Self::NotCloser(resp.not_closer),
+            Err(peers) => Self::Closer(peers),
+        }
+    }
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Locate {
+    type Resp = LocateResp<A>;
+    const IDEMPOTENT: bool = true;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Upload {
+    pub data: Bytes,
+    // Absent from peers that predate hash algorithms, which only spoke SHA3-256
+    #[serde(default)]
+    pub algorithm: HashAlgorithm,
+    // Absent from peers that predate tagged uploads, whose data is stored under whatever tag it hashes to
+    #[serde(default)]
+    pub tag: Option<Tag>,
+    // Absent from peers that predate chunk uploads, whose uploads are all kept in their own right
+    #[serde(default)]
+    pub chunk: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadResp {
+    // Ok(_) => I stored the resource under this tag, which the uploader should check against their own
+    // Err(_) => I won't store the resource, and here's why
+    pub result: Result<Tag, ProtocolError>,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Upload {
+    type Resp = UploadResp;
+    const DATA: bool = true;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Download {
+    pub tag: Tag,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DownloadResp {
+    // Ok(Some(_)) => I own the resource and here it is
+    // Ok(None) => I do not own the resource
+    // Err(_) => I won't serve the resource, and here's why
+    pub result: Result<Option<Bytes>, ProtocolError>,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Download {
+    type Resp = DownloadResp;
+    const IDEMPOTENT: bool = true;
+    const DATA: bool = true;
+}
+
+/// Challenge a peer to prove that it still holds some data. See [`crate::audit`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Audit {
+    pub tag: Tag,
+    pub challenge: Challenge,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuditResp {
+    // Ok(Some(_)) => I own the resource and here's my answer to the challenge
+    // Ok(None) => I do not own the resource, or the challenge doesn't fit it
+    // Err(_) => I won't answer the challenge, and here's why
+    pub result: Result<Option<Tag>, ProtocolError>,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Audit {
+    type Resp = AuditResp;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Renew the leases of chunks that a peer holds, since we hold a tree node or manifest that refers to them. See
+/// [`crate::store`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Renew {
+    pub tags: Vec<Tag>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RenewResp {
+    // The number of the chunks that I hold, and so renewed
+    pub renewed: usize,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for Renew {
+    type Resp = RenewResp;
+    const IDEMPOTENT: bool = true;
+}
+
+/// Summarise the tags that we hold in a region of the keyspace that we share with a peer. See [`crate::sync`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncTags {
+    pub region: Region,
+    pub summary: Summary,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SyncTagsResp {
+    // Ok(_) => Here are the tags I hold in the region that you seem to be missing, and a summary of what I hold there
+    // Err(_) => I won't compare notes with you, and here's why
+    pub result: Result<SyncReply, ProtocolError>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SyncReply {
+    pub missing: Vec<Tag>,
+    pub summary: Summary,
+}
+
+impl<A: DeserializeOwned + Send + Sync> Msg<A> for SyncTags {
+    type Resp = SyncTagsResp;
+    const IDEMPOTENT: bool = true;
+}
@@ -0,0 +1,86 @@
+use crate::Tag;
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    sync::{Arc, OnceLock},
+};
+
+/// How human-readable names (such as `apple_river_3f`) are derived from tags.
+///
+/// Names are made of words chosen by the leading bytes of the tag, optionally followed by a checksum of two hex
+/// digits taken from its final byte. Names only contain alphanumeric characters and underscores, so they are safe to
+/// use as identifiers in formats like graphviz.
+#[derive(Clone, Debug)]
+pub struct NameFormat {
+    /// The words that names are made from. Lists longer than 256 words use two bytes of the tag per word.
+    pub words: Arc<[String]>,
+    /// The number of words in each name, limited by the length of the tag. Each word adds 8 bits of entropy with the
+    /// default list.
+    pub word_count: usize,
+    /// Whether to append a checksum, making it much less likely for two names to collide.
+    pub checksum: bool,
+}
+
+impl Default for NameFormat {
+    fn default() -> Self {
+        static DEFAULT_WORDS: OnceLock<Arc<[String]>> = OnceLock::new();
+        Self {
+            words: DEFAULT_WORDS
+                .get_or_init(|| Self::parse_words(include_str!("../data/words.txt")))
+                .clone(),
+            word_count: 2,
+            checksum: true,
+        }
+    }
+}
+
+impl NameFormat {
+    /// Use the words in `list`, one per line, in place of the default list. Blank lines and repeated words are
+    /// ignored, since they would make names less distinct.
+    pub fn with_wordlist(self, list: &str) -> Self {
+        Self {
+            words: Self::parse_words(list),
+            ..self
+        }
+    }
+
+    pub fn with_word_count(self, word_count: usize) -> Self {
+        Self { word_count, ..self }
+    }
+
+    fn parse_words(list: &str) -> Arc<[String]> {
+        let mut seen = HashSet::new();
+        list.lines()
+            .map(|word| {
+                word.trim()
+                    .chars()
+                    .map(|c| if c.is_alphanumeric() { c } else { '_' })
+                    .collect::<String>()
+            })
+            .filter(|word| !word.is_empty() && seen.insert(word.clone()))
+            .collect()
+    }
+
+    pub fn name(&self, tag: Tag) -> String {
+        let bytes_per_word = if self.words.len() > 256 { 2 } else { 1 };
+        let mut name = String::new();
+        for chunk in tag.chunks(bytes_per_word).take(self.word_count) {
+            let idx = chunk.iter().fold(0, |idx, b| idx << 8 | *b as usize);
+            if !name.is_empty() {
+                name.push('_');
+            }
+            match self.words.get(idx % self.words.len().max(1)) {
+                Some(word) => name.push_str(word),
+                // Without any words, fall back to hex
+                None => name.push_str(&hex::encode(chunk)),
+            }
+        }
+        if self.checksum {
+            if !name.is_empty() {
+                name.push('_');
+            }
+            write!(name, "{:02x}", tag[tag.len() - 1]).unwrap();
+        }
+        name
+    }
+}
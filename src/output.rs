@@ -0,0 +1,109 @@
+//! What the `nettle` binary prints when given `--json`.
+//!
+//! These are kept apart from the binary so that scripts can rely on their shape. Commands that query a node, such as
+//! `status`, `peers` and `locate`, print the structures from [`http`](crate::http) as the node returned them.
+//!
+//! Results are printed to stdout, except for `download` writing its data to stdout, which prints [`Downloaded`] to
+//! stderr instead. Failures are printed to stderr as an [`ErrorReport`], and the exit code is that of its
+//! [`ErrorKind`].
+
+use crate::Tag;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use url::Url;
+
+/// Printed by `run` once the node knows its host URL.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Running {
+    pub name: String,
+    pub tag: Tag,
+    pub url: Url,
+}
+
+/// Printed by `upload`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Uploaded {
+    pub tag: Tag,
+    pub bytes: u64,
+}
+
+/// Printed by `download`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Downloaded {
+    pub tag: Tag,
+    pub bytes: u64,
+    /// The file that the data was written to, or `None` for stdout.
+    pub output: Option<PathBuf>,
+}
+
+/// Printed by `tag` for each file, and by `verify` for a file that matches.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tagged {
+    pub tag: Tag,
+    /// The file as it was given, where `-` is stdin.
+    pub file: PathBuf,
+}
+
+/// The ways in which a command can fail, each with its own exit code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// Anything not covered below.
+    Failure,
+    /// The data or node asked for doesn't exist.
+    NotFound,
+    /// The node couldn't be reached.
+    Unreachable,
+    /// The node refused the admin token, or wanted one.
+    Unauthorized,
+    /// The node runs a version of nettle that we can't talk to.
+    Incompatible,
+    /// A file didn't match its tag.
+    Mismatch,
+}
+
+impl ErrorKind {
+    pub const ALL: [Self; 6] = [
+        Self::Failure,
+        Self::NotFound,
+        Self::Unreachable,
+        Self::Unauthorized,
+        Self::Incompatible,
+        Self::Mismatch,
+    ];
+
+    /// The code that the binary exits with. 2 is left to argument errors.
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Self::Failure => 1,
+            Self::NotFound => 3,
+            Self::Unreachable => 4,
+            Self::Unauthorized => 5,
+            Self::Incompatible => 6,
+            Self::Mismatch => 7,
+        }
+    }
+}
+
+/// Printed to stderr when a command fails.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorReport {
+    pub error: ErrorDetail,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorDetail {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ErrorReport {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            error: ErrorDetail {
+                kind,
+                message: message.into(),
+            },
+        }
+    }
+}
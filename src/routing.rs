@@ -0,0 +1,238 @@
+//! A node's routing table: the peers it knows, bucketed by their distance from it.
+//!
+//! Peers are indexed by identity and by [level](Tag::bucket_index), and every change goes through [`RoutingTable`]
+//! so that the indices always agree with each other.
+
+use crate::{Capabilities, PublicId, SignedAddr, Tag};
+use rand::prelude::*;
+use slotmap::SlotMap;
+use std::{collections::HashMap, time::Duration};
+
+/// The most peers that a routing table keeps at each level.
+pub const MAX_LEVEL_PEERS: usize = 2;
+
+slotmap::new_key_type! { struct PeerIdx; }
+
+/// A peer in a routing table.
+#[derive(Clone, Debug)]
+pub struct Peer<A> {
+    pub id: PublicId,
+    pub addr: A,
+    /// The most recently measured round trip time.
+    pub ping: Duration,
+    /// The peer's most recent record of its own address, which we pass on to others in its place.
+    pub record: SignedAddr<A>,
+    /// The optional features that both we and the peer support.
+    pub capabilities: Capabilities,
+    /// How far ahead of our clock the peer's is, in milliseconds, as most recently estimated, if it has been.
+    pub clock_offset: Option<i64>,
+}
+
+/// What [`RoutingTable::insert`] did with a peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InsertOutcome {
+    /// The peer was added at this level.
+    Inserted { level: usize },
+    /// The peer was already in the table, and its ping and capabilities were updated.
+    Updated,
+    /// The peer was added at this level in place of another, which was evicted to keep within the table's limit.
+    Replaced { level: usize, evicted: PublicId },
+    /// The peer's level already holds [`MAX_LEVEL_PEERS`] peers.
+    Full { level: usize },
+    /// The table holds as many peers as it's limited to, and none of them are more expendable than the newcomer.
+    AtLimit { level: usize },
+    /// The peer shares our tag, so has no level.
+    Ours,
+}
+
+pub struct RoutingTable<A> {
+    self_tag: Tag,
+    peers: SlotMap<PeerIdx, Peer<A>>,
+    by_id: HashMap<PublicId, PeerIdx>,
+    by_level: [Vec<PeerIdx>; 256],
+    max_peers: Option<usize>,
+}
+
+impl<A> RoutingTable<A> {
+    /// An empty table for a node with the given tag.
+    pub fn new(self_tag: Tag) -> Self {
+        const EMPTY: Vec<PeerIdx> = Vec::new();
+        Self {
+            self_tag,
+            peers: SlotMap::default(),
+            by_id: HashMap::default(),
+            by_level: [EMPTY; 256],
+            max_peers: None,
+        }
+    }
+
+    /// The most peers that the table keeps in total, if it's limited beyond the limit on each level.
+    pub fn max_peers(&self) -> Option<usize> {
+        self.max_peers
+    }
+
+    /// Limit the number of peers that the table keeps in total, or lift the limit with `None`. Peers beyond a new limit
+    /// are evicted, most expendable first, and returned.
+    pub fn set_max_peers(&mut self, max_peers: Option<usize>) -> Vec<Peer<A>> {
+        self.max_peers = max_peers;
+        let mut evicted = Vec::new();
+        while max_peers.is_some_and(|max| self.len() > max) {
+            let Some(id) = self.expendable().map(|(_, id)| id) else {
+                break;
+            };
+            evicted.extend(self.remove(&id));
+        }
+        evicted
+    }
+
+    /// The tag that peers are bucketed by their distance from.
+    pub fn self_tag(&self) -> Tag {
+        self.self_tag
+    }
+
+    /// The level that a peer with the given identity belongs at, or `None` if it shares our tag.
+    pub fn level_of(&self, id: &PublicId) -> Option<usize> {
+        self.self_tag.bucket_index(id.tag)
+    }
+
+    /// Whether [`RoutingTable::insert`] would add a peer with the given identity, evicting another if need be.
+    pub fn has_room_for(&self, id: &PublicId) -> bool {
+        self.level_of(id).is_some_and(|level| {
+            self.bucket_len(level) < MAX_LEVEL_PEERS && self.room_at(level).is_ok()
+        }) && !self.contains(id)
+    }
+
+    // Whether a newcomer at `level` fits within the limit on our total peers: `Ok(None)` if it fits as it is,
+    // `Ok(Some(_))` if it fits in place of that peer, and `Err(())` if it doesn't fit at all
+    fn room_at(&self, level: usize) -> Result<Option<PublicId>, ()> {
+        if self.max_peers.is_none_or(|max| self.len() < max) {
+            return Ok(None);
+        }
+        match self.expendable() {
+            // Levels are ranked by how full they are and then how far, so a newcomer only displaces a peer from a
+            // level that would still be more expendable than its own
+            Some((victim, id))
+                if (self.bucket_len(level) + 1, level) < (self.bucket_len(victim), victim) =>
+            {
+                Ok(Some(id))
+            }
+            _ => Err(()),
+        }
+    }
+
+    // The level whose peers we can best afford to lose, and the slowest peer at it
+    fn expendable(&self) -> Option<(usize, PublicId)> {
+        let level = (0..self.by_level.len())
+            .filter(|level| self.bucket_len(*level) > 0)
+            .max_by_key(|level| (self.bucket_len(*level), *level))?;
+        let slowest = self.by_level[level]
+            .iter()
+            .map(|idx| &self.peers[*idx])
+            .max_by_key(|peer| peer.ping)?;
+        Some((level, slowest.id.clone()))
+    }
+
+    /// Add a peer, or update the one with the same identity.
+    ///
+    /// The capacity of the peer's level is checked at the same time, so it can't be overfilled by peers that were each
+    /// found to have room before any of them were inserted.
+    pub fn insert(&mut self, peer: Peer<A>) -> InsertOutcome {
+        let Some(level) = self.level_of(&peer.id) else {
+            return InsertOutcome::Ours;
+        };
+        if let Some(idx) = self.by_id.get(&peer.id) {
+            let existing = &mut self.peers[*idx];
+            existing.ping = peer.ping;
+            existing.capabilities = peer.capabilities;
+            return InsertOutcome::Updated;
+        }
+        if self.by_level[level].len() >= MAX_LEVEL_PEERS {
+            return InsertOutcome::Full { level };
+        }
+        let Ok(victim) = self.room_at(level) else {
+            return InsertOutcome::AtLimit { level };
+        };
+        let evicted = victim.and_then(|victim| Some(self.remove(&victim)?.id));
+        let id = peer.id.clone();
+        let idx = self.peers.insert(peer);
+        self.by_id.insert(id, idx);
+        self.by_level[level].push(idx);
+        match evicted {
+            Some(evicted) => InsertOutcome::Replaced { level, evicted },
+            None => InsertOutcome::Inserted { level },
+        }
+    }
+
+    /// Remove the peer with the given identity, returning it if it was in the table.
+    pub fn remove(&mut self, id: &PublicId) -> Option<Peer<A>> {
+        let idx = self.by_id.remove(id)?;
+        let peer = self.peers.remove(idx)?;
+        if let Some(level) = self.level_of(id) {
+            self.by_level[level].retain(|other| *other != idx);
+        }
+        Some(peer)
+    }
+
+    /// Re-bucket every peer by its distance from `self_tag`, as after rotating our identity.
+    ///
+    /// Peers that no longer fit, because they share the new tag, their new level is already full or they're beyond
+    /// the limit on our total peers, are removed and returned.
+    pub fn rekey(&mut self, self_tag: Tag) -> Vec<Peer<A>> {
+        let peers = std::mem::take(&mut self.peers);
+        let max_peers = self.max_peers;
+        *self = Self::new(self_tag);
+        let mut evicted = Vec::new();
+        for (_, peer) in peers {
+            if self.has_room_for(&peer.id) {
+                self.insert(peer);
+            } else {
+                evicted.push(peer);
+            }
+        }
+        evicted.extend(self.set_max_peers(max_peers));
+        evicted
+    }
+
+    pub fn get(&self, id: &PublicId) -> Option<&Peer<A>> {
+        Some(&self.peers[*self.by_id.get(id)?])
+    }
+
+    pub fn get_mut(&mut self, id: &PublicId) -> Option<&mut Peer<A>> {
+        Some(&mut self.peers[*self.by_id.get(id)?])
+    }
+
+    pub fn contains(&self, id: &PublicId) -> bool {
+        self.by_id.contains_key(id)
+    }
+
+    /// Up to `k` peers, closest to `tag` first.
+    pub fn closest(&self, tag: Tag, k: usize) -> Vec<&Peer<A>> {
+        let mut peers = self.peers.values().collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.id.tag.dist_to(tag));
+        peers.truncate(k);
+        peers
+    }
+
+    /// A peer chosen uniformly at random, if we have any.
+    pub fn random(&self, rng: &mut impl Rng) -> Option<&Peer<A>> {
+        self.peers.values().choose(rng)
+    }
+
+    /// The number of peers at `level`.
+    pub fn bucket_len(&self, level: usize) -> usize {
+        self.by_level[level].len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Every peer, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Peer<A>> {
+        self.peers.values()
+    }
+}
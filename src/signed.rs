@@ -0,0 +1,95 @@
+use crate::{identity::Signature, PrivateId, PublicId};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// How far a message's timestamp may be from our own clock before we refuse it.
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SignatureError {
+    #[error("signature does not match the sender's key")]
+    Invalid,
+    #[error("message timestamp is too far from the current time")]
+    Expired,
+    #[error("message has already been received")]
+    Replayed,
+}
+
+/// An address that a node has vouched for, so that others can pass it on without being able to forge it.
+///
+/// The time at which the record was issued is the timestamp of the [`Signed`] message containing it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddrRecord<A> {
+    pub addr: A,
+    /// Milliseconds since the unix epoch, after which the record should no longer be trusted.
+    pub expires: u64,
+}
+
+/// A node's address, signed by the node itself.
+pub type SignedAddr<A> = Signed<AddrRecord<A>>;
+
+/// A message signed by its sender.
+///
+/// The signature covers the sender, the timestamp, the nonce, and the body, so none of them can be tampered with.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Signed<T> {
+    pub sender: PublicId,
+    /// Milliseconds since the unix epoch, according to the sender.
+    pub timestamp: u64,
+    /// A random 128-bit id for the message, so that replays and retransmissions of it can be recognised.
+    pub nonce: u128,
+    pub body: T,
+    pub signature: Signature,
+}
+
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+impl<T: Serialize> Signed<T> {
+    pub async fn new(id: &PrivateId, timestamp: u64, nonce: u128, body: T) -> Self {
+        let signature = id
+            .sign(&Self::canonical(&id.pub_id, timestamp, nonce, &body))
+            .await;
+        Self {
+            sender: id.pub_id.clone(),
+            timestamp,
+            nonce,
+            body,
+            signature,
+        }
+    }
+
+    // The bytes that are actually signed
+    fn canonical(sender: &PublicId, timestamp: u64, nonce: u128, body: &T) -> Vec<u8> {
+        serde_json::to_vec(&(sender.tag, timestamp, nonce, body))
+            .expect("message could not be encoded")
+    }
+
+    /// Check the signature, without regard for when the message was sent or whether it has been seen before.
+    pub fn verify(&self) -> Result<(), SignatureError> {
+        let bytes = Self::canonical(&self.sender, self.timestamp, self.nonce, &self.body);
+        if self.sender.verify(&bytes, &self.signature) {
+            Ok(())
+        } else {
+            Err(SignatureError::Invalid)
+        }
+    }
+}
+
+impl<A: Serialize> SignedAddr<A> {
+    /// Check that the record was signed by the node it describes, and that it is still current.
+    pub fn verify_record(&self) -> Result<(), SignatureError> {
+        self.verify()?;
+        let now = now_millis();
+        if self.body.expires <= now || self.timestamp > now + MAX_CLOCK_SKEW.as_millis() as u64 {
+            Err(SignatureError::Expired)
+        } else {
+            Ok(())
+        }
+    }
+}
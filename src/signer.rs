@@ -0,0 +1,74 @@
+use crate::{Signature, Tag};
+use rsa::{Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use std::future::Future;
+
+/// Something that can sign on behalf of an identity, such as a key in memory or one held by a hardware security
+/// module or agent.
+///
+/// Signatures are RSA PKCS#1 v1.5 signatures, without a digest prefix, over the SHA3-256 digest of the message (see
+/// [`Tag::digest`]).
+#[async_trait::async_trait]
+pub trait Signer: Send + Sync {
+    /// The public half of the key that signatures are made with.
+    fn public_key(&self) -> &RsaPublicKey;
+
+    async fn sign(&self, bytes: &[u8]) -> Signature;
+
+    /// The private key, if it is held in process memory. Identities without one can sign, but can't decrypt or be
+    /// saved.
+    fn private_key(&self) -> Option<&RsaPrivateKey> {
+        None
+    }
+}
+
+/// The default signer, with the key held in memory.
+#[async_trait::async_trait]
+impl Signer for RsaPrivateKey {
+    fn public_key(&self) -> &RsaPublicKey {
+        self.as_ref()
+    }
+
+    async fn sign(&self, bytes: &[u8]) -> Signature {
+        RsaPrivateKey::sign(self, Pkcs1v15Sign::new_unprefixed(), &*Tag::digest(bytes))
+            .expect("key is too small to sign a digest")
+            .into()
+    }
+
+    fn private_key(&self) -> Option<&RsaPrivateKey> {
+        Some(self)
+    }
+}
+
+/// A signer that hands each message to a callback, so that signing can be bridged to an ssh-agent, a PKCS#11 token,
+/// or anything else that holds the key.
+///
+/// The callback is given the message itself, not its digest.
+pub struct CallbackSigner<F> {
+    key: RsaPublicKey,
+    callback: F,
+}
+
+impl<F, Fut> CallbackSigner<F>
+where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync,
+    Fut: Future<Output = Signature> + Send,
+{
+    pub fn new(key: RsaPublicKey, callback: F) -> Self {
+        Self { key, callback }
+    }
+}
+
+#[async_trait::async_trait]
+impl<F, Fut> Signer for CallbackSigner<F>
+where
+    F: Fn(Vec<u8>) -> Fut + Send + Sync,
+    Fut: Future<Output = Signature> + Send,
+{
+    fn public_key(&self) -> &RsaPublicKey {
+        &self.key
+    }
+
+    async fn sign(&self, bytes: &[u8]) -> Signature {
+        (self.callback)(bytes.to_vec()).await
+    }
+}
@@ -0,0 +1,337 @@
+//! Support for simulating networks of in-memory nodes, primarily for use in tests.
+
+use crate::{mem, routing::MAX_LEVEL_PEERS, Node, PrivateId, PublicId, Tag};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Duration,
+};
+
+/// How newly spawned nodes choose their initial peer.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Topology {
+    /// Each node bootstraps from a randomly chosen existing node.
+    Random,
+    /// Each node bootstraps from the first node in the simulation.
+    Star,
+    /// Each node bootstraps from the node spawned before it.
+    Line,
+    /// Nodes have no initial peers.
+    Isolated,
+}
+
+struct SimNode {
+    node: Arc<Node<mem::Mem>>,
+    addr: mem::Addr,
+    alive: bool,
+}
+
+/// A simulated network of nodes using the [`mem`] backend.
+pub struct Sim {
+    network: mem::Network,
+    nodes: Vec<SimNode>,
+    // Only present for seeded simulations, in which case nodes' identities and behaviour are derived from it
+    seed_rng: Option<ChaCha8Rng>,
+    sign_messages: bool,
+    peer_hints: bool,
+}
+
+impl Sim {
+    pub fn new(network: mem::Network) -> Self {
+        Self {
+            network,
+            nodes: Vec::new(),
+            seed_rng: None,
+            sign_messages: true,
+            peer_hints: true,
+        }
+    }
+
+    /// Create a simulation in which the identity and random choices of every node are derived from `seed`.
+    pub fn with_seed(network: mem::Network, seed: u64) -> Self {
+        Self {
+            seed_rng: Some(ChaCha8Rng::seed_from_u64(seed)),
+            ..Self::new(network)
+        }
+    }
+
+    /// Set whether nodes spawned from now on sign their messages. Signing is enabled by default, but is costly enough
+    /// that large or long-running simulations may want to do without it.
+    pub fn set_signing(&mut self, sign_messages: bool) {
+        self.sign_messages = sign_messages;
+    }
+
+    /// Set whether nodes spawned from now on follow the peers passed on to them in pongs. See [`Node::set_peer_hints`].
+    pub fn set_peer_hints(&mut self, peer_hints: bool) {
+        self.peer_hints = peer_hints;
+    }
+
+    pub fn network(&self) -> &mem::Network {
+        &self.network
+    }
+
+    /// The number of nodes that have been spawned, including those that have since been killed.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn node(&self, idx: usize) -> &Arc<Node<mem::Mem>> {
+        &self.nodes[idx].node
+    }
+
+    pub fn addr(&self, idx: usize) -> &mem::Addr {
+        &self.nodes[idx].addr
+    }
+
+    pub fn is_alive(&self, idx: usize) -> bool {
+        self.nodes[idx].alive
+    }
+
+    /// Every node that hasn't been killed.
+    pub fn nodes(&self) -> impl Iterator<Item = &Arc<Node<mem::Mem>>> + '_ {
+        self.nodes.iter().filter(|n| n.alive).map(|n| &n.node)
+    }
+
+    // Pick up to `n` distinct random nodes that haven't been killed
+    fn choose_alive(&mut self, n: usize) -> Vec<usize> {
+        let alive = (0..self.nodes.len()).filter(|idx| self.nodes[*idx].alive);
+        match &mut self.seed_rng {
+            Some(rng) => alive.choose_multiple(rng, n),
+            None => alive.choose_multiple(&mut thread_rng(), n),
+        }
+    }
+
+    /// Spawn `n` new nodes, connected to the existing nodes according to `topology`.
+    pub async fn spawn_nodes(&mut self, n: usize, topology: Topology) {
+        for _ in 0..n {
+            let initial_peer = match topology {
+                Topology::Random => self.choose_alive(1).pop(),
+                Topology::Star => Some(0).filter(|_| !self.nodes.is_empty()),
+                Topology::Line => self.nodes.len().checked_sub(1),
+                Topology::Isolated => None,
+            };
+            let initial_peers = initial_peer
+                .map(|idx| self.nodes[idx].addr.clone())
+                .into_iter()
+                .collect();
+            self.spawn_node(initial_peers).await;
+        }
+    }
+
+    /// Spawn a single node with the given initial peers, returning its index.
+    pub async fn spawn_node(&mut self, initial_peers: Vec<mem::Addr>) -> usize {
+        let node = self.create_node(initial_peers).await;
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    async fn create_node(&mut self, initial_peers: Vec<mem::Addr>) -> SimNode {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: self.network.clone(),
+            sign_messages: self.sign_messages,
+        };
+        let node = match &mut self.seed_rng {
+            Some(rng) => {
+                let (id_seed, node_seed) = (rng.gen::<[u8; 32]>(), rng.gen());
+                let private_id = PrivateId::from_seed_async(id_seed).await;
+                Node::with_seed(private_id, addr.clone(), initial_peers, config, node_seed).await
+            }
+            None => {
+                let private_id = PrivateId::generate_async().await;
+                Node::new(private_id, addr.clone(), initial_peers, config).await
+            }
+        }
+        .unwrap();
+        node.set_peer_hints(self.peer_hints);
+        tokio::task::spawn(node.clone().run());
+        SimNode {
+            node,
+            addr,
+            alive: true,
+        }
+    }
+
+    /// Kill a node, taking it off the network. Its data is lost and messages sent to it fail.
+    pub fn kill(&mut self, idx: usize) {
+        let n = &mut self.nodes[idx];
+        if n.alive {
+            n.alive = false;
+            n.node.shutdown();
+            self.network.disconnect(&n.addr);
+        }
+    }
+
+    /// Replace a node with a new node with a fresh identity, bootstrapping from a few random living nodes.
+    ///
+    /// A [`mem::Addr`] is bound to a single node for its lifetime, so the new node is given a new address.
+    pub async fn restart(&mut self, idx: usize) {
+        self.kill(idx);
+        // Use several initial peers, since any one of them may leave too
+        let initial_peers = self
+            .choose_alive(3)
+            .into_iter()
+            .map(|other| self.nodes[other].addr.clone())
+            .collect();
+        self.nodes[idx] = self.create_node(initial_peers).await;
+    }
+
+    /// Restart a randomly chosen `fraction` of the living nodes, one at a time, spread evenly over `period`.
+    pub async fn churn(&mut self, fraction: f64, period: Duration) {
+        let mut victims = (0..self.nodes.len())
+            .filter(|idx| self.nodes[*idx].alive)
+            .collect::<Vec<_>>();
+        match &mut self.seed_rng {
+            Some(rng) => victims.shuffle(rng),
+            None => victims.shuffle(&mut thread_rng()),
+        }
+        victims.truncate((victims.len() as f64 * fraction).round() as usize);
+
+        let interval = period / victims.len().max(1) as u32;
+        for idx in victims {
+            tokio::time::sleep(interval).await;
+            self.restart(idx).await;
+        }
+    }
+
+    /// Poll `condition` until it holds, returning whether it did so before `timeout` elapsed.
+    pub async fn run_until(
+        &self,
+        mut condition: impl FnMut(&Self) -> bool,
+        timeout: Duration,
+    ) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if condition(self) {
+                break true;
+            } else if tokio::time::Instant::now() >= deadline {
+                break false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Every (node, peer) pair in the network's routing tables.
+    pub fn topology(&self) -> BTreeSet<(Tag, Tag)> {
+        self.nodes()
+            .flat_map(|node| {
+                node.get_peers()
+                    .into_iter()
+                    .map(|peer| (node.id().tag, peer.tag))
+            })
+            .collect()
+    }
+
+    /// Whether every node has at least one peer.
+    pub fn all_have_peers(&self) -> bool {
+        self.nodes().all(|node| !node.get_peers().is_empty())
+    }
+
+    /// Whether every node can be reached from every other by following peer links in either direction.
+    pub fn is_connected(&self) -> bool {
+        graph_connected(&self.nodes().cloned().collect::<Vec<_>>())
+    }
+
+    /// The average fraction of capacity used by each node's non-empty buckets, between `0.0` and `1.0`.
+    pub fn average_bucket_fill(&self) -> f64 {
+        let (filled, buckets) = self
+            .nodes()
+            .flat_map(|node| {
+                let mut buckets = HashMap::<u16, usize>::new();
+                for peer in node.peer_info() {
+                    *buckets.entry(peer.level).or_default() += 1;
+                }
+                buckets.into_values()
+            })
+            .fold((0, 0), |(filled, buckets), n| (filled + n, buckets + 1));
+        if buckets == 0 {
+            0.0
+        } else {
+            filled as f64 / (buckets * MAX_LEVEL_PEERS) as f64
+        }
+    }
+
+    /// The average [`Node::routing_completeness`] across all nodes.
+    pub fn average_routing_completeness(&self) -> f64 {
+        self.nodes()
+            .map(|node| node.routing_completeness())
+            .sum::<f64>()
+            / self.len().max(1) as f64
+    }
+
+    /// Ask every node to shut down.
+    pub fn shutdown(&self) {
+        for node in self.nodes() {
+            node.shutdown();
+        }
+    }
+}
+
+// The undirected graph formed by the nodes' peer links, with each node's neighbours
+fn peer_graph(nodes: &[Arc<Node<mem::Mem>>]) -> HashMap<PublicId, HashSet<PublicId>> {
+    let mut links = HashMap::<PublicId, HashSet<PublicId>>::new();
+    for node in nodes {
+        links.entry(node.id()).or_default();
+        for peer in node.peer_info() {
+            links.entry(node.id()).or_default().insert(peer.id.clone());
+            links.entry(peer.id).or_default().insert(node.id());
+        }
+    }
+    links
+}
+
+// The number of hops from `from` to every node reachable from it
+fn hops_from(
+    links: &HashMap<PublicId, HashSet<PublicId>>,
+    from: &PublicId,
+) -> HashMap<PublicId, usize> {
+    let mut hops = HashMap::from([(from.clone(), 0)]);
+    let mut queue = VecDeque::from([from.clone()]);
+    while let Some(id) = queue.pop_front() {
+        let next = hops[&id] + 1;
+        for neighbour in links.get(&id).into_iter().flatten() {
+            if !hops.contains_key(neighbour) {
+                hops.insert(neighbour.clone(), next);
+                queue.push_back(neighbour.clone());
+            }
+        }
+    }
+    hops
+}
+
+/// Whether every node can be reached from every other by following peer links in either direction.
+pub fn graph_connected(nodes: &[Arc<Node<mem::Mem>>]) -> bool {
+    match nodes.first() {
+        Some(first) => {
+            let hops = hops_from(&peer_graph(nodes), &first.id());
+            nodes.iter().all(|node| hops.contains_key(&node.id()))
+        }
+        None => true,
+    }
+}
+
+/// The average number of peer links on the shortest path between each pair of nodes that can reach one another.
+///
+/// Returns `None` if no node can reach any other.
+pub fn average_path_length(nodes: &[Arc<Node<mem::Mem>>]) -> Option<f64> {
+    let links = peer_graph(nodes);
+    let ids = nodes.iter().map(|node| node.id()).collect::<HashSet<_>>();
+    let (total, paths) = nodes
+        .iter()
+        .flat_map(|node| hops_from(&links, &node.id()))
+        .filter(|(id, hops)| *hops > 0 && ids.contains(id))
+        .map(|(_, hops)| hops)
+        .fold((0, 0), |(total, paths), hops| (total + hops, paths + 1));
+    if paths == 0 {
+        None
+    } else {
+        Some(total as f64 / paths as f64)
+    }
+}
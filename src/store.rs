@@ -0,0 +1,706 @@
+//! A node's local data store.
+//!
+//! Entries are kept under the tag of their contents. They may be compressed at rest, but are always handed out as the
+//! bytes that their tag names, so nothing outside of the store ever sees the compressed form.
+//!
+//! A store may be given a budget of bytes, beyond which it evicts the entries used least recently, and a time to live,
+//! after which entries expire. Pinned entries are exempt from both, so are only dropped when asked.
+//!
+//! The store keeps track of which of its entries are [tree nodes](crate::tree) or
+//! [erasure-coded manifests](crate::erasure), and of the chunks that they refer to. Data stored only
+//! [as a chunk](Store::insert_chunk) is held on a lease of [`CHUNK_LEASE`], which whoever holds the entry referring
+//! to it [renews](Store::renew), wherever in the network that is. A chunk that nothing here refers to is dropped by
+//! [`Store::collect_garbage`] once its lease runs out. Data stored or pinned in its own right is never collected,
+//! whatever refers to it, so nobody can have another's data dropped by storing a tree node that lists it and then
+//! letting that go.
+//!
+//! Entries uploaded by others are charged to their [publisher](Publisher), who may be held to a [quota](Quotas).
+//!
+//! Entries may also be [cached](Store::insert_cached) copies of data owned by somebody else, kept because it's popular.
+//! Cached entries are evicted before any others, never displace them, and are left out of [`Store::tags`], since
+//! they're not ours to pass on. Storing or pinning the same data in earnest makes it an ordinary entry.
+
+use crate::{erasure::Manifest, tree::TreeNode, Tag, CHUNK_LEASE};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+/// A codec that stored data may be compressed with.
+///
+/// Without the `zstd` feature, nothing is compressed with [`Codec::Zstd`], and data is stored as it is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    Zstd,
+}
+
+impl Codec {
+    #[cfg(feature = "zstd")]
+    fn compress(self, data: &[u8], level: i32) -> Option<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::compress(data, level).ok(),
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    fn decompress(self, data: &[u8], len: usize) -> Option<Vec<u8>> {
+        match self {
+            Self::Zstd => zstd::bulk::decompress(data, len).ok(),
+        }
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    fn compress(self, _data: &[u8], _level: i32) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Never called, since nothing was compressed
+    #[cfg(not(feature = "zstd"))]
+    fn decompress(self, _data: &[u8], _len: usize) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// How a [`Store`] compresses the data put in it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Compression {
+    pub codec: Codec,
+    /// The compression level passed to the codec.
+    pub level: i32,
+    /// The fraction of an entry's size, between `0.0` and `1.0`, that compressing it must save for the compressed form
+    /// to be kept. Entries that don't compress this well are stored as they are.
+    pub min_gain: f64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Zstd,
+            level: 3,
+            min_gain: 0.1,
+        }
+    }
+}
+
+/// Who an entry is charged to.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Publisher {
+    /// Whoever sent uploads that weren't signed, all together.
+    Anonymous,
+    /// The node whose identity has this tag.
+    Node(Tag),
+}
+
+/// The most bytes that each publisher may have stored with us at once, before any compression.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Quotas {
+    /// The quota of each node that signs its uploads, if any.
+    pub per_publisher: Option<usize>,
+    /// The quota shared by all anonymous uploads, if any. A quota of `0` refuses them entirely.
+    pub anonymous: Option<usize>,
+}
+
+impl Quotas {
+    pub fn of(&self, publisher: Publisher) -> Option<usize> {
+        match publisher {
+            Publisher::Anonymous => self.anonymous,
+            Publisher::Node(_) => self.per_publisher,
+        }
+    }
+}
+
+// An entry's bytes as stored, flagged with the codec that they're compressed with, if any
+struct Entry {
+    codec: Option<Codec>,
+    bytes: Bytes,
+    len: usize,
+    pinned: bool,
+    // When the entry was last used, as a tick of the store's clock and as an instant
+    used: u64,
+    used_at: Instant,
+    stored_at: Instant,
+    // When the entry's lease was last renewed, which only matters if it's a chunk
+    renewed_at: Instant,
+    // The chunks that the entry refers to, if it's a tree node or a manifest
+    refs: Vec<Tag>,
+    // Whether the entry was stored or pinned in its own right, rather than only as a chunk, so is never collected
+    independent: bool,
+    publisher: Option<Publisher>,
+    // Whether the entry is a cached copy of somebody else's data
+    cached: bool,
+}
+
+/// A summary of an entry in a [`Store`], as listed by [`Store::entries`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryInfo {
+    pub tag: Tag,
+    /// The length of the data.
+    pub len: usize,
+    /// The number of bytes that the entry takes up as stored, after any compression.
+    pub stored_len: usize,
+    pub pinned: bool,
+    /// Whether the entry is a cached copy of data that somebody else owns.
+    #[serde(default)]
+    pub cached: bool,
+}
+
+/// The data that a node holds, by tag.
+#[derive(Default)]
+pub struct Store {
+    entries: HashMap<Tag, Entry>,
+    // Unpinned entries by when they were last used, least recently first, with cached entries kept apart
+    recency: BTreeMap<u64, Tag>,
+    cache_recency: BTreeMap<u64, Tag>,
+    clock: u64,
+    compression: Option<Compression>,
+    budget: Option<usize>,
+    ttl: Option<Duration>,
+    stored_bytes: usize,
+    logical_bytes: usize,
+    pinned_bytes: usize,
+    cached_bytes: usize,
+    // The number of references to each chunk that's referred to at all, whether or not we hold it
+    references: HashMap<Tag, usize>,
+    // The entries stored as chunks, which are collected once their lease runs out, in no particular order. Those that
+    // have since gone or been stored in their own right are only dropped from here when next looked at.
+    chunks: VecDeque<Tag>,
+    quotas: Quotas,
+    // The bytes charged to each publisher with anything stored
+    usage: HashMap<Publisher, usize>,
+}
+
+impl Store {
+    /// An empty store, compressing what's put in it if `compression` is given.
+    pub fn new(compression: Option<Compression>) -> Self {
+        Self {
+            compression,
+            ..Self::default()
+        }
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Change how entries are compressed from now on. Entries already stored are left as they are.
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    pub fn budget(&self) -> Option<usize> {
+        self.budget
+    }
+
+    /// Limit the number of bytes that entries may take up as stored, or lift the limit with `None`. Unpinned entries
+    /// are evicted, least recently used first, until the store is within its new budget.
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    pub fn ttl(&self) -> Option<Duration> {
+        self.ttl
+    }
+
+    /// Have unpinned entries expire once they've been stored for `ttl`, or keep them indefinitely with `None`.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    pub fn quotas(&self) -> Quotas {
+        self.quotas
+    }
+
+    /// Change the publishers' quotas. Entries already stored are kept, even if their publisher is now over quota.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = quotas;
+    }
+
+    /// Whether `publisher` may store another `len` bytes without going over its quota.
+    pub fn has_quota_for(&self, publisher: Publisher, len: usize) -> bool {
+        self.quotas
+            .of(publisher)
+            .is_none_or(|quota| self.usage(publisher) + len <= quota)
+    }
+
+    /// The number of bytes charged to `publisher`, before any compression.
+    pub fn usage(&self, publisher: Publisher) -> usize {
+        self.usage.get(&publisher).copied().unwrap_or(0)
+    }
+
+    /// Up to `n` of the publishers with the most bytes charged to them, most first.
+    pub fn top_publishers(&self, n: usize) -> Vec<(Publisher, usize)> {
+        let mut usage = self
+            .usage
+            .iter()
+            .map(|(publisher, bytes)| (*publisher, *bytes))
+            .collect::<Vec<_>>();
+        usage.sort_by_key(|(_, bytes)| std::cmp::Reverse(*bytes));
+        usage.truncate(n);
+        usage
+    }
+
+    /// Store `data` under `tag`, returning whether it's new to us rather than a copy of data we already had.
+    ///
+    /// If the store goes over budget, the least recently used unpinned entries are evicted. Data too large to fit
+    /// alongside the pinned entries isn't stored at all, and `false` is returned.
+    ///
+    /// Uncompressed entries share their memory with `data`.
+    pub fn insert(&mut self, tag: Tag, data: Bytes) -> bool {
+        self.insert_from(tag, data, None)
+    }
+
+    /// Like [`Store::insert`], charging the entry to `publisher` if it's new. Quotas are left for the caller to check
+    /// with [`Store::has_quota_for`].
+    ///
+    /// A cached copy of the data becomes an ordinary entry, and counts as new.
+    pub fn insert_from(&mut self, tag: Tag, data: Bytes, publisher: Option<Publisher>) -> bool {
+        self.insert_as(tag, data, publisher, true)
+    }
+
+    /// Like [`Store::insert_from`], but keeping the data only as a chunk of a tree node or manifest, so that it's
+    /// dropped by [`Store::collect_garbage`] once its lease runs out. Data already stored in its own right stays that
+    /// way, and a chunk already stored has its lease renewed.
+    pub fn insert_chunk(&mut self, tag: Tag, data: Bytes, publisher: Option<Publisher>) -> bool {
+        self.insert_as(tag, data, publisher, false)
+    }
+
+    fn insert_as(
+        &mut self,
+        tag: Tag,
+        data: Bytes,
+        publisher: Option<Publisher>,
+        independent: bool,
+    ) -> bool {
+        if self.is_expired(tag) {
+            self.remove(tag);
+        }
+        if let Some(entry) = self.entries.get_mut(&tag) {
+            entry.independent |= independent;
+            // Another copy of a chunk renews its lease
+            entry.renewed_at = Instant::now();
+            let promoted = self.promote(tag);
+            if promoted {
+                let entry = self.entries.get_mut(&tag).expect("entry is present");
+                entry.publisher = publisher;
+                if let Some(publisher) = publisher {
+                    *self.usage.entry(publisher).or_default() += entry.len;
+                }
+                // Cached entries are never collected, so it only now counts as a chunk
+                if !entry.independent {
+                    self.chunks.push_back(tag);
+                }
+            }
+            self.touch(tag);
+            return promoted;
+        }
+        self.store(tag, data, publisher, false, independent)
+    }
+
+    /// Record that `publisher` uploaded the data held under `tag` again, as [`Store::insert_from`] or
+    /// [`Store::insert_chunk`] would if given another copy of it, returning whether we held it at all.
+    ///
+    /// No copy is needed, so nothing is stored if the data is gone by the time this is called.
+    pub fn reinsert_from(&mut self, tag: Tag, publisher: Option<Publisher>, chunk: bool) -> bool {
+        if !self.contains(tag) {
+            return false;
+        }
+        self.insert_as(tag, Bytes::new(), publisher, !chunk);
+        true
+    }
+
+    /// Keep a cached copy of `data`, which somebody else owns, under `tag`, returning whether it's new to us.
+    ///
+    /// Cached data only takes up room that ordinary entries don't need, evicting other cached entries, least recently
+    /// used first, to make space. Data that won't fit isn't stored, and `false` is returned.
+    pub fn insert_cached(&mut self, tag: Tag, data: Bytes) -> bool {
+        if self.is_expired(tag) {
+            self.remove(tag);
+        }
+        if self.entries.contains_key(&tag) {
+            self.touch(tag);
+            return false;
+        }
+        self.store(tag, data, None, true, false)
+    }
+
+    fn store(
+        &mut self,
+        tag: Tag,
+        data: Bytes,
+        publisher: Option<Publisher>,
+        cached: bool,
+        independent: bool,
+    ) -> bool {
+        let len = data.len();
+        let refs = references(&data);
+        let compressed = self.compression.and_then(|compression| {
+            let compressed = compression.codec.compress(&data, compression.level)?;
+            let gain = 1.0 - compressed.len() as f64 / len.max(1) as f64;
+            (gain >= compression.min_gain).then(|| (compression.codec, Bytes::from(compressed)))
+        });
+        let (codec, bytes) = match compressed {
+            Some((codec, bytes)) => (Some(codec), bytes),
+            None => (None, data),
+        };
+        // Don't evict anything to make room for data that won't fit anyway. Cached data may only evict other cached
+        // data.
+        let reserved = if cached {
+            self.stored_bytes - self.cached_bytes
+        } else {
+            self.pinned_bytes
+        };
+        if self
+            .budget
+            .is_some_and(|budget| bytes.len() > budget.saturating_sub(reserved))
+        {
+            debug!("{} is too large to fit within our storage budget", tag);
+            return false;
+        }
+        self.stored_bytes += bytes.len();
+        self.logical_bytes += len;
+        if cached {
+            self.cached_bytes += bytes.len();
+        }
+        self.clock += 1;
+        let clock = self.clock;
+        self.recency_of(cached).insert(clock, tag);
+        for chunk in &refs {
+            *self.references.entry(*chunk).or_default() += 1;
+        }
+        let now = Instant::now();
+        self.entries.insert(
+            tag,
+            Entry {
+                codec,
+                bytes,
+                len,
+                pinned: false,
+                used: self.clock,
+                used_at: now,
+                stored_at: now,
+                renewed_at: now,
+                refs,
+                independent,
+                publisher,
+                cached,
+            },
+        );
+        if let Some(publisher) = publisher {
+            *self.usage.entry(publisher).or_default() += len;
+        }
+        if !cached && !independent {
+            self.chunks.push_back(tag);
+        }
+        self.evict();
+        true
+    }
+
+    /// The data stored under `tag`, decompressed if need be. Uncompressed entries are handed out without copying them.
+    ///
+    /// This counts as a use of the entry, so puts off its eviction.
+    pub fn get(&mut self, tag: Tag) -> Option<Bytes> {
+        if self.is_expired(tag) {
+            self.remove(tag);
+        }
+        self.touch(tag);
+        let entry = self.entries.get(&tag)?;
+        match entry.codec {
+            None => Some(entry.bytes.clone()),
+            Some(codec) => match codec.decompress(&entry.bytes, entry.len) {
+                Some(data) => Some(data.into()),
+                None => {
+                    error!("Could not decompress our copy of {}", tag);
+                    None
+                }
+            },
+        }
+    }
+
+    pub fn contains(&self, tag: Tag) -> bool {
+        self.entries.contains_key(&tag) && !self.is_expired(tag)
+    }
+
+    /// Drop the entry stored under `tag`, pinned or not, returning whether there was one.
+    pub fn remove(&mut self, tag: Tag) -> bool {
+        match self.entries.remove(&tag) {
+            Some(entry) => {
+                self.stored_bytes -= entry.bytes.len();
+                self.logical_bytes -= entry.len;
+                if entry.cached {
+                    self.cached_bytes -= entry.bytes.len();
+                }
+                if entry.pinned {
+                    self.pinned_bytes -= entry.bytes.len();
+                } else {
+                    self.recency_of(entry.cached).remove(&entry.used);
+                }
+                if let Some(publisher) = entry.publisher {
+                    if let Some(usage) = self.usage.get_mut(&publisher) {
+                        *usage -= entry.len;
+                        if *usage == 0 {
+                            self.usage.remove(&publisher);
+                        }
+                    }
+                }
+                for chunk in entry.refs {
+                    let count = self
+                        .references
+                        .get_mut(&chunk)
+                        .expect("chunk is referenced");
+                    *count -= 1;
+                    if *count == 0 {
+                        self.references.remove(&chunk);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Protect the entry stored under `tag` from eviction and expiry, returning whether there is one. A cached entry
+    /// becomes an ordinary one, and an entry stored as a chunk is kept in its own right from then on, so is never
+    /// collected, even once unpinned.
+    pub fn pin(&mut self, tag: Tag) -> bool {
+        if !self.contains(tag) {
+            return false;
+        }
+        self.promote(tag);
+        let entry = self.entries.get_mut(&tag).expect("entry is present");
+        entry.independent = true;
+        if !entry.pinned {
+            entry.pinned = true;
+            self.pinned_bytes += entry.bytes.len();
+            self.recency.remove(&entry.used);
+        }
+        true
+    }
+
+    /// Let the entry stored under `tag` be evicted or expire again, returning whether it was pinned.
+    ///
+    /// Its time to live starts afresh, and the store evicts what it must to get back within its budget.
+    pub fn unpin(&mut self, tag: Tag) -> bool {
+        let Some(entry) = self.entries.get_mut(&tag).filter(|entry| entry.pinned) else {
+            return false;
+        };
+        entry.pinned = false;
+        entry.stored_at = Instant::now();
+        self.pinned_bytes -= entry.bytes.len();
+        self.clock += 1;
+        entry.used = self.clock;
+        self.recency.insert(self.clock, tag);
+        self.evict();
+        true
+    }
+
+    pub fn is_pinned(&self, tag: Tag) -> bool {
+        self.entries.get(&tag).is_some_and(|entry| entry.pinned)
+    }
+
+    /// Whether the entry stored under `tag` was stored or pinned in its own right, rather than only as a chunk.
+    pub fn is_independent(&self, tag: Tag) -> bool {
+        self.entries
+            .get(&tag)
+            .is_some_and(|entry| entry.independent)
+    }
+
+    pub fn is_cached(&self, tag: Tag) -> bool {
+        self.entries.get(&tag).is_some_and(|entry| entry.cached)
+    }
+
+    /// Drop the unpinned entries that have outlived the store's time to live, returning how many there were.
+    pub fn expire(&mut self) -> usize {
+        let expired = self
+            .entries
+            .keys()
+            .copied()
+            .filter(|tag| self.is_expired(*tag))
+            .collect::<Vec<_>>();
+        for tag in &expired {
+            self.remove(*tag);
+        }
+        expired.len()
+    }
+
+    /// The chunks that the entries we hold refer to, whether or not we hold them too, in no particular order.
+    pub fn referenced(&self) -> impl Iterator<Item = Tag> + '_ {
+        self.references.keys().copied()
+    }
+
+    /// The number of entries that refer to `tag` as a chunk.
+    pub fn references(&self, tag: Tag) -> usize {
+        self.references.get(&tag).copied().unwrap_or(0)
+    }
+
+    /// Renew the lease of the chunk stored under `tag` for another [`CHUNK_LEASE`], returning whether we hold it.
+    pub fn renew(&mut self, tag: Tag) -> bool {
+        if !self.contains(tag) {
+            return false;
+        }
+        let entry = self.entries.get_mut(&tag).expect("entry is present");
+        entry.renewed_at = Instant::now();
+        true
+    }
+
+    /// Look at up to `batch` chunks, dropping those whose lease has run out, returning how many were dropped.
+    ///
+    /// Chunks that an entry we hold still refers to are kept, however long ago their lease was renewed. A chunk is
+    /// also kept until it has gone unused for `grace`, since a download that read the entry referring to it may yet
+    /// come for it. Chunks that have since been stored or pinned in their own right are left alone for good.
+    pub fn collect_garbage(&mut self, batch: usize, grace: Duration) -> usize {
+        let now = Instant::now();
+        let mut collected = 0;
+        // Chunks that are kept go to the back of the queue, so look at each no more than once
+        for _ in 0..batch.min(self.chunks.len()) {
+            let Some(tag) = self.chunks.pop_front() else {
+                break;
+            };
+            let Some(entry) = self.entries.get(&tag).filter(|entry| !entry.independent) else {
+                continue;
+            };
+            if self.references.contains_key(&tag)
+                || now.duration_since(entry.renewed_at) < CHUNK_LEASE
+                || now.duration_since(entry.used_at) < grace
+            {
+                self.chunks.push_back(tag);
+                continue;
+            }
+            debug!("Collecting chunk {}, whose lease has run out", tag);
+            self.remove(tag);
+            collected += 1;
+        }
+        collected
+    }
+
+    /// A summary of every entry, in no particular order.
+    pub fn entries(&self) -> impl Iterator<Item = EntryInfo> + '_ {
+        self.entries
+            .iter()
+            .filter(|(tag, _)| !self.is_expired(**tag))
+            .map(|(tag, entry)| EntryInfo {
+                tag: *tag,
+                len: entry.len,
+                stored_len: entry.bytes.len(),
+                pinned: entry.pinned,
+                cached: entry.cached,
+            })
+    }
+
+    /// The tags of every entry that isn't a cached copy, in no particular order.
+    pub fn tags(&self) -> impl Iterator<Item = Tag> + '_ {
+        self.entries
+            .iter()
+            .filter(|(tag, entry)| !entry.cached && !self.is_expired(**tag))
+            .map(|(tag, _)| *tag)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of bytes that the entries take up as stored, after any compression.
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes
+    }
+
+    /// The number of bytes that the entries hold, before any compression.
+    pub fn logical_bytes(&self) -> usize {
+        self.logical_bytes
+    }
+
+    /// The number of bytes that pinned entries take up as stored, after any compression.
+    pub fn pinned_bytes(&self) -> usize {
+        self.pinned_bytes
+    }
+
+    /// The number of bytes that cached entries take up as stored, after any compression.
+    pub fn cached_bytes(&self) -> usize {
+        self.cached_bytes
+    }
+
+    fn recency_of(&mut self, cached: bool) -> &mut BTreeMap<u64, Tag> {
+        if cached {
+            &mut self.cache_recency
+        } else {
+            &mut self.recency
+        }
+    }
+
+    // Make a cached entry an ordinary one, returning whether it was cached
+    fn promote(&mut self, tag: Tag) -> bool {
+        let Some(entry) = self.entries.get_mut(&tag).filter(|entry| entry.cached) else {
+            return false;
+        };
+        entry.cached = false;
+        self.cached_bytes -= entry.bytes.len();
+        if !entry.pinned && self.cache_recency.remove(&entry.used).is_some() {
+            self.recency.insert(entry.used, tag);
+        }
+        true
+    }
+
+    // Count the entry as just used, if it's there and unpinned
+    fn touch(&mut self, tag: Tag) {
+        if let Some(entry) = self.entries.get_mut(&tag) {
+            entry.used_at = Instant::now();
+            if !entry.pinned {
+                let recency = if entry.cached {
+                    &mut self.cache_recency
+                } else {
+                    &mut self.recency
+                };
+                recency.remove(&entry.used);
+                self.clock += 1;
+                entry.used = self.clock;
+                recency.insert(self.clock, tag);
+            }
+        }
+    }
+
+    fn is_expired(&self, tag: Tag) -> bool {
+        match (self.entries.get(&tag), self.ttl) {
+            (Some(entry), Some(ttl)) => !entry.pinned && entry.stored_at.elapsed() >= ttl,
+            _ => false,
+        }
+    }
+
+    // Evict unpinned entries, cached ones first and then least recently used first, until we're within budget
+    fn evict(&mut self) {
+        let Some(budget) = self.budget else {
+            return;
+        };
+        while self.stored_bytes > budget {
+            let Some((_, &tag)) = self
+                .cache_recency
+                .first_key_value()
+                .or_else(|| self.recency.first_key_value())
+            else {
+                break;
+            };
+            debug!("Evicting {} to stay within our storage budget", tag);
+            self.remove(tag);
+        }
+    }
+}
+
+// The chunks that `data` refers to, if it's a tree node or an erasure-coded manifest
+fn references(data: &[u8]) -> Vec<Tag> {
+    if let Ok(node) = TreeNode::decode(data) {
+        node.children
+    } else if let Ok(manifest) = Manifest::decode(data) {
+        manifest.shards
+    } else {
+        Vec::new()
+    }
+}
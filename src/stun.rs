@@ -0,0 +1,195 @@
+//! A minimal STUN client, just enough to learn the address that a node appears to have from outside its NAT.
+//!
+//! Only the binding request of [RFC 5389](https://www.rfc-editor.org/rfc/rfc5389) is supported, without
+//! authentication or message integrity. Responses are read from `XOR-MAPPED-ADDRESS`, falling back to the older
+//! `MAPPED-ADDRESS`.
+
+use rand::prelude::*;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::net::{lookup_host, UdpSocket};
+
+/// Public STUN servers to ask when none are configured.
+pub const DEFAULT_SERVERS: &[&str] = &[
+    "stun.l.google.com:19302",
+    "stun1.l.google.com:19302",
+    "stun.cloudflare.com:3478",
+];
+
+const MAGIC_COOKIE: u32 = 0x2112_a442;
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const MAPPED_ADDRESS: u16 = 0x0001;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const HEADER_LEN: usize = 20;
+// How long to wait for each server, and how many times to send it a request, since UDP may drop either
+const ATTEMPT_TIMEOUT: Duration = Duration::from_millis(500);
+const ATTEMPTS: usize = 3;
+
+#[derive(Debug, Error)]
+pub enum StunError {
+    #[error("io: {0}")]
+    Io(#[from] io::Error),
+    #[error("malformed response: {0}")]
+    Malformed(&'static str),
+    #[error("no server responded")]
+    NoResponse,
+}
+
+/// What STUN servers told us about our address.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Mapping {
+    /// Our address as seen by the first server to respond.
+    pub addr: SocketAddr,
+    /// Whether different servers saw us at different addresses, in which case we're behind a symmetric NAT and the
+    /// mapped port is of no use to anybody else.
+    pub symmetric: bool,
+}
+
+/// Encode a binding request with the given transaction id.
+pub fn binding_request(transaction: [u8; 12]) -> [u8; HEADER_LEN] {
+    let mut packet = [0; HEADER_LEN];
+    packet[0..2].copy_from_slice(&BINDING_REQUEST.to_be_bytes());
+    // The length of the attributes, of which there are none
+    packet[2..4].copy_from_slice(&0u16.to_be_bytes());
+    packet[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    packet[8..20].copy_from_slice(&transaction);
+    packet
+}
+
+/// Decode the mapped address from the response to the binding request with the given transaction id.
+pub fn parse_binding_response(
+    packet: &[u8],
+    transaction: [u8; 12],
+) -> Result<SocketAddr, StunError> {
+    let header = packet
+        .get(..HEADER_LEN)
+        .ok_or(StunError::Malformed("too short"))?;
+    if u16::from_be_bytes([header[0], header[1]]) != BINDING_RESPONSE {
+        return Err(StunError::Malformed("not a binding response"));
+    }
+    if header[4..8] != MAGIC_COOKIE.to_be_bytes() {
+        return Err(StunError::Malformed("bad magic cookie"));
+    }
+    if header[8..20] != transaction {
+        return Err(StunError::Malformed("wrong transaction"));
+    }
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let mut attrs = packet
+        .get(HEADER_LEN..HEADER_LEN + len)
+        .ok_or(StunError::Malformed("truncated"))?;
+
+    let mut mapped = None;
+    while attrs.len() >= 4 {
+        let kind = u16::from_be_bytes([attrs[0], attrs[1]]);
+        let len = u16::from_be_bytes([attrs[2], attrs[3]]) as usize;
+        let value = attrs
+            .get(4..4 + len)
+            .ok_or(StunError::Malformed("truncated attribute"))?;
+        match kind {
+            XOR_MAPPED_ADDRESS => return parse_address(value, Some(transaction)),
+            MAPPED_ADDRESS => mapped = Some(parse_address(value, None)?),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of four bytes
+        attrs = attrs.get(4 + len.next_multiple_of(4)..).unwrap_or_default();
+    }
+    mapped.ok_or(StunError::Malformed("no mapped address"))
+}
+
+// Decode a (XOR-)MAPPED-ADDRESS attribute, undoing the XOR if we're given the transaction id
+fn parse_address(value: &[u8], xor: Option<[u8; 12]>) -> Result<SocketAddr, StunError> {
+    if value.len() < 4 {
+        return Err(StunError::Malformed("address too short"));
+    }
+    let (family, port, addr) = (value[1], &value[2..4], &value[4..]);
+    // The cookie followed by the transaction id, which XORed addresses are XORed with
+    let mut key = MAGIC_COOKIE.to_be_bytes().to_vec();
+    key.extend(xor.unwrap_or_default());
+    let unmask = |bytes: &[u8]| -> Vec<u8> {
+        match xor {
+            Some(_) => bytes.iter().zip(&key).map(|(b, k)| b ^ k).collect(),
+            None => bytes.to_vec(),
+        }
+    };
+    let port = u16::from_be_bytes(unmask(port).try_into().unwrap());
+    let ip = match (family, addr.len()) {
+        (0x01, 4) => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(unmask(addr)).unwrap())),
+        (0x02, 16) => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(unmask(addr)).unwrap())),
+        _ => return Err(StunError::Malformed("unknown address family")),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+// Ask a single server for our address, resending the request a few times in case it gets lost
+async fn query(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr, StunError> {
+    let transaction = thread_rng().gen::<[u8; 12]>();
+    let request = binding_request(transaction);
+    let mut buf = [0; 1024];
+    for _ in 0..ATTEMPTS {
+        socket.send_to(&request, server).await?;
+        let deadline = tokio::time::Instant::now() + ATTEMPT_TIMEOUT;
+        // Ignore anything that isn't the response we're waiting for, such as late responses to earlier attempts
+        while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let (len, from) = res?;
+            if from == server {
+                if let Ok(addr) = parse_binding_response(&buf[..len], transaction) {
+                    return Ok(addr);
+                }
+            }
+        }
+    }
+    Err(StunError::NoResponse)
+}
+
+/// Ask the given STUN servers (as `host:port`) what our address is.
+///
+/// Servers are asked in turn until two have responded, from the same local socket, so that a symmetric NAT can be
+/// detected.
+pub async fn discover<S: AsRef<str>>(servers: &[S]) -> Result<Mapping, StunError> {
+    let mut sockets = Vec::<UdpSocket>::new();
+    let mut seen = Vec::<SocketAddr>::new();
+    for server in servers {
+        let Some(server) = lookup_host(server.as_ref())
+            .await
+            .ok()
+            .and_then(|mut addrs| addrs.next())
+        else {
+            continue;
+        };
+        // Keep one socket per address family, since the NAT maps each local socket separately
+        let socket = match sockets.iter().position(|socket| {
+            socket
+                .local_addr()
+                .is_ok_and(|addr| addr.is_ipv4() == server.is_ipv4())
+        }) {
+            Some(idx) => &sockets[idx],
+            None => {
+                let local: SocketAddr = if server.is_ipv4() {
+                    (Ipv4Addr::UNSPECIFIED, 0).into()
+                } else {
+                    (Ipv6Addr::UNSPECIFIED, 0).into()
+                };
+                sockets.push(UdpSocket::bind(local).await?);
+                sockets.last().unwrap()
+            }
+        };
+        if let Ok(addr) = query(socket, server).await {
+            seen.push(addr);
+        }
+        if seen.len() >= 2 {
+            break;
+        }
+    }
+    let addr = *seen.first().ok_or(StunError::NoResponse)?;
+    Ok(Mapping {
+        addr,
+        symmetric: seen
+            .iter()
+            .any(|other| *other != addr && other.is_ipv4() == addr.is_ipv4()),
+    })
+}
@@ -0,0 +1,87 @@
+//! Anti-entropy between neighbours, so that replicas which drift apart find their way back together.
+//!
+//! Two peers are jointly responsible for the [region](Region) of the keyspace that shares the longest prefix of both
+//! their tags. Each side sends the other a [summary](Summary) of the tags that it holds in that region: a Bloom filter,
+//! which takes up a bounded number of bytes however much data is held. Whatever a side holds that's missing from the
+//! other's summary is passed across. A false positive only hides a tag until the next exchange, since each summary is
+//! hashed with a fresh seed.
+
+use crate::Tag;
+use serde::{Deserialize, Serialize};
+
+/// The most bytes that a summary's filter takes up (4 KiB), however many tags it summarises.
+pub const MAX_SUMMARY_BYTES: usize = 4 * 1024;
+/// The most hash functions that a summary uses for each tag.
+pub const MAX_HASHES: u32 = 16;
+
+/// The tags that share the first `len` bits of `prefix`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Region {
+    pub prefix: Tag,
+    pub len: u16,
+}
+
+impl Region {
+    /// The smallest region containing both `a` and `b`.
+    pub fn shared(a: Tag, b: Tag) -> Self {
+        Self {
+            prefix: a,
+            len: a.common_prefix_len(b),
+        }
+    }
+
+    pub fn contains(&self, tag: Tag) -> bool {
+        self.prefix.common_prefix_len(tag) >= self.len
+    }
+}
+
+/// A Bloom filter of tags, which may say that it contains tags it doesn't but never the reverse.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Summary {
+    pub bits: Vec<u8>,
+    pub hashes: u32,
+    pub seed: u64,
+}
+
+impl Summary {
+    /// A summary of `tags` hashed with `seed`, sized so that a tag it doesn't contain shows up with probability
+    /// `false_positive_rate`, unless that would take more than [`MAX_SUMMARY_BYTES`].
+    pub fn new(tags: &[Tag], false_positive_rate: f64, seed: u64) -> Self {
+        let n = tags.len().max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let rate = false_positive_rate.clamp(f64::MIN_POSITIVE, 1.0);
+        let bits = (-n * rate.ln() / (ln2 * ln2)).ceil() as usize;
+        let len = bits.div_ceil(8).clamp(1, MAX_SUMMARY_BYTES);
+        let hashes = ((len * 8) as f64 / n * ln2).round() as u32;
+        let mut summary = Self {
+            bits: vec![0; len],
+            hashes: hashes.clamp(1, MAX_HASHES),
+            seed,
+        };
+        for tag in tags {
+            for bit in summary.positions(*tag) {
+                summary.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        summary
+    }
+
+    /// Whether the summary may contain `tag`. A summary with no bits, or an absurd number of hashes, contains nothing.
+    pub fn contains(&self, tag: Tag) -> bool {
+        !self.bits.is_empty()
+            && self.hashes <= MAX_HASHES
+            && self
+                .positions(tag)
+                .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // The bits that `tag` sets. Tags in a region share their leading bytes, so these are drawn from the trailing ones.
+    fn positions(&self, tag: Tag) -> impl Iterator<Item = usize> {
+        let word = |i: usize| u64::from_be_bytes(tag[i..i + 8].try_into().unwrap());
+        let h1 = word(16) ^ self.seed;
+        let h2 = word(24).rotate_left(self.seed as u32 % 64) | 1;
+        let m = self.bits.len() as u64 * 8;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m.max(1)) as usize)
+    }
+}
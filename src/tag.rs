@@ -1,22 +1,78 @@
 use rand::prelude::*;
 use rsa::{traits::PublicKeyParts, RsaPublicKey};
 use serde::{Deserialize, Serialize};
-use std::fmt;
+use sha3::{Digest, Sha3_256};
+use std::{fmt, io};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
 
+// How much of a reader is hashed at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The hash function that a [`Tag`] was computed with.
+///
+/// Each algorithm has a one-byte code that is written in front of the digest in a tag's textual form, so that tags
+/// say how to verify the data they name.
+#[derive(
+    Copy, Clone, Debug, Default, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum HashAlgorithm {
+    /// SHA3-256, code 0. The only algorithm before codes existed, so untagged digests are taken to be SHA3-256.
+    #[default]
+    Sha3_256,
+    /// BLAKE3, code 1. Much faster than SHA3-256 for large data.
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [Self; 2] = [Self::Sha3_256, Self::Blake3];
+
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Sha3_256 => 0,
+            Self::Blake3 => 1,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|alg| alg.code() == code)
+    }
+}
+
+/// A 256-bit digest, along with the algorithm that produced it.
+///
+/// Distances, levels and buckets only look at the digest bytes, so tags made with different algorithms share one
+/// keyspace.
 #[derive(Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 #[serde(try_from = "String")]
 #[serde(into = "String")]
-pub struct Tag([u8; 32]);
+pub struct Tag {
+    // Kept first, so that tags are ordered by their digest
+    bytes: [u8; 32],
+    algorithm: HashAlgorithm,
+}
 
 impl std::ops::Deref for Tag {
     type Target = [u8; 32];
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.bytes
     }
 }
 
+/// Why a string couldn't be parsed as a [`Tag`].
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TagParseError {
+    #[error("expected 64 or 66 hex digits, found {got} hex digits")]
+    WrongLength { got: usize },
+    /// `at` is the byte offset into the string as given, including any whitespace or `0x` prefix.
+    #[error("tag contains a character that is not a hex digit at byte {at}")]
+    InvalidCharacter { at: usize },
+    #[error("tag has unknown hash algorithm code {0:02x}")]
+    UnknownAlgorithm(u8),
+}
+
 impl TryFrom<String> for Tag {
-    type Error = &'static str;
+    type Error = TagParseError;
     fn try_from(s: String) -> Result<Self, Self::Error> {
         Self::try_from_hex(s)
     }
@@ -24,74 +80,277 @@ impl TryFrom<String> for Tag {
 
 impl Into<String> for Tag {
     fn into(self) -> String {
-        hex::encode(self.0)
+        self.to_string()
     }
 }
 
 impl fmt::Debug for Tag {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        hex::encode(self.0).fmt(f)
+        self.to_string().fmt(f)
     }
 }
 
+/// The algorithm code as two hex digits, followed by the 64 hex digits of the digest.
 impl fmt::Display for Tag {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        hex::encode(self.0).fmt(f)
+        write!(
+            f,
+            "{:02x}{}",
+            self.algorithm.code(),
+            hex::encode(self.bytes)
+        )
     }
 }
 
 impl Tag {
-    pub fn try_from_hex<B: AsRef<[u8]>>(hex: B) -> Result<Self, &'static str> {
+    /// Parses a tag as written by its `Display` impl. A bare 64-digit digest, as written before tags carried their
+    /// algorithm, is taken to be SHA3-256.
+    ///
+    /// Either case is accepted, as is surrounding whitespace and a `0x` prefix, since tags are often pasted in by hand.
+    pub fn try_from_hex<B: AsRef<[u8]>>(hex: B) -> Result<Self, TagParseError> {
+        let full = hex.as_ref();
+        let trimmed = full.trim_ascii_start();
+        let unprefixed = (trimmed.strip_prefix(b"0x"))
+            .or_else(|| trimmed.strip_prefix(b"0X"))
+            .unwrap_or(trimmed);
+        // Where the digits start in the original string, for error positions
+        let offset = full.len() - unprefixed.len();
+        let hex = unprefixed.trim_ascii_end();
+
+        if let Some(at) = hex.iter().position(|b| !b.is_ascii_hexdigit()) {
+            return Err(TagParseError::InvalidCharacter { at: offset + at });
+        }
+        let (algorithm, digest) = match hex.len() {
+            64 => (HashAlgorithm::Sha3_256, hex),
+            66 => {
+                let mut code = [0];
+                hex::decode_to_slice(&hex[..2], &mut code).expect("checked to be hex");
+                let algorithm = HashAlgorithm::from_code(code[0])
+                    .ok_or(TagParseError::UnknownAlgorithm(code[0]))?;
+                (algorithm, &hex[2..])
+            }
+            got => return Err(TagParseError::WrongLength { got }),
+        };
         let mut bytes = [0; 32];
-        hex::decode_to_slice(hex, &mut bytes).map_err(|_| "malformed tag")?;
-        Ok(Self::from_bytes(bytes))
+        hex::decode_to_slice(digest, &mut bytes).expect("checked to be hex");
+        Ok(Self::from_digest(algorithm, bytes))
     }
 
+    /// A tag for a SHA3-256 digest, or for something that isn't a digest at all, like a distance.
     pub fn from_bytes(bytes: [u8; 32]) -> Self {
-        Self(bytes)
+        Self::from_digest(HashAlgorithm::default(), bytes)
+    }
+
+    pub fn from_digest(algorithm: HashAlgorithm, bytes: [u8; 32]) -> Self {
+        Self { bytes, algorithm }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Whether `bytes` hash to this tag, using the tag's own algorithm.
+    pub fn is_digest_of<B: AsRef<[u8]>>(&self, bytes: B) -> bool {
+        Self::digest_with(self.algorithm, bytes) == *self
     }
 
     pub fn digest_many<B: AsRef<[u8]>, I: IntoIterator<Item = B>>(bytes: I) -> Self {
-        use sha3::{Digest, Sha3_256};
-        let mut hasher = Sha3_256::new();
+        let mut hasher = TagHasher::new();
         for bytes in bytes {
             hasher.update(bytes);
         }
-        Self(hasher.finalize().into())
+        hasher.finish()
+    }
+
+    /// Like [`Tag::digest`], but reading the input in chunks rather than needing all of it in memory.
+    pub fn digest_reader<R: io::Read>(reader: R) -> io::Result<Self> {
+        Self::digest_reader_with(HashAlgorithm::default(), reader)
+    }
+
+    /// Like [`Tag::digest_reader`], but using the given algorithm.
+    pub fn digest_reader_with<R: io::Read>(
+        algorithm: HashAlgorithm,
+        mut reader: R,
+    ) -> io::Result<Self> {
+        let mut hasher = TagHasher::with_algorithm(algorithm);
+        io::copy(&mut reader, &mut hasher)?;
+        Ok(hasher.finish())
+    }
+
+    /// Like [`Tag::digest_reader`], but for async readers.
+    pub async fn digest_async_reader<R: AsyncRead + Unpin>(reader: R) -> io::Result<Self> {
+        Self::digest_async_reader_with(HashAlgorithm::default(), reader).await
+    }
+
+    /// Like [`Tag::digest_async_reader`], but using the given algorithm.
+    pub async fn digest_async_reader_with<R: AsyncRead + Unpin>(
+        algorithm: HashAlgorithm,
+        mut reader: R,
+    ) -> io::Result<Self> {
+        let mut hasher = TagHasher::with_algorithm(algorithm);
+        let mut buf = vec![0; CHUNK_SIZE];
+        loop {
+            match reader.read(&mut buf).await? {
+                0 => break Ok(hasher.finish()),
+                n => hasher.update(&buf[..n]),
+            }
+        }
     }
 
     pub fn fingerprint(key: &RsaPublicKey) -> Self {
         Self::digest_many([key.n(), key.e()].map(|x| x.to_bytes_le()))
     }
 
+    /// The SHA3-256 digest of `bytes`.
     pub fn digest<B: AsRef<[u8]>>(bytes: B) -> Self {
-        Self::digest_many([bytes])
+        Self::digest_with(HashAlgorithm::default(), bytes)
+    }
+
+    pub fn digest_with<B: AsRef<[u8]>>(algorithm: HashAlgorithm, bytes: B) -> Self {
+        let mut hasher = TagHasher::with_algorithm(algorithm);
+        hasher.update(bytes);
+        hasher.finish()
     }
 
     pub fn generate() -> Self {
-        Self(thread_rng().gen())
+        Self::from_bytes(thread_rng().gen())
+    }
+
+    /// A random tag whose distance from `base` has the given [`level`](Tag::level), such as a lookup target for
+    /// refreshing one routing table bucket.
+    ///
+    /// The bits above the level match `base`, the bit at the level differs, and the rest are random. Panics if `level`
+    /// is more than 255.
+    pub fn random_at_level(base: &Tag, level: u16, rng: &mut impl Rng) -> Self {
+        Self::at_level(base, level, rng.gen())
+    }
+
+    /// The closest tag to `base` whose distance from it has the given [`level`](Tag::level), which differs from
+    /// `base` only in the bit at that level. Panics if `level` is more than 255.
+    pub fn at_level_min(base: &Tag, level: u16) -> Self {
+        Self::at_level(base, level, [0; 32])
+    }
+
+    // Uses the bits of `low` below the level's bit as the low bits of the distance
+    fn at_level(base: &Tag, level: u16, mut low: [u8; 32]) -> Self {
+        assert!(level <= 255, "there are only 256 levels");
+        // The level's bit, counting from the most significant bit of the first byte as `bit` does
+        let i = (255 - level) as usize;
+        low[..i / 8].fill(0);
+        low[i / 8] &= 0xff >> (i % 8);
+        low[i / 8] |= 0x80 >> (i % 8);
+        let bytes = *base.dist_to(Self::from_bytes(low));
+        Self::from_digest(base.algorithm, bytes)
     }
 
+    /// The XOR of the two digests, whatever algorithms produced them.
     pub fn dist_to(&self, other: Self) -> Self {
-        let mut dist = self.0;
+        let mut dist = self.bytes;
         for i in 0..dist.len() {
-            dist[i] ^= other.0[i];
+            dist[i] ^= other.bytes[i];
         }
-        Self(dist)
-    }
-
-    // log2, effectively
-    pub fn level(&self) -> u16 {
-        self.0
-            .into_iter()
-            .enumerate()
-            .find_map(|(i, b)| {
-                if b != 0 {
-                    Some(255 - i as u16 * 8 - b.ilog2() as u16)
-                } else {
-                    None
-                }
-            })
-            .unwrap_or(0)
+        Self::from_bytes(dist)
+    }
+
+    /// The position of the highest set bit, counting from the least significant bit of the last byte. In other
+    /// words, the integer part of log2 of the tag as a 256-bit big-endian number.
+    ///
+    /// The zero tag, which is the distance between identical tags, has no set bits and so no level.
+    pub fn level(&self) -> Option<u16> {
+        255u16.checked_sub(self.leading_zeros())
+    }
+
+    /// The number of leading zero bits, from 0 to 256.
+    pub fn leading_zeros(&self) -> u16 {
+        self.bytes
+            .iter()
+            .position(|b| *b != 0)
+            .map_or(256, |i| i as u16 * 8 + self.bytes[i].leading_zeros() as u16)
+    }
+
+    /// The number of leading bits that this tag has in common with `other`, from 0 to 256.
+    pub fn common_prefix_len(&self, other: Self) -> u16 {
+        self.dist_to(other).leading_zeros()
+    }
+
+    /// The bit at position `i`, counting from the most significant bit of the first byte.
+    ///
+    /// Panics if `i` is 256 or more.
+    pub fn bit(&self, i: u16) -> bool {
+        let i = i as usize;
+        self.bytes[i / 8] >> (7 - i % 8) & 1 == 1
+    }
+
+    /// The routing table bucket that a node with this tag puts a peer with tag `other` in, from 0 to 255.
+    ///
+    /// This is the [`level`](Tag::level) of the distance between them, `255 - common_prefix_len`: peers that differ
+    /// from us in the very first bit go in bucket 255, and those differing only in the last bit in bucket 0. A node
+    /// never peers with its own tag, so identical tags have no bucket.
+    pub fn bucket_index(&self, other: Self) -> Option<usize> {
+        self.dist_to(other).level().map(usize::from)
+    }
+}
+
+/// Computes a [`Tag`] incrementally, for data that arrives a piece at a time.
+///
+/// Feeding the hasher the same bytes as [`Tag::digest`], however they are split up, gives the same tag.
+#[derive(Clone)]
+pub struct TagHasher(Hasher);
+
+// Hashers are short-lived, so there's no point boxing the larger BLAKE3 state
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+enum Hasher {
+    Sha3_256(Sha3_256),
+    Blake3(blake3::Hasher),
+}
+
+impl Default for TagHasher {
+    fn default() -> Self {
+        Self::with_algorithm(HashAlgorithm::default())
+    }
+}
+
+impl TagHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_algorithm(algorithm: HashAlgorithm) -> Self {
+        Self(match algorithm {
+            HashAlgorithm::Sha3_256 => Hasher::Sha3_256(Sha3_256::new()),
+            HashAlgorithm::Blake3 => Hasher::Blake3(blake3::Hasher::new()),
+        })
+    }
+
+    pub fn update<B: AsRef<[u8]>>(&mut self, bytes: B) {
+        match &mut self.0 {
+            Hasher::Sha3_256(hasher) => hasher.update(bytes),
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes.as_ref());
+            }
+        }
+    }
+
+    pub fn finish(self) -> Tag {
+        match self.0 {
+            Hasher::Sha3_256(hasher) => {
+                Tag::from_digest(HashAlgorithm::Sha3_256, hasher.finalize().into())
+            }
+            Hasher::Blake3(hasher) => {
+                Tag::from_digest(HashAlgorithm::Blake3, *hasher.finalize().as_bytes())
+            }
+        }
+    }
+}
+
+impl io::Write for TagHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
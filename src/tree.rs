@@ -0,0 +1,192 @@
+//! Merkle trees, for content too large to store as a single blob.
+//!
+//! Content is split into chunks, each stored under its own digest. A tree node lists the tags of up to
+//! [`Layout::fanout`] chunks, or of nodes one level further down, and is stored under its own digest in turn. The tag of
+//! the root node names the content as a whole, and every blob fetched on the way down can be checked against the tag
+//! that its parent gives for it before anything beneath it is fetched.
+//!
+//! A node is laid out as follows, with integers big-endian:
+//!
+//! | Bytes    | Contents                                                                          |
+//! |----------|-----------------------------------------------------------------------------------|
+//! | 4        | The magic bytes `NTRE`                                                            |
+//! | 1        | The format version, currently `1`                                                 |
+//! | 1        | The node's depth: `1` if its children are chunks, otherwise one more than theirs  |
+//! | 8        | The length of the content beneath the node                                        |
+//! | 2        | The number of children, *n*, which is at least `1`                                |
+//! | 33 × *n* | Each child's tag: its [algorithm code](HashAlgorithm::code), then its digest      |
+//!
+//! Nothing follows the last child. Content of any length, including none, has a root node above at least one chunk.
+
+use crate::{HashAlgorithm, Tag};
+use bytes::Bytes;
+use std::cmp::Ordering;
+use thiserror::Error;
+
+const MAGIC: &[u8; 4] = b"NTRE";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 16;
+const CHILD_LEN: usize = 33;
+
+/// How content is split up into a tree by [`build`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Layout {
+    /// The length of each chunk, other than the last.
+    pub chunk_size: usize,
+    /// The most children that a node has.
+    pub fanout: usize,
+    /// The algorithm that chunks and nodes are tagged with.
+    pub algorithm: HashAlgorithm,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            chunk_size: 256 * 1024,
+            fanout: 128,
+            algorithm: HashAlgorithm::default(),
+        }
+    }
+}
+
+/// Why a tree could not be read.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum TreeError {
+    #[error("not a tree node")]
+    NotANode,
+    #[error("unsupported tree node version {0}")]
+    UnsupportedVersion(u8),
+    #[error("tree node is truncated")]
+    Truncated,
+    #[error("tree node has trailing bytes")]
+    Trailing,
+    #[error("tree node has no children")]
+    Empty,
+    #[error("tree node has unknown hash algorithm code {0:02x}")]
+    UnknownAlgorithm(u8),
+    /// A node's depth was not one less than its parent's, or was zero.
+    #[error("expected a tree node of depth {expected}, found {found}")]
+    WrongDepth { expected: u8, found: u8 },
+    /// The chunks beneath the root did not add up to the length that it gives.
+    #[error("tree content does not match its declared length")]
+    WrongLength,
+}
+
+/// A node of a tree, as stored under its tag.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeNode {
+    /// `1` if the children are chunks, otherwise one more than the depth of the children.
+    pub depth: u8,
+    /// The length of the content beneath the node.
+    pub len: u64,
+    pub children: Vec<Tag>,
+}
+
+impl TreeNode {
+    pub fn encode(&self) -> Bytes {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + CHILD_LEN * self.children.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.depth);
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.extend_from_slice(&(self.children.len() as u16).to_be_bytes());
+        for child in &self.children {
+            bytes.push(child.algorithm().code());
+            bytes.extend_from_slice(&**child);
+        }
+        bytes.into()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, TreeError> {
+        if bytes.get(..4) != Some(MAGIC.as_slice()) {
+            return Err(TreeError::NotANode);
+        }
+        let header = bytes.get(..HEADER_LEN).ok_or(TreeError::Truncated)?;
+        if header[4] != VERSION {
+            return Err(TreeError::UnsupportedVersion(header[4]));
+        }
+        let depth = header[5];
+        if depth == 0 {
+            return Err(TreeError::WrongDepth {
+                expected: 1,
+                found: 0,
+            });
+        }
+        let len = u64::from_be_bytes(header[6..14].try_into().unwrap());
+        let count = u16::from_be_bytes([header[14], header[15]]) as usize;
+        if count == 0 {
+            return Err(TreeError::Empty);
+        }
+        let body = &bytes[HEADER_LEN..];
+        match body.len().cmp(&(count * CHILD_LEN)) {
+            Ordering::Less => return Err(TreeError::Truncated),
+            Ordering::Greater => return Err(TreeError::Trailing),
+            Ordering::Equal => {}
+        }
+        let children = body
+            .chunks(CHILD_LEN)
+            .map(|child| {
+                let algorithm = HashAlgorithm::from_code(child[0])
+                    .ok_or(TreeError::UnknownAlgorithm(child[0]))?;
+                Ok(Tag::from_digest(algorithm, child[1..].try_into().unwrap()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            depth,
+            len,
+            children,
+        })
+    }
+}
+
+/// Split `data` into a tree, returning the tag of its root along with every blob to store, each under its tag.
+///
+/// Blobs come bottom-up, ending with the root, so that storing them in order never leaves a node pointing at blobs that
+/// aren't yet stored. Chunks share their memory with `data`. Panics if the chunk size is zero or the fanout is less
+/// than 2.
+pub fn build(data: &Bytes, layout: Layout) -> (Tag, Vec<(Tag, Bytes)>) {
+    assert!(layout.chunk_size > 0, "chunks must not be empty");
+    assert!(
+        (2..=u16::MAX as usize).contains(&layout.fanout),
+        "fanout must be between 2 and {}",
+        u16::MAX
+    );
+    let mut blobs = Vec::new();
+    let mut push = |blob: Bytes| {
+        let tag = Tag::digest_with(layout.algorithm, &*blob);
+        blobs.push((tag, blob));
+        tag
+    };
+
+    // Each entry of a level is a tag along with the length of the content beneath it
+    let mut level = if data.is_empty() {
+        vec![(push(data.clone()), 0)]
+    } else {
+        (0..data.len())
+            .step_by(layout.chunk_size)
+            .map(|start| {
+                let chunk = data.slice(start..(start + layout.chunk_size).min(data.len()));
+                let len = chunk.len() as u64;
+                (push(chunk), len)
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut depth = 0u8;
+    loop {
+        depth = depth.checked_add(1).expect("tree is too deep");
+        level = level
+            .chunks(layout.fanout)
+            .map(|children| {
+                let node = TreeNode {
+                    depth,
+                    len: children.iter().map(|(_, len)| len).sum(),
+                    children: children.iter().map(|(tag, _)| *tag).collect(),
+                };
+                (push(node.encode()), node.len)
+            })
+            .collect();
+        if let [(root, _)] = level[..] {
+            break (root, blobs);
+        }
+    }
+}
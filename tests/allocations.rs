@@ -0,0 +1,105 @@
+#![cfg(feature = "http")]
+
+use bytes::Bytes;
+use nettle::{http, mem, Node, PrivateId, Tag};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    net::TcpListener,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+// Larger than any buffer that hyper or tokio allocate, so that every allocation this big is a copy of a blob
+const BLOB_LEN: usize = 4 << 20;
+
+static LARGE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+// Counts the allocations of at least `BLOB_LEN` bytes
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= BLOB_LEN {
+            LARGE_ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size >= BLOB_LEN {
+            LARGE_ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+// Count the blob-sized allocations made while running `f`
+async fn large_allocations<F: std::future::Future>(f: F) -> (F::Output, usize) {
+    let before = LARGE_ALLOCATIONS.load(Ordering::SeqCst);
+    let out = f.await;
+    (out, LARGE_ALLOCATIONS.load(Ordering::SeqCst) - before)
+}
+
+// Everything is in one test, since tests running alongside it would be counted too
+#[tokio::test]
+async fn one_allocation_per_stored_blob() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    // The only allocation is the one that made the blob in the first place
+    let (tag, allocations) = large_allocations(async {
+        node.do_upload(Bytes::from(vec![1; BLOB_LEN]))
+            .await
+            .unwrap()
+    })
+    .await;
+    assert_eq!(allocations, 1);
+    // Storing it again is reported, and keeps the copy we already had
+    let stored = node.load_data(tag).await.unwrap();
+    assert!(!node.save_data(tag, Bytes::from(vec![1; BLOB_LEN])).await);
+    assert_eq!(node.load_data(tag).await.unwrap().as_ptr(), stored.as_ptr());
+
+    // Uploading over HTTP buffers the body once, and stores that buffer as it is
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let url = http::parse_addr(&format!("http://127.0.0.1:{}", port)).unwrap();
+    let node = Node::<http::Http>::new(
+        PrivateId::generate(),
+        url.clone(),
+        Vec::new(),
+        http::Config {
+            bind_addrs: vec![([127, 0, 0, 1], port).into()],
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    tokio::spawn(node.clone().run());
+    let client = reqwest::Client::new();
+    while client
+        .get(url.join("status").unwrap())
+        .send()
+        .await
+        .is_err()
+    {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let body = Bytes::from(vec![2; BLOB_LEN]);
+    let tag = Tag::digest(&body);
+    let (resp, allocations) =
+        large_allocations(client.post(url.join("data").unwrap()).body(body).send()).await;
+    assert_eq!(resp.unwrap().text().await.unwrap(), tag.to_string());
+    assert_eq!(allocations, 1);
+    assert!(node.has_data(tag).await);
+}
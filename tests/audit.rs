@@ -0,0 +1,189 @@
+use bytes::Bytes;
+use nettle::{
+    audit::{Challenge, MAX_RANGE},
+    mem, AuditOutcome, HashAlgorithm, Node, PrivateId, Tag, MAX_AUDIT_FAILURES,
+};
+use rand::prelude::*;
+use std::sync::Arc;
+
+#[test]
+fn challenges() {
+    let data = vec![7; 3 * MAX_RANGE as usize];
+    let mut rng = StdRng::seed_from_u64(0);
+    for _ in 0..100 {
+        let challenge = Challenge::random(&mut rng, data.len());
+        assert!(challenge.len >= 1 && challenge.len <= MAX_RANGE);
+        assert!(challenge.offset + challenge.len <= data.len() as u64);
+        let answer = challenge.answer(HashAlgorithm::Sha3_256, &data).unwrap();
+
+        // The answer depends on both the bytes in the range and the nonce
+        let mut altered = data.clone();
+        altered[challenge.offset as usize] ^= 1;
+        assert_ne!(
+            challenge.answer(HashAlgorithm::Sha3_256, &altered),
+            Some(answer)
+        );
+        let renonced = Challenge {
+            nonce: challenge.nonce.wrapping_add(1),
+            ..challenge
+        };
+        assert_ne!(
+            renonced.answer(HashAlgorithm::Sha3_256, &data),
+            Some(answer)
+        );
+    }
+
+    // Only empty data gets an empty range
+    let challenge = Challenge::random(&mut rng, 0);
+    assert_eq!((challenge.offset, challenge.len), (0, 0));
+    assert!(challenge.answer(HashAlgorithm::Sha3_256, b"").is_some());
+
+    let outside = Challenge {
+        offset: data.len() as u64 - 1,
+        len: 2,
+        nonce: 0,
+    };
+    assert_eq!(outside.answer(HashAlgorithm::Sha3_256, &data), None);
+}
+
+// A node that uploads data, and a node that it places the data with
+async fn placement(data: &Bytes) -> (Arc<Node<mem::Mem>>, Arc<Node<mem::Mem>>) {
+    let network = mem::Network::default();
+    let mut nodes = Vec::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::<mem::Mem>::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let tag = Tag::digest(data);
+    nodes.sort_by_key(|node| std::cmp::Reverse(node.id().tag.dist_to(tag)));
+    let (uploader, holder) = (nodes[0].clone(), nodes[1].clone());
+    uploader.accept_peer(holder.id(), holder.addr()).await;
+    holder.accept_peer(uploader.id(), uploader.addr()).await;
+    // The holder is the only one with a copy
+    uploader.set_replication(1, 1);
+    assert_eq!(uploader.do_upload(data.clone()).await, Ok(tag));
+    assert!(holder.has_data(tag).await);
+    (uploader, holder)
+}
+
+#[tokio::test]
+async fn dropped_replicas_are_caught() {
+    let data = Bytes::from_static(b"data that the holder claims to keep");
+    let tag = Tag::digest(&data);
+    let (uploader, holder) = placement(&data).await;
+    assert_eq!(
+        uploader.audit(tag, &holder.id()).await,
+        Some(AuditOutcome::Passed)
+    );
+    assert_eq!(uploader.audit(Tag::generate(), &holder.id()).await, None);
+
+    // The holder quietly throws its copy away, and is caught out
+    assert!(holder.remove_data(tag).await);
+    assert_eq!(
+        uploader.audit(tag, &holder.id()).await,
+        Some(AuditOutcome::Failed)
+    );
+    // There's no point auditing a copy that's known to be gone, and we have no copy of our own to place again
+    assert_eq!(uploader.audit(tag, &holder.id()).await, None);
+    assert!(!holder.has_data(tag).await);
+}
+
+#[tokio::test]
+async fn failed_audits_replace_the_data() {
+    let data = Bytes::from_static(b"data that the uploader keeps a copy of");
+    let tag = Tag::digest(&data);
+    let (uploader, holder) = placement(&data).await;
+    uploader.save_data(tag, data.clone()).await;
+
+    holder.remove_data(tag).await;
+    assert_eq!(
+        uploader.audit(tag, &holder.id()).await,
+        Some(AuditOutcome::Failed)
+    );
+    assert!(holder.has_data(tag).await);
+    // And the new copy is audited in turn
+    assert_eq!(
+        uploader.audit(tag, &holder.id()).await,
+        Some(AuditOutcome::Passed)
+    );
+}
+
+#[tokio::test]
+async fn repeat_offenders_are_dropped() {
+    let data = Bytes::from_static(b"data that keeps going missing");
+    let tag = Tag::digest(&data);
+    let (uploader, holder) = placement(&data).await;
+    uploader.save_data(tag, data.clone()).await;
+
+    for failures in 1..=MAX_AUDIT_FAILURES {
+        assert!(holder.remove_data(tag).await);
+        assert_eq!(
+            uploader.audit(tag, &holder.id()).await,
+            Some(AuditOutcome::Failed)
+        );
+        assert_eq!(
+            uploader.get_peers().contains(&holder.id()),
+            failures < MAX_AUDIT_FAILURES
+        );
+    }
+    // Once dropped, the holder isn't taken back
+    assert!(!uploader.accept_peer(holder.id(), holder.addr()).await);
+}
+
+#[tokio::test]
+async fn every_replica_is_audited() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::new();
+    for _ in 0..3 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::<mem::Mem>::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let data = Bytes::from_static(b"data with more than one holder");
+    let tag = Tag::digest(&data);
+    nodes.sort_by_key(|node| std::cmp::Reverse(node.id().tag.dist_to(tag)));
+    let (uploader, holders) = (nodes[0].clone(), &nodes[1..]);
+    for holder in holders {
+        uploader.accept_peer(holder.id(), holder.addr()).await;
+        holder.accept_peer(uploader.id(), uploader.addr()).await;
+    }
+    // Both holders get a copy, and neither is the uploader
+    uploader.set_replication(2, 2);
+    assert_eq!(uploader.do_upload(data.clone()).await, Ok(tag));
+    for holder in holders {
+        assert!(holder.has_data(tag).await);
+        assert_eq!(
+            uploader.audit(tag, &holder.id()).await,
+            Some(AuditOutcome::Passed)
+        );
+    }
+
+    // Either holder is caught out on its own, without the other's audits being touched
+    assert!(holders[1].remove_data(tag).await);
+    assert_eq!(
+        uploader.audit(tag, &holders[1].id()).await,
+        Some(AuditOutcome::Failed)
+    );
+    assert_eq!(uploader.audit(tag, &holders[1].id()).await, None);
+    assert_eq!(
+        uploader.audit(tag, &holders[0].id()).await,
+        Some(AuditOutcome::Passed)
+    );
+}
@@ -0,0 +1,467 @@
+#![cfg(feature = "http")]
+
+use nettle::{
+    http,
+    output::{Downloaded, ErrorKind, ErrorReport, Tagged, Uploaded},
+    HashAlgorithm, PrivateId, Tag, PROTOCOL_VERSION,
+};
+use std::{
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+// A path for a file that's unique to the calling test
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("nettle-{}-{}", name, std::process::id()))
+}
+
+fn config_file(name: &str, contents: &str) -> PathBuf {
+    let path = temp_path(name).with_extension("toml");
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+fn nettle(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_nettle"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn config_precedence() {
+    let path = config_file(
+        "precedence",
+        "port = 1000\nweb_ui = true\nmax_upload_size = 5\ninitial_peers = [\"http://10.0.0.1:8000\"]\n",
+    );
+    let out = nettle(&[
+        "run",
+        "--config",
+        path.to_str().unwrap(),
+        "--print-config",
+        "--port",
+        "2000",
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    let config = String::from_utf8(out.stdout)
+        .unwrap()
+        .parse::<toml::Table>()
+        .unwrap();
+    // Flags beat the file, which beats the defaults
+    assert_eq!(config["port"].as_integer(), Some(2000));
+    assert_eq!(config["max_upload_size"].as_integer(), Some(5));
+    assert_eq!(config["web_ui"].as_bool(), Some(true));
+    assert_eq!(
+        config["key_bits"].as_integer(),
+        Some(nettle::DEFAULT_KEY_BITS as i64)
+    );
+    // Addresses from the file are normalised like those given as flags
+    assert_eq!(
+        config["initial_peers"].as_array().unwrap()[0].as_str(),
+        Some("http://10.0.0.1:8000/")
+    );
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn malformed_config() {
+    for (name, contents, expected) in [
+        ("unknown-key", "prot = 1\n", "unknown field `prot`"),
+        ("wrong-type", "port = \"high\"\n", "expected u16"),
+        ("bad-syntax", "port = \n", "line 1"),
+        (
+            "bad-address",
+            "address = [\"localhost\"]\n",
+            "`localhost` is not an IP address",
+        ),
+    ] {
+        let path = config_file(name, contents);
+        let out = nettle(&["run", "-c", path.to_str().unwrap(), "--print-config"]);
+        assert!(!out.status.success());
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(stderr.contains("Invalid config file"), "{}", stderr);
+        assert!(stderr.contains(expected), "{}", stderr);
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn listen_addresses() {
+    // IPv6 addresses may be bracketed or not
+    let out = nettle(&[
+        "run",
+        "--print-config",
+        "--address",
+        "::1",
+        "--address",
+        "[::1]",
+        "--address",
+        "127.0.0.1",
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    let config = String::from_utf8(out.stdout)
+        .unwrap()
+        .parse::<toml::Table>()
+        .unwrap();
+    assert_eq!(
+        config["address"].as_array().unwrap(),
+        &["::1", "::1", "127.0.0.1"].map(toml::Value::from)
+    );
+
+    // Names are refused up front, rather than when the node starts listening
+    let out = nettle(&["run", "--print-config", "--address", "localhost"]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("`localhost` is not an IP address"),
+        "{}",
+        stderr
+    );
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+// Start a node with the given key file, returning the identity it reports running as
+fn running_as(key: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nettle"))
+        .args(["run", "--key", key, "--key-bits", "1024"])
+        .args(["--address", "127.0.0.1", "--port", "0"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let line = BufReader::new(child.stdout.take().unwrap())
+        .lines()
+        .map(Result::unwrap)
+        .find(|line| line.starts_with("Running as"));
+    child.kill().unwrap();
+    child.wait().unwrap();
+    line.expect("node never reported its identity")
+}
+
+#[test]
+fn persistent_identity() {
+    let path = temp_path("key").with_extension("pem");
+    let _ = fs::remove_file(&path);
+    let first = running_as(path.to_str().unwrap());
+    assert!(path.exists());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+    }
+    // A restarted node is the same node
+    assert_eq!(running_as(path.to_str().unwrap()), first);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn unreadable_identity() {
+    let path = temp_path("bad-key").with_extension("pem");
+    fs::write(&path, "not a key").unwrap();
+    let out = nettle(&["run", "--key", path.to_str().unwrap(), "--port", "0"]);
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Could not load identity"), "{}", stderr);
+    // The broken key is left for the operator to deal with, rather than replaced
+    assert_eq!(fs::read_to_string(&path).unwrap(), "not a key");
+    fs::remove_file(path).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn graceful_shutdown() {
+    let config = config_file("shutdown", "stun = []\n");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nettle"))
+        .args(["run", "-c", config.to_str().unwrap(), "--key-bits", "1024"])
+        .args(["--address", "127.0.0.1", "--port", "0"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    // Signals are only handled once the node has started
+    stderr
+        .by_ref()
+        .map(Result::unwrap)
+        .find(|line| line.contains("Starting node"))
+        .expect("node never started");
+    let kill = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(kill.success());
+    assert!(child.wait().unwrap().success());
+    assert!(stderr
+        .map(Result::unwrap)
+        .any(|line| line.contains("Shutting down, leaving 0 peer(s)")));
+    fs::remove_file(config).unwrap();
+}
+
+// Start a node with the given extra flags, returning it once it's serving requests along with its URL
+fn start_node(name: &str, args: &[&str]) -> (Child, String, PathBuf) {
+    let config = config_file(name, "stun = []\n");
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+        .to_string();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nettle"))
+        .args(["run", "-c", config.to_str().unwrap(), "--key-bits", "1024"])
+        .args(["--address", "127.0.0.1", "--port", &port])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(child.stderr.take().unwrap()).lines();
+    lines
+        .by_ref()
+        .map(Result::unwrap)
+        .find(|line| line.contains("Started HTTP server"))
+        .expect("node never started");
+    // Keep reading what the node logs, since it can't write to stderr once the pipe is closed
+    std::thread::spawn(move || lines.for_each(drop));
+    (child, format!("http://127.0.0.1:{}/", port), config)
+}
+
+#[test]
+fn status_and_peers() {
+    let (mut node, url, config) = start_node("status", &["--admin-token", "secret"]);
+    let out = nettle(&["status", "--node", &url, "--json"]);
+    assert!(out.status.success(), "{:?}", out);
+    let status = serde_json::from_slice::<http::Status>(&out.stdout).unwrap();
+    assert_eq!(status.version, PROTOCOL_VERSION);
+    assert_eq!(status.addr.as_str(), url);
+    let out = nettle(&["status", "--node", &url]);
+    let text = String::from_utf8(out.stdout).unwrap();
+    assert!(text.contains(&status.name), "{}", text);
+    assert!(text.contains(&status.tag.to_string()), "{}", text);
+
+    // The peers of a node are only shown to its admin
+    let out = nettle(&["peers", "--node", &url]);
+    assert_eq!(out.status.code(), Some(5));
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("--admin-token"));
+    let out = nettle(&["peers", "--node", &url, "--admin-token", "wrong"]);
+    assert_eq!(out.status.code(), Some(5));
+    let out = nettle(&["peers", "--node", &url, "--admin-token", "secret"]);
+    assert!(out.status.success(), "{:?}", out);
+    assert!(String::from_utf8(out.stdout).unwrap().starts_with("NAME"));
+    let out = nettle(&["peers", "--node", &url, "--admin-token", "secret", "--json"]);
+    assert!(serde_json::from_slice::<Vec<http::PeerEntry>>(&out.stdout)
+        .unwrap()
+        .is_empty());
+
+    node.kill().unwrap();
+    node.wait().unwrap();
+    fs::remove_file(config).unwrap();
+    let out = nettle(&["status", "--node", &url]);
+    assert_eq!(out.status.code(), Some(4));
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("Could not reach node"));
+}
+
+// Serve a single request with the given JSON body, returning the URL to make it to
+fn serve_once(body: String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = [0; 4096];
+        let _ = stream.read(&mut buf).unwrap();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+    });
+    url
+}
+
+#[test]
+fn incompatible_node() {
+    let id = PrivateId::generate();
+    let status = http::Status {
+        version: PROTOCOL_VERSION + 1,
+        tag: id.pub_id.tag,
+        name: id.pub_id.human_readable_name(2),
+        addr: "http://127.0.0.1:1/".parse().unwrap(),
+        peers: 0,
+        levels: Default::default(),
+        entries: 0,
+        uptime_secs: 0,
+        observed_ips: Default::default(),
+    };
+    let url = serve_once(serde_json::to_string(&status).unwrap());
+    let out = nettle(&["status", "--node", &url]);
+    assert_eq!(out.status.code(), Some(6));
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("protocol version"));
+
+    // A node that's too different to even describe its version
+    let url = serve_once("{\"tag\": 7}".to_string());
+    let out = nettle(&["status", "--node", &url]);
+    assert_eq!(out.status.code(), Some(6));
+}
+
+#[test]
+fn tag_and_verify() {
+    let path = temp_path("tag").with_extension("txt");
+    fs::write(&path, "hello nettle").unwrap();
+    let file = path.to_str().unwrap();
+    let tag = Tag::digest("hello nettle").to_string();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_nettle"))
+        .args(["tag", file, "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"from stdin")
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{}  {}\n{}  -\n", tag, file, Tag::digest("from stdin"))
+    );
+
+    // Files that can't be read don't stop the others being tagged
+    let missing = temp_path("missing").with_extension("txt");
+    let out = nettle(&["tag", missing.to_str().unwrap(), file]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{}  {}\n", tag, file)
+    );
+
+    assert!(nettle(&["verify", &tag, file]).status.success());
+    // Tags made with other algorithms are checked with those algorithms
+    let blake3 = Tag::digest_with(HashAlgorithm::Blake3, "hello nettle").to_string();
+    assert!(nettle(&["verify", &blake3, file]).status.success());
+    let out = nettle(&["verify", &Tag::digest("goodbye").to_string(), file]);
+    assert_eq!(out.status.code(), Some(7));
+    assert!(String::from_utf8(out.stderr).unwrap().contains(&tag));
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn upload_and_download() {
+    let (mut node, url, config) = start_node("transfer", &[]);
+    let path = temp_path("upload").with_extension("bin");
+    let data = (0..200_000).map(|i| i as u8).collect::<Vec<_>>();
+    fs::write(&path, &data).unwrap();
+
+    // Progress goes to stderr, leaving stdout with nothing but the result
+    let out = nettle(&["upload", path.to_str().unwrap(), "--node", &url]);
+    assert!(out.status.success(), "{:?}", out);
+    let tag = Tag::digest(&data).to_string();
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("{}\n", tag));
+    let out = nettle(&["download", &tag, "--node", &url, "--quiet"]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(out.stdout, data);
+
+    node.kill().unwrap();
+    node.wait().unwrap();
+    fs::remove_file(path).unwrap();
+    fs::remove_file(config).unwrap();
+}
+
+// The last line of stderr, which is where failures are reported
+fn error_report(stderr: &[u8]) -> ErrorReport {
+    let stderr = String::from_utf8(stderr.to_vec()).unwrap();
+    serde_json::from_str(stderr.lines().last().unwrap()).unwrap()
+}
+
+#[test]
+fn json_output() {
+    let (mut node, url, config) = start_node("json", &[]);
+    let path = temp_path("json").with_extension("txt");
+    fs::write(&path, "hello nettle").unwrap();
+    let tag = Tag::digest("hello nettle");
+
+    let out = nettle(&["upload", path.to_str().unwrap(), "--node", &url, "--json"]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(
+        serde_json::from_slice::<Uploaded>(&out.stdout).unwrap(),
+        Uploaded { tag, bytes: 12 }
+    );
+
+    let copy = temp_path("json-copy").with_extension("txt");
+    let out = nettle(&[
+        "--json",
+        "download",
+        &tag.to_string(),
+        "--node",
+        &url,
+        "--output",
+        copy.to_str().unwrap(),
+    ]);
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(
+        serde_json::from_slice::<Downloaded>(&out.stdout).unwrap(),
+        Downloaded {
+            tag,
+            bytes: 12,
+            output: Some(copy.clone()),
+        }
+    );
+    // Data written to stdout is left alone, with what was downloaded going to stderr instead
+    let out = nettle(&["download", &tag.to_string(), "--node", &url, "--json"]);
+    assert_eq!(out.stdout, b"hello nettle");
+    assert!(serde_json::from_slice::<Downloaded>(&out.stderr).is_ok());
+
+    let out = nettle(&["tag", path.to_str().unwrap(), "--json"]);
+    assert_eq!(
+        serde_json::from_slice::<Vec<Tagged>>(&out.stdout).unwrap(),
+        [Tagged {
+            tag,
+            file: path.clone()
+        }]
+    );
+
+    let missing = Tag::digest("goodbye").to_string();
+    let out = nettle(&["download", &missing, "--node", &url, "--json"]);
+    assert_eq!(
+        out.status.code(),
+        Some(ErrorKind::NotFound.exit_code().into())
+    );
+    assert!(out.stdout.is_empty());
+    assert_eq!(error_report(&out.stderr).error.kind, ErrorKind::NotFound);
+    let out = nettle(&["verify", &missing, path.to_str().unwrap(), "--json"]);
+    assert_eq!(
+        out.status.code(),
+        Some(ErrorKind::Mismatch.exit_code().into())
+    );
+    assert_eq!(error_report(&out.stderr).error.kind, ErrorKind::Mismatch);
+
+    node.kill().unwrap();
+    node.wait().unwrap();
+    let out = nettle(&["status", "--node", &url, "--json"]);
+    assert_eq!(
+        out.status.code(),
+        Some(ErrorKind::Unreachable.exit_code().into())
+    );
+    assert!(out.stdout.is_empty());
+    assert_eq!(error_report(&out.stderr).error.kind, ErrorKind::Unreachable);
+
+    fs::remove_file(path).unwrap();
+    fs::remove_file(copy).unwrap();
+    fs::remove_file(config).unwrap();
+}
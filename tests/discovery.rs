@@ -1,94 +1,1198 @@
-use nettle::{mem, Node, PrivateId, PublicId, Tag};
+use bytes::Bytes;
+use nettle::{
+    mem,
+    sim::{self, Sim, Topology},
+    DiscoverError, DownloadError, HashAlgorithm, LocateReply, Node, PrivateId, ProtocolError,
+    Signed, Tag, UploadError, DEFAULT_MISS_TTL, DEFAULT_REPLICAS, DEFAULT_UPLOAD_QUORUM,
+    MAX_AUDIT_FAILURES,
+};
 use rand::prelude::*;
-use std::{borrow::Cow, collections::HashSet, fs::File, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
-struct Graph {
-    close_to: Tag,
-    nodes: Vec<Arc<Node<mem::Mem>>>,
+#[tokio::test(flavor = "multi_thread")]
+async fn discovery() {
+    let mut sim = Sim::new(Default::default());
+    sim.network().record();
+    sim.spawn_nodes(51, Topology::Random).await;
+
+    // Lookups need more than the one peer that each node starts out with
+    let waits = sim
+        .nodes()
+        .map(|node| node.wait_for_peers(2, Duration::from_secs(30)));
+    for waited in futures::future::join_all(waits).await {
+        waited.unwrap();
+    }
+    assert!(sim.is_connected());
+
+    let messages = sim.network().stop_recording();
+    assert!(messages
+        .iter()
+        .any(|msg| msg.kind == nettle::Request::Discover));
+    // No node should ever try to discover itself
+    assert!(messages
+        .iter()
+        .filter(|msg| msg.kind == nettle::Request::Discover)
+        .all(|msg| msg.from != msg.to));
+
+    // Lookups should take a logarithmic number of hops, which needs peers at most levels
+    let nodes = sim.nodes().cloned().collect::<Vec<_>>();
+    let path_length = sim::average_path_length(&nodes).unwrap();
+    assert!(path_length <= 4.0, "average path length {}", path_length);
+    let completeness = sim.average_routing_completeness();
+    assert!(completeness >= 0.5, "routing completeness {}", completeness);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lossy_discovery() {
+    let mut sim = Sim::new(mem::Network::new(
+        mem::NetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(20),
+            drop_chance: 0.1,
+            corrupt_chance: 0.0,
+        },
+        42,
+    ));
+    sim.set_signing(false);
+    sim.spawn_nodes(31, Topology::Random).await;
+
+    // Despite the losses, every node should still be reachable from every other
+    assert!(
+        sim.run_until(|sim| sim.is_connected(), Duration::from_secs(60))
+            .await
+    );
+}
+
+type Pair = (Arc<Node<mem::Mem>>, Arc<Node<mem::Mem>>);
+
+// The number of `pairs` in which the first node can find the second
+async fn found(pairs: &[Pair]) -> usize {
+    let mut found = 0;
+    for (a, b) in pairs {
+        if matches!(a.locate_data(b.id().tag).await, Ok((_, (id, _))) if id == b.id()) {
+            found += 1;
+        }
+    }
+    found
+}
+
+#[tokio::test(start_paused = true)]
+async fn partition_and_heal() {
+    // The number of pairs of nodes on opposite sides that are tried each way
+    const SAMPLES: usize = 40;
+    let mut sim = Sim::with_seed(mem::Network::new(Default::default(), 9), 9);
+    sim.spawn_nodes(50, Topology::Random).await;
+    assert!(
+        sim.run_until(|sim| sim.is_connected(), Duration::from_secs(30))
+            .await
+    );
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let (left, right) = (0..sim.len() / 2, sim.len() / 2..sim.len());
+    // The same pairs are tried each time, so that lookups across the partition can be compared
+    let mut rng = StdRng::seed_from_u64(9);
+    let cross = (0..SAMPLES)
+        .map(|_| (rng.gen_range(left.clone()), rng.gen_range(right.clone())))
+        .flat_map(|(a, b)| [(a, b), (b, a)])
+        .map(|(a, b)| (sim.node(a).clone(), sim.node(b).clone()))
+        .collect::<Vec<_>>();
+
+    // Routing isn't perfect, so compare against how well lookups worked before the partition
+    let before = found(&cross).await;
+    assert!(before > 0);
+
+    sim.network().partition([
+        left.clone().map(|idx| sim.addr(idx)).collect::<Vec<_>>(),
+        right.clone().map(|idx| sim.addr(idx)).collect::<Vec<_>>(),
+    ]);
+    // Give nodes time to notice that their peers on the other side have gone away
+    tokio::time::sleep(Duration::from_secs(25)).await;
+    assert_eq!(found(&cross).await, 0);
+
+    sim.network().heal();
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    let after = found(&cross).await;
+    assert!(
+        after + SAMPLES / 10 >= before,
+        "{} lookups succeeded after healing, {} before",
+        after,
+        before
+    );
+    sim.shutdown();
 }
 
-impl<'a> dot::Labeller<'a, PublicId, [PublicId; 2]> for Graph {
-    fn graph_id(&'a self) -> dot::Id<'a> {
-        dot::Id::new("network").unwrap()
+// Time only advances when every node is idle, so the network behaves identically on every run
+#[tokio::test(start_paused = true)]
+async fn deterministic_topology() {
+    let topology = |seed| async move {
+        let mut sim = Sim::with_seed(mem::Network::new(Default::default(), seed), seed);
+        sim.spawn_nodes(16, Topology::Random).await;
+        tokio::time::sleep(Duration::from_secs(30)).await;
+        sim.shutdown();
+        sim.topology()
+    };
+    let first = topology(7).await;
+    assert!(!first.is_empty());
+    assert_eq!(topology(7).await, first);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn data_placement() {
+    let mut sim = Sim::new(Default::default());
+    sim.spawn_nodes(30, Topology::Random).await;
+    assert!(
+        sim.run_until(
+            |sim| sim.is_connected() && sim.average_bucket_fill() >= 0.5,
+            Duration::from_secs(30)
+        )
+        .await
+    );
+
+    for i in 0..20 {
+        let data = Bytes::from(format!("item {}", i));
+        let uploader = sim.nodes().choose(&mut thread_rng()).unwrap().clone();
+        let placement = uploader
+            .place(HashAlgorithm::default(), data.clone())
+            .await
+            .unwrap();
+        let tag = placement.tag;
+
+        assert!((DEFAULT_UPLOAD_QUORUM..=DEFAULT_REPLICAS).contains(&placement.copies));
+
+        // The data should be stored by no more nodes than took a copy (fewer if some handed it on to a node that
+        // already had it), the closest of which none of its peers are closer to
+        let mut holders = Vec::new();
+        for node in sim.nodes() {
+            if node.has_data(tag).await {
+                holders.push(node);
+            }
+        }
+        assert!((1..=placement.copies).contains(&holders.len()));
+        let holder = holders
+            .into_iter()
+            .min_by_key(|holder| holder.id().tag.dist_to(tag))
+            .unwrap();
+        let holder_dist = holder.id().tag.dist_to(tag);
+        assert!(holder
+            .get_peers()
+            .iter()
+            .all(|peer| peer.tag.dist_to(tag) >= holder_dist));
+
+        assert_eq!(uploader.do_download(tag).await.unwrap(), Some(data));
+    }
+}
+
+#[tokio::test]
+async fn addr_debug() {
+    let mut sim = Sim::new(Default::default());
+    let unused = mem::Addr::default();
+    let name = format!("{:?}", unused);
+    assert!(name.starts_with("<addr ") && name.ends_with('>'));
+    // The identifier is stable until the address is used
+    assert_eq!(format!("{:?}", unused), name);
+
+    sim.spawn_nodes(2, Topology::Line).await;
+    for idx in 0..sim.len() {
+        assert_eq!(
+            format!("{:?}", sim.addr(idx)),
+            format!("{:?}", sim.node(idx).id())
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn churn() {
+    let mut sim = Sim::new(Default::default());
+    sim.set_signing(false);
+    sim.spawn_nodes(30, Topology::Random).await;
+    assert!(
+        sim.run_until(|sim| sim.is_connected(), Duration::from_secs(30))
+            .await
+    );
+
+    let mut tags = Vec::new();
+    for i in 0..20 {
+        let data = Bytes::from(format!("blob {}", i));
+        let uploader = sim.nodes().choose(&mut thread_rng()).unwrap().clone();
+        tags.push(uploader.do_upload(data).await.unwrap());
     }
 
-    fn node_id(&'a self, n: &PublicId) -> dot::Id<'a> {
-        dot::Id::new(n.human_readable_name(2)).unwrap()
+    sim.churn(0.3, Duration::from_secs(15)).await;
+    assert_eq!(sim.nodes().count(), 30);
+    assert!(
+        sim.run_until(|sim| sim.is_connected(), Duration::from_secs(30))
+            .await
+    );
+
+    // Without replication, data held by a node that left is gone for good. However, every lookup should still finish.
+    let mut downloadable = 0;
+    for tag in tags {
+        let node = sim.nodes().choose(&mut thread_rng()).unwrap().clone();
+        let res = tokio::time::timeout(Duration::from_secs(10), node.do_download(tag))
+            .await
+            .expect("lookup did not terminate");
+        if let Ok(Some(_)) = res {
+            downloadable += 1;
+        }
     }
+    eprintln!("{}/20 blobs remained downloadable", downloadable);
+}
+
+#[tokio::test]
+async fn locate_falls_back_to_other_candidates() {
+    let network = mem::Network::default();
+    let data = Bytes::from_static(b"held by the runner-up");
+    let tag = Tag::digest(&data);
 
-    fn node_label<'b>(&'b self, n: &PublicId) -> dot::LabelText<'b> {
-        dot::LabelText::label(format!(
-            "{}{}",
-            n.human_readable_name(2),
-            255 - self.close_to.dist_to(n.tag).level() as usize
-        ))
+    // Three nodes, by increasing distance from the data
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..3 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    nodes.sort_by_key(|node| node.id().tag.dist_to(tag));
+    let [closest, runner_up, searcher] = &nodes[..] else {
+        unreachable!()
+    };
+    for peer in [closest, runner_up] {
+        assert!(searcher.accept_peer(peer.id(), peer.addr()).await);
     }
+    runner_up
+        .recv_upload(HashAlgorithm::default(), data.clone())
+        .await
+        .unwrap();
+
+    // Everybody closer is suggested, closest first
+    let LocateReply::Closer(suggested) = searcher.recv_locate(tag).await else {
+        panic!("nobody closer was suggested");
+    };
+    assert_eq!(
+        suggested.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+        vec![closest.id(), runner_up.id()]
+    );
 
-    fn edge_label<'b>(&'b self, [a, b]: &[PublicId; 2]) -> dot::LabelText<'b> {
-        dot::LabelText::label(a.tag.dist_to(b.tag).level().to_string())
+    // Losing the closest node doesn't stop the lookup
+    network.disconnect(&closest.addr());
+    assert_eq!(
+        searcher
+            .locate_data(tag)
+            .await
+            .map(|(found, (id, _))| (found, id)),
+        Ok((true, runner_up.id()))
+    );
+    assert_eq!(searcher.do_download(tag).await, Ok(Some(data)));
+
+    // But if nobody responds, that's an error rather than a miss
+    network.disconnect(&runner_up.addr());
+    assert!(searcher.locate_data(tag).await.is_err());
+}
+
+#[tokio::test]
+async fn locate_steps_sideways() {
+    let network = mem::Network::default();
+    let data = Bytes::from_static(b"held off to one side");
+    let tag = Tag::digest(&data);
+
+    // Three nodes, by increasing distance from the data
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..3 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
     }
+    nodes.sort_by_key(|node| node.id().tag.dist_to(tag));
+    let [closest, holder, searcher] = &nodes[..] else {
+        unreachable!()
+    };
+    // The searcher only knows the closest node, which is a dead end but for the holder, which is no closer than it
+    assert!(searcher.accept_peer(closest.id(), closest.addr()).await);
+    assert!(closest.accept_peer(holder.id(), holder.addr()).await);
+    holder
+        .recv_upload(HashAlgorithm::default(), data.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        closest.recv_locate(tag).await,
+        LocateReply::NotCloser(vec![(holder.id(), holder.addr())])
+    );
+
+    let located = searcher.locate(tag).await.unwrap();
+    assert!(located.found);
+    assert_eq!(located.owner.0, holder.id());
+    assert_eq!(located.path, vec![closest.id(), holder.id()]);
+    assert_eq!(searcher.do_download(tag).await, Ok(Some(data)));
+
+    // Without the holder, the closest node is as far as the lookup gets
+    assert!(closest.disconnect(&(&holder.id()).into()).await);
+    let located = searcher.locate(tag).await.unwrap();
+    assert!(!located.found);
+    assert_eq!(located.owner.0, closest.id());
+}
 
-    fn kind(&self) -> dot::Kind {
-        dot::Kind::Graph
+// A node, a peer of it, and some data that the peer is closer to than the node, which neither holds
+async fn missing_data(network: &mem::Network) -> (Arc<Node<mem::Mem>>, Arc<Node<mem::Mem>>, Bytes) {
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
     }
+    let (searcher, peer) = (nodes[0].clone(), nodes[1].clone());
+    assert!(searcher.accept_peer(peer.id(), peer.addr()).await);
+    let data = (0u32..)
+        .map(|i| Bytes::from(format!("not uploaded yet {}", i)))
+        .find(|data| {
+            let tag = Tag::digest(data);
+            peer.id().tag.dist_to(tag) < searcher.id().tag.dist_to(tag)
+        })
+        .unwrap();
+    (searcher, peer, data)
 }
 
-impl<'a> dot::GraphWalk<'a, PublicId, [PublicId; 2]> for Graph {
-    fn nodes(&self) -> dot::Nodes<'a, PublicId> {
-        Cow::Owned(self.nodes.iter().map(|n| n.id().clone()).collect())
+// The number of lookups that have gone out over the network since recording began
+fn lookups(network: &mem::Network) -> usize {
+    network
+        .messages()
+        .iter()
+        .filter(|msg| msg.kind == nettle::Request::Locate)
+        .count()
+}
+
+#[tokio::test(start_paused = true)]
+async fn misses_are_remembered() {
+    let network = mem::Network::default();
+    let (searcher, _peer, data) = missing_data(&network).await;
+    let tag = Tag::digest(&data);
+    network.record();
+
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(lookups(&network), 1);
+    // Asking again answers from the cache, unless told not to
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(searcher.exists(tag, false).await, Ok(false));
+    assert_eq!(lookups(&network), 1);
+    assert_eq!(searcher.exists(tag, true).await, Ok(false));
+    assert_eq!(searcher.do_download_with(tag, true).await, Ok(None));
+    assert_eq!(lookups(&network), 3);
+
+    // Until the miss is forgotten
+    tokio::time::advance(DEFAULT_MISS_TTL - Duration::from_millis(1)).await;
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(lookups(&network), 3);
+    tokio::time::advance(Duration::from_millis(1)).await;
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(lookups(&network), 4);
+
+    // Or never remembered at all
+    searcher.set_miss_ttl(None);
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(lookups(&network), 6);
+}
+
+#[tokio::test]
+async fn uploads_forget_misses() {
+    let network = mem::Network::default();
+    let (searcher, peer, data) = missing_data(&network).await;
+    let tag = Tag::digest(&data);
+
+    // Data uploaded elsewhere stays hidden until the miss is forgotten
+    assert_eq!(searcher.exists(tag, false).await, Ok(false));
+    peer.recv_upload(HashAlgorithm::default(), data.clone())
+        .await
+        .unwrap();
+    assert_eq!(searcher.do_download(tag).await, Ok(None));
+    assert_eq!(searcher.exists(tag, true).await, Ok(true));
+
+    // But uploading it ourselves, or being sent it, shows it straight away
+    let other = Bytes::from_static(b"uploaded by the searcher");
+    let other_tag = Tag::digest(&other);
+    assert_eq!(searcher.do_download(other_tag).await, Ok(None));
+    assert_eq!(searcher.do_upload(other.clone()).await, Ok(other_tag));
+    assert_eq!(searcher.do_download(other_tag).await, Ok(Some(other)));
+
+    let sent = Bytes::from_static(b"sent to the searcher");
+    let sent_tag = Tag::digest(&sent);
+    assert_eq!(searcher.exists(sent_tag, false).await, Ok(false));
+    searcher
+        .recv_upload(HashAlgorithm::default(), sent.clone())
+        .await
+        .unwrap();
+    assert_eq!(searcher.exists(sent_tag, false).await, Ok(true));
+    assert_eq!(searcher.do_download(sent_tag).await, Ok(Some(sent)));
+}
+
+#[tokio::test]
+async fn corrupted_transfers() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
     }
+    let [uploader, receiver] = &nodes[..] else {
+        unreachable!()
+    };
+    uploader.discover_peer(None, receiver.addr()).await.unwrap();
+    uploader.set_replication(1, 1);
 
-    fn edges(&'a self) -> dot::Edges<'a, [PublicId; 2]> {
-        Cow::Owned(
-            self.nodes
-                .iter()
-                .flat_map(|n| {
-                    n.get_peers()
-                        .into_iter()
-                        .map(|p| [n.id().clone(), p])
-                        .map(|[a, b]| if a.tag > b.tag { [b, a] } else { [a, b] })
-                })
-                .collect::<HashSet<_>>()
-                .into_iter()
-                .collect(),
-        )
+    // Some data that belongs with the receiver
+    let data = (0u32..)
+        .map(|i| Bytes::from(i.to_le_bytes().repeat(100)))
+        .find(|data| {
+            let tag = Tag::digest(data);
+            receiver.id().tag.dist_to(tag) < uploader.id().tag.dist_to(tag)
+        })
+        .unwrap();
+    let tag = Tag::digest(&data);
+
+    // Everything sent between the two nodes arrives damaged
+    let corrupting = mem::NetworkConfig {
+        corrupt_chance: 1.0,
+        ..Default::default()
+    };
+    network.set_link(&uploader.addr(), &receiver.addr(), corrupting.clone());
+    match uploader.do_upload(data.clone()).await {
+        Err(UploadError::Rejected { peer, reason }) => {
+            assert_eq!(peer, receiver.id());
+            assert_eq!(reason, ProtocolError::Mismatch);
+        }
+        res => panic!("corruption went unnoticed: {:?}", res),
     }
+    assert!(!receiver.has_data(tag).await);
+    assert_eq!(receiver.stats().entries, 0);
 
-    fn source(&self, e: &[PublicId; 2]) -> PublicId {
-        e[0].clone()
+    // Once the link is healthy the upload goes through, but downloading over a bad link is still caught
+    network.set_link(&uploader.addr(), &receiver.addr(), Default::default());
+    assert_eq!(uploader.do_upload(data.clone()).await, Ok(tag));
+    assert!(receiver.has_data(tag).await);
+    network.set_link(&uploader.addr(), &receiver.addr(), corrupting);
+    assert_eq!(
+        uploader.do_download(tag).await,
+        Err(DownloadError::Integrity)
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn uploads_survive_a_lost_replica() {
+    let network = mem::Network::new(
+        mem::NetworkConfig {
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(10),
+            ..Default::default()
+        },
+        0,
+    );
+    let data = Bytes::from_static(b"kept by most of its closest nodes");
+    let tag = Tag::digest(&data);
+
+    // An uploader further from the data than the three nodes it places copies with
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..4 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    nodes.sort_by_key(|node| node.id().tag.dist_to(tag));
+    let uploader = nodes.pop().unwrap();
+    for a in nodes.iter().chain([&uploader]) {
+        for b in nodes.iter().chain([&uploader]) {
+            if a.id() != b.id() {
+                a.accept_peer(b.id(), b.addr()).await;
+            }
+        }
     }
 
-    fn target(&self, e: &[PublicId; 2]) -> PublicId {
-        e[1].clone()
+    // One of them drops off the network once the uploader has looked them all up, but before the data arrives
+    network.record();
+    let upload = tokio::spawn({
+        let uploader = uploader.clone();
+        let data = data.clone();
+        async move { uploader.place(HashAlgorithm::default(), data).await }
+    });
+    while network
+        .messages()
+        .iter()
+        .filter(|msg| msg.kind == nettle::Request::FindNode)
+        .count()
+        < nodes.len()
+    {
+        tokio::time::sleep(Duration::from_millis(1)).await;
     }
+    network.disconnect(&nodes[1].addr());
+
+    let placement = upload.await.unwrap().unwrap();
+    assert_eq!((placement.tag, placement.copies), (tag, 2));
+    assert!(nodes[0].has_data(tag).await);
+    assert!(!nodes[1].has_data(tag).await);
+    assert!(nodes[2].has_data(tag).await);
+    assert!(!uploader.has_data(tag).await);
 }
 
-#[tokio::test(flavor = "multi_thread")]
-async fn discovery() {
-    let spawn_node = |peers: Vec<mem::Addr>| async move {
-        let private_id = PrivateId::generate();
-        let addr: mem::Addr = Default::default();
-        let node = Node::<mem::Mem>::new(private_id, addr.clone(), peers, addr.clone())
+// A node, and three peers of it closer to `data` that each hold a copy of it, closest first
+async fn replicated(
+    network: &mem::Network,
+    data: &Bytes,
+) -> (Arc<Node<mem::Mem>>, Vec<Arc<Node<mem::Mem>>>) {
+    let tag = Tag::digest(data);
+    // Try again if the holders are too alike for the node to peer with all of them
+    let (downloader, nodes) = loop {
+        let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+        for _ in 0..4 {
+            let addr = mem::Addr::default();
+            let config = mem::Config {
+                addr: addr.clone(),
+                network: network.clone(),
+                sign_messages: true,
+            };
+            nodes.push(
+                Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                    .await
+                    .unwrap(),
+            );
+        }
+        nodes.sort_by_key(|node| node.id().tag.dist_to(tag));
+        let downloader = nodes.pop().unwrap();
+        let mut accepted = true;
+        for holder in &nodes {
+            accepted &= downloader.accept_peer(holder.id(), holder.addr()).await;
+        }
+        if accepted {
+            break (downloader, nodes);
+        }
+    };
+    for holder in &nodes {
+        holder
+            .recv_upload(HashAlgorithm::default(), data.clone())
             .await
             .unwrap();
-        tokio::task::spawn(node.clone().run());
-        (node, addr)
+    }
+    (downloader, nodes)
+}
+
+#[tokio::test(start_paused = true)]
+async fn downloads_outpace_slow_holders() {
+    let network = mem::Network::new(
+        mem::NetworkConfig {
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(10),
+            ..Default::default()
+        },
+        0,
+    );
+    let data = Bytes::from_static(b"held by a slow node and its faster neighbours");
+    let tag = Tag::digest(&data);
+    let (downloader, holders) = replicated(&network, &data).await;
+    let slow = mem::NetworkConfig {
+        min_latency: Duration::from_secs(1),
+        max_latency: Duration::from_secs(1),
+        ..Default::default()
+    };
+    network.set_link(&downloader.addr(), &holders[0].addr(), slow);
+
+    // Finding the data takes a round trip to the slow node, but fetching it doesn't have to
+    let started = tokio::time::Instant::now();
+    assert_eq!(downloader.do_download(tag).await, Ok(Some(data.clone())));
+    assert!(started.elapsed() < Duration::from_millis(1500));
+
+    // Without asking anybody else, we're stuck waiting
+    downloader.set_download_fanout(1);
+    let started = tokio::time::Instant::now();
+    assert_eq!(downloader.do_download(tag).await, Ok(Some(data)));
+    assert!(started.elapsed() >= Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn downloads_skip_corrupt_holders() {
+    let network = mem::Network::default();
+    let data = Bytes::from_static(b"held by a faulty node and its sound neighbours");
+    let tag = Tag::digest(&data);
+    let (downloader, holders) = replicated(&network, &data).await;
+    let corrupting = mem::NetworkConfig {
+        corrupt_chance: 1.0,
+        ..Default::default()
     };
+    network.set_link(&downloader.addr(), &holders[0].addr(), corrupting);
 
-    let mut nodes = vec![spawn_node(Vec::new()).await];
-    for _ in 0..50 {
-        let (_parent, parent_port) = nodes.iter().choose(&mut thread_rng()).unwrap();
-        nodes.push(spawn_node(vec![parent_port.clone()]).await);
+    // Every download gets a sound copy from somebody, and the node that keeps sending bad ones is dropped
+    for _ in 0..MAX_AUDIT_FAILURES {
+        assert!(downloader.get_peers().contains(&holders[0].id()));
+        assert_eq!(downloader.do_download(tag).await, Ok(Some(data.clone())));
     }
+    assert!(!downloader.get_peers().contains(&holders[0].id()));
+    assert_eq!(downloader.do_download(tag).await, Ok(Some(data)));
+}
 
-    tokio::time::sleep(std::time::Duration::from_secs(30)).await;
+#[tokio::test]
+async fn downloads_share_stored_data() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    let data = Bytes::from(vec![7; 1 << 20]);
+    let tag = node.do_upload(data.clone()).await.unwrap();
 
-    dot::render(
-        &Graph {
-            close_to: nodes.iter().choose(&mut thread_rng()).unwrap().0.id().tag,
-            nodes: nodes.into_iter().map(|n| n.0).collect(),
-        },
-        &mut File::create("graph.dot").unwrap(),
+    // Serving the same blob over and over hands out the stored copy rather than copying it each time
+    let stored = node.load_data(tag).await.unwrap();
+    assert_eq!(stored, data);
+    for _ in 0..3 {
+        let downloaded = node.do_download(tag).await.unwrap().unwrap();
+        assert_eq!(downloaded.as_ptr(), stored.as_ptr());
+        let served = node.recv_download(tag).await.unwrap().unwrap();
+        assert_eq!(served.as_ptr(), stored.as_ptr());
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn waiting_for_peers() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let [node, peer] = &nodes[..] else {
+        unreachable!()
+    };
+    assert_eq!(node.peer_count(), 0);
+    assert_eq!(node.wait_for_peers(0, Duration::ZERO).await, Ok(()));
+    assert!(node
+        .wait_for_peers(1, Duration::from_secs(1))
+        .await
+        .is_err());
+
+    // Waiters wake as soon as the peer is added, rather than at the deadline
+    let started = tokio::time::Instant::now();
+    let waiting = tokio::spawn({
+        let node = node.clone();
+        async move { node.wait_for_peers(1, Duration::from_secs(60)).await }
+    });
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(node.accept_peer(peer.id(), peer.addr()).await);
+    assert_eq!(waiting.await.unwrap(), Ok(()));
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(node.peer_count(), 1);
+
+    // Losing the peer counts too
+    assert!(node.disconnect(&(&peer.id()).into()).await);
+    assert_eq!(node.peer_count(), 0);
+    assert!(node
+        .wait_for_peers(1, Duration::from_secs(1))
+        .await
+        .is_err());
+}
+
+#[tokio::test(start_paused = true)]
+async fn bootstrap_retries() {
+    let network = mem::Network::default();
+    let config = |addr: &mem::Addr| mem::Config {
+        addr: addr.clone(),
+        network: network.clone(),
+        sign_messages: true,
+    };
+    let peer_addr = mem::Addr::default();
+    let _peer = Node::<mem::Mem>::new(
+        PrivateId::generate(),
+        peer_addr.clone(),
+        Vec::new(),
+        config(&peer_addr),
+    )
+    .await
+    .unwrap();
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(
+        PrivateId::generate(),
+        addr.clone(),
+        vec![peer_addr.clone()],
+        config(&addr),
     )
+    .await
     .unwrap();
+
+    // Our only initial peer can't be reached, so bootstrapping finds nobody
+    network.disconnect(&peer_addr);
+    node.bootstrap().await;
+    assert_eq!(node.peer_count(), 0);
+
+    // Once it's back, bootstrapping again reaches it
+    network.reconnect(&peer_addr);
+    node.bootstrap().await;
+    assert_eq!(node.peer_count(), 1);
+
+    // Bootstrapping once more changes nothing
+    node.bootstrap().await;
+    assert_eq!(node.peer_count(), 1);
+
+    // Nor do bootstraps that overlap
+    futures::future::join(node.bootstrap(), node.bootstrap()).await;
+    assert_eq!(node.peer_count(), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn maintain_without_serving() {
+    let network = mem::Network::default();
+    let config = |addr: &mem::Addr| mem::Config {
+        addr: addr.clone(),
+        network: network.clone(),
+        sign_messages: true,
+    };
+    let peer_addr = mem::Addr::default();
+    let _peer = Node::<mem::Mem>::new(
+        PrivateId::generate(),
+        peer_addr.clone(),
+        Vec::new(),
+        config(&peer_addr),
+    )
+    .await
+    .unwrap();
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(
+        PrivateId::generate(),
+        addr.clone(),
+        vec![peer_addr.clone()],
+        config(&addr),
+    )
+    .await
+    .unwrap();
+
+    // Maintenance bootstraps by itself when we have no peers
+    let maintaining = tokio::spawn(node.clone().maintain());
+    node.wait_for_peers(1, Duration::from_secs(60))
+        .await
+        .unwrap();
+
+    node.shutdown();
+    tokio::time::timeout(Duration::from_secs(1), maintaining)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn find_node_has_no_side_effects() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for i in 0..6 {
+        // The searcher only knows a hub, which knows everybody else, so everybody else needs room at their level
+        let id = std::iter::repeat_with(PrivateId::generate)
+            .find(|id| i < 2 || nodes[1].can_accept_peer(&id.pub_id))
+            .unwrap();
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        let node = Node::<mem::Mem>::new(id, addr, Vec::new(), config)
+            .await
+            .unwrap();
+        if i >= 2 {
+            assert!(nodes[1].accept_peer(node.id(), node.addr()).await);
+        }
+        nodes.push(node);
+    }
+    let hub = &nodes[1];
+    assert!(nodes[0].accept_peer(hub.id(), hub.addr()).await);
+    let searcher = &nodes[0];
+    let peers_before = nodes
+        .iter()
+        .map(|node| node.get_peers())
+        .collect::<Vec<_>>();
+
+    let target = Tag::generate();
+    let found = searcher.find_node(target, 3).await;
+    let mut expected = nodes[1..].iter().map(|node| node.id()).collect::<Vec<_>>();
+    expected.sort_by_key(|id| id.tag.dist_to(target));
+    expected.truncate(3);
+    assert_eq!(
+        found.into_iter().map(|(id, _)| id).collect::<Vec<_>>(),
+        expected
+    );
+
+    // Nobody was greeted, and nobody's peers changed
+    for (node, before) in nodes.iter().zip(peers_before) {
+        assert_eq!(node.get_peers(), before);
+        assert_eq!(node.metrics().requests(nettle::Request::Greet), 0);
+    }
+    assert_eq!(hub.recv_find_node(target, 10).await.len(), 4);
+}
+
+#[tokio::test]
+async fn peer_info() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..3 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let [asker, target, other] = &nodes[..] else {
+        unreachable!()
+    };
+    assert!(asker.accept_peer(target.id(), target.addr()).await);
+    assert!(target.accept_peer(other.id(), other.addr()).await);
+    // The nodes share a clock, so the ping they exchanged on peering should find them in step
+    let offset = asker.peer_info()[0].clock_offset.unwrap();
+    assert!(offset.abs() < 1_000, "clock offset {}ms", offset);
+    target
+        .recv_upload(HashAlgorithm::default(), b"some data".to_vec().into())
+        .await
+        .unwrap();
+
+    let info = asker.query_peer_info(&target.id()).await.unwrap().unwrap();
+    let stats = target.stats();
+    assert_eq!(info.version, nettle::PROTOCOL_VERSION);
+    assert_eq!((info.peers, stats.peers), (1, 1));
+    assert_eq!((info.entries, stats.entries), (1, 1));
+    assert_eq!(info.levels, stats.levels);
+    assert!(info.uptime_secs <= stats.uptime_secs);
+    assert_eq!(target.metrics().requests(nettle::Request::Info), 1);
+
+    // Only peers can be asked
+    assert!(asker.query_peer_info(&other.id()).await.is_err());
+    network.disconnect(&target.addr());
+    assert!(asker.query_peer_info(&target.id()).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bootstrap_skips_unresponsive_peers() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..5 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    // The only live initial peer comes last, after four whose messages never arrive
+    let addr = mem::Addr::default();
+    let config = mem::Config {
+        addr: addr.clone(),
+        network: network.clone(),
+        sign_messages: true,
+    };
+    let initial = nodes.iter().map(|node| node.addr()).collect();
+    let newcomer = Node::<mem::Mem>::new(PrivateId::generate(), addr, initial, config)
+        .await
+        .unwrap();
+    let black_hole = mem::NetworkConfig {
+        min_latency: Duration::from_secs(60 * 60),
+        max_latency: Duration::from_secs(60 * 60),
+        ..Default::default()
+    };
+    for node in &nodes[..4] {
+        network.set_link(&newcomer.addr(), &node.addr(), black_hole.clone());
+    }
+    tokio::spawn(newcomer.clone().run());
+
+    let live = &nodes[4];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !newcomer.get_peers().contains(&live.id()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the live initial peer was held up by the others");
+}
+
+#[tokio::test]
+async fn redirect_loops_end() {
+    // Two nodes sharing the first bit of their tags, and a newcomer that differs from both in it
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut id_where = |pred: &dyn Fn(Tag) -> bool| loop {
+        let id = PrivateId::generate_with(&mut rng);
+        if pred(id.pub_id.tag) {
+            return id;
+        }
+    };
+    let a_id = id_where(&|_| true);
+    let a_tag = a_id.pub_id.tag;
+    let b_id = id_where(&|tag| a_tag.bucket_index(tag) == Some(254));
+    let newcomer_id = id_where(&|tag| a_tag.bucket_index(tag) == Some(255));
+
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for id in [a_id, b_id, newcomer_id] {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(Node::new(id, addr, Vec::new(), config).await.unwrap());
+    }
+    let (a, b, newcomer) = (&nodes[0], &nodes[1], &nodes[2]);
+    assert!(a.accept_peer(b.id(), b.addr()).await);
+    assert!(b.accept_peer(a.id(), a.addr()).await);
+    // Both are full, with a peer closer than the newcomer, so each suggests the other in its place
+    a.set_max_peers(Some(1));
+    b.set_max_peers(Some(1));
+
+    let discovered = tokio::time::timeout(Duration::from_secs(5), newcomer.discover_from(a.addr()))
+        .await
+        .expect("the newcomer was bounced between the nodes indefinitely");
+    assert_eq!(discovered, Err(DiscoverError::Loop(a.addr())));
+    assert_eq!(
+        newcomer.discover_from(b.addr()).await,
+        Err(DiscoverError::Loop(b.addr()))
+    );
+
+    newcomer.set_max_redirects(0);
+    assert_eq!(
+        newcomer.discover_from(a.addr()).await,
+        Err(DiscoverError::TooManyRedirects(0))
+    );
+    assert!(newcomer.get_peers().is_empty());
+    // Once there's room, the first suggestion is taken up as normal
+    newcomer.set_max_redirects(1);
+    b.set_max_peers(None);
+    assert_eq!(newcomer.discover_from(a.addr()).await, Ok(()));
+    assert_eq!(newcomer.get_peers(), vec![b.id()]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn discover_walks_are_short() {
+    const NODES: usize = 32;
+    // Each node's discover tick comes round every 5 seconds
+    const TICKS: usize = 12;
+    let mut sim = Sim::with_seed(mem::Network::new(Default::default(), 3), 3);
+    sim.spawn_nodes(NODES, Topology::Random).await;
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert!(sim.is_connected());
+
+    sim.network().record();
+    tokio::time::sleep(Duration::from_secs(5 * TICKS as u64)).await;
+    let discovers = sim
+        .network()
+        .stop_recording()
+        .iter()
+        .filter(|msg| msg.kind == nettle::Request::Discover)
+        .count();
+    // Walking down from level 255 a step at a time took over 6 requests a tick on this network
+    let per_tick = discovers as f64 / (NODES * TICKS) as f64;
+    assert!(per_tick < 4.0, "{} discover requests per tick", per_tick);
+    let completeness = sim.average_routing_completeness();
+    assert!(completeness >= 0.5, "routing completeness {}", completeness);
+    sim.shutdown();
+}
+
+#[tokio::test]
+async fn data_is_handed_to_closer_newcomers() {
+    let network = mem::Network::default();
+    let data = Bytes::from_static(b"belongs with the newcomer");
+    let tag = Tag::digest(&data);
+    let node = |id: PrivateId| {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        Node::<mem::Mem>::new(id, addr, Vec::new(), config)
+    };
+    // Ids by increasing distance from the data: the newcomer, then the holder, then the searcher
+    let mut ids = (0..3).map(|_| PrivateId::generate()).collect::<Vec<_>>();
+    ids.sort_by_key(|id| id.pub_id.tag.dist_to(tag));
+    let mut ids = ids.into_iter();
+    let (newcomer, holder, searcher) = (
+        node(ids.next().unwrap()).await.unwrap(),
+        node(ids.next().unwrap()).await.unwrap(),
+        node(ids.next().unwrap()).await.unwrap(),
+    );
+    assert!(searcher.accept_peer(holder.id(), holder.addr()).await);
+    assert!(holder.save_data(tag, data.clone()).await);
+    tokio::spawn(holder.clone().run());
+
+    // Once the newcomer joins, the holder passes the data on to it
+    newcomer.discover_peer(None, holder.addr()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while holder.has_data(tag).await {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the data was never handed on");
+    assert!(newcomer.has_data(tag).await);
+
+    // So a lookup that goes to the newcomer finds it there
+    assert!(searcher.accept_peer(newcomer.id(), newcomer.addr()).await);
+    assert_eq!(
+        searcher
+            .locate_data(tag)
+            .await
+            .map(|(found, (id, _))| (found, id)),
+        Ok((true, newcomer.id()))
+    );
+    assert_eq!(searcher.do_download(tag).await, Ok(Some(data)));
+}
+
+#[tokio::test]
+async fn data_outlives_its_owner() {
+    let network = mem::Network::default();
+    let data = Bytes::from_static(b"should not go down with its owner");
+    let tag = Tag::digest(&data);
+    let mut nodes = Vec::new();
+    // Few enough that every node has room to peer with every other
+    for _ in 0..3 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::<mem::Mem>::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    for a in &nodes {
+        for b in &nodes {
+            if a.id() != b.id() {
+                a.accept_peer(b.id(), b.addr()).await;
+            }
+        }
+    }
+    let (owner, others) = nodes.split_first().unwrap();
+    assert!(owner.save_data(tag, data.clone()).await);
+
+    let run = tokio::spawn(owner.clone().run());
+    owner.shutdown();
+    run.await.unwrap().unwrap();
+    network.disconnect(&owner.addr());
+
+    assert!(others.iter().any(|node| node.stats().entries == 1));
+    for node in others {
+        assert_eq!(node.do_download(tag).await, Ok(Some(data.clone())));
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn peer_hints_speed_up_convergence() {
+    // In a line, each node starts out knowing only its neighbour, so there's plenty for hints to fill in
+    let bucket_fill = |peer_hints| async move {
+        let mut sim = Sim::with_seed(mem::Network::new(Default::default(), 1), 1);
+        sim.set_peer_hints(peer_hints);
+        sim.spawn_nodes(32, Topology::Line).await;
+        tokio::time::sleep(Duration::from_secs(15)).await;
+        sim.shutdown();
+        sim.average_bucket_fill()
+    };
+    let (without, with) = (bucket_fill(false).await, bucket_fill(true).await);
+    assert!(
+        with > without,
+        "average bucket fill {} with hints, {} without",
+        with,
+        without
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn peers_follow_address_changes() {
+    let mut sim = Sim::with_seed(mem::Network::new(Default::default(), 5), 5);
+    sim.spawn_nodes(8, Topology::Random).await;
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    let mover = sim.node(0).clone();
+    let peers = sim
+        .nodes()
+        .filter(|node| node.get_peers().contains(&mover.id()))
+        .cloned()
+        .collect::<Vec<_>>();
+    assert!(!peers.is_empty());
+    let stale =
+        peers[0].inspect_routing(|routing| routing.get(&mover.id()).unwrap().record.clone());
+
+    // The old address stops working as soon as the mover leaves it
+    let old = mover.addr();
+    let new = mem::Addr::default();
+    assert!(mover.set_addr(new.clone()).await > 0);
+    sim.network().disconnect(&old);
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    for node in &peers {
+        node.inspect_routing(|routing| {
+            let peer = routing.get(&mover.id()).expect("the mover was dropped");
+            assert_eq!(peer.addr, new);
+            assert_eq!(peer.record.body.addr, new);
+        });
+    }
+
+    // Nobody else can move the mover, nor can its old record move it back
+    let fresh = mover.addr_record().await;
+    let stranger = PrivateId::generate();
+    assert!(
+        !peers[0]
+            .recv_readdress(Some(&stranger.pub_id), fresh.clone())
+            .await
+    );
+    let forged = Signed::new(&stranger, fresh.timestamp + 1, 0, fresh.body.clone()).await;
+    assert!(!peers[0].recv_readdress(None, forged).await);
+    assert!(!peers[0].recv_readdress(None, stale).await);
+    peers[0].inspect_routing(|routing| assert_eq!(routing.get(&mover.id()).unwrap().addr, new));
+    sim.shutdown();
 }
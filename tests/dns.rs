@@ -0,0 +1,62 @@
+#![cfg(feature = "http")]
+
+use nettle::dns::{parse_srv, parse_txt};
+
+#[test]
+fn txt_records() {
+    assert_eq!(
+        parse_txt(&["nettle-peer=http://10.0.0.1:34093"])
+            .unwrap()
+            .unwrap()
+            .as_str(),
+        "http://10.0.0.1:34093/"
+    );
+    assert_eq!(
+        parse_txt(&[" nettle-peer= https://peer.example.com/nettle "])
+            .unwrap()
+            .unwrap()
+            .as_str(),
+        "https://peer.example.com/nettle/"
+    );
+    // Long records arrive as several strings
+    assert_eq!(
+        parse_txt(&["nettle-peer=http://peer.exa", "mple.com:8000"])
+            .unwrap()
+            .unwrap()
+            .as_str(),
+        "http://peer.example.com:8000/"
+    );
+}
+
+#[test]
+fn unrelated_txt_records() {
+    // Domains have other TXT records, which aren't mistaken for malformed peers
+    for txt in [
+        "v=spf1 include:_spf.example.com -all",
+        "google-site-verification=abc123",
+        "",
+        "Nettle-Peer=http://10.0.0.1:34093",
+    ] {
+        assert!(parse_txt(&[txt]).is_none(), "{}", txt);
+    }
+    assert!(parse_txt::<&str>(&[]).is_none());
+}
+
+#[test]
+fn malformed_txt_records() {
+    for txt in [
+        "nettle-peer=",
+        "nettle-peer=10.0.0.1:34093",
+        "nettle-peer=not a url",
+    ] {
+        assert!(matches!(parse_txt(&[txt]), Some(Err(_))), "{}", txt);
+    }
+}
+
+#[test]
+fn srv_records() {
+    assert_eq!(
+        parse_srv("peer1.example.com.", 34093).unwrap().as_str(),
+        "http://peer1.example.com:34093/"
+    );
+}
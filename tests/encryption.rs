@@ -0,0 +1,47 @@
+use nettle::{envelope, mem, Node, PrivateId};
+use std::sync::Arc;
+
+async fn mem_node(id: PrivateId) -> Arc<Node<mem::Mem>> {
+    let addr = mem::Addr::default();
+    Node::new(id, addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn encrypted_upload() {
+    let alice = mem_node(PrivateId::generate()).await;
+    let bob = mem_node(PrivateId::generate()).await;
+    alice.discover_peer(None, bob.addr()).await.unwrap();
+
+    let secret = b"meet me at the usual place".as_slice();
+    let tag = alice.upload_for(&bob.id(), secret).await.unwrap();
+
+    // Only the recipient can read the data
+    assert_eq!(
+        &*bob.download_encrypted(tag).await.unwrap().unwrap(),
+        secret
+    );
+    assert!(alice.download_encrypted(tag).await.is_err());
+
+    // Everybody else just sees ciphertext
+    let stored = alice.do_download(tag).await.unwrap().unwrap();
+    assert!(!stored.windows(secret.len()).any(|w| w == secret));
+}
+
+#[test]
+fn tampered_envelope() {
+    let recipient = PrivateId::from_seed(b"recipient");
+    let sealed = envelope::seal(&recipient.pub_id, b"hello");
+    assert_eq!(&*envelope::open(&recipient, &sealed).unwrap(), b"hello");
+    assert!(envelope::open(&PrivateId::from_seed(b"someone else"), &sealed).is_err());
+
+    // Changing any byte, whether in the header or the payload, is detected
+    for i in [0, 1, 100, sealed.len() - 30, sealed.len() - 1] {
+        let mut tampered = sealed.to_vec();
+        tampered[i] ^= 1;
+        assert!(envelope::open(&recipient, &tampered).is_err(), "byte {}", i);
+    }
+    assert!(envelope::open(&recipient, &sealed[..sealed.len() / 2]).is_err());
+    assert!(envelope::open(&recipient, &[]).is_err());
+}
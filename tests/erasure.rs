@@ -0,0 +1,223 @@
+use bytes::Bytes;
+use nettle::{
+    erasure::{self, ErasureError, Manifest, Scheme},
+    mem,
+    store::Quotas,
+    DownloadError, Node, PrivateId, Tag, UploadError, CHUNK_LEASE, GC_GRACE,
+};
+use std::sync::Arc;
+
+const SCHEME: Scheme = Scheme {
+    data_shards: 3,
+    parity_shards: 2,
+    algorithm: nettle::HashAlgorithm::Sha3_256,
+};
+
+fn content() -> Bytes {
+    Bytes::from_static(b"an archive too precious to lose, but too large to copy five times over")
+}
+
+#[test]
+fn reconstruction() {
+    let data = content();
+    let (manifest, shards) = erasure::encode(&data, SCHEME);
+    assert_eq!(shards.len(), 5);
+    assert_eq!(Manifest::decode(&manifest.encode()), Ok(manifest.clone()));
+
+    // Any three shards will do
+    for missing in [[0, 1], [0, 4], [2, 3], [3, 4]] {
+        let mut available = shards.iter().cloned().map(Some).collect::<Vec<_>>();
+        for i in missing {
+            available[i] = None;
+        }
+        assert_eq!(erasure::reconstruct(&manifest, available), Ok(data.clone()));
+    }
+
+    // But two won't, and neither will a corrupted third
+    let mut available = shards.iter().cloned().map(Some).collect::<Vec<_>>();
+    available[0] = None;
+    available[1] = None;
+    let mut corrupt = shards[2].to_vec();
+    corrupt[0] ^= 1;
+    available[2] = Some(corrupt.into());
+    assert_eq!(
+        erasure::reconstruct(&manifest, available),
+        Err(ErasureError::TooFewShards {
+            found: 2,
+            needed: 3
+        })
+    );
+
+    // Content too short to fill every data shard still comes back whole
+    let (manifest, shards) = erasure::encode(b"", SCHEME);
+    assert_eq!(
+        erasure::reconstruct(&manifest, shards.into_iter().map(Some).collect()),
+        Ok(Bytes::new())
+    );
+}
+
+#[test]
+fn malformed_manifests() {
+    let (manifest, _) = erasure::encode(&content(), SCHEME);
+    let encoded = manifest.encode();
+    let tampered = |i: usize, byte: u8| {
+        let mut bytes = encoded.to_vec();
+        bytes[i] = byte;
+        Manifest::decode(&bytes)
+    };
+    assert_eq!(tampered(0, b'X'), Err(ErasureError::NotAManifest));
+    assert_eq!(tampered(4, 2), Err(ErasureError::UnsupportedVersion(2)));
+    assert_eq!(tampered(5, 4), Err(ErasureError::Truncated));
+    assert_eq!(tampered(6, 1), Err(ErasureError::Trailing));
+    assert_eq!(
+        tampered(15, 0xff),
+        Err(ErasureError::UnknownAlgorithm(0xff))
+    );
+    assert_eq!(
+        Manifest::decode(&encoded[..encoded.len() - 1]),
+        Err(ErasureError::Truncated)
+    );
+
+    // A scheme with no parity shards is invalid, even if the manifest is the right length for it
+    let mut bytes = encoded[..encoded.len() - 2 * 33].to_vec();
+    bytes[6] = 0;
+    assert_eq!(
+        Manifest::decode(&bytes),
+        Err(ErasureError::InvalidScheme { data: 3, parity: 0 })
+    );
+}
+
+#[tokio::test]
+async fn erasure_coded_transfer() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..6 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    for a in &nodes {
+        for b in &nodes {
+            if a.id() != b.id() {
+                a.accept_peer(b.id(), b.addr()).await;
+            }
+        }
+    }
+    let data = content();
+    let root = nodes[0].upload_erasure(data.clone(), SCHEME).await.unwrap();
+    assert_eq!(
+        nodes[5].download_erasure(root).await,
+        Ok(Some(data.clone()))
+    );
+    assert_eq!(nodes[5].download_erasure(Tag::generate()).await, Ok(None));
+
+    // Every shard is on a different node
+    let downloader = &nodes[0];
+    let manifest = downloader.do_download(root).await.unwrap().unwrap();
+    let shards = Manifest::decode(&manifest).unwrap().shards;
+    let mut holders = Vec::new();
+    for tag in &shards {
+        let mut holding = Vec::new();
+        for node in &nodes[1..] {
+            if node.has_data(*tag).await {
+                holding.push(node.clone());
+            }
+        }
+        assert_eq!(holding.len(), 1);
+        holders.push(holding.remove(0));
+    }
+
+    // Corrupt whatever the holders of all but three shards send the downloader, keeping the manifest to hand
+    downloader.save_data(root, manifest).await;
+    let corrupting = mem::NetworkConfig {
+        corrupt_chance: 1.0,
+        ..Default::default()
+    };
+    for holder in &holders[..2] {
+        network.set_link(&downloader.addr(), &holder.addr(), corrupting.clone());
+    }
+    assert_eq!(downloader.download_erasure(root).await, Ok(Some(data)));
+
+    // Then one more, leaving too few
+    network.set_link(&downloader.addr(), &holders[2].addr(), corrupting);
+    assert_eq!(
+        downloader.download_erasure(root).await,
+        Err(DownloadError::Erasure(ErasureError::TooFewShards {
+            found: 2,
+            needed: 3
+        }))
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn failed_uploads_leave_nothing_behind() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..6 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    for a in &nodes {
+        for b in &nodes {
+            if a.id() != b.id() {
+                a.accept_peer(b.id(), b.addr()).await;
+            }
+        }
+    }
+
+    // Each shard goes to the closest node that doesn't have one yet, so the node that the last one goes to refuses it
+    let data = content();
+    let (manifest, _) = erasure::encode(&data, SCHEME);
+    let mut holders = nodes[1..].to_vec();
+    for tag in &manifest.shards {
+        let closest = (0..holders.len())
+            .min_by_key(|i| holders[*i].id().tag.dist_to(*tag))
+            .unwrap();
+        let holder = holders.remove(closest);
+        if holders.is_empty() {
+            holder.set_quotas(Quotas {
+                per_publisher: Some(0),
+                anonymous: None,
+            });
+        }
+    }
+    assert!(matches!(
+        nodes[0].upload_erasure(data, SCHEME).await,
+        Err(UploadError::Rejected { .. })
+    ));
+    let mut placed = 0;
+    for node in &nodes {
+        for tag in &manifest.shards {
+            if node.has_data(*tag).await {
+                placed += 1;
+            }
+        }
+    }
+    assert_eq!(placed, manifest.shards.len() - 1);
+
+    // The shards that were placed have no manifest to renew them, so they go once their lease runs out
+    tokio::time::advance(CHUNK_LEASE + GC_GRACE).await;
+    let mut collected = 0;
+    for node in &nodes {
+        assert_eq!(node.renew_chunks().await, 0);
+        collected += node.collect_garbage();
+    }
+    assert_eq!(collected, placed);
+}
@@ -0,0 +1,55 @@
+use std::{path::Path, process::Command};
+
+// Library users with their own backend can leave out the HTTP one, along with everything it pulls in
+#[test]
+fn builds_without_http() {
+    let status = Command::new(env!("CARGO"))
+        .args([
+            "check",
+            "--quiet",
+            "--lib",
+            "--tests",
+            "--no-default-features",
+        ])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        // A target directory of its own, so that we neither wait on nor disturb the build running this test
+        .env(
+            "CARGO_TARGET_DIR",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/target/no-default-features"),
+        )
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+// The library builds for browsers with the client backend, given nothing that needs sockets or C sources
+#[test]
+fn builds_for_wasm32() {
+    const TARGET: &str = "wasm32-unknown-unknown";
+    let sysroot = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    let sysroot = String::from_utf8(sysroot.stdout).unwrap();
+    assert!(
+        Path::new(sysroot.trim())
+            .join("lib/rustlib")
+            .join(TARGET)
+            .exists(),
+        "the {} target isn't installed, try `rustup target add {}`",
+        TARGET,
+        TARGET
+    );
+    let status = Command::new(env!("CARGO"))
+        .args(["check", "--quiet", "--lib", "--no-default-features"])
+        .args(["--features", "wasm-client", "--target", TARGET])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .env(
+            "CARGO_TARGET_DIR",
+            concat!(env!("CARGO_MANIFEST_DIR"), "/target/wasm32"),
+        )
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
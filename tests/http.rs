@@ -0,0 +1,1490 @@
+#![cfg(feature = "http")]
+
+use nettle::{
+    client, http,
+    msg::{self, Greet, Ping, Pong},
+    AddrRecord, GreetRefusal, Node, PrivateId, SignatureError, Signed, Tag, PROTOCOL_VERSION,
+};
+use reqwest::Url;
+use std::{
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+// Find a free port by briefly binding to it
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+async fn create_node(config: http::Config) -> (Arc<Node<http::Http>>, Url) {
+    let port = free_port();
+    let url = http::parse_addr(&format!("http://127.0.0.1:{}", port)).unwrap();
+    let node = Node::<http::Http>::new(
+        PrivateId::generate(),
+        url.clone(),
+        Vec::new(),
+        http::Config {
+            bind_addrs: vec![([127, 0, 0, 1], port).into()],
+            ..config
+        },
+    )
+    .await
+    .unwrap();
+    (node, url)
+}
+
+async fn wait_for_server(url: &Url) {
+    let client = reqwest::Client::new();
+    for _ in 0..100 {
+        if client.get(format!("{}status", url)).send().await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+// Sign a message as though it were sent by a node with the given identity
+async fn signed<T: serde::Serialize>(id: &PrivateId, nonce: u128, body: T) -> Signed<T> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    Signed::new(id, now, nonce, body).await
+}
+
+async fn spawn_node(config: http::Config) -> (Arc<Node<http::Http>>, Url) {
+    let (node, url) = create_node(config).await;
+    tokio::task::spawn(node.clone().run());
+    wait_for_server(&url).await;
+    (node, url)
+}
+
+#[tokio::test]
+async fn upload_size_limit() {
+    let (_node, url) = spawn_node(http::Config {
+        max_upload_size: 1024,
+        ..Default::default()
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    let data = vec![42; 1024];
+    let resp = client
+        .post(format!("{}data", url))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(resp.text().await.unwrap(), Tag::digest(&data).to_string());
+
+    let resp = client
+        .post(format!("{}data", url))
+        .body(vec![42; 1025])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+
+    // Without a content length the limit must still be enforced on the stream itself
+    let mut stream = TcpStream::connect(url.socket_addrs(|| None).unwrap()[0])
+        .await
+        .unwrap();
+    stream
+        .write_all(b"POST /data HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n")
+        .await
+        .unwrap();
+    for _ in 0..64 {
+        let mut chunk = b"40\r\n".to_vec();
+        chunk.extend([0; 64]);
+        chunk.extend(b"\r\n");
+        if stream.write_all(&chunk).await.is_err() {
+            break;
+        }
+    }
+    let _ = stream.write_all(b"0\r\n\r\n").await;
+    let mut resp = String::new();
+    let _ = stream.read_to_string(&mut resp).await;
+    assert!(resp.starts_with("HTTP/1.1 413"), "{}", resp);
+}
+
+#[tokio::test]
+async fn busy_uploads() {
+    let (node, url) = spawn_node(Default::default()).await;
+    node.set_upload_limit(1, Duration::from_millis(100));
+    let client = reqwest::Client::new();
+
+    let permit = node.upload_permit().await.unwrap();
+    let resp = client
+        .post(format!("{}data", url))
+        .body(b"too busy".to_vec())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+    assert!(resp.headers().contains_key(reqwest::header::RETRY_AFTER));
+    let metrics = client
+        .get(format!("{}metrics", url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(
+        metrics.contains("nettle_busy_rejections_total 1\n"),
+        "{}",
+        metrics
+    );
+
+    drop(permit);
+    let resp = client
+        .post(format!("{}data", url))
+        .body(b"too busy".to_vec())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn tagged_uploads() {
+    let (node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+    let data = b"known in advance".to_vec();
+    let tag = Tag::digest(&data);
+
+    // Data that isn't what the tag says is refused
+    let resp = client
+        .put(format!("{}data/{}", url, tag))
+        .body(b"something else".to_vec())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNPROCESSABLE_ENTITY);
+    assert!(resp.text().await.unwrap().contains(&tag.to_string()));
+    assert!(!node.has_data(Tag::digest(b"something else")).await);
+
+    let resp = client
+        .put(format!("{}data/{}", url, tag))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    assert_eq!(resp.text().await.unwrap(), tag.to_string());
+    assert!(node.has_data(tag).await);
+
+    // Once the node has the data, it answers before the body is sent
+    let mut stream = TcpStream::connect(url.socket_addrs(|| None).unwrap()[0])
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "PUT /data/{} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+                tag,
+                data.len()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut resp = vec![0; 1024];
+    let n = stream.read(&mut resp).await.unwrap();
+    let resp = String::from_utf8_lossy(&resp[..n]);
+    assert!(resp.starts_with("HTTP/1.1 200"), "{}", resp);
+
+    let resp = client
+        .put(format!("{}data/abcd", url))
+        .body(data)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn data_routes() {
+    let (_node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+
+    let data = b"hello, world!".to_vec();
+    let resp = client
+        .post(format!("{}data", url))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    let tag = resp.text().await.unwrap();
+    assert_eq!(tag, Tag::digest(&data).to_string());
+
+    let resp = client
+        .get(format!("{}data/{}", url, tag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(&*resp.bytes().await.unwrap(), &*data);
+
+    // `upload` is no longer special, it's just a malformed tag
+    for (path, reason) in [
+        ("data/upload", "not a hex digit at byte 0"),
+        ("data/zz", "not a hex digit at byte 0"),
+        ("data/abcd", "found 4 hex digits"),
+    ] {
+        let resp = client.get(format!("{}{}", url, path)).send().await.unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+        assert!(resp.text().await.unwrap().contains(reason));
+    }
+    let resp = client
+        .get(format!("{}data/{}", url, Tag::digest(b"missing")))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn status() {
+    let (node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+
+    let resp = client.get(format!("{}status", url)).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let status = resp.json::<http::Status>().await.unwrap();
+    assert_eq!(status.version, PROTOCOL_VERSION);
+    assert_eq!(status.tag, node.id().tag);
+    assert_eq!(status.name, node.id().human_readable_name(2));
+    assert_eq!(status.addr, url);
+    assert_eq!(status.peers, 0);
+    assert!(status.levels.is_empty());
+
+    // With no peers, the node is not ready
+    let resp = client
+        .get(format!("{}status?ready=true", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+
+    let (other, _) = spawn_node(Default::default()).await;
+    other.discover_peer(None, url.clone()).await.unwrap();
+    let resp = client
+        .get(format!("{}status?ready=true", url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let status = resp.json::<http::Status>().await.unwrap();
+    assert_eq!(status.peers, 1);
+    assert_eq!(status.levels.values().sum::<usize>(), 1);
+}
+
+#[tokio::test]
+async fn peers() {
+    let (a, a_url) = spawn_node(Default::default()).await;
+    let (b, b_url) = spawn_node(http::Config {
+        admin_token: Some("secret".to_string()),
+        ..Default::default()
+    })
+    .await;
+    a.discover_peer(None, b_url.clone()).await.unwrap();
+    let client = reqwest::Client::new();
+
+    let peers = client
+        .get(format!("{}peers", a_url))
+        .send()
+        .await
+        .unwrap()
+        .json::<Vec<http::PeerEntry>>()
+        .await
+        .unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].tag, b.id().tag);
+    assert_eq!(peers[0].addr, b_url);
+    assert_eq!(
+        peers[0].level,
+        a.id().tag.bucket_index(b.id().tag).unwrap() as u16
+    );
+
+    let resp = client.get(format!("{}peers", b_url)).send().await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let peers = client
+        .get(format!("{}peers", b_url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json::<Vec<http::PeerEntry>>()
+        .await
+        .unwrap();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].tag, a.id().tag);
+    assert_eq!(peers[0].addr, a_url);
+
+    // The older listing gives away the same peers, so it needs the token too
+    let resp = client
+        .get(format!("{}list_peers", b_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    let peers = client
+        .get(format!("{}list_peers", b_url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap()
+        .json::<Vec<(String, String)>>()
+        .await
+        .unwrap();
+    assert_eq!(peers, [(format!("{:?}", a.id()), a_url.to_string())]);
+
+    // Peers can be dropped by their textual identity
+    let disconnect = |id: String| {
+        client
+            .delete(format!("{}peers/{}", b_url, id))
+            .bearer_auth("secret")
+            .send()
+    };
+    let resp = disconnect("nettle:1234".to_string()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let resp = disconnect(a.id().to_string()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NO_CONTENT);
+    assert!(b.get_peers().is_empty());
+    let resp = disconnect(a.id().tag.to_string()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn metrics() {
+    let (a, a_url) = spawn_node(Default::default()).await;
+    let (_b, b_url) = spawn_node(Default::default()).await;
+    a.discover_peer(None, b_url.clone()).await.unwrap();
+    let client = reqwest::Client::new();
+
+    let data = b"some data".to_vec();
+    let tag = client
+        .post(format!("{}data", a_url))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    client
+        .get(format!("{}data/{}", b_url, tag))
+        .send()
+        .await
+        .unwrap();
+
+    let metrics = |url: Url| {
+        let client = client.clone();
+        async move {
+            client
+                .get(format!("{}metrics", url))
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap()
+        }
+    };
+    let a_metrics = metrics(a_url).await;
+    let b_metrics = metrics(b_url).await;
+    for m in [&a_metrics, &b_metrics] {
+        assert!(m.contains("nettle_peers 1\n"), "{}", m);
+        assert!(m.contains("# TYPE nettle_requests_total counter\n"));
+    }
+    assert!(b_metrics.contains("nettle_requests_total{type=\"greet\"} 1\n"));
+    assert!(a_metrics.contains("nettle_upload_bytes_total 9\n"));
+    assert!(b_metrics.contains("nettle_download_bytes_total 9\n"));
+    // With so few nodes, both of them hold a copy
+    for m in [&a_metrics, &b_metrics] {
+        assert!(m.contains("nettle_stored_entries 1\n"), "{}", m);
+    }
+}
+
+#[tokio::test]
+async fn retry_transient_failures() {
+    let (a, _) = spawn_node(http::Config {
+        retries: 5,
+        retry_backoff: Duration::from_millis(50),
+        ..Default::default()
+    })
+    .await;
+
+    // Create a node, but only start serving requests a little later
+    let (b, url) = create_node(Default::default()).await;
+    let b_clone = b.clone();
+    tokio::task::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        b_clone.run().await
+    });
+
+    // Accepting a peer pings it, which should be retried until the peer comes up
+    assert!(a.accept_peer(b.id().clone(), url).await);
+}
+
+#[tokio::test]
+async fn http2_prior_knowledge() {
+    let config = || http::Config {
+        pool_max_idle_per_host: 1,
+        pool_idle_timeout: Some(Duration::from_secs(300)),
+        tcp_keepalive: Some(Duration::from_secs(15)),
+        http2_prior_knowledge: true,
+        ..Default::default()
+    };
+    let (a, _) = spawn_node(config()).await;
+    let (b, b_url) = spawn_node(config()).await;
+
+    a.discover_peer(None, b_url).await.unwrap();
+    assert_eq!(a.get_peers(), vec![b.id().clone()]);
+    assert_eq!(b.get_peers(), vec![a.id().clone()]);
+}
+
+#[tokio::test]
+async fn greet_with_invalid_addr() {
+    let (node, url) = spawn_node(Default::default()).await;
+
+    let sender = PrivateId::generate();
+    for addr in ["mailto:nettle@example.com", "data:text/plain,nettle"] {
+        let reply = node
+            .recv_greet(Greet::new(
+                (sender.pub_id.clone(), addr.parse().unwrap()),
+                Tag::generate(),
+            ))
+            .await
+            .unwrap();
+        let proof = sender.answer_challenge(reply.challenge, &node.id()).await;
+        assert!(!node.recv_prove(sender.pub_id.clone(), proof).await);
+    }
+    assert!(node.get_peers().is_empty());
+
+    // Malformed addresses are rejected before they reach the node
+    #[derive(serde::Serialize)]
+    struct RawGreet {
+        sender: (nettle::PublicId, &'static str),
+    }
+    let resp = reqwest::Client::new()
+        .post(format!("{}peer/greet", url))
+        .json(&RawGreet {
+            sender: (sender.pub_id, "http://[::1"),
+        })
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_client_error());
+    assert!(node.get_peers().is_empty());
+
+    // The node is still alive and well
+    let resp = reqwest::get(format!("{}status", url)).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+}
+
+#[tokio::test]
+async fn graceful_shutdown() {
+    let (node, url) = create_node(Default::default()).await;
+    let run = tokio::task::spawn(node.clone().run());
+    wait_for_server(&url).await;
+    let client = reqwest::Client::new();
+
+    let data = vec![7; 8 * 1024 * 1024];
+    let tag = client
+        .post(format!("{}data", url))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    // Start a download, but shut down before the body has been read
+    let resp = client
+        .get(format!("{}data/{}", url, tag))
+        .send()
+        .await
+        .unwrap();
+    node.shutdown();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(&*resp.bytes().await.unwrap(), &*data);
+
+    run.await.unwrap().unwrap();
+    assert!(client.get(format!("{}status", url)).send().await.is_err());
+}
+
+#[tokio::test]
+async fn range_requests() {
+    let (_node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+
+    let data = (0..100u8).collect::<Vec<_>>();
+    let tag = client
+        .post(format!("{}data", url))
+        .body(data.clone())
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+
+    let get = |range: Option<&'static str>| {
+        let mut req = client.get(format!("{}data/{}", url, tag));
+        if let Some(range) = range {
+            req = req.header(reqwest::header::RANGE, range);
+        }
+        async move { req.send().await.unwrap() }
+    };
+
+    let resp = get(None).await;
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.headers()[reqwest::header::ACCEPT_RANGES], "bytes");
+    assert_eq!(&*resp.bytes().await.unwrap(), &*data);
+
+    for (range, content_range, expected) in [
+        ("bytes=10-19", "bytes 10-19/100", &data[10..20]),
+        ("bytes=90-", "bytes 90-99/100", &data[90..]),
+        ("bytes=-5", "bytes 95-99/100", &data[95..]),
+        ("bytes=-500", "bytes 0-99/100", &data[..]),
+        ("bytes=99-300000000", "bytes 99-99/100", &data[99..]),
+    ] {
+        let resp = get(Some(range)).await;
+        assert_eq!(
+            resp.status(),
+            reqwest::StatusCode::PARTIAL_CONTENT,
+            "{}",
+            range
+        );
+        assert_eq!(
+            resp.headers()[reqwest::header::CONTENT_RANGE],
+            content_range
+        );
+        assert_eq!(&*resp.bytes().await.unwrap(), expected);
+    }
+
+    for range in ["bytes=100-", "bytes=300000000-300000099", "bytes=-0"] {
+        let resp = get(Some(range)).await;
+        assert_eq!(
+            resp.status(),
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE,
+            "{}",
+            range
+        );
+        assert_eq!(
+            resp.headers()[reqwest::header::CONTENT_RANGE],
+            "bytes */100"
+        );
+    }
+
+    // Malformed or multi-part ranges are ignored
+    for range in ["bytes=20-10", "items=0-5", "bytes=0-1,5-6"] {
+        let resp = get(Some(range)).await;
+        assert_eq!(resp.status(), reqwest::StatusCode::OK, "{}", range);
+        assert_eq!(&*resp.bytes().await.unwrap(), &*data);
+    }
+}
+
+#[tokio::test]
+async fn conditional_requests() {
+    let (node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+
+    let tag = node.do_upload(b"cache me".to_vec().into()).await.unwrap();
+    let etag = format!("\"{}\"", tag);
+
+    let resp = client
+        .get(format!("{}data/{}", url, tag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert_eq!(resp.headers()[reqwest::header::ETAG], etag.as_str());
+    assert_eq!(
+        resp.headers()[reqwest::header::CACHE_CONTROL],
+        "public, max-age=31536000, immutable"
+    );
+
+    for if_none_match in [
+        etag.clone(),
+        format!("\"abc\", W/{}", etag),
+        "*".to_string(),
+    ] {
+        let resp = client
+            .get(format!("{}data/{}", url, tag))
+            .header(reqwest::header::IF_NONE_MATCH, if_none_match)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers()[reqwest::header::ETAG], etag.as_str());
+    }
+
+    // A conditional request for a tag we don't have needn't touch the network either
+    let missing = Tag::digest(b"missing");
+    let resp = client
+        .get(format!("{}data/{}", url, missing))
+        .header(reqwest::header::IF_NONE_MATCH, format!("\"{}\"", missing))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_MODIFIED);
+
+    let resp = client
+        .get(format!("{}data/{}", url, missing))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+    assert!(resp.headers().get(reqwest::header::CACHE_CONTROL).is_none());
+    assert!(resp.headers().get(reqwest::header::ETAG).is_none());
+}
+
+#[tokio::test]
+async fn content_types() {
+    let (_node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+
+    let upload = |data: &'static [u8], content_type: Option<&'static str>| {
+        let mut req = client.post(format!("{}data", url)).body(data);
+        if let Some(content_type) = content_type {
+            req = req.header(reqwest::header::CONTENT_TYPE, content_type);
+        }
+        async move { req.send().await.unwrap().text().await.unwrap() }
+    };
+    let content_type = |tag: String| {
+        let req = client.get(format!("{}data/{}", url, tag));
+        async move {
+            let resp = req.send().await.unwrap();
+            assert_eq!(resp.headers()["x-content-type-options"], "nosniff");
+            resp.headers()[reqwest::header::CONTENT_TYPE]
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    let png = upload(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", None).await;
+    assert_eq!(content_type(png).await, "image/png");
+
+    let json = upload(br#"{"hello": "world"}"#, Some("application/json")).await;
+    assert_eq!(content_type(json).await, "application/json");
+
+    let bin = upload(b"\xff\xfe\xfd", None).await;
+    assert_eq!(content_type(bin).await, "application/octet-stream");
+
+    let resp = client.get(format!("{}data/zz", url)).send().await.unwrap();
+    assert_eq!(resp.headers()["x-content-type-options"], "nosniff");
+}
+
+#[tokio::test]
+async fn web_ui() {
+    let (_, url) = spawn_node(Default::default()).await;
+    let resp = reqwest::get(url.clone()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+
+    let (_, url) = spawn_node(http::Config {
+        web_ui: true,
+        ..Default::default()
+    })
+    .await;
+    let resp = reqwest::get(url.clone()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    assert!(resp.headers()[reqwest::header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    assert!(resp.text().await.unwrap().contains("<title>Nettle</title>"));
+}
+
+#[tokio::test]
+async fn form_upload() {
+    let (node, url) = spawn_node(http::Config {
+        max_upload_size: 1024,
+        ..Default::default()
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    let post_form = |parts: &[(&str, Option<&str>, &[u8])]| {
+        let mut body = Vec::new();
+        for (name, filename, data) in parts {
+            body.extend(b"--BOUNDARY\r\n");
+            match filename {
+                Some(filename) => body.extend(
+                    format!(
+                        "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n",
+                        name, filename
+                    )
+                    .bytes(),
+                ),
+                None => body.extend(
+                    format!("Content-Disposition: form-data; name=\"{}\"\r\n", name).bytes(),
+                ),
+            }
+            body.extend(b"\r\n");
+            body.extend(*data);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"--BOUNDARY--\r\n");
+        client
+            .post(format!("{}data/form", url))
+            .header(
+                reqwest::header::CONTENT_TYPE,
+                "multipart/form-data; boundary=BOUNDARY",
+            )
+            .body(body)
+            .send()
+    };
+
+    let resp = post_form(&[
+        ("comment", None, b"not a file"),
+        ("file", Some("a.txt"), b"hello"),
+        ("file", Some("b.txt"), b"world!"),
+    ])
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::CREATED);
+    let uploads = resp.json::<Vec<http::FormUpload>>().await.unwrap();
+    assert_eq!(uploads.len(), 2);
+    assert_eq!(uploads[0].filename, "a.txt");
+    assert_eq!(uploads[0].bytes, 5);
+    assert_eq!(uploads[0].tag, Tag::digest(b"hello"));
+    assert_eq!(uploads[1].filename, "b.txt");
+    assert_eq!(uploads[1].tag, Tag::digest(b"world!"));
+    assert_eq!(
+        node.do_download(uploads[1].tag).await.unwrap().as_deref(),
+        Some(&b"world!"[..])
+    );
+
+    let resp = post_form(&[("comment", None, b"no files here")])
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+
+    let resp = post_form(&[("file", Some("big.bin"), &[0; 1025])])
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn multiple_bind_addrs() {
+    let ports = [free_port(), free_port()];
+    let url: Url = "http://nettle.example.com/".parse().unwrap();
+    let node = Node::<http::Http>::new(
+        PrivateId::generate(),
+        url.clone(),
+        Vec::new(),
+        http::Config {
+            bind_addrs: ports.map(|port| ([127, 0, 0, 1], port).into()).to_vec(),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    let run = tokio::task::spawn(node.clone().run());
+    for port in ports {
+        wait_for_server(&format!("http://127.0.0.1:{}/", port).parse().unwrap()).await;
+        let status = reqwest::get(format!("http://127.0.0.1:{}/status", port))
+            .await
+            .unwrap()
+            .json::<http::Status>()
+            .await
+            .unwrap();
+        // The advertised address is independent of where we listen
+        assert_eq!(status.addr, url);
+    }
+    node.shutdown();
+    run.await.unwrap().unwrap();
+
+    // If any address can't be bound, the node fails to start
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let node = Node::<http::Http>::new(
+        PrivateId::generate(),
+        url,
+        Vec::new(),
+        http::Config {
+            bind_addrs: vec![
+                ([127, 0, 0, 1], free_port()).into(),
+                taken.local_addr().unwrap(),
+            ],
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert!(node.run().await.is_err());
+}
+
+#[tokio::test]
+async fn path_prefix() {
+    let spawn_prefixed = |prefix: &'static str, url_suffix: &'static str| async move {
+        let port = free_port();
+        let url: Url = format!("http://127.0.0.1:{}/{}{}", port, prefix, url_suffix)
+            .parse()
+            .unwrap();
+        let node = Node::<http::Http>::new(
+            PrivateId::generate(),
+            url.clone(),
+            Vec::new(),
+            http::Config {
+                bind_addrs: vec![([127, 0, 0, 1], port).into()],
+                path_prefix: Some(prefix.to_string()),
+                web_ui: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        tokio::task::spawn(node.clone().run());
+        wait_for_server(&http::parse_addr(url.as_str()).unwrap()).await;
+        (node, url)
+    };
+    // Peer addresses both with and without a trailing slash should work
+    let (a, a_url) = spawn_prefixed("nettle", "/").await;
+    let (b, b_url) = spawn_prefixed("deeply/nested", "").await;
+
+    a.discover_peer(None, b_url.clone()).await.unwrap();
+    assert_eq!(a.get_peers(), vec![b.id().clone()]);
+    assert_eq!(b.get_peers(), vec![a.id().clone()]);
+
+    let tag = a.do_upload(b"prefixed".to_vec().into()).await.unwrap();
+    assert_eq!(
+        b.do_download(tag).await.unwrap().as_deref(),
+        Some(&b"prefixed"[..])
+    );
+
+    let resp = reqwest::get(format!("{}data/{}", a_url, tag))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let resp = reqwest::get(format!("{}/status", b_url)).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    let resp = reqwest::get(a_url.clone()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+
+    // Nothing is served outside of the prefix
+    let resp = reqwest::get(a_url.as_str().replace("nettle/", "status"))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn canonical_addrs() {
+    let equivalent = [
+        ("http://example.com:34093", "http://example.com:34093/"),
+        ("http://example.com:34093", "HTTP://EXAMPLE.COM:34093"),
+        ("http://example.com:34093/", "http://example.com:34093//"),
+        ("http://example.com", "http://example.com:80"),
+        ("https://example.com/", "https://example.com:443/"),
+        ("http://example.com/nettle", "http://example.com/nettle/"),
+        ("http://example.com/nettle", "http://example.com/%6Eettle"),
+        ("http://example.com/~user", "http://example.com/%7euser"),
+        ("http://example.com/a%2Fb", "http://example.com/a%2fb"),
+        ("http://example.com/", "http://example.com/?query#fragment"),
+        ("http://[::1]:34093", "http://[0:0:0:0:0:0:0:1]:34093"),
+    ];
+    for (a, b) in equivalent {
+        assert_eq!(
+            http::parse_addr(a).unwrap(),
+            http::parse_addr(b).unwrap(),
+            "{} and {}",
+            a,
+            b
+        );
+    }
+    let distinct = [
+        ("http://example.com:34093", "https://example.com:34093"),
+        ("http://example.com:34093", "http://example.com:34094"),
+        ("http://example.com:34093", "http://example.org:34093"),
+        ("http://example.com", "http://example.com:443"),
+        ("http://example.com/nettle", "http://example.com/Nettle"),
+        ("http://example.com/nettle", "http://example.com/nettle/x"),
+        // An escaped slash is part of a segment, rather than separating two
+        ("http://example.com/a%2Fb", "http://example.com/a/b"),
+    ];
+    for (a, b) in distinct {
+        assert_ne!(
+            http::parse_addr(a).unwrap(),
+            http::parse_addr(b).unwrap(),
+            "{} and {}",
+            a,
+            b
+        );
+    }
+}
+
+#[test]
+fn canonical_addr() {
+    let url = Url::parse("HTTP://Example.COM:80/a%7e%2f%41b///?x=1#y").unwrap();
+    let canonical = http::canonical_addr(url);
+    assert_eq!(canonical.as_str(), "http://example.com/a~%2FAb/");
+    // Canonicalising is idempotent
+    assert_eq!(http::canonical_addr(canonical.clone()), canonical);
+}
+
+#[test]
+fn addrs_with_new_ips() {
+    let with_ip = |addr: &str, ip: &str| {
+        http::addr_with_ip(&http::parse_addr(addr).unwrap(), ip.parse().unwrap())
+            .map(|addr| addr.to_string())
+    };
+    assert_eq!(
+        with_ip("http://198.51.100.1:34093/nettle/", "203.0.113.7").as_deref(),
+        Some("http://203.0.113.7:34093/nettle/")
+    );
+    assert_eq!(
+        with_ip("http://[2001:db8::1]:34093", "2001:db8::2").as_deref(),
+        Some("http://[2001:db8::2]:34093/")
+    );
+    assert_eq!(with_ip("http://example.com:34093", "203.0.113.7"), None);
+}
+
+#[tokio::test]
+async fn non_canonical_peer_addrs() {
+    let (a, _) = spawn_node(Default::default()).await;
+    let (b, b_url) = spawn_node(Default::default()).await;
+    let written = format!("HTTP://127.0.0.1:{}//?via=test", b_url.port().unwrap());
+    a.discover_peer(None, Url::parse(&written).unwrap())
+        .await
+        .unwrap();
+    // The peer is known by its canonical address, however it was written
+    a.inspect_routing(|routing| assert_eq!(routing.get(&b.id()).unwrap().addr, b_url));
+}
+
+#[tokio::test]
+async fn rate_limit() {
+    let (node, url) = spawn_node(http::Config {
+        rate_limit: Some(http::RateLimit {
+            cheap: http::Rate {
+                per_second: 0.1,
+                burst: 5,
+            },
+            expensive: http::Rate {
+                per_second: 0.1,
+                burst: 2,
+            },
+            trusted_proxy_header: Some("X-Forwarded-For".to_string()),
+        }),
+        ..Default::default()
+    })
+    .await;
+    let client = reqwest::Client::new();
+    let sender = PrivateId::from_seed(b"rate limited");
+
+    let burst = |path: &'static str, body: serde_json::Value, forwarded_for: &'static str| {
+        let client = client.clone();
+        let url = url.clone();
+        let sender = &sender;
+        async move {
+            let mut limited = 0;
+            for _ in 0..20 {
+                let resp = client
+                    .get(format!("{}peer/{}", url, path))
+                    .header("X-Forwarded-For", forwarded_for)
+                    .json(&signed(sender, rand::random(), body.clone()).await)
+                    .send()
+                    .await
+                    .unwrap();
+                if resp.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                    let retry_after = resp.headers()[reqwest::header::RETRY_AFTER]
+                        .to_str()
+                        .unwrap()
+                        .parse::<u64>()
+                        .unwrap();
+                    assert!(retry_after > 0 && retry_after <= 10);
+                    limited += 1;
+                } else {
+                    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+                }
+            }
+            limited
+        }
+    };
+
+    let ping = serde_json::json!({ "sent": 0 });
+    let locate = serde_json::json!({ "tag": Tag::digest(b"x") });
+    assert_eq!(burst("ping", ping.clone(), "10.0.0.1").await, 15);
+    assert_eq!(burst("locate", locate, "10.0.0.1").await, 18);
+    // Each client has their own buckets
+    assert_eq!(burst("ping", ping, "203.0.113.7, 10.0.0.2").await, 15);
+
+    // Limited requests never reached the node
+    let metrics = node.metrics();
+    assert_eq!(metrics.requests(nettle::Request::Ping), 10);
+    assert_eq!(metrics.requests(nettle::Request::Locate), 2);
+}
+
+#[tokio::test]
+async fn debug_graph() {
+    let (a, a_url) = spawn_node(http::Config {
+        admin_token: Some("secret".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let (b, b_url) = spawn_node(Default::default()).await;
+    a.discover_peer(None, b_url).await.unwrap();
+    let client = reqwest::Client::new();
+
+    let resp = client
+        .get(format!("{}debug/graph", a_url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    let resp = client
+        .get(format!("{}debug/graph", a_url))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp.headers()[reqwest::header::CONTENT_TYPE],
+        "text/vnd.graphviz"
+    );
+    let dot = resp.text().await.unwrap();
+    assert!(dot.starts_with("graph network {"));
+    assert!(dot.trim_end().ends_with('}'));
+    for id in [a.id(), b.id()] {
+        assert!(dot.contains(&format!(
+            "\"{}\" [label=\"{}\"];",
+            id.tag,
+            id.human_readable_name(2)
+        )));
+    }
+    assert!(dot.contains(&format!(
+        "\"{}\" -- \"{}\" [label=\"{}\"];",
+        a.id().tag,
+        b.id().tag,
+        a.id().tag.bucket_index(b.id().tag).unwrap() as u16
+    )));
+}
+
+#[tokio::test]
+async fn locate() {
+    let (a, a_url) = spawn_node(http::Config {
+        admin_token: Some("secret".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let (b, b_url) = spawn_node(Default::default()).await;
+    a.discover_peer(None, b_url.clone()).await.unwrap();
+    let client = reqwest::Client::new();
+    let locate = |tag: String| {
+        client
+            .get(format!("{}locate/{}", a_url, tag))
+            .bearer_auth("secret")
+            .send()
+    };
+
+    // Find data that `b` is closer to than `a`, so that `a` has to ask it
+    let (data, tag) = (0u32..)
+        .map(|i| i.to_le_bytes())
+        .map(|data| (data, Tag::digest(data)))
+        .find(|(_, tag)| b.id().tag.dist_to(*tag) < a.id().tag.dist_to(*tag))
+        .unwrap();
+    let report = locate(tag.to_string())
+        .await
+        .unwrap()
+        .json::<http::LocateReport>()
+        .await
+        .unwrap();
+    assert!(!report.found);
+    assert_eq!(report.node.tag, b.id().tag);
+    assert_eq!(report.path.len(), 1);
+
+    b.save_data(tag, data.to_vec().into()).await;
+    let report = locate(tag.to_string())
+        .await
+        .unwrap()
+        .json::<http::LocateReport>()
+        .await
+        .unwrap();
+    assert!(report.found);
+    assert_eq!(report.tag, tag);
+    assert_eq!(report.node.tag, b.id().tag);
+    assert_eq!(report.node.name, b.id().human_readable_name(2));
+    assert_eq!(report.node.addr, b_url);
+    assert_eq!(report.path[0].tag, b.id().tag);
+
+    let resp = locate("nonsense".to_string()).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let resp = client
+        .get(format!("{}locate/{}", a_url, tag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn observe_addr() {
+    let (_, a_url) = spawn_node(Default::default()).await;
+    assert_eq!(
+        http::observe_public_ip(&[a_url.clone(), "mailto:nettle@example.com".parse().unwrap()])
+            .await,
+        Some([127, 0, 0, 1].into())
+    );
+
+    // Nodes learn their address from their initial peers too
+    let port = free_port();
+    let b = Node::<http::Http>::new(
+        PrivateId::generate(),
+        format!("http://127.0.0.1:{}/", port).parse().unwrap(),
+        vec![a_url],
+        http::Config {
+            bind_addrs: vec![([127, 0, 0, 1], port).into()],
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    tokio::task::spawn(b.clone().run());
+    for _ in 0..100 {
+        if !b.observed_ips().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let status = reqwest::get(format!("http://127.0.0.1:{}/status", port))
+        .await
+        .unwrap()
+        .json::<http::Status>()
+        .await
+        .unwrap();
+    assert_eq!(
+        status.observed_ips.into_iter().collect::<Vec<_>>(),
+        vec![([127, 0, 0, 1].into(), 1)]
+    );
+}
+
+#[tokio::test]
+async fn signed_messages() {
+    let (node, url) = spawn_node(Default::default()).await;
+    let client = reqwest::Client::new();
+    let sender = PrivateId::from_seed(b"signed messages");
+    let ping = |body: &Signed<Ping>| client.get(format!("{}peer/ping", url)).json(body).send();
+
+    let msg = signed(&sender, 1, Ping { sent: 1_000 }).await;
+    let resp = ping(&msg).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::OK);
+    // The response is signed by the node, and carries its address record
+    let pong = resp.json::<Signed<Pong<Url>>>().await.unwrap();
+    assert_eq!(pong.sender, node.id());
+    assert!(pong.verify().is_ok());
+    assert_eq!(pong.body.record.sender, node.id());
+    assert_eq!(pong.body.record.body.addr, url);
+    assert!(pong.body.record.verify_record().is_ok());
+    // Along with when it received and answered our ping
+    assert_eq!(pong.body.ping_sent, 1_000);
+    assert!(pong.body.received > 0 && pong.body.sent >= pong.body.received);
+
+    // Sending the same message again is rejected
+    let resp = ping(&msg).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // As is a message signed by someone other than its supposed sender
+    let mut forged = signed(&PrivateId::from_seed(b"forger"), 2, Ping { sent: 1_000 }).await;
+    forged.sender = sender.pub_id.clone();
+    let resp = ping(&forged).await.unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+
+    // Nodes can't greet on behalf of others
+    let greet = signed(
+        &sender,
+        3,
+        serde_json::json!({
+            "sender": (PrivateId::from_seed(b"victim").pub_id, url.clone()),
+            "challenge": Tag::generate(),
+        }),
+    )
+    .await;
+    let resp = client
+        .get(format!("{}peer/greet", url))
+        .json(&greet)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), reqwest::StatusCode::UNAUTHORIZED);
+    assert!(node.get_peers().is_empty());
+    assert_eq!(node.metrics().requests(nettle::Request::Ping), 1);
+}
+
+#[tokio::test]
+async fn forged_addr_records() {
+    let (node, _) = create_node(Default::default()).await;
+    let victim = PrivateId::from_seed(b"victim");
+    let victim_url = http::parse_addr("http://victim.example").unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let hour = 60 * 60 * 1000;
+    let genuine = Signed::new(
+        &victim,
+        now,
+        1,
+        AddrRecord {
+            addr: victim_url.clone(),
+            expires: now + hour,
+        },
+    )
+    .await;
+    assert!(genuine.verify_record().is_ok());
+    // Somebody else claims that the victim lives at their address
+    let mut forged = genuine.clone();
+    forged.body.addr = http::parse_addr("http://attacker.example").unwrap();
+    assert_eq!(forged.verify_record(), Err(SignatureError::Invalid));
+    let expired = Signed::new(
+        &victim,
+        now - 2 * hour,
+        1,
+        AddrRecord {
+            addr: victim_url.clone(),
+            expires: now - hour,
+        },
+    )
+    .await;
+    assert_eq!(expired.verify_record(), Err(SignatureError::Expired));
+
+    // A peer that turns down our greeting and suggests somebody else, with a record of their address
+    #[derive(Clone, serde::Serialize)]
+    struct GreetResp {
+        result: Result<(), GreetRefusal<Url>>,
+    }
+    for (alt, followed) in [(genuine, true), (forged, false), (expired, false)] {
+        let port = free_port();
+        let resp = GreetResp {
+            result: Err(GreetRefusal::Redirect(Some(alt))),
+        };
+        let app = axum::Router::new().route(
+            "/peer/greet",
+            axum::routing::get(move || async move {
+                let id = PrivateId::from_seed(b"referrer");
+                axum::Json(signed(&id, rand::random(), resp).await)
+            }),
+        );
+        tokio::spawn(
+            axum::Server::bind(&([127, 0, 0, 1], port).into()).serve(app.into_make_service()),
+        );
+        let url = http::parse_addr(&format!("http://127.0.0.1:{}", port)).unwrap();
+        wait_for_server(&url).await;
+
+        let suggested = node.discover_peer(None, url).await.unwrap_err();
+        assert_eq!(suggested.is_some(), followed);
+        assert!(suggested.map_or(true, |addr| addr == victim_url));
+    }
+}
+
+#[tokio::test]
+async fn protocol_version_mismatch() {
+    let (node, url) = spawn_node(Default::default()).await;
+    let sender = PrivateId::generate();
+    let greet = Greet {
+        version: PROTOCOL_VERSION + 1,
+        ..Greet::new((sender.pub_id.clone(), url.clone()), Tag::generate())
+    };
+    assert!(matches!(
+        node.recv_greet(greet).await,
+        Err(GreetRefusal::VersionMismatch { ours, theirs })
+            if ours == PROTOCOL_VERSION && theirs == PROTOCOL_VERSION + 1
+    ));
+    assert!(node.get_peers().is_empty());
+
+    // A node from the future turns down our greeting
+    let greetings = Arc::new(AtomicUsize::new(0));
+    let port = free_port();
+    let app = axum::Router::new().route(
+        "/peer/greet",
+        axum::routing::get({
+            let greetings = greetings.clone();
+            move || async move {
+                greetings.fetch_add(1, Ordering::SeqCst);
+                #[derive(serde::Serialize)]
+                struct GreetResp {
+                    result: Result<(), GreetRefusal<Url>>,
+                }
+                let resp = GreetResp {
+                    result: Err(GreetRefusal::VersionMismatch {
+                        ours: PROTOCOL_VERSION + 1,
+                        theirs: PROTOCOL_VERSION,
+                    }),
+                };
+                axum::Json(signed(&PrivateId::from_seed(b"future"), rand::random(), resp).await)
+            }
+        }),
+    );
+    tokio::spawn(axum::Server::bind(&([127, 0, 0, 1], port).into()).serve(app.into_make_service()));
+    let future_url = http::parse_addr(&format!("http://127.0.0.1:{}", port)).unwrap();
+    wait_for_server(&future_url).await;
+
+    // It isn't greeted again
+    for _ in 0..3 {
+        assert_eq!(
+            node.discover_peer(None, future_url.clone()).await,
+            Err(None)
+        );
+    }
+    assert_eq!(greetings.load(Ordering::SeqCst), 1);
+    assert!(node.get_peers().is_empty());
+}
+
+#[tokio::test]
+async fn message_size_limits() {
+    let (node, url) = spawn_node(http::Config {
+        max_message_size: 4096,
+        ..Default::default()
+    })
+    .await;
+    async fn send<T: serde::Serialize>(url: &Url, path: &str, body: T) -> reqwest::StatusCode {
+        let sender = PrivateId::from_seed(b"oversized");
+        reqwest::Client::new()
+            .get(format!("{}peer/{}", url, path))
+            .json(&signed(&sender, rand::random(), body).await)
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    // Unknown fields are ignored, so padding makes an otherwise valid message too large
+    let tag = Tag::digest(b"x");
+    let padded = serde_json::json!({ "tag": tag, "padding": "x".repeat(8192) });
+    assert_eq!(
+        send(&url, "locate", padded).await,
+        reqwest::StatusCode::PAYLOAD_TOO_LARGE
+    );
+    // The message was refused before the node saw it
+    assert_eq!(node.metrics().requests(nettle::Request::Locate), 0);
+    assert_eq!(node.metrics().oversized_messages(), 1);
+    assert_eq!(
+        send(&url, "locate", msg::Locate { tag }).await,
+        reqwest::StatusCode::OK
+    );
+
+    // Messages that carry data may be much larger
+    let upload = msg::Upload {
+        data: vec![42; 8192].into(),
+        algorithm: Default::default(),
+        tag: None,
+        chunk: false,
+    };
+    assert_eq!(send(&url, "upload", upload).await, reqwest::StatusCode::OK);
+    assert_eq!(node.metrics().requests(nettle::Request::Upload), 1);
+
+    // Responses are held to the same limits, and a greeting's response is larger than this
+    let (small, _) = spawn_node(http::Config {
+        max_message_size: 256,
+        ..Default::default()
+    })
+    .await;
+    assert!(small.discover_peer(None, url.clone()).await.is_err());
+    assert!(small.metrics().oversized_messages() > 0);
+    assert!(node.get_peers().is_empty());
+}
+
+#[test]
+fn node_errors() {
+    let err = nettle::Error::Backend(http::parse_addr("not an address").unwrap_err());
+    assert_eq!(
+        err.to_string(),
+        "backend error: invalid peer address: not an address"
+    );
+    assert_eq!(
+        std::error::Error::source(&err).unwrap().to_string(),
+        "invalid peer address: not an address"
+    );
+
+    // Applications can pass it along with `?` like any other error
+    let err_string = err.to_string();
+    let boxed: Box<dyn std::error::Error + Send + Sync> = err.into();
+    assert_eq!(boxed.to_string(), err_string);
+    assert!(boxed.source().is_some());
+
+    for (err, message) in [
+        (nettle::Error::<http::Error>::HostPanic, "host panicked"),
+        (nettle::Error::Timeout, "timed out"),
+        (nettle::Error::Shutdown, "node shut down"),
+    ] {
+        assert_eq!(err.to_string(), message);
+        assert!(std::error::Error::source(&err).is_none());
+    }
+}
+
+#[tokio::test]
+async fn client_downloads_through_a_gateway() {
+    let (holder, _) = spawn_node(Default::default()).await;
+    let (gateway, gateway_url) = spawn_node(Default::default()).await;
+    holder
+        .discover_peer(None, gateway_url.clone())
+        .await
+        .unwrap();
+    let data = bytes::Bytes::from_static(b"fetched from a page");
+    let tag = holder.do_upload(data.clone()).await.unwrap();
+
+    // Nobody can reach a client, so the address it gives is never used
+    let client = Node::<client::Client>::new(
+        PrivateId::generate(),
+        "http://client.invalid".parse().unwrap(),
+        Vec::new(),
+        Default::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        client::add_gateway(&client, gateway_url.clone())
+            .await
+            .unwrap(),
+        gateway.id()
+    );
+    assert_eq!(client.do_download(tag).await, Ok(Some(data)));
+    assert_eq!(client.do_download(Tag::digest(b"nothing")).await, Ok(None));
+
+    // Whatever a client uploads goes to the nodes that can be reached
+    let data = bytes::Bytes::from_static(b"uploaded from a page");
+    let tag = client.do_upload(data.clone()).await.unwrap();
+    assert_eq!(client.stats().entries, 0);
+    assert_eq!(holder.do_download(tag).await, Ok(Some(data)));
+
+    // Without having been greeted, neither node takes the client on as a peer
+    assert_eq!(gateway.peer_count(), 1);
+    assert_eq!(holder.peer_count(), 1);
+
+    // A gateway that isn't there can't be used
+    let missing = http::parse_addr(&format!("http://127.0.0.1:{}", free_port())).unwrap();
+    assert!(client::add_gateway(&client, missing).await.is_err());
+
+    // Pages on other origins may use the peer protocol
+    let resp = reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("{}peer/locate", gateway_url),
+        )
+        .header("Origin", "http://page.example")
+        .header("Access-Control-Request-Method", "POST")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+}
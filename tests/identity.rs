@@ -0,0 +1,241 @@
+use nettle::{IdParseError, KeyError, NameFormat, PrivateId, PublicIdRef, Tag};
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("nettle-{}-{}.pem", name, std::process::id()))
+}
+
+#[test]
+fn pem_round_trip() {
+    let id = PrivateId::from_seed(b"round trip");
+    let loaded = PrivateId::from_pem(&id.to_pem().unwrap()).unwrap();
+    assert_eq!(loaded.pub_id, id.pub_id);
+    assert_eq!(loaded.pub_id.tag, id.pub_id.tag);
+}
+
+#[test]
+fn key_file_round_trip() {
+    let path = temp_path("key-file");
+    let id = PrivateId::generate();
+    id.save_to_file(&path).unwrap();
+    let loaded = PrivateId::load_from_file(&path);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.unwrap().pub_id, id.pub_id);
+}
+
+#[test]
+fn corrupted_key_file() {
+    let path = temp_path("corrupted");
+    let mut pem = PrivateId::from_seed(b"corrupted").to_pem().unwrap();
+    pem.replace_range(100..110, "!!!!!!!!!!");
+    std::fs::write(&path, pem).unwrap();
+    let loaded = PrivateId::load_from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(loaded, Err(KeyError::Malformed(_))));
+
+    assert!(matches!(
+        PrivateId::load_from_file(temp_path("missing")),
+        Err(KeyError::Io(_))
+    ));
+}
+
+#[tokio::test]
+async fn async_generation_does_not_block() {
+    let ticks = Arc::new(AtomicUsize::new(0));
+    let ticker = tokio::task::spawn({
+        let ticks = ticks.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(10));
+            loop {
+                interval.tick().await;
+                ticks.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let ids = futures::future::join_all((0..16).map(|_| PrivateId::generate_async())).await;
+    let elapsed = start.elapsed();
+    ticker.abort();
+
+    assert_eq!(ids.len(), 16);
+    // This test uses a single-threaded runtime, so the interval only ticks if key generation happens elsewhere
+    let expected = elapsed.as_millis() as usize / 10;
+    assert!(
+        ticks.load(Ordering::Relaxed) * 2 >= expected,
+        "{} ticks in {:?}",
+        ticks.load(Ordering::Relaxed),
+        elapsed
+    );
+}
+
+#[test]
+fn names_are_deterministic() {
+    let id = PrivateId::from_seed(b"names").pub_id;
+    let name = id.human_readable_name(2);
+    assert_eq!(
+        name,
+        PrivateId::from_seed(b"names").pub_id.human_readable_name(2)
+    );
+    assert_eq!(format!("{:?}", id), name);
+
+    // Two words and a checksum
+    let parts = name.split('_').collect::<Vec<_>>();
+    assert_eq!(parts.len(), 3);
+    assert_eq!(parts[2], &hex::encode(*id.tag)[62..]);
+    // More words can be used, up to the length of the tag
+    assert_eq!(id.human_readable_name(5).split('_').count(), 6);
+    assert_eq!(id.human_readable_name(100).split('_').count(), 33);
+    assert!(id
+        .human_readable_name(5)
+        .starts_with(&name[..name.len() - 3]));
+}
+
+#[test]
+fn custom_wordlist() {
+    let tag = Tag::digest(b"custom");
+    // Short lists wrap around rather than panicking, and repeated words don't count
+    assert_eq!(
+        NameFormat::default()
+            .with_wordlist("red\ngreen\nred")
+            .words
+            .len(),
+        2
+    );
+    let format = NameFormat::default().with_wordlist("red\ngreen\nsky blue\n\nred\n");
+    let name = format.name(tag);
+    assert!(name
+        .split('_')
+        .take(2)
+        .all(|word| ["red", "green", "sky"].contains(&word) || word == "blue"));
+    assert!(name.chars().all(|c| c.is_alphanumeric() || c == '_'));
+
+    // Long lists use more of the tag per word
+    let words = (0..1000)
+        .map(|i| format!("w{}", i))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let format = NameFormat::default()
+        .with_wordlist(&words)
+        .with_word_count(3);
+    let expected = tag
+        .chunks(2)
+        .take(3)
+        .map(|c| format!("w{}", (c[0] as usize * 256 + c[1] as usize) % 1000))
+        .collect::<Vec<_>>()
+        .join("_");
+    assert_eq!(format.name(tag), format!("{}_{:02x}", expected, tag[31]));
+
+    // An empty list falls back to hex
+    let format = NameFormat::default().with_wordlist("");
+    assert_eq!(
+        format.name(tag),
+        format!("{:02x}_{:02x}_{:02x}", tag[0], tag[1], tag[31])
+    );
+}
+
+#[test]
+fn names_are_unique() {
+    let names = |format: &NameFormat, n: u32| {
+        (0..n)
+            .map(|i| format.name(Tag::digest(i.to_le_bytes())))
+            .collect::<HashSet<_>>()
+            .len()
+    };
+    // The checksum keeps names distinct across a network of a few hundred nodes...
+    assert_eq!(names(&NameFormat::default(), 300), 300);
+    // ...and more words can be used for larger networks
+    let format = NameFormat::default().with_word_count(3);
+    assert_eq!(names(&format, 10000), 10000);
+
+    // Without the checksum, collisions are all but certain
+    let format = NameFormat {
+        checksum: false,
+        ..NameFormat::default()
+    };
+    assert!(names(&format, 2000) < 2000);
+}
+
+#[test]
+fn textual_ids() {
+    let id = PrivateId::from_seed(b"textual").pub_id;
+    let text = id.to_string();
+    assert_eq!(text, format!("nettle:{}", id.tag));
+    assert_eq!(id.short().len(), 8);
+    assert!(hex::encode(*id.tag).starts_with(&id.short()));
+
+    // Both the prefixed and bare forms parse
+    let parsed = text.parse::<PublicIdRef>().unwrap();
+    assert!(parsed.matches(&id));
+    assert_eq!(parsed.to_string(), text);
+    assert_eq!(id.tag.to_string().parse::<PublicIdRef>(), Ok(parsed));
+    // As do ids written before tags carried their hash algorithm
+    assert_eq!(
+        format!("nettle:{}", hex::encode(*id.tag)).parse::<PublicIdRef>(),
+        Ok(parsed)
+    );
+    assert!(!parsed.matches(&PrivateId::from_seed(b"other").pub_id));
+
+    assert_eq!(
+        "nettle:abcd".parse::<PublicIdRef>(),
+        Err(IdParseError::WrongLength(4))
+    );
+    assert_eq!(
+        format!("{}0", id.tag).parse::<PublicIdRef>(),
+        Err(IdParseError::WrongLength(67))
+    );
+    assert_eq!(
+        "g".repeat(64).parse::<PublicIdRef>(),
+        Err(IdParseError::NotHex)
+    );
+    // Lengths are counted in bytes, so multi-byte characters are rejected as non-hex rather than miscounted
+    assert_eq!(
+        "é".repeat(32).parse::<PublicIdRef>(),
+        Err(IdParseError::NotHex)
+    );
+    assert!("".parse::<PublicIdRef>().is_err());
+}
+
+#[tokio::test]
+async fn key_sizes() {
+    for bits in [1024, 3072] {
+        let id = PrivateId::from_seed_with_bits(b"key sizes", bits).unwrap();
+        assert_eq!(id.key_bits(), bits);
+
+        // The tag is derived from the key, whatever its size
+        let json = serde_json::to_string(&id.pub_id).unwrap();
+        let pub_id = serde_json::from_str::<nettle::PublicId>(&json).unwrap();
+        assert_eq!(pub_id, id.pub_id);
+        assert_eq!(pub_id.tag, id.pub_id.tag);
+        let loaded = PrivateId::from_pem(&id.to_pem().unwrap()).unwrap();
+        assert_eq!(loaded.pub_id.tag, id.pub_id.tag);
+        assert_eq!(loaded.key_bits(), bits);
+
+        let sig = id.sign(b"sized").await;
+        assert!(pub_id.verify(b"sized", &sig));
+    }
+    assert_eq!(PrivateId::from_seed(b"default").key_bits(), 2048);
+
+    for bits in [0, 512, 1023, 1025, 16384] {
+        assert!(matches!(
+            PrivateId::from_seed_with_bits(b"invalid", bits),
+            Err(KeyError::InvalidSize(b)) if b == bits
+        ));
+    }
+}
@@ -0,0 +1,414 @@
+use nettle::{
+    audit::Challenge,
+    msg::*,
+    sync::{Region, Summary},
+    AddrRecord, Capabilities, GreetRefusal, GreetReply, HashAlgorithm, LocateReply, NodeStats,
+    PrivateId, ProtocolError, Signature, Signed, Tag,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use url::Url;
+
+// Check that a message serialises to exactly `expected`, and that it survives a round trip
+fn snapshot<T: Serialize + DeserializeOwned>(msg: T, expected: Value) {
+    let value = serde_json::to_value(&msg).unwrap();
+    assert_eq!(value, expected);
+    let parsed = serde_json::from_value::<T>(value).unwrap();
+    assert_eq!(serde_json::to_value(parsed).unwrap(), expected);
+}
+
+fn value<T: Serialize>(x: &T) -> Value {
+    serde_json::to_value(x).unwrap()
+}
+
+#[tokio::test]
+async fn wire_format() {
+    let alice = PrivateId::from_seed(b"alice");
+    let bob = PrivateId::from_seed(b"bob").pub_id;
+    let url = Url::parse("http://127.0.0.1:8000/").unwrap();
+    let tag = Tag::digest(b"wire format");
+    let proof = Signature::from(vec![1, 2, 3]);
+    let record = Signed::new(
+        &alice,
+        1_000,
+        7,
+        AddrRecord {
+            addr: url.clone(),
+            expires: 2_000,
+        },
+    )
+    .await;
+    // Signing is deterministic, so the whole record can be written out
+    let record_json = json!({
+        "sender": value(&alice.pub_id),
+        "timestamp": 1_000,
+        "nonce": 7,
+        "body": { "addr": "http://127.0.0.1:8000/", "expires": 2_000 },
+        "signature": value(&record.signature),
+    });
+    assert_eq!(value(&record), record_json);
+
+    snapshot(
+        Greet {
+            sender: (bob.clone(), url.clone()),
+            challenge: tag,
+            version: 3,
+            capabilities: Capabilities(5),
+        },
+        json!({
+            "sender": [value(&bob), "http://127.0.0.1:8000/"],
+            "challenge": tag.to_string(),
+            "version": 3,
+            "capabilities": 5,
+        }),
+    );
+    snapshot(
+        GreetResp::<Url> {
+            result: Ok(GreetReply {
+                id: bob.clone(),
+                proof: proof.clone(),
+                challenge: tag,
+                endorsement: None,
+                capabilities: Capabilities::NONE,
+            }),
+        },
+        json!({ "result": { "Ok": {
+            "id": value(&bob),
+            "proof": value(&proof),
+            "challenge": tag.to_string(),
+            "endorsement": null,
+            "capabilities": 0,
+        } } }),
+    );
+    snapshot(
+        GreetResp {
+            result: Err(GreetRefusal::Redirect(Some(record.clone()))),
+        },
+        json!({ "result": { "Err": { "Redirect": record_json.clone() } } }),
+    );
+    snapshot(
+        GreetResp::<Url> {
+            result: Err(GreetRefusal::VersionMismatch { ours: 2, theirs: 1 }),
+        },
+        json!({ "result": { "Err": { "VersionMismatch": { "ours": 2, "theirs": 1 } } } }),
+    );
+    snapshot(
+        Prove {
+            id: bob.clone(),
+            proof: proof.clone(),
+        },
+        json!({ "id": value(&bob), "proof": value(&proof) }),
+    );
+    snapshot(ProveResp { accepted: true }, json!({ "accepted": true }));
+    snapshot(
+        RotateResp { forgotten: false },
+        json!({ "forgotten": false }),
+    );
+    snapshot(
+        Readdress {
+            record: record.clone(),
+        },
+        json!({ "record": record_json.clone() }),
+    );
+    snapshot(ReaddressResp { updated: true }, json!({ "updated": true }));
+    snapshot(Ping { sent: 1_000 }, json!({ "sent": 1_000 }));
+    snapshot(
+        Pong {
+            record: record.clone(),
+            peer: Some(record.clone()),
+            ping_sent: 1_000,
+            received: 1_010,
+            sent: 1_011,
+        },
+        json!({
+            "record": record_json.clone(),
+            "peer": record_json.clone(),
+            "ping_sent": 1_000,
+            "received": 1_010,
+            "sent": 1_011,
+        }),
+    );
+    // Older peers don't pass anybody on
+    let pong =
+        serde_json::from_value::<Pong<Url>>(json!({ "record": record_json.clone() })).unwrap();
+    assert!(pong.peer.is_none());
+    assert_eq!(pong.clock_offset(2_000), None);
+    snapshot(Observe, Value::Null);
+    snapshot(
+        ObserveResp {
+            addr: "10.0.0.1:1234".parse().unwrap(),
+        },
+        json!({ "addr": "10.0.0.1:1234" }),
+    );
+    snapshot(Info, json!(null));
+    snapshot(
+        InfoResp {
+            stats: Some(NodeStats {
+                version: 6,
+                peers: 3,
+                entries: 10,
+                stored_bytes: 800,
+                logical_bytes: 1000,
+                pinned_bytes: 200,
+                uptime_secs: 60,
+                levels: Some([(254, 1), (255, 2)].into()),
+            }),
+        },
+        json!({
+            "stats": {
+                "version": 6,
+                "peers": 3,
+                "entries": 10,
+                "stored_bytes": 800,
+                "logical_bytes": 1000,
+                "pinned_bytes": 200,
+                "uptime_secs": 60,
+                "levels": { "254": 1, "255": 2 },
+            }
+        }),
+    );
+    snapshot(InfoResp { stats: None }, json!({ "stats": null }));
+    snapshot(
+        Discover {
+            target: tag,
+            max_level: 12,
+        },
+        json!({ "target": tag.to_string(), "max_level": 12 }),
+    );
+    snapshot(
+        DiscoverResp {
+            peer: Some(record.clone()),
+        },
+        json!({ "peer": record_json.clone() }),
+    );
+    snapshot(DiscoverResp::<Url> { peer: None }, json!({ "peer": null }));
+    snapshot(
+        FindNode { target: tag, k: 3 },
+        json!({ "target": tag.to_string(), "k": 3 }),
+    );
+    snapshot(
+        FindNodeResp {
+            peers: vec![(bob.clone(), url.clone())],
+        },
+        json!({ "peers": [[value(&bob), "http://127.0.0.1:8000/"]] }),
+    );
+    snapshot(Locate { tag }, json!({ "tag": tag.to_string() }));
+    snapshot(
+        LocateResp::<Url>::from(LocateReply::Found),
+        json!({ "result": { "Ok": true }, "not_closer": [] }),
+    );
+    snapshot(
+        LocateResp::from(LocateReply::Closer(vec![(bob.clone(), url.clone())])),
+        json!({
+            "result": { "Err": [[value(&bob), "http://127.0.0.1:8000/"]] },
+            "not_closer": [],
+        }),
+    );
+    snapshot(
+        LocateResp::from(LocateReply::NotCloser(vec![(bob.clone(), url.clone())])),
+        json!({
+            "result": { "Ok": false },
+            "not_closer": [[value(&bob), "http://127.0.0.1:8000/"]],
+        }),
+    );
+    snapshot(
+        Upload {
+            data: vec![4, 5].into(),
+            algorithm: HashAlgorithm::Blake3,
+            tag: None,
+            chunk: false,
+        },
+        json!({ "data": [4, 5], "algorithm": "Blake3", "tag": null, "chunk": false }),
+    );
+    snapshot(
+        Upload {
+            data: vec![4, 5].into(),
+            algorithm: HashAlgorithm::default(),
+            tag: Some(tag),
+            chunk: true,
+        },
+        json!({ "data": [4, 5], "algorithm": "Sha3_256", "tag": tag.to_string(), "chunk": true }),
+    );
+    snapshot(
+        UploadResp { result: Ok(tag) },
+        json!({ "result": { "Ok": tag.to_string() } }),
+    );
+    snapshot(
+        UploadResp {
+            result: Err(ProtocolError::StorageFull),
+        },
+        json!({ "result": { "Err": "StorageFull" } }),
+    );
+    snapshot(Download { tag }, json!({ "tag": tag.to_string() }));
+    snapshot(
+        DownloadResp {
+            result: Ok(Some(vec![6].into())),
+        },
+        json!({ "result": { "Ok": [6] } }),
+    );
+    snapshot(
+        DownloadResp { result: Ok(None) },
+        json!({ "result": { "Ok": null } }),
+    );
+    snapshot(
+        DownloadResp {
+            result: Err(ProtocolError::Throttled),
+        },
+        json!({ "result": { "Err": "Throttled" } }),
+    );
+    snapshot(
+        Renew { tags: vec![tag] },
+        json!({ "tags": [tag.to_string()] }),
+    );
+    snapshot(RenewResp { renewed: 1 }, json!({ "renewed": 1 }));
+    snapshot(
+        Audit {
+            tag,
+            challenge: Challenge {
+                offset: 16,
+                len: 32,
+                nonce: 7,
+            },
+        },
+        json!({
+            "tag": tag.to_string(),
+            "challenge": { "offset": 16, "len": 32, "nonce": 7 },
+        }),
+    );
+    snapshot(
+        AuditResp {
+            result: Ok(Some(tag)),
+        },
+        json!({ "result": { "Ok": tag.to_string() } }),
+    );
+    let summary = Summary {
+        bits: vec![1, 2],
+        hashes: 3,
+        seed: 4,
+    };
+    snapshot(
+        SyncTags {
+            region: Region {
+                prefix: tag,
+                len: 5,
+            },
+            summary: summary.clone(),
+        },
+        json!({
+            "region": { "prefix": tag.to_string(), "len": 5 },
+            "summary": { "bits": [1, 2], "hashes": 3, "seed": 4 },
+        }),
+    );
+    snapshot(
+        SyncTagsResp {
+            result: Ok(SyncReply {
+                missing: vec![tag],
+                summary,
+            }),
+        },
+        json!({
+            "result": { "Ok": {
+                "missing": [tag.to_string()],
+                "summary": { "bits": [1, 2], "hashes": 3, "seed": 4 },
+            } },
+        }),
+    );
+
+    let endorsement = Signed::new(&alice, 1_000, 8, bob.clone()).await;
+    let endorsement_json = value(&endorsement);
+    snapshot(
+        Rotate { endorsement },
+        json!({ "endorsement": endorsement_json }),
+    );
+}
+
+#[tokio::test]
+async fn clock_offsets() {
+    let record = Signed::new(
+        &PrivateId::from_seed(b"alice"),
+        0,
+        0,
+        AddrRecord {
+            addr: Url::parse("http://127.0.0.1:8000/").unwrap(),
+            expires: 0,
+        },
+    )
+    .await;
+    let pong = |ping_sent, received, sent| Pong {
+        record: record.clone(),
+        peer: None,
+        ping_sent,
+        received,
+        sent,
+    };
+
+    // 10ms each way, with the responder's clock 500ms ahead of ours
+    assert_eq!(pong(1_000, 1_510, 1_515).clock_offset(1_025), Some(500));
+    // Or 500ms behind
+    assert_eq!(pong(1_000, 510, 515).clock_offset(1_025), Some(-500));
+    // Clocks in step
+    assert_eq!(pong(1_000, 1_010, 1_015).clock_offset(1_025), Some(0));
+    // Asymmetric transit skews the estimate by half the difference: 30ms out and 10ms back look like a clock 10ms ahead
+    assert_eq!(pong(1_000, 1_030, 1_030).clock_offset(1_040), Some(10));
+    // A clock set a day ahead is out by a day, not by any transit time
+    assert_eq!(
+        pong(0, 86_400_000, 86_400_000).clock_offset(0),
+        Some(86_400_000)
+    );
+    // Offsets too large to represent are none at all
+    assert_eq!(pong(0, u64::MAX, u64::MAX).clock_offset(0), None);
+}
+
+#[test]
+fn older_uploads_are_sha3() {
+    let upload = serde_json::from_value::<Upload>(json!({ "data": [1, 2, 3] })).unwrap();
+    assert_eq!(upload.algorithm, HashAlgorithm::Sha3_256);
+}
+
+#[test]
+fn older_locate_misses_suggest_nobody() {
+    let resp = serde_json::from_value::<LocateResp<Url>>(json!({ "result": { "Ok": false } }));
+    assert_eq!(
+        LocateReply::from(resp.unwrap()),
+        LocateReply::NotCloser(Vec::new())
+    );
+}
+
+#[test]
+fn older_uploads_are_untagged() {
+    let upload = serde_json::from_value::<Upload>(json!({ "data": [4, 5] })).unwrap();
+    assert_eq!(upload.algorithm, HashAlgorithm::default());
+    assert_eq!(upload.tag, None);
+    assert!(!upload.chunk);
+}
+
+#[test]
+fn older_greetings_have_no_version() {
+    let bob = PrivateId::from_seed(b"bob").pub_id;
+    let greet = serde_json::from_value::<Greet<Url>>(json!({
+        "sender": [value(&bob), "http://127.0.0.1:8000/"],
+        "challenge": Tag::digest(b"challenge"),
+    }))
+    .unwrap();
+    assert_eq!(greet.version, 0);
+    assert_eq!(greet.capabilities, Capabilities::NONE);
+}
+
+#[test]
+fn protocol_errors() {
+    for err in [
+        ProtocolError::TooLarge,
+        ProtocolError::NotResponsible,
+        ProtocolError::StorageFull,
+        ProtocolError::Throttled,
+        ProtocolError::Busy,
+        ProtocolError::Mismatch,
+        ProtocolError::QuotaExceeded,
+        ProtocolError::VersionMismatch,
+        ProtocolError::Internal,
+    ] {
+        let json = serde_json::to_value(err).unwrap();
+        assert_eq!(json, Value::String(format!("{:?}", err)));
+        assert_eq!(serde_json::from_value::<ProtocolError>(json).unwrap(), err);
+    }
+}
@@ -0,0 +1,81 @@
+use nettle::{
+    output::{Downloaded, ErrorKind, ErrorReport, Running, Tagged, Uploaded},
+    Tag,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::fmt::Debug;
+
+// Check that `value` is printed as `expected`, and is read back unchanged
+fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T, expected: Value) {
+    let text = serde_json::to_string(&value).unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), expected);
+    assert_eq!(serde_json::from_str::<T>(&text).unwrap(), value);
+}
+
+#[test]
+fn results() {
+    let tag = Tag::digest("hello nettle");
+    round_trip(
+        Running {
+            name: "quick-fox".to_string(),
+            tag,
+            url: "http://127.0.0.1:34093/".parse().unwrap(),
+        },
+        json!({"name": "quick-fox", "tag": tag.to_string(), "url": "http://127.0.0.1:34093/"}),
+    );
+    round_trip(
+        Uploaded { tag, bytes: 12 },
+        json!({"tag": tag.to_string(), "bytes": 12}),
+    );
+    round_trip(
+        Downloaded {
+            tag,
+            bytes: 12,
+            output: None,
+        },
+        json!({"tag": tag.to_string(), "bytes": 12, "output": null}),
+    );
+    round_trip(
+        Downloaded {
+            tag,
+            bytes: 12,
+            output: Some("hello.txt".into()),
+        },
+        json!({"tag": tag.to_string(), "bytes": 12, "output": "hello.txt"}),
+    );
+    round_trip(
+        Tagged {
+            tag,
+            file: "-".into(),
+        },
+        json!({"tag": tag.to_string(), "file": "-"}),
+    );
+}
+
+#[test]
+fn errors() {
+    round_trip(
+        ErrorReport::new(ErrorKind::NotFound, "nope"),
+        json!({"error": {"kind": "not_found", "message": "nope"}}),
+    );
+    let kinds = ErrorKind::ALL
+        .iter()
+        .map(|kind| serde_json::to_value(kind).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        [
+            "failure",
+            "not_found",
+            "unreachable",
+            "unauthorized",
+            "incompatible",
+            "mismatch"
+        ]
+    );
+    // Every kind can be told apart by its exit code, none of which clash with success or clap's usage errors
+    let mut codes = ErrorKind::ALL.map(ErrorKind::exit_code);
+    codes.sort();
+    assert_eq!(codes, [1, 3, 4, 5, 6, 7]);
+}
@@ -0,0 +1,45 @@
+use bytes::Bytes;
+use nettle::{mem, LocateReply, Node, PrivateId, Tag};
+use std::sync::Arc;
+
+#[tokio::test]
+async fn survives_panics_under_lock() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let [node, peer] = &nodes[..] else {
+        unreachable!()
+    };
+    assert!(node.accept_peer(peer.id(), peer.addr()).await);
+    let data = Bytes::from_static(b"stored before the panic");
+    let tag = Tag::digest(&data);
+    assert!(node.save_data(tag, data.clone()).await);
+
+    // A bug that panics while the routing table is locked takes down only the task it happened in
+    let panicking = node.clone();
+    let res = tokio::spawn(async move {
+        panicking.inspect_routing(|_| panic!("bug in the routing table"));
+    })
+    .await;
+    assert!(res.unwrap_err().is_panic());
+
+    // Everything else carries on as before
+    let record = node.recv_ping().await;
+    assert_eq!(record.sender, node.id());
+    assert!(record.verify_record().is_ok());
+    assert_eq!(node.recv_locate(tag).await, LocateReply::Found);
+    assert_eq!(node.get_peers(), vec![peer.id()]);
+    assert_eq!(node.do_download(tag).await, Ok(Some(data)));
+}
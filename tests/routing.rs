@@ -0,0 +1,242 @@
+use nettle::{
+    routing::{InsertOutcome, Peer, RoutingTable, MAX_LEVEL_PEERS},
+    AddrRecord, Capabilities, PrivateId, Signed, Tag,
+};
+use rand::prelude::*;
+use std::time::Duration;
+
+// Our tag in most tests, from which a peer's level is 255 minus the number of leading zeros in its tag
+fn zero() -> Tag {
+    Tag::from_bytes([0; 32])
+}
+
+async fn peer(id: &PrivateId) -> Peer<String> {
+    let addr = format!("peer-{}", id.pub_id.tag);
+    Peer {
+        id: id.pub_id.clone(),
+        addr: addr.clone(),
+        ping: Duration::from_millis(10),
+        record: Signed::new(id, 0, 0, AddrRecord { addr, expires: 0 }).await,
+        capabilities: Capabilities::NONE,
+        clock_offset: None,
+    }
+}
+
+// A peer at the given level from `zero()`, which must be a high one so that one turns up quickly
+async fn peer_at(level: usize) -> Peer<String> {
+    loop {
+        let id = PrivateId::generate();
+        if zero().bucket_index(id.pub_id.tag) == Some(level) {
+            return peer(&id).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn insert_and_remove() {
+    let mut table = RoutingTable::new(zero());
+    let a = peer_at(255).await;
+    assert!(table.has_room_for(&a.id));
+    assert_eq!(
+        table.insert(a.clone()),
+        InsertOutcome::Inserted { level: 255 }
+    );
+    assert!(table.contains(&a.id));
+    assert!(!table.has_room_for(&a.id));
+    assert_eq!(table.level_of(&a.id), Some(255));
+    assert_eq!((table.len(), table.bucket_len(255)), (1, 1));
+
+    // Inserting a peer again updates it in place
+    let mut again = a.clone();
+    again.ping = Duration::from_millis(20);
+    assert_eq!(table.insert(again), InsertOutcome::Updated);
+    assert_eq!(table.get(&a.id).unwrap().ping, Duration::from_millis(20));
+    assert_eq!((table.len(), table.bucket_len(255)), (1, 1));
+
+    table.get_mut(&a.id).unwrap().ping = Duration::from_millis(30);
+    assert_eq!(table.get(&a.id).unwrap().ping, Duration::from_millis(30));
+
+    assert_eq!(table.remove(&a.id).unwrap().id, a.id);
+    assert!(!table.contains(&a.id) && table.get(&a.id).is_none());
+    assert!(table.is_empty());
+    assert_eq!(table.bucket_len(255), 0);
+    // Removing a peer that isn't there changes nothing
+    assert!(table.remove(&a.id).is_none());
+    assert!(table.remove(&PrivateId::generate().pub_id).is_none());
+    assert!(table.is_empty());
+}
+
+#[tokio::test]
+async fn full_level() {
+    let mut table = RoutingTable::new(zero());
+    let mut peers = Vec::new();
+    for _ in 0..MAX_LEVEL_PEERS + 1 {
+        peers.push(peer_at(255).await);
+    }
+    let extra = peers.pop().unwrap();
+    for peer in &peers {
+        assert_eq!(
+            table.insert(peer.clone()),
+            InsertOutcome::Inserted { level: 255 }
+        );
+    }
+
+    // Even a peer that was found to have room earlier is turned away once the level has filled up
+    assert!(!table.has_room_for(&extra.id));
+    assert_eq!(
+        table.insert(extra.clone()),
+        InsertOutcome::Full { level: 255 }
+    );
+    assert!(!table.contains(&extra.id));
+    assert_eq!(table.bucket_len(255), MAX_LEVEL_PEERS);
+    // Other levels are unaffected
+    let other = peer_at(254).await;
+    assert_eq!(table.insert(other), InsertOutcome::Inserted { level: 254 });
+    // Peers already in a full level can still be updated
+    assert_eq!(table.insert(peers[0].clone()), InsertOutcome::Updated);
+
+    // Removing a peer makes room again
+    table.remove(&peers[0].id);
+    assert!(table.has_room_for(&extra.id));
+    assert_eq!(table.insert(extra), InsertOutcome::Inserted { level: 255 });
+    assert_eq!(table.len(), MAX_LEVEL_PEERS + 1);
+}
+
+#[tokio::test]
+async fn peer_limit() {
+    let mut table = RoutingTable::new(zero());
+    table.set_max_peers(Some(4));
+    let with_ping = |mut peer: Peer<String>, ms| {
+        peer.ping = Duration::from_millis(ms);
+        peer
+    };
+    let (fast, slow) = (peer_at(255).await, with_ping(peer_at(255).await, 50));
+    let (near, nearer) = (peer_at(254).await, with_ping(peer_at(254).await, 50));
+    for peer in [&fast, &slow, &near, &nearer] {
+        assert!(matches!(
+            table.insert(peer.clone()),
+            InsertOutcome::Inserted { .. }
+        ));
+    }
+
+    // At the limit, a peer at an empty level takes the place of the slowest peer at the fullest, farthest level
+    let closer = peer_at(253).await;
+    assert!(table.has_room_for(&closer.id));
+    assert_eq!(
+        table.insert(closer.clone()),
+        InsertOutcome::Replaced {
+            level: 253,
+            evicted: slow.id.clone()
+        }
+    );
+    // But not a peer that would leave its own level the most expendable
+    let far = peer_at(255).await;
+    assert!(!table.has_room_for(&far.id));
+    assert_eq!(table.insert(far), InsertOutcome::AtLimit { level: 255 });
+    // A closer level may fill up at the expense of a farther one
+    let closest = peer_at(253).await;
+    assert_eq!(
+        table.insert(closest.clone()),
+        InsertOutcome::Replaced {
+            level: 253,
+            evicted: nearer.id.clone()
+        }
+    );
+    assert_eq!(table.len(), 4);
+    assert_eq!(
+        (0..256).map(|level| table.bucket_len(level)).sum::<usize>(),
+        4
+    );
+
+    // Lowering the limit drops the most expendable peers, and lifting it leaves the rest be
+    let dropped = table.set_max_peers(Some(2));
+    let mut dropped = dropped.into_iter().map(|peer| peer.id).collect::<Vec<_>>();
+    assert!(dropped.contains(&fast.id) && dropped.len() == 2);
+    dropped.retain(|id| *id != fast.id);
+    assert!(dropped[0] == closer.id || dropped[0] == closest.id);
+    assert!(table.contains(&near.id) && table.bucket_len(253) == 1);
+    assert!(table.set_max_peers(None).is_empty());
+    assert!(table.has_room_for(&slow.id));
+}
+
+#[tokio::test]
+async fn peer_limit_keeps_close_levels() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut peers = Vec::new();
+    for level in 253..256 {
+        for _ in 0..MAX_LEVEL_PEERS {
+            peers.push(peer_at(level).await);
+        }
+    }
+    for _ in 0..10 {
+        peers.shuffle(&mut rng);
+        let mut table = RoutingTable::new(zero());
+        table.set_max_peers(Some(4));
+        for peer in &peers {
+            table.insert(peer.clone());
+            assert!(table.len() <= 4);
+        }
+        // However the peers turned up, the closest levels are the fullest
+        let fill = (253..256)
+            .map(|level| table.bucket_len(level))
+            .collect::<Vec<_>>();
+        assert_eq!(fill, [2, 1, 1]);
+    }
+}
+
+#[tokio::test]
+async fn own_tag() {
+    let id = PrivateId::generate();
+    let mut table = RoutingTable::new(id.pub_id.tag);
+    assert!(!table.has_room_for(&id.pub_id));
+    assert_eq!(table.insert(peer(&id).await), InsertOutcome::Ours);
+    assert!(table.is_empty());
+}
+
+#[tokio::test]
+async fn closest_and_random() {
+    let mut table = RoutingTable::new(zero());
+    let mut rng = StdRng::seed_from_u64(0);
+    assert!(table.closest(zero(), 4).is_empty());
+    assert!(table.random(&mut rng).is_none());
+
+    let mut peers = Vec::new();
+    for level in [255, 255, 254] {
+        let peer = peer_at(level).await;
+        table.insert(peer.clone());
+        peers.push(peer);
+    }
+    let target = peers[1].id.tag;
+    let closest = table.closest(target, 2);
+    assert_eq!(closest.len(), 2);
+    assert_eq!(closest[0].id, peers[1].id);
+    assert!(closest[0].id.tag.dist_to(target) <= closest[1].id.tag.dist_to(target));
+    assert_eq!(table.closest(target, 10).len(), 3);
+
+    for _ in 0..8 {
+        let chosen = table.random(&mut rng).unwrap();
+        assert!(peers.iter().any(|peer| peer.id == chosen.id));
+    }
+    assert_eq!(table.iter().count(), 3);
+}
+
+#[tokio::test]
+async fn rekey() {
+    let mut table = RoutingTable::new(zero());
+    let (a, b) = (peer_at(255).await, peer_at(255).await);
+    table.insert(a.clone());
+    table.insert(b.clone());
+
+    // Taking on one peer's tag leaves no level for it, and moves the other to its level from the new tag
+    let evicted = table.rekey(a.id.tag);
+    assert_eq!(table.self_tag(), a.id.tag);
+    assert_eq!(evicted.len(), 1);
+    assert_eq!(evicted[0].id, a.id);
+    assert!(!table.contains(&a.id));
+    let level = a.id.tag.bucket_index(b.id.tag).unwrap();
+    assert_eq!(table.level_of(&b.id), Some(level));
+    assert_eq!(table.bucket_len(level), 1);
+    assert_eq!(table.bucket_len(255), usize::from(level == 255));
+    assert_eq!(table.remove(&b.id).unwrap().id, b.id);
+    assert_eq!(table.bucket_len(level), 0);
+}
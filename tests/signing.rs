@@ -0,0 +1,338 @@
+use bytes::Bytes;
+use nettle::{
+    mem,
+    msg::{Greet, Upload},
+    CallbackSigner, HashAlgorithm, KeyError, Node, PrivateId, Request, Signature, SignatureError,
+    Signed, Signer, Tag, MAX_CLOCK_SKEW,
+};
+use rsa::RsaPublicKey;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[tokio::test]
+async fn tampered_body() {
+    let id = PrivateId::from_seed(b"tampered");
+    let mut msg = Signed::new(&id, now_millis(), 1, "pay alice 5".to_string()).await;
+    assert_eq!(msg.verify(), Ok(()));
+    msg.body = "pay mallory 500".to_string();
+    assert_eq!(msg.verify(), Err(SignatureError::Invalid));
+}
+
+#[tokio::test]
+async fn wrong_key() {
+    let (alice, mallory) = (
+        PrivateId::from_seed(b"alice"),
+        PrivateId::from_seed(b"mallory"),
+    );
+    // Mallory tries to pass off a message as coming from Alice
+    let mut msg = Signed::new(&mallory, now_millis(), 1, "hello").await;
+    msg.sender = alice.pub_id.clone();
+    assert_eq!(msg.verify(), Err(SignatureError::Invalid));
+
+    // The same goes for the nonce and timestamp
+    let msg = Signed::new(&alice, now_millis(), 1, "hello").await;
+    let mut renonced = msg.clone();
+    renonced.nonce += 1;
+    assert_eq!(renonced.verify(), Err(SignatureError::Invalid));
+    let mut retimed = msg;
+    retimed.timestamp += 1;
+    assert_eq!(retimed.verify(), Err(SignatureError::Invalid));
+}
+
+#[tokio::test]
+async fn replayed_and_expired() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    let sender = PrivateId::from_seed(b"sender");
+
+    let msg = node.seal("ping").await;
+    assert_eq!(
+        node.open(msg.clone()).map(|(id, _)| id),
+        Ok(node.id().clone())
+    );
+    assert_eq!(node.open(msg).unwrap_err(), SignatureError::Replayed);
+
+    // Nonces are per-sender, so another sender may use the same one
+    let now = now_millis();
+    assert!(node.open(Signed::new(&sender, now, 7, ()).await).is_ok());
+    let other = PrivateId::from_seed(b"other");
+    assert!(node.open(Signed::new(&other, now, 7, ()).await).is_ok());
+    assert_eq!(
+        node.open(Signed::new(&sender, now + 1, 7, ()).await)
+            .unwrap_err(),
+        SignatureError::Replayed
+    );
+
+    let stale = now - 2 * MAX_CLOCK_SKEW.as_millis() as u64;
+    assert_eq!(
+        node.open(Signed::new(&sender, stale, 8, ()).await)
+            .unwrap_err(),
+        SignatureError::Expired
+    );
+    let future = now + 2 * MAX_CLOCK_SKEW.as_millis() as u64;
+    assert_eq!(
+        node.open(Signed::new(&sender, future, 9, ()).await)
+            .unwrap_err(),
+        SignatureError::Expired
+    );
+}
+
+#[tokio::test]
+async fn replayed_upload() {
+    let alice = mem_node(PrivateId::generate()).await;
+    let bob = mem_node(PrivateId::generate()).await;
+
+    // Capture an upload on its way from Alice to Bob
+    let upload = alice
+        .seal(Upload {
+            data: b"only once".to_vec().into(),
+            algorithm: HashAlgorithm::default(),
+            tag: None,
+            chunk: false,
+        })
+        .await;
+    let (sender, msg) = bob.open(upload.clone()).unwrap();
+    assert_eq!(sender, alice.id());
+    assert_eq!(
+        bob.recv_upload(msg.algorithm, msg.data).await,
+        Ok(Tag::digest(b"only once"))
+    );
+
+    // Played back verbatim, it's recognised and never reaches the node
+    assert_eq!(bob.open(upload).unwrap_err(), SignatureError::Replayed);
+    assert_eq!(bob.metrics().requests(Request::Upload), 1);
+    assert_eq!(bob.metrics().upload_bytes(), 9);
+
+    // Request ids are drawn from the full 128 bits
+    let mut nonces = Vec::new();
+    for _ in 0..8 {
+        nonces.push(alice.seal(()).await.nonce);
+    }
+    assert!(nonces.iter().any(|nonce| *nonce > u64::MAX as u128));
+}
+
+async fn mem_node(id: PrivateId) -> Arc<Node<mem::Mem>> {
+    let addr = mem::Addr::default();
+    Node::new(id, addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn greeting_proves_key_ownership() {
+    let alice = mem_node(PrivateId::generate()).await;
+    let bob = mem_node(PrivateId::generate()).await;
+    assert_eq!(alice.discover_peer(None, bob.addr()).await, Ok(()));
+    // Both sides end up verified and peered
+    assert_eq!(alice.get_peers(), vec![bob.id().clone()]);
+    assert_eq!(bob.get_peers(), vec![alice.id().clone()]);
+}
+
+#[tokio::test]
+async fn impostor_greeting() {
+    let node = mem_node(PrivateId::generate()).await;
+    let mallory = mem_node(PrivateId::generate()).await;
+    let victim = PrivateId::from_seed(b"victim");
+
+    // Mallory claims to be the victim, and the node proves its own identity in return
+    let challenge = Tag::generate();
+    let reply = node
+        .recv_greet(Greet::new(
+            (victim.pub_id.clone(), mallory.addr()),
+            challenge,
+        ))
+        .await
+        .unwrap();
+    assert_eq!(reply.id, node.id());
+    assert!(reply
+        .id
+        .verify_challenge(challenge, &victim.pub_id, &reply.proof));
+    // The answer is only good for the challenger it was given to
+    assert!(!reply
+        .id
+        .verify_challenge(challenge, &mallory.id(), &reply.proof));
+
+    // But Mallory can't answer the node's challenge on the victim's behalf
+    let forged = PrivateId::from_seed(b"mallory")
+        .answer_challenge(reply.challenge, &node.id())
+        .await;
+    assert!(!node.recv_prove(victim.pub_id.clone(), forged).await);
+    assert!(node.get_peers().is_empty());
+
+    // Each challenge may only be answered once, so a wrong answer can't be followed up with guesses
+    let reply = node
+        .recv_greet(Greet::new(
+            (victim.pub_id.clone(), mallory.addr()),
+            Tag::generate(),
+        ))
+        .await
+        .unwrap();
+    let stale = victim.answer_challenge(Tag::generate(), &node.id()).await;
+    assert!(!node.recv_prove(victim.pub_id.clone(), stale).await);
+    let genuine = victim.answer_challenge(reply.challenge, &node.id()).await;
+    assert!(!node.recv_prove(victim.pub_id.clone(), genuine).await);
+    assert!(node.get_peers().is_empty());
+}
+
+#[tokio::test]
+async fn identity_rotation() {
+    let alice = mem_node(PrivateId::from_seed(b"alice")).await;
+    let bob = mem_node(PrivateId::from_seed(b"bob")).await;
+    let carol = mem_node(PrivateId::from_seed(b"carol")).await;
+    alice.discover_peer(None, bob.addr()).await.unwrap();
+    alice.discover_peer(None, carol.addr()).await.unwrap();
+
+    bob.discover_peer(None, carol.addr()).await.unwrap();
+
+    let mut tags = Vec::new();
+    for i in 0..20u8 {
+        tags.push(bob.do_upload(Bytes::from(vec![i; 64])).await.unwrap());
+    }
+    let old_id = alice.id();
+
+    let new = PrivateId::from_seed(b"alice, again");
+    let new_id = new.pub_id.clone();
+    alice.rotate_identity(new).await;
+    assert_eq!(alice.id(), new_id);
+
+    // Alice keeps her peers, who now know her by her new identity
+    let mut peers = alice.get_peers();
+    peers.sort_by_key(|id| id.tag);
+    let mut expected = vec![bob.id(), carol.id()];
+    expected.sort_by_key(|id| id.tag);
+    assert_eq!(peers, expected);
+    for peer in [&bob, &carol] {
+        let peers = peer.get_peers();
+        assert!(peers.contains(&new_id) && !peers.contains(&old_id));
+    }
+
+    // Nothing that was stored is lost, and Alice only keeps what she's still the closest node to
+    for tag in tags {
+        let mut holders = Vec::new();
+        for node in [&alice, &bob, &carol] {
+            if node.has_data(tag).await {
+                holders.push(node.id());
+            }
+        }
+        assert!(!holders.is_empty());
+        if holders.contains(&new_id) {
+            let dist = new_id.tag.dist_to(tag);
+            assert!(dist < bob.id().tag.dist_to(tag) && dist < carol.id().tag.dist_to(tag));
+        }
+    }
+
+    // Data sent to her old identity can still be read
+    let secret = bob.upload_for(&old_id, b"for the old key").await.unwrap();
+    assert_eq!(
+        &*alice.download_encrypted(secret).await.unwrap().unwrap(),
+        b"for the old key"
+    );
+
+    // Somebody who heard of Alice under her old identity can still reach her
+    let dave = mem_node(PrivateId::from_seed(b"dave")).await;
+    dave.discover_peer(Some(&old_id), alice.addr())
+        .await
+        .unwrap();
+    assert_eq!(dave.get_peers(), vec![new_id]);
+}
+
+#[tokio::test]
+async fn rotation_replay() {
+    let alice = mem_node(PrivateId::from_seed(b"alice")).await;
+    let bob = mem_node(PrivateId::from_seed(b"bob")).await;
+    let carol = mem_node(PrivateId::from_seed(b"carol")).await;
+    bob.discover_peer(None, alice.addr()).await.unwrap();
+    let new = PrivateId::from_seed(b"alice, again").pub_id;
+
+    // Somebody who captured Alice's endorsement can't replay it to make Bob drop her again
+    let key = PrivateId::from_seed(b"alice");
+    let endorsement = Signed::new(&key, now_millis(), 1, new.clone()).await;
+    assert!(bob.recv_rotate(endorsement.clone()).await);
+    assert!(bob.get_peers().is_empty());
+    assert!(bob.accept_peer(alice.id(), alice.addr()).await);
+    assert!(!bob.recv_rotate(endorsement).await);
+    assert_eq!(bob.get_peers(), vec![alice.id()]);
+
+    // Nor can an old one be dug up once its nonce has been forgotten
+    let stale = now_millis() - 2 * MAX_CLOCK_SKEW.as_millis() as u64;
+    let endorsement = Signed::new(&key, stale, 2, new.clone()).await;
+    assert!(!bob.recv_rotate(endorsement).await);
+    assert_eq!(bob.get_peers(), vec![alice.id()]);
+
+    // Endorsements from those who aren't our peers are ignored
+    let endorsement = Signed::new(&PrivateId::from_seed(b"carol"), now_millis(), 3, new).await;
+    assert!(!bob.recv_rotate(endorsement).await);
+    assert!(!bob.get_peers().contains(&carol.id()));
+}
+
+// Signs with an ordinary key, but counts how often it's asked to
+struct CountingSigner {
+    inner: PrivateId,
+    count: Arc<AtomicUsize>,
+}
+
+#[async_trait::async_trait]
+impl Signer for CountingSigner {
+    fn public_key(&self) -> &RsaPublicKey {
+        &self.inner.pub_id.key
+    }
+
+    async fn sign(&self, bytes: &[u8]) -> Signature {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.inner.sign(bytes).await
+    }
+}
+
+#[tokio::test]
+async fn external_signer() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let id = PrivateId::from_signer(CountingSigner {
+        inner: PrivateId::from_seed(b"hsm"),
+        count: count.clone(),
+    });
+    assert_eq!(id.pub_id, PrivateId::from_seed(b"hsm").pub_id);
+    // The key material never leaves the signer
+    assert!(matches!(id.to_pem(), Err(KeyError::External)));
+    assert!(id.decrypt(&id.pub_id.encrypt(b"secret")).is_none());
+
+    // Every signature the node makes goes through the signer
+    let node = mem_node(id).await;
+    let peer = mem_node(PrivateId::from_seed(b"peer")).await;
+    assert_eq!(count.load(Ordering::Relaxed), 0);
+    node.discover_peer(None, peer.addr()).await.unwrap();
+    let after_greeting = count.load(Ordering::Relaxed);
+    assert!(after_greeting > 0);
+    let msg = node.seal("hello").await;
+    assert_eq!(msg.verify(), Ok(()));
+    assert_eq!(count.load(Ordering::Relaxed), after_greeting + 1);
+    assert_eq!(peer.get_peers(), vec![node.id()]);
+}
+
+#[tokio::test]
+async fn callback_signer() {
+    let agent = Arc::new(PrivateId::from_seed(b"agent"));
+    let id = PrivateId::from_signer(CallbackSigner::new(agent.pub_id.key.clone(), {
+        let agent = agent.clone();
+        move |bytes: Vec<u8>| {
+            let agent = agent.clone();
+            async move { agent.sign(&bytes).await }
+        }
+    }));
+    let msg = Signed::new(&id, now_millis(), 1, "hello").await;
+    assert_eq!(msg.sender, agent.pub_id);
+    assert_eq!(msg.verify(), Ok(()));
+}
@@ -0,0 +1,560 @@
+use bytes::Bytes;
+use nettle::{
+    mem,
+    store::{EntryInfo, Publisher, Quotas, Store},
+    tree::{self, Layout, TreeNode},
+    HashAlgorithm, LocateReply, Node, PrivateId, ProtocolError, PublicId, Tag, CHUNK_LEASE,
+    POPULARITY_THRESHOLD,
+};
+use rand::prelude::*;
+use std::{collections::HashSet, time::Duration};
+
+fn compressible() -> Bytes {
+    "the quick brown fox jumps over the lazy dog\n"
+        .repeat(1000)
+        .into()
+}
+
+fn incompressible() -> Bytes {
+    let mut data = vec![0; 16 * 1024];
+    StdRng::seed_from_u64(0).fill_bytes(&mut data);
+    data.into()
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn compressed_store() {
+    let mut store = Store::new(Some(nettle::store::Compression::default()));
+    let (text, noise) = (compressible(), incompressible());
+    for data in [&text, &noise] {
+        assert!(store.insert(Tag::digest(data), data.clone()));
+        assert_eq!(store.get(Tag::digest(data)).as_ref(), Some(data));
+    }
+    assert!(!store.insert(Tag::digest(&text), text.clone()));
+    assert_eq!(store.logical_bytes(), text.len() + noise.len());
+    // Only the text was worth compressing, so the noise is stored as it is
+    assert!(store.stored_bytes() < noise.len() + text.len() / 10);
+    assert!(store.stored_bytes() > noise.len());
+
+    assert!(store.remove(Tag::digest(&text)));
+    assert!(!store.remove(Tag::digest(&text)));
+    assert_eq!(store.stored_bytes(), noise.len());
+    assert_eq!(store.logical_bytes(), noise.len());
+
+    // Demanding more of a gain than the text gives leaves it uncompressed too
+    store.set_compression(Some(nettle::store::Compression {
+        min_gain: 0.9999,
+        ..nettle::store::Compression::default()
+    }));
+    store.insert(Tag::digest(&text), text.clone());
+    assert_eq!(store.stored_bytes(), store.logical_bytes());
+}
+
+#[cfg(feature = "zstd")]
+#[tokio::test]
+async fn compressed_downloads() {
+    let addr = mem::Addr::default();
+    let holder =
+        Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+            .await
+            .unwrap();
+    holder.set_compression(Some(nettle::store::Compression::default()));
+
+    for data in [compressible(), incompressible()] {
+        let tag = holder
+            .recv_upload(Default::default(), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(tag, Tag::digest(&data));
+        // Whatever the holder serves is the data that the tag names, not its compressed form
+        let served = holder.recv_download(tag).await.unwrap().unwrap();
+        assert_eq!(Tag::digest(&served), tag);
+        assert_eq!(served, data);
+    }
+    let stats = holder.stats();
+    assert_eq!(stats.entries, 2);
+    assert_eq!(
+        stats.logical_bytes,
+        (compressible().len() + incompressible().len()) as u64
+    );
+    assert!(stats.stored_bytes < stats.logical_bytes);
+}
+
+#[test]
+fn budgeted_store() {
+    let blob = |i: u8| Bytes::from(vec![i; 100]);
+    let mut store = Store::new(None);
+    store.set_budget(Some(350));
+    for i in 0..3 {
+        store.insert(Tag::digest(blob(i)), blob(i));
+    }
+    assert!(store.pin(Tag::digest(blob(0))));
+    assert!(!store.pin(Tag::digest(blob(9))));
+    assert_eq!(store.pinned_bytes(), 100);
+    // Using the second entry leaves the third as the least recently used one that may be evicted
+    store.get(Tag::digest(blob(1)));
+
+    // Fill the store well past its budget
+    for i in 3..10 {
+        store.insert(Tag::digest(blob(i)), blob(i));
+        assert!(store.stored_bytes() <= 350);
+        assert!(store.contains(Tag::digest(blob(0))));
+        if i == 3 {
+            assert!(!store.contains(Tag::digest(blob(2))));
+            assert!(store.contains(Tag::digest(blob(1))));
+        }
+    }
+    assert_eq!(
+        store.tags().collect::<HashSet<_>>(),
+        [0, 8, 9].map(|i| Tag::digest(blob(i))).into()
+    );
+    let pinned = store
+        .entries()
+        .filter(|entry| entry.pinned)
+        .map(|entry| entry.tag)
+        .collect::<Vec<_>>();
+    assert_eq!(pinned, [Tag::digest(blob(0))]);
+
+    // Data too large to fit beside what's pinned isn't kept
+    let large = Bytes::from(vec![0xff; 300]);
+    assert!(!store.insert(Tag::digest(&large), large.clone()));
+    assert!(!store.contains(Tag::digest(large)));
+    assert!(store.contains(Tag::digest(blob(0))));
+
+    // Once unpinned, an entry is fair game again
+    assert!(store.unpin(Tag::digest(blob(0))));
+    assert!(!store.unpin(Tag::digest(blob(0))));
+    assert_eq!(store.pinned_bytes(), 0);
+    store.set_budget(Some(200));
+    assert_eq!(store.len(), 2);
+    assert!(!store.contains(Tag::digest(blob(8))));
+}
+
+#[test]
+fn cached_store() {
+    let blob = |i: u8| Bytes::from(vec![i; 100]);
+    let mut store = Store::new(None);
+    store.set_budget(Some(300));
+    for i in 0..2 {
+        store.insert(Tag::digest(blob(i)), blob(i));
+    }
+    assert!(store.insert_cached(Tag::digest(blob(2)), blob(2)));
+    assert!(store.is_cached(Tag::digest(blob(2))));
+    assert_eq!(store.cached_bytes(), 100);
+
+    // Cached data only makes room for itself by evicting other cached data
+    assert!(store.insert_cached(Tag::digest(blob(3)), blob(3)));
+    assert!(!store.contains(Tag::digest(blob(2))));
+    let large = Bytes::from(vec![0xff; 200]);
+    assert!(!store.insert_cached(Tag::digest(&large), large));
+    assert_eq!(store.len(), 3);
+    // Cached data isn't ours, so isn't listed among the tags that we'd pass on
+    assert_eq!(
+        store.tags().collect::<HashSet<_>>(),
+        [0, 1].map(|i| Tag::digest(blob(i))).into()
+    );
+
+    // Cached data is evicted first, even when it was used more recently
+    store.get(Tag::digest(blob(3)));
+    store.insert(Tag::digest(blob(4)), blob(4));
+    assert!(!store.contains(Tag::digest(blob(3))));
+    assert_eq!(store.len(), 3);
+    assert_eq!(store.cached_bytes(), 0);
+
+    // Storing cached data in earnest makes it an ordinary entry
+    store.set_budget(None);
+    assert!(store.insert_cached(Tag::digest(blob(5)), blob(5)));
+    assert!(!store.insert_cached(Tag::digest(blob(5)), blob(5)));
+    assert!(store.insert(Tag::digest(blob(5)), blob(5)));
+    assert!(!store.is_cached(Tag::digest(blob(5))));
+    assert!(store.tags().any(|tag| tag == Tag::digest(blob(5))));
+    assert_eq!(store.cached_bytes(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn expiring_store() {
+    let (pinned, unpinned) = (compressible(), incompressible());
+    let mut store = Store::new(None);
+    store.set_ttl(Some(Duration::from_secs(60)));
+    store.insert(Tag::digest(&pinned), pinned.clone());
+    store.insert(Tag::digest(&unpinned), unpinned.clone());
+    store.pin(Tag::digest(&pinned));
+
+    tokio::time::advance(Duration::from_secs(59)).await;
+    assert!(store.contains(Tag::digest(&unpinned)));
+    tokio::time::advance(Duration::from_secs(1)).await;
+    assert!(!store.contains(Tag::digest(&unpinned)));
+    assert_eq!(store.get(Tag::digest(&unpinned)), None);
+    assert_eq!(store.expire(), 0);
+    assert_eq!(store.get(Tag::digest(&pinned)), Some(pinned.clone()));
+
+    // Unpinning starts the clock again
+    store.unpin(Tag::digest(&pinned));
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(store.expire(), 0);
+    tokio::time::advance(Duration::from_secs(30)).await;
+    assert_eq!(store.expire(), 1);
+    assert!(store.is_empty());
+}
+
+#[tokio::test]
+async fn pinned_downloads() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::<mem::Mem>::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let data = compressible();
+    let tag = Tag::digest(&data);
+    // The data is held by whichever node it would be uploaded to
+    nodes.sort_by_key(|node| node.id().tag.dist_to(tag));
+    let (holder, pinner) = (&nodes[0], &nodes[1]);
+    holder.accept_peer(pinner.id(), pinner.addr()).await;
+    pinner.accept_peer(holder.id(), holder.addr()).await;
+
+    holder.save_data(tag, data.clone()).await;
+    pinner.set_storage_budget(Some(data.len()));
+    assert!(!pinner.pin(tag));
+    assert_eq!(pinner.pin_and_fetch(tag).await, Ok(true));
+    assert_eq!(pinner.pin_and_fetch(Tag::generate()).await, Ok(false));
+    assert_eq!(pinner.stats().pinned_bytes, data.len() as u64);
+
+    // Nothing else fits beside the pinned data, but it isn't evicted to make room
+    let other = incompressible();
+    pinner.save_data(Tag::digest(&other), other).await;
+    assert_eq!(pinner.load_data(tag).await, Some(data.clone()));
+    assert_eq!(
+        pinner.list_data(),
+        [EntryInfo {
+            tag,
+            len: data.len(),
+            stored_len: data.len(),
+            pinned: true,
+            cached: false,
+        }]
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn garbage_collection() {
+    let grace = Duration::from_secs(60);
+    let data = compressible();
+    let layout = Layout {
+        // Not a multiple of the text's period, so that no two chunks are the same
+        chunk_size: data.len() / 4 + 1,
+        ..Layout::default()
+    };
+    let (root, blobs) = tree::build(&data, layout);
+    let chunks = blobs[..blobs.len() - 1]
+        .iter()
+        .map(|(tag, _)| *tag)
+        .collect::<Vec<_>>();
+    assert_eq!(chunks.len(), 4);
+    let mut store = Store::new(None);
+    for (tag, blob) in &blobs {
+        if *tag == root {
+            store.insert(*tag, blob.clone());
+        } else {
+            store.insert_chunk(*tag, blob.clone(), None);
+        }
+    }
+    let unrelated = incompressible();
+    store.insert(Tag::digest(&unrelated), unrelated.clone());
+    assert!(chunks.iter().all(|chunk| store.references(*chunk) == 1));
+
+    store.pin(chunks[0]);
+    store.remove(root);
+    assert_eq!(store.references(chunks[1]), 0);
+    // Chunks outlive what refers to them until their lease runs out, unless it's renewed or they're used
+    tokio::time::advance(CHUNK_LEASE / 2).await;
+    assert!(store.renew(chunks[2]));
+    assert!(!store.renew(Tag::digest(b"missing")));
+    tokio::time::advance(CHUNK_LEASE / 2 - grace / 2).await;
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 0);
+    store.get(chunks[1]);
+    tokio::time::advance(grace / 2).await;
+    // The pinned chunk stays, and so do the renewed one and the one that was used lately, for now
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 1);
+    assert!(!store.contains(chunks[3]));
+    tokio::time::advance(grace / 2).await;
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 1);
+    assert!(!store.contains(chunks[1]));
+    tokio::time::advance(CHUNK_LEASE / 2).await;
+    assert_eq!(store.collect_garbage(1, grace), 1);
+    assert!(!store.contains(chunks[2]));
+
+    // Pinning a chunk keeps it in its own right, even once unpinned
+    assert!(store.is_independent(chunks[0]));
+    store.unpin(chunks[0]);
+    tokio::time::advance(CHUNK_LEASE + grace).await;
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 0);
+    let mut tags = store.tags().collect::<Vec<_>>();
+    tags.sort();
+    let mut expected = vec![chunks[0], Tag::digest(&unrelated)];
+    expected.sort();
+    assert_eq!(tags, expected);
+
+    // Chunks that something here refers to are kept however long ago their lease was renewed
+    let (root, blobs) = tree::build(&data, layout);
+    for (tag, blob) in &blobs {
+        store.insert_chunk(*tag, blob.clone(), None);
+    }
+    store.pin(root);
+    tokio::time::advance(CHUNK_LEASE + grace).await;
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 0);
+    store.remove(root);
+    // Once nothing refers to them, chunks whose lease ran out long ago go straight away, bar the one that was pinned
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 3);
+    assert!(store.contains(chunks[0]));
+
+    // Data stored in its own right is never collected, whatever lists it
+    let listing = TreeNode {
+        depth: 1,
+        len: unrelated.len() as u64,
+        children: vec![Tag::digest(&unrelated)],
+    }
+    .encode();
+    store.insert(Tag::digest(&listing), listing.clone());
+    assert_eq!(store.references(Tag::digest(&unrelated)), 1);
+    store.remove(Tag::digest(&listing));
+    tokio::time::advance(grace).await;
+    assert_eq!(store.collect_garbage(usize::MAX, grace), 0);
+    assert!(store.contains(Tag::digest(&unrelated)));
+}
+
+#[tokio::test]
+async fn publisher_quotas() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    node.set_quotas(Quotas {
+        per_publisher: Some(250),
+        anonymous: Some(0),
+    });
+    let (greedy, modest) = (PrivateId::generate().pub_id, PrivateId::generate().pub_id);
+    let blob = |i: u8| Bytes::from(vec![i; 100]);
+    let upload = |sender: Option<&PublicId>, data: Bytes| {
+        let (node, sender) = (&node, sender.cloned());
+        async move {
+            node.recv_upload_from(sender.as_ref(), Default::default(), data)
+                .await
+        }
+    };
+
+    assert_eq!(
+        upload(Some(&greedy), blob(0)).await,
+        Ok(Tag::digest(blob(0)))
+    );
+    assert_eq!(
+        upload(Some(&greedy), blob(1)).await,
+        Ok(Tag::digest(blob(1)))
+    );
+    assert_eq!(
+        upload(Some(&greedy), blob(2)).await,
+        Err(ProtocolError::QuotaExceeded)
+    );
+    // Copies of data already stored don't count against the quota
+    assert_eq!(
+        upload(Some(&greedy), blob(1)).await,
+        Ok(Tag::digest(blob(1)))
+    );
+    assert_eq!(
+        upload(Some(&modest), blob(2)).await,
+        Ok(Tag::digest(blob(2)))
+    );
+    assert_eq!(
+        upload(None, blob(3)).await,
+        Err(ProtocolError::QuotaExceeded)
+    );
+    assert_eq!(node.top_publishers(1), [(Publisher::Node(greedy.tag), 200)]);
+
+    // Dropping data frees up the quota it took
+    assert!(node.remove_data(Tag::digest(blob(0))).await);
+    assert_eq!(
+        upload(Some(&greedy), blob(3)).await,
+        Ok(Tag::digest(blob(3)))
+    );
+    assert_eq!(
+        node.top_publishers(usize::MAX),
+        [
+            (Publisher::Node(greedy.tag), 200),
+            (Publisher::Node(modest.tag), 100)
+        ]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn concurrent_uploads_are_limited() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    node.set_upload_limit(4, Duration::from_secs(60));
+    let blobs = (0..50u8)
+        .map(|i| Bytes::from(vec![i; 1 << 20]))
+        .collect::<Vec<_>>();
+    let uploads = blobs.iter().map(|data| {
+        let (node, data) = (node.clone(), data.clone());
+        tokio::spawn(async move { node.recv_upload(Default::default(), data).await })
+    });
+    let tags = futures::future::join_all(uploads).await;
+
+    for (data, tag) in blobs.iter().zip(tags) {
+        let tag = tag.unwrap().unwrap();
+        assert_eq!(tag, Tag::digest(data));
+        assert_eq!(node.load_data(tag).await.as_ref(), Some(data));
+    }
+    assert!((1..=4).contains(&node.metrics().peak_concurrent_uploads()));
+    assert_eq!(node.metrics().busy_rejections(), 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn busy_nodes_turn_uploads_away() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    node.set_upload_limit(1, Duration::from_secs(1));
+    let data = Bytes::from_static(b"waiting its turn");
+
+    // An upload that can't get a turn in time is turned away
+    let permit = node.upload_permit().await.unwrap();
+    assert_eq!(
+        node.recv_upload(Default::default(), data.clone()).await,
+        Err(ProtocolError::Busy)
+    );
+    assert_eq!(node.metrics().busy_rejections(), 1);
+    assert!(!node.has_data(Tag::digest(&data)).await);
+
+    // But one whose turn comes up while it waits goes ahead
+    let upload = tokio::spawn({
+        let (node, data) = (node.clone(), data.clone());
+        async move { node.recv_upload(Default::default(), data).await }
+    });
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    drop(permit);
+    assert_eq!(upload.await.unwrap(), Ok(Tag::digest(&data)));
+    assert_eq!(node.metrics().busy_rejections(), 1);
+}
+
+#[tokio::test]
+async fn tagged_uploads() {
+    let addr = mem::Addr::default();
+    let node = Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+        .await
+        .unwrap();
+    let data = Bytes::from_static(b"tagged ahead of time");
+    let tag = Tag::digest(&data);
+    let other = Bytes::from_static(b"something else entirely");
+
+    // Data that isn't what its tag says is refused, rather than stored under what it hashes to
+    assert_eq!(
+        node.recv_tagged_upload_from(None, tag, other.clone(), false)
+            .await,
+        Err(ProtocolError::Mismatch)
+    );
+    assert!(!node.has_data(tag).await);
+    assert!(!node.has_data(Tag::digest(&other)).await);
+
+    assert_eq!(
+        node.recv_tagged_upload_from(None, tag, data.clone(), false)
+            .await,
+        Ok(tag)
+    );
+    assert_eq!(node.load_data(tag).await, Some(data.clone()));
+
+    // Once the node has the data, further uploads of it aren't looked at
+    let received = node.metrics().upload_bytes();
+    assert_eq!(
+        node.recv_tagged_upload_from(None, tag, other.clone(), false)
+            .await,
+        Ok(tag)
+    );
+    assert_eq!(node.metrics().upload_bytes(), received);
+    assert_eq!(node.load_data(tag).await, Some(data.clone()));
+
+    // Once the node's copy has gone, uploads are checked again
+    node.set_data_ttl(Some(Duration::ZERO));
+    assert_eq!(
+        node.recv_tagged_upload_from(None, tag, other.clone(), false)
+            .await,
+        Err(ProtocolError::Mismatch)
+    );
+    assert!(!node.has_data(tag).await);
+
+    // The store never takes the upload's word for what it holds
+    let mut store = Store::new(None);
+    assert!(!store.reinsert_from(tag, None, false));
+    assert!(!store.contains(tag));
+    store.insert(tag, data.clone());
+    assert!(store.reinsert_from(tag, Some(Publisher::Anonymous), false));
+    assert_eq!(store.get(tag), Some(data));
+
+    // Untagged uploads are stored under whatever they hash to
+    let untagged = Bytes::from_static(b"no tag given");
+    assert_eq!(
+        node.recv_upload_from(None, HashAlgorithm::Blake3, untagged.clone())
+            .await,
+        Ok(Tag::digest_with(HashAlgorithm::Blake3, &untagged))
+    );
+}
+
+#[tokio::test]
+async fn popular_data_is_cached() {
+    let network = mem::Network::default();
+    let mut nodes = Vec::new();
+    for _ in 0..3 {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::<mem::Mem>::new(PrivateId::generate(), addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let data = compressible();
+    let tag = Tag::digest(&data);
+    // Lookups from the client pass through the intermediate on their way to the owner
+    nodes.sort_by_key(|node| std::cmp::Reverse(node.id().tag.dist_to(tag)));
+    let (client, intermediate, owner) = (&nodes[0], &nodes[1], &nodes[2]);
+    for (a, b) in [(client, intermediate), (intermediate, owner)] {
+        a.accept_peer(b.id(), b.addr()).await;
+        b.accept_peer(a.id(), a.addr()).await;
+    }
+    owner.save_data(tag, data.clone()).await;
+
+    assert_eq!(intermediate.cache_popular().await, 0);
+    for _ in 0..=POPULARITY_THRESHOLD as usize {
+        let located = client.locate(tag).await.unwrap();
+        assert!(located.found);
+        assert_eq!(located.owner.0, owner.id());
+    }
+    assert_eq!(intermediate.cache_popular().await, 1);
+
+    // The intermediate now serves the data itself, without passing the client on to the owner
+    assert_eq!(intermediate.recv_locate(tag).await, LocateReply::Found);
+    let located = client.locate(tag).await.unwrap();
+    assert!(located.found);
+    assert_eq!(located.owner.0, intermediate.id());
+    assert_eq!(client.do_download(tag).await, Ok(Some(data)));
+    // Its copy is only a cache, so it's not ours to hand on
+    assert!(intermediate
+        .list_data()
+        .iter()
+        .all(|entry| entry.tag == tag && entry.cached));
+}
@@ -0,0 +1,138 @@
+use nettle::stun::{self, binding_request, parse_binding_response, Mapping, StunError};
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UdpSocket;
+
+// The transaction id of the sample responses in RFC 5769
+const TRANSACTION: [u8; 12] = [
+    0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+];
+
+// RFC 5769, section 2.2
+const IPV4_RESPONSE: &[u8] = &[
+    0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+    0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43,
+    0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3, 0x8c, 0x74, 0x89, 0xf9,
+    0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00, 0x04, 0xc0, 0x7d, 0x4c, 0x96,
+];
+
+// RFC 5769, section 2.3
+const IPV6_RESPONSE: &[u8] = &[
+    0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86,
+    0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76, 0x65, 0x63,
+    0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa,
+    0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9, 0x00, 0x08, 0x00, 0x14,
+    0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9, 0x7c, 0x82, 0x92, 0xc2, 0x75,
+    0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb, 0x0b, 0x4c,
+];
+
+#[test]
+fn request_format() {
+    let request = binding_request(TRANSACTION);
+    assert_eq!(
+        request[..8],
+        [0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42]
+    );
+    assert_eq!(request[8..], TRANSACTION);
+}
+
+#[test]
+fn captured_responses() {
+    assert_eq!(
+        parse_binding_response(IPV4_RESPONSE, TRANSACTION).unwrap(),
+        "192.0.2.1:32853".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(
+        parse_binding_response(IPV6_RESPONSE, TRANSACTION).unwrap(),
+        "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse::<SocketAddr>()
+            .unwrap()
+    );
+}
+
+#[test]
+fn malformed_responses() {
+    let mut other_transaction = TRANSACTION;
+    other_transaction[0] ^= 1;
+    assert!(matches!(
+        parse_binding_response(IPV4_RESPONSE, other_transaction),
+        Err(StunError::Malformed(_))
+    ));
+    // Every truncation is refused rather than misread
+    for len in 0..IPV4_RESPONSE.len() {
+        assert!(parse_binding_response(&IPV4_RESPONSE[..len], TRANSACTION).is_err());
+    }
+    let mut request = binding_request(TRANSACTION).to_vec();
+    assert!(parse_binding_response(&request, TRANSACTION).is_err());
+    // A response without any address
+    request[1] = 0x01;
+    assert!(parse_binding_response(&request, TRANSACTION).is_err());
+}
+
+#[test]
+fn plain_mapped_address() {
+    // Older servers only send MAPPED-ADDRESS, which isn't XORed
+    let mut response = vec![0x01, 0x01, 0x00, 0x0c, 0x21, 0x12, 0xa4, 0x42];
+    response.extend(TRANSACTION);
+    response.extend([
+        0x00, 0x01, 0x00, 0x08, 0x00, 0x01, 0x1f, 0x90, 203, 0, 113, 7,
+    ]);
+    assert_eq!(
+        parse_binding_response(&response, TRANSACTION).unwrap(),
+        "203.0.113.7:8080".parse::<SocketAddr>().unwrap()
+    );
+}
+
+// Run a STUN server on localhost that reports each client's address with its port shifted by `shift`
+async fn fake_server(shift: u16) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1024];
+        loop {
+            let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 20);
+            let mut response = vec![0x01, 0x01, 0x00, 0x0c];
+            response.extend(&buf[4..20]);
+            let port = (from.port() + shift) ^ 0x2112;
+            let SocketAddr::V4(from) = from else {
+                unreachable!()
+            };
+            let ip = u32::from(*from.ip()) ^ 0x2112_a442;
+            response.extend([0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            response.extend(port.to_be_bytes());
+            response.extend(ip.to_be_bytes());
+            socket.send_to(&response, from).await.unwrap();
+        }
+    });
+    addr.to_string()
+}
+
+#[tokio::test]
+async fn discover() {
+    let honest = fake_server(0).await;
+    let Mapping { addr, symmetric } = stun::discover(&[&honest, &honest]).await.unwrap();
+    assert_eq!(addr.ip(), IpAddr::from([127, 0, 0, 1]));
+    assert!(!symmetric);
+
+    // Servers that see us at different ports mean the NAT maps each destination separately
+    let shifted = fake_server(1).await;
+    assert!(
+        stun::discover(&[honest.as_str(), &shifted])
+            .await
+            .unwrap()
+            .symmetric
+    );
+
+    // Unreachable or unresolvable servers are skipped
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let silent = socket.local_addr().unwrap().to_string();
+    let mapping = stun::discover(&[silent.as_str(), "nowhere.invalid:3478", &honest])
+        .await
+        .unwrap();
+    assert!(!mapping.symmetric);
+    assert!(matches!(
+        stun::discover(&[silent]).await,
+        Err(StunError::NoResponse)
+    ));
+}
@@ -0,0 +1,123 @@
+use bytes::Bytes;
+use nettle::{
+    mem,
+    sync::{Region, Summary, MAX_SUMMARY_BYTES},
+    Node, PrivateId, Synced, Tag,
+};
+use std::sync::Arc;
+
+#[test]
+fn summaries() {
+    let tags = (0..1000).map(|_| Tag::generate()).collect::<Vec<_>>();
+    let summary = Summary::new(&tags, 0.01, 7);
+    assert!(tags.iter().all(|tag| summary.contains(*tag)));
+    let false_positives = (0..10_000)
+        .filter(|_| summary.contains(Tag::generate()))
+        .count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+
+    // Another seed gives other false positives
+    let reseeded = Summary::new(&tags, 0.01, 8);
+    assert_ne!(summary.bits, reseeded.bits);
+
+    // A lower rate takes more room, but never more than the limit
+    assert!(Summary::new(&tags, 0.0001, 7).bits.len() > summary.bits.len());
+    let many = (0..100_000).map(|_| Tag::generate()).collect::<Vec<_>>();
+    let summary = Summary::new(&many, 0.01, 7);
+    assert_eq!(summary.bits.len(), MAX_SUMMARY_BYTES);
+    assert!(many.iter().all(|tag| summary.contains(*tag)));
+
+    assert!(!Summary::new(&[], 0.01, 7).contains(Tag::generate()));
+}
+
+#[test]
+fn regions() {
+    let mut bytes = [0xaa; 32];
+    let a = Tag::from_bytes(bytes);
+    bytes[1] ^= 0x01;
+    let b = Tag::from_bytes(bytes);
+    let region = Region::shared(a, b);
+    assert_eq!(region.len, 15);
+    assert!(region.contains(a) && region.contains(b));
+    assert_eq!(Region::shared(b, a).len, region.len);
+    bytes[1] ^= 0x03;
+    assert!(!region.contains(Tag::from_bytes(bytes)));
+    bytes[1] ^= 0x02;
+    bytes[31] = 0;
+    assert!(region.contains(Tag::from_bytes(bytes)));
+}
+
+// Two peers whose tags share at least their first bit, so that there's some of the keyspace they're not responsible for
+async fn pair() -> (Arc<Node<mem::Mem>>, Arc<Node<mem::Mem>>) {
+    let network = mem::Network::default();
+    let ids = loop {
+        let ids = [PrivateId::generate(), PrivateId::generate()];
+        if ids[0].pub_id.tag.common_prefix_len(ids[1].pub_id.tag) > 0 {
+            break ids;
+        }
+    };
+    let mut nodes = Vec::new();
+    for id in ids {
+        let addr = mem::Addr::default();
+        let config = mem::Config {
+            addr: addr.clone(),
+            network: network.clone(),
+            sign_messages: true,
+        };
+        nodes.push(
+            Node::<mem::Mem>::new(id, addr, Vec::new(), config)
+                .await
+                .unwrap(),
+        );
+    }
+    let (a, b) = (nodes[0].clone(), nodes[1].clone());
+    a.accept_peer(b.id(), b.addr()).await;
+    b.accept_peer(a.id(), a.addr()).await;
+    (a, b)
+}
+
+#[tokio::test]
+async fn disjoint_stores_converge() {
+    let (a, b) = pair().await;
+    let region = Region::shared(a.id().tag, b.id().tag);
+    // Blobs in the region that the two nodes share, and one outside of it
+    let mut blobs = (0u32..)
+        .map(|i| Bytes::from(format!("blob {}", i)))
+        .filter(|data| region.contains(Tag::digest(data)));
+    let (ours, theirs) = (
+        blobs.by_ref().take(10).collect::<Vec<_>>(),
+        blobs.take(10).collect::<Vec<_>>(),
+    );
+    let elsewhere = (0u32..)
+        .map(|i| Bytes::from(format!("other blob {}", i)))
+        .find(|data| !region.contains(Tag::digest(data)))
+        .unwrap();
+    for data in &ours {
+        a.save_data(Tag::digest(data), data.clone()).await;
+    }
+    for data in &theirs {
+        b.save_data(Tag::digest(data), data.clone()).await;
+    }
+    a.save_data(Tag::digest(&elsewhere), elsewhere.clone())
+        .await;
+    // A false positive would hide a blob until the next exchange, so rule them out to count exactly
+    for node in [&a, &b] {
+        node.set_sync_false_positive_rate(1e-9);
+    }
+
+    assert_eq!(
+        a.sync_with(&(b.id(), b.addr())).await,
+        Some(Synced {
+            fetched: 10,
+            sent: 10
+        })
+    );
+    for data in ours.iter().chain(&theirs) {
+        assert!(a.has_data(Tag::digest(data)).await);
+        assert!(b.has_data(Tag::digest(data)).await);
+    }
+    assert!(!b.has_data(Tag::digest(&elsewhere)).await);
+
+    // Once converged, there's nothing more to pass either way
+    assert_eq!(b.anti_entropy().await, Some(Synced::default()));
+}
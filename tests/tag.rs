@@ -0,0 +1,321 @@
+use nettle::{mem, HashAlgorithm, Node, PrivateId, Tag, TagHasher, TagParseError};
+use rand::prelude::*;
+use std::sync::Arc;
+
+#[tokio::test]
+async fn streaming_digest() {
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(0);
+    for len in [
+        0,
+        1,
+        31,
+        4096,
+        64 * 1024 - 1,
+        64 * 1024,
+        64 * 1024 + 1,
+        1_000_000,
+    ] {
+        let mut data = vec![0; len];
+        rng.fill_bytes(&mut data);
+        let tag = Tag::digest(&data);
+
+        assert_eq!(Tag::digest_reader(data.as_slice()).unwrap(), tag);
+        assert_eq!(
+            Tag::digest_async_reader(data.as_slice()).await.unwrap(),
+            tag
+        );
+        for algorithm in HashAlgorithm::ALL {
+            let tag = Tag::digest_with(algorithm, &data);
+            assert_eq!(
+                Tag::digest_reader_with(algorithm, data.as_slice()).unwrap(),
+                tag
+            );
+            assert_eq!(
+                Tag::digest_async_reader_with(algorithm, data.as_slice())
+                    .await
+                    .unwrap(),
+                tag
+            );
+        }
+
+        // However the input is split up, the tag is the same
+        let mut hasher = TagHasher::new();
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            let (chunk, tail) = rest.split_at(rng.gen_range(1..=rest.len().min(10_000)));
+            hasher.update(chunk);
+            rest = tail;
+        }
+        assert_eq!(hasher.finish(), tag);
+    }
+}
+
+// Pairs of tags sharing prefixes of every length, including identical tags
+fn tag_pairs(rng: &mut impl Rng) -> Vec<(Tag, Tag)> {
+    (0..=256)
+        .map(|prefix| {
+            let a = rng.gen::<[u8; 32]>();
+            let mut b = rng.gen::<[u8; 32]>();
+            for i in 0..prefix.min(256) {
+                let mask = 0x80 >> (i % 8);
+                b[i / 8] = (b[i / 8] & !mask) | (a[i / 8] & mask);
+            }
+            if prefix < 256 {
+                // The tags differ at the first bit after the prefix
+                let mask = 0x80 >> (prefix % 8);
+                b[prefix / 8] = (b[prefix / 8] & !mask) | (!a[prefix / 8] & mask);
+            }
+            (Tag::from_bytes(a), Tag::from_bytes(b))
+        })
+        .collect()
+}
+
+#[test]
+fn prefixes_and_buckets() {
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(1);
+    for (prefix, (a, b)) in tag_pairs(&mut rng).into_iter().enumerate() {
+        let prefix = prefix as u16;
+        assert_eq!(a.common_prefix_len(b), prefix);
+        assert_eq!(b.common_prefix_len(a), prefix);
+        assert_eq!(a.dist_to(b), b.dist_to(a));
+        assert_eq!(a.dist_to(b).leading_zeros(), prefix);
+        assert!((0..prefix).all(|i| a.bit(i) == b.bit(i)));
+        if prefix < 256 {
+            assert_ne!(a.bit(prefix), b.bit(prefix));
+            assert_eq!(a.bucket_index(b), Some(255 - prefix as usize));
+        } else {
+            assert_eq!(a, b);
+            assert_eq!(a.bucket_index(b), None);
+        }
+        assert_eq!(a.bucket_index(b), b.bucket_index(a));
+        assert_eq!(a.bucket_index(b), a.dist_to(b).level().map(usize::from));
+    }
+}
+
+#[test]
+fn distances_and_levels() {
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(2);
+    for _ in 0..1000 {
+        let [a, b, c] = [(); 3].map(|()| Tag::from_bytes(rng.gen()));
+        // XOR distances compose exactly, so a detour can't put a tag in a further bucket than both legs
+        assert_eq!(a.dist_to(c), a.dist_to(b).dist_to(b.dist_to(c)));
+        assert!(a.bucket_index(c) <= a.bucket_index(b).max(b.bucket_index(c)));
+        // Closer tags are never in further buckets
+        if a.dist_to(b) <= a.dist_to(c) {
+            assert!(a.bucket_index(b) <= a.bucket_index(c));
+        }
+        assert_eq!(a.dist_to(a).leading_zeros(), 256);
+    }
+
+    let mut bytes = [0; 32];
+    assert_eq!(Tag::from_bytes(bytes).leading_zeros(), 256);
+    bytes[0] = 0x80;
+    assert_eq!(Tag::from_bytes(bytes).leading_zeros(), 0);
+    assert_eq!(Tag::from_bytes(bytes).level(), Some(255));
+    assert!(Tag::from_bytes(bytes).bit(0));
+    bytes = [0; 32];
+    bytes[31] = 1;
+    assert_eq!(Tag::from_bytes(bytes).leading_zeros(), 255);
+    assert_eq!(Tag::from_bytes(bytes).level(), Some(0));
+    assert!(Tag::from_bytes(bytes).bit(255));
+}
+
+#[test]
+fn zero_distance_has_no_level() {
+    let tag = Tag::generate();
+    assert_eq!(tag.dist_to(tag).level(), None);
+    assert_eq!(tag.bucket_index(tag), None);
+
+    // Differing in only the lowest bit is the closest that two distinct tags can be, which is not the same thing
+    let mut bytes = *tag;
+    bytes[31] ^= 1;
+    assert_eq!(tag.dist_to(Tag::from_bytes(bytes)).level(), Some(0));
+    assert_eq!(tag.bucket_index(Tag::from_bytes(bytes)), Some(0));
+
+    let mut bytes = *tag;
+    bytes[0] ^= 0x80;
+    assert_eq!(tag.bucket_index(Tag::from_bytes(bytes)), Some(255));
+    bytes[31] ^= 1;
+    assert_eq!(tag.bucket_index(Tag::from_bytes(bytes)), Some(255));
+}
+
+#[test]
+fn algorithm_prefixes() {
+    let data = b"the same bytes, hashed twice";
+    let sha3 = Tag::digest(data);
+    let blake3 = Tag::digest_with(HashAlgorithm::Blake3, data);
+    assert_eq!(sha3.algorithm(), HashAlgorithm::Sha3_256);
+    assert_eq!(blake3.algorithm(), HashAlgorithm::Blake3);
+    assert_eq!(*blake3, *blake3::hash(data).as_bytes());
+    assert_ne!(sha3, blake3);
+
+    for tag in [sha3, blake3] {
+        let hex = tag.to_string();
+        assert_eq!(hex.len(), 66);
+        assert_eq!(&hex[..2], format!("{:02x}", tag.algorithm().code()));
+        assert_eq!(Tag::try_from_hex(&hex), Ok(tag));
+        let json = serde_json::to_string(&tag).unwrap();
+        assert_eq!(json, format!("\"{}\"", hex));
+        assert_eq!(serde_json::from_str::<Tag>(&json).unwrap(), tag);
+
+        let mut hasher = TagHasher::with_algorithm(tag.algorithm());
+        hasher.update(&data[..10]);
+        hasher.update(&data[10..]);
+        assert_eq!(hasher.finish(), tag);
+    }
+
+    // Bare digests are from before tags had prefixes, when everything was SHA3-256
+    assert_eq!(Tag::try_from_hex(hex::encode(*sha3)), Ok(sha3));
+    assert!(Tag::try_from_hex(format!("ff{}", hex::encode(*sha3))).is_err());
+    assert!(Tag::try_from_hex(&sha3.to_string()[1..]).is_err());
+
+    // Distance only depends on the digests
+    assert_eq!(sha3.dist_to(blake3), Tag::from_bytes(*blake3).dist_to(sha3));
+    assert_eq!(blake3.dist_to(Tag::from_bytes(*blake3)).level(), None);
+}
+
+#[test]
+fn verification() {
+    let data = b"some data to check";
+    for algorithm in HashAlgorithm::ALL {
+        let tag = Tag::digest_with(algorithm, data);
+        assert!(tag.is_digest_of(data));
+        assert!(!tag.is_digest_of(b"some other data"));
+    }
+    // The same digest claimed by the wrong algorithm doesn't verify
+    let blake3 = Tag::digest_with(HashAlgorithm::Blake3, data);
+    assert!(!Tag::from_bytes(*blake3).is_digest_of(data));
+}
+
+#[tokio::test]
+async fn upload_with_algorithm() {
+    let mut nodes = Vec::<Arc<Node<mem::Mem>>>::new();
+    for _ in 0..2 {
+        let addr = mem::Addr::default();
+        nodes.push(
+            Node::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+                .await
+                .unwrap(),
+        );
+    }
+    nodes[0].discover_peer(None, nodes[1].addr()).await.unwrap();
+
+    // Whichever node ends up storing each blob, both can find and verify it
+    for i in 0..16u32 {
+        let data = i.to_le_bytes().repeat(1000).into_boxed_slice();
+        let tag = nodes[0]
+            .do_upload_with(HashAlgorithm::Blake3, data.clone().into())
+            .await
+            .unwrap();
+        assert_eq!(tag, Tag::digest_with(HashAlgorithm::Blake3, &data));
+        for node in &nodes {
+            assert_eq!(
+                node.do_download(tag).await.unwrap(),
+                Some(data.clone().into())
+            );
+        }
+        // The data isn't stored under its SHA3-256 tag
+        assert_eq!(nodes[1].do_download(Tag::digest(&data)).await, Ok(None));
+    }
+}
+
+#[test]
+fn parse_errors() {
+    let tag = Tag::digest(b"parse me");
+    let hex = tag.to_string();
+
+    // Forgiving of how tags tend to get pasted around
+    for input in [
+        hex.clone(),
+        hex.to_uppercase(),
+        format!("0x{}", hex),
+        format!("0X{}", hex),
+        format!("  {}\n", hex),
+        format!("\t0x{} ", hex.to_uppercase()),
+        hex[2..].to_string(),
+    ] {
+        assert_eq!(Tag::try_from_hex(&input), Ok(tag), "{:?}", input);
+    }
+
+    assert_eq!(
+        Tag::try_from_hex(""),
+        Err(TagParseError::WrongLength { got: 0 })
+    );
+    assert_eq!(
+        Tag::try_from_hex(&hex[1..]),
+        Err(TagParseError::WrongLength { got: 65 })
+    );
+    assert_eq!(
+        TagParseError::WrongLength { got: 65 }.to_string(),
+        "expected 64 or 66 hex digits, found 65 hex digits"
+    );
+    assert_eq!(
+        Tag::try_from_hex(format!("0x{}00", hex)),
+        Err(TagParseError::WrongLength { got: 68 })
+    );
+    assert_eq!(
+        Tag::try_from_hex("upload"),
+        Err(TagParseError::InvalidCharacter { at: 0 })
+    );
+    // Positions count from the start of the input, whitespace and prefix included
+    let mut bad = format!(" 0x{}", hex);
+    bad.replace_range(10..11, "g");
+    assert_eq!(
+        Tag::try_from_hex(&bad),
+        Err(TagParseError::InvalidCharacter { at: 10 })
+    );
+    assert_eq!(
+        Tag::try_from_hex("é".repeat(33)),
+        Err(TagParseError::InvalidCharacter { at: 0 })
+    );
+    assert_eq!(
+        Tag::try_from_hex(format!("7f{}", &hex[2..])),
+        Err(TagParseError::UnknownAlgorithm(0x7f))
+    );
+
+    // Serde reports the same reasons
+    let err = serde_json::from_str::<Tag>("\"0x12\"").unwrap_err();
+    assert!(err.to_string().contains("found 2 hex digits"), "{}", err);
+}
+
+#[test]
+fn tags_at_level() {
+    let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(2);
+    let mut bases = vec![Tag::from_bytes([0; 32]), Tag::from_bytes([0xff; 32])];
+    bases.extend((0..8).map(|_| Tag::from_bytes(rng.gen())));
+
+    for base in bases {
+        for level in 0..=255u16 {
+            let min = Tag::at_level_min(&base, level);
+            assert_eq!(base.dist_to(min).level(), Some(level));
+            assert_eq!(base.dist_to(min).leading_zeros(), 255 - level);
+            assert!(min.bit(255 - level) != base.bit(255 - level));
+            for i in (0..256).filter(|i| *i != 255 - level) {
+                assert_eq!(min.bit(i), base.bit(i));
+            }
+
+            // Every bit below the level should be free to take either value
+            let (mut ones, mut zeros) = ([false; 256], [false; 256]);
+            for _ in 0..32 {
+                let tag = Tag::random_at_level(&base, level, &mut rng);
+                assert_eq!(base.dist_to(tag).level(), Some(level));
+                assert_eq!(base.bucket_index(tag), Some(level as usize));
+                assert!(base.dist_to(min) <= base.dist_to(tag));
+                let dist = base.dist_to(tag);
+                for i in 0..256 {
+                    ones[i as usize] |= dist.bit(i);
+                    zeros[i as usize] |= !dist.bit(i);
+                }
+            }
+            let first_free = 256 - level as usize;
+            assert!(ones[first_free..].iter().all(|b| *b));
+            assert!(zeros[first_free..].iter().all(|b| *b));
+        }
+    }
+}
+
+#[test]
+#[should_panic]
+fn no_level_above_255() {
+    Tag::at_level_min(&Tag::generate(), 256);
+}
@@ -0,0 +1,287 @@
+use bytes::Bytes;
+use nettle::{
+    mem,
+    tree::{self, Layout, TreeError, TreeNode},
+    DownloadError, HashAlgorithm, Node, PrivateId, Tag, CHUNK_LEASE, GC_GRACE,
+};
+use std::sync::Arc;
+
+// Small enough that a short message makes a tree several levels deep
+const LAYOUT: Layout = Layout {
+    chunk_size: 4,
+    fanout: 3,
+    algorithm: HashAlgorithm::Sha3_256,
+};
+
+fn content() -> Bytes {
+    Bytes::from_static(b"a short message split across many tiny chunks")
+}
+
+// Nodes that are all peered with each other
+async fn mesh(count: usize) -> Vec<Arc<Node<mem::Mem>>> {
+    let mut nodes = Vec::new();
+    for _ in 0..count {
+        let addr = mem::Addr::default();
+        nodes.push(
+            Node::<mem::Mem>::new(PrivateId::generate(), addr.clone(), Vec::new(), addr.into())
+                .await
+                .unwrap(),
+        );
+    }
+    for a in &nodes {
+        for b in &nodes {
+            if a.id() != b.id() {
+                a.accept_peer(b.id(), b.addr()).await;
+            }
+        }
+    }
+    nodes
+}
+
+#[test]
+fn build() {
+    let data = content();
+    let (root, blobs) = tree::build(&data, LAYOUT);
+    assert_eq!(blobs.last().unwrap().0, root);
+    assert!(blobs.iter().all(|(tag, blob)| tag.is_digest_of(blob)));
+
+    // 45 bytes make 12 chunks, under 4 nodes, under 2 nodes, under the root
+    assert_eq!(blobs.len(), 12 + 4 + 2 + 1);
+    let node = TreeNode::decode(&blobs.last().unwrap().1).unwrap();
+    assert_eq!(node.depth, 3);
+    assert_eq!(node.len, data.len() as u64);
+    assert_eq!(node.children.len(), 2);
+    assert_eq!(node.encode(), blobs.last().unwrap().1);
+
+    // The same content always makes the same tree
+    assert_eq!(tree::build(&data, LAYOUT).0, root);
+    assert_ne!(
+        tree::build(
+            &data,
+            Layout {
+                chunk_size: 5,
+                ..LAYOUT
+            }
+        )
+        .0,
+        root
+    );
+
+    // Even empty content has a root above a chunk
+    let (_, blobs) = tree::build(&Bytes::new(), LAYOUT);
+    assert_eq!(blobs.len(), 2);
+    assert_eq!(
+        TreeNode::decode(&blobs[1].1).unwrap().children,
+        [blobs[0].0]
+    );
+}
+
+#[test]
+fn malformed_nodes() {
+    let node = TreeNode {
+        depth: 1,
+        len: 10,
+        children: vec![
+            Tag::digest(b"a"),
+            Tag::digest_with(HashAlgorithm::Blake3, b"b"),
+        ],
+    };
+    let encoded = node.encode();
+    assert_eq!(encoded.len(), 16 + 2 * 33);
+    assert_eq!(TreeNode::decode(&encoded), Ok(node.clone()));
+
+    let tampered = |i: usize, byte: u8| {
+        let mut bytes = encoded.to_vec();
+        bytes[i] = byte;
+        TreeNode::decode(&bytes)
+    };
+    assert_eq!(tampered(0, b'X'), Err(TreeError::NotANode));
+    assert_eq!(tampered(4, 2), Err(TreeError::UnsupportedVersion(2)));
+    assert_eq!(
+        tampered(5, 0),
+        Err(TreeError::WrongDepth {
+            expected: 1,
+            found: 0
+        })
+    );
+    assert_eq!(tampered(15, 0), Err(TreeError::Empty));
+    assert_eq!(tampered(15, 3), Err(TreeError::Truncated));
+    assert_eq!(tampered(15, 1), Err(TreeError::Trailing));
+    assert_eq!(tampered(16, 0xff), Err(TreeError::UnknownAlgorithm(0xff)));
+    assert_eq!(
+        TreeNode::decode(&encoded[..encoded.len() - 1]),
+        Err(TreeError::Truncated)
+    );
+    assert_eq!(TreeNode::decode(&encoded[..10]), Err(TreeError::Truncated));
+    assert_eq!(TreeNode::decode(b"plain data"), Err(TreeError::NotANode));
+}
+
+#[tokio::test]
+async fn tree_transfer() {
+    let nodes = mesh(4).await;
+    let data = content();
+    let root = nodes[0]
+        .upload_tree_with(data.clone(), LAYOUT)
+        .await
+        .unwrap();
+    assert_eq!(root, tree::build(&data, LAYOUT).0);
+    for node in &nodes {
+        assert_eq!(node.download_tree(root).await, Ok(Some(data.clone())));
+    }
+
+    // Plain uploads aren't trees
+    let plain = nodes[0].do_upload(data.clone()).await.unwrap();
+    assert_eq!(
+        nodes[1].download_tree(plain).await,
+        Err(DownloadError::Tree(TreeError::NotANode))
+    );
+    assert_eq!(nodes[1].download_tree(Tag::generate()).await, Ok(None));
+}
+
+// Store a tree on two replicas, with the blob at index `bad` corrupted on one of them and, if `everywhere`, on the
+// other too, then download it from a third node
+async fn corrupted(bad: usize, everywhere: bool) -> Result<Option<Bytes>, DownloadError> {
+    let nodes = mesh(3).await;
+    let (root, blobs) = tree::build(&content(), LAYOUT);
+    let (bad_tag, good) = &blobs[bad];
+    let mut corrupt = good.to_vec();
+    corrupt[0] ^= 1;
+    let corrupt = Bytes::from(corrupt);
+
+    let replicas = &nodes[1..];
+    assert!(replicas[0].save_data(*bad_tag, corrupt.clone()).await);
+    if everywhere {
+        assert!(replicas[1].save_data(*bad_tag, corrupt).await);
+    }
+    for replica in replicas {
+        for (tag, blob) in &blobs {
+            replica.save_data(*tag, blob.clone()).await;
+        }
+    }
+    nodes[0].download_tree(root).await
+}
+
+#[tokio::test]
+async fn corrupted_leaf() {
+    assert_eq!(corrupted(5, false).await, Ok(Some(content())));
+    assert_eq!(corrupted(5, true).await, Err(DownloadError::Integrity));
+}
+
+#[tokio::test]
+async fn corrupted_interior_node() {
+    // Blobs come bottom-up, so this is the first node on the level below the root
+    assert_eq!(corrupted(12 + 4, false).await, Ok(Some(content())));
+    assert_eq!(corrupted(12 + 4, true).await, Err(DownloadError::Integrity));
+    // And this is the root itself
+    assert_eq!(corrupted(12 + 4 + 2, false).await, Ok(Some(content())));
+    assert_eq!(
+        corrupted(12 + 4 + 2, true).await,
+        Err(DownloadError::Integrity)
+    );
+}
+
+// The number of copies of each of `tags` that `nodes` hold between them
+async fn copies(nodes: &[Arc<Node<mem::Mem>>], tags: &[(Tag, Bytes)]) -> usize {
+    let mut copies = 0;
+    for node in nodes {
+        for (tag, _) in tags {
+            if node.has_data(*tag).await {
+                copies += 1;
+            }
+        }
+    }
+    copies
+}
+
+#[tokio::test(start_paused = true)]
+async fn unrenewed_chunks_are_collected() {
+    // More nodes than replicas, so that most chunks are held by nodes that don't hold the root
+    let nodes = mesh(8).await;
+    let data = content();
+    let root = nodes[0]
+        .upload_tree_with(data.clone(), LAYOUT)
+        .await
+        .unwrap();
+    let unrelated = nodes[0]
+        .do_upload(Bytes::from_static(b"unrelated"))
+        .await
+        .unwrap();
+    // Blobs come bottom-up, with the root last
+    let blobs = tree::build(&data, LAYOUT).1;
+    let levels = [&blobs[18..], &blobs[16..18], &blobs[12..16], &blobs[..12]];
+    let mut apart = 0;
+    for node in &nodes {
+        if !node.has_data(root).await {
+            apart += copies(std::slice::from_ref(node), &blobs).await;
+        }
+    }
+    assert!(apart > 0);
+
+    // While the root is held anywhere, the chunks under it are renewed wherever they are
+    for _ in 0..4 {
+        tokio::time::advance(CHUNK_LEASE / 2).await;
+        let mut renewed = 0;
+        for node in &nodes {
+            renewed += node.renew_chunks().await;
+        }
+        assert!(renewed > 0);
+        for node in &nodes {
+            assert_eq!(node.collect_garbage(), 0);
+        }
+    }
+    assert_eq!(nodes[7].download_tree(root).await, Ok(Some(data.clone())));
+
+    // Once it's gone, each level goes in turn, since nothing renews the level below it any more
+    for node in &nodes {
+        node.remove_data(root).await;
+    }
+    for (i, level) in levels.iter().enumerate().skip(1) {
+        let held = copies(&nodes, level).await;
+        assert!(held > 0);
+        tokio::time::advance(CHUNK_LEASE + GC_GRACE).await;
+        for node in &nodes {
+            node.renew_chunks().await;
+        }
+        let mut collected = 0;
+        for node in &nodes {
+            collected += node.collect_garbage();
+        }
+        assert_eq!(collected, held);
+        for level in &levels[i + 1..] {
+            assert!(copies(&nodes, level).await > 0);
+        }
+    }
+    assert_eq!(copies(&nodes, &blobs).await, 0);
+    for node in &nodes {
+        if node.stats().entries > 0 {
+            assert!(node.has_data(unrelated).await);
+            assert_eq!(node.stats().entries, 1);
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn listed_data_outlives_the_listing() {
+    let nodes = mesh(2).await;
+    let (owner, other) = (&nodes[0], &nodes[1]);
+    let data = content();
+    let tag = owner.do_upload(data.clone()).await.unwrap();
+
+    // A tree node that lists somebody else's data doesn't make it a chunk that goes along with the node
+    let listing = TreeNode {
+        depth: 1,
+        len: data.len() as u64,
+        children: vec![tag],
+    };
+    let listing = other.do_upload(listing.encode()).await.unwrap();
+    for node in &nodes {
+        assert_eq!(node.stats().entries, 2);
+        assert!(node.remove_data(listing).await);
+    }
+    tokio::time::advance(CHUNK_LEASE + GC_GRACE).await;
+    for node in &nodes {
+        assert_eq!(node.collect_garbage(), 0);
+        assert!(node.has_data(tag).await);
+    }
+    assert_eq!(other.do_download(tag).await, Ok(Some(data)));
+}